@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    tonic_prost_build::compile_protos("proto/admin.proto")?;
+
+    #[cfg(feature = "spiffe")]
+    tonic_prost_build::compile_protos("proto/workload.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,535 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Request, Response,
+    header::{REFERER, USER_AGENT},
+};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    application::{BoxError, ProxyBody},
+    logger::{self, LogFile, Utc},
+};
+
+/// Entries waiting to be written. Past this entries are dropped rather than holding up
+/// requests behind a slow disk.
+const ACCESS_QUEUE_SIZE: usize = 16384;
+
+/// Sent to peers with every request, taken from the client when it sent one.
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// `[access_log]`: one line per request with type = "application", or per proxied session with
+/// type = "network", written to its own file apart from the application log and rotated by its
+/// own settings. Requests without an `X-Request-Id` are given one, which peers are sent along
+/// with the request.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    /// moves the file to `<path>.1` once it would grow past `capacity_mb`
+    #[serde(default)]
+    pub rotate: bool,
+    #[serde(default = "default_capacity_mb")]
+    pub capacity_mb: u64,
+    /// rotated files kept, the oldest is deleted past this
+    #[serde(default = "default_archives")]
+    pub archives: usize,
+}
+
+/// How entries are written. `common` and `combined` are the Common and Combined Log Formats
+/// with the duration in milliseconds, the peer and the request id after the usual fields,
+/// `json` is one object per line. Sessions have no request to show, so both text formats write
+/// them the same way, see [`SessionEntry`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from("jalb-access.log")
+}
+
+fn default_capacity_mb() -> u64 {
+    100
+}
+
+fn default_archives() -> usize {
+    5
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity_mb == 0 {
+            return Err("capacity_mb must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a request's line is made of.
+#[derive(Debug, Clone, Serialize)]
+struct AccessEntry {
+    /// when the request arrived
+    timestamp: String,
+    client: IpAddr,
+    method: String,
+    path: String,
+    protocol: &'static str,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    peer: Option<SocketAddr>,
+    request_id: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    #[serde(skip)]
+    start: SystemTime,
+}
+
+impl AccessEntry {
+    fn line(&self, format: AccessLogFormat) -> String {
+        if format == AccessLogFormat::Json {
+            return serde_json::to_string(self).expect("entries serialize");
+        }
+
+        let utc = Utc::from(self.start);
+        let mut line = format!(
+            "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} ",
+            self.client,
+            utc.day,
+            MONTHS[utc.month as usize - 1],
+            utc.year,
+            utc.hour,
+            utc.minute,
+            utc.second,
+            self.method,
+            quoted(&self.path),
+            self.protocol,
+            self.status,
+        );
+        match self.bytes {
+            0 => line.push('-'),
+            bytes => line.push_str(&bytes.to_string()),
+        }
+        if format == AccessLogFormat::Combined {
+            for header in [&self.referer, &self.user_agent] {
+                line.push_str(&format!(
+                    " \"{}\"",
+                    quoted(header.as_deref().unwrap_or("-"))
+                ));
+            }
+        }
+        let peer = self.peer.map_or("-".to_string(), |peer| peer.to_string());
+        line.push_str(&format!(
+            " {:.3} {} {}",
+            self.duration_ms, peer, self.request_id
+        ));
+        line
+    }
+}
+
+/// How a network session came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEnd {
+    ClientClose,
+    UpstreamClose,
+    /// the connect, idle or session timeout
+    Timeout,
+    /// closed through the admin api
+    Terminated,
+    Error,
+}
+
+impl SessionEnd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ClientClose => "client_close",
+            Self::UpstreamClose => "upstream_close",
+            Self::Timeout => "timeout",
+            Self::Terminated => "terminated",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// What a network session's line is made of. As text:
+/// `client - - [date] "TCP peer" end sent received duration_ms`.
+#[derive(Debug, Clone, Serialize)]
+struct SessionEntry {
+    /// when the connection was accepted
+    timestamp: String,
+    client: SocketAddr,
+    peer: Option<SocketAddr>,
+    /// bytes copied from the client to the peer
+    sent: u64,
+    /// bytes copied from the peer to the client
+    received: u64,
+    duration_ms: f64,
+    end: SessionEnd,
+    #[serde(skip)]
+    start: SystemTime,
+}
+
+impl SessionEntry {
+    fn line(&self, format: AccessLogFormat) -> String {
+        if format == AccessLogFormat::Json {
+            return serde_json::to_string(self).expect("entries serialize");
+        }
+
+        let utc = Utc::from(self.start);
+        let peer = self.peer.map_or("-".to_string(), |peer| peer.to_string());
+        format!(
+            "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"TCP {}\" {} {} {} {:.3}",
+            self.client,
+            utc.day,
+            MONTHS[utc.month as usize - 1],
+            utc.year,
+            utc.hour,
+            utc.minute,
+            utc.second,
+            peer,
+            self.end.name(),
+            self.sent,
+            self.received,
+            self.duration_ms,
+        )
+    }
+}
+
+/// `value` fit to go between double quotes.
+fn quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes one line per request or session to `[access_log] path` from a thread of its own, so
+/// connections never wait on the disk.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sender: mpsc::Sender<String>,
+    dropped: AtomicU64,
+    random: SystemRandom,
+}
+
+impl AccessLog {
+    /// `config` was checked by [`AccessLogConfig::validate`] when the config was loaded.
+    pub fn open(config: &AccessLogConfig) -> Result<Arc<Self>, io::Error> {
+        let max_size = config.rotate.then_some(config.capacity_mb * 1024 * 1024);
+        let mut file = LogFile::open(&config.path, max_size, config.archives)?;
+        let (sender, mut receiver) = mpsc::channel::<String>(ACCESS_QUEUE_SIZE);
+
+        let path = config.path.clone();
+        std::thread::spawn(move || {
+            while let Some(line) = receiver.blocking_recv() {
+                if let Err(e) = file.write(line.as_bytes()) {
+                    tracing::error!("failed to write access log {}: {}", path.display(), e);
+                }
+            }
+        });
+
+        Ok(Arc::new(Self {
+            format: config.format,
+            sender,
+            dropped: AtomicU64::new(0),
+            random: SystemRandom::new(),
+        }))
+    }
+
+    /// Starts the entry of `req`, giving it a request id unless the client sent one.
+    pub fn begin<B>(self: &Arc<Self>, req: &mut Request<B>, client: IpAddr) -> PendingEntry {
+        let request_id = match req.headers().get(&REQUEST_ID) {
+            Some(id) => String::from_utf8_lossy(id.as_bytes()).into_owned(),
+            None => {
+                let id = self.request_id();
+                let value = HeaderValue::from_str(&id).expect("hex is a header value");
+                req.headers_mut().insert(REQUEST_ID, value);
+                id
+            }
+        };
+        let header = |headers: &HeaderMap, name| {
+            let value = headers.get(name)?;
+            Some(String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+
+        let start = SystemTime::now();
+        PendingEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            entry: AccessEntry {
+                timestamp: logger::timestamp(start),
+                client,
+                method: req.method().to_string(),
+                path: req
+                    .uri()
+                    .path_and_query()
+                    .map_or("/".to_string(), |path| path.to_string()),
+                protocol: protocol(req.version()),
+                status: 0,
+                bytes: 0,
+                duration_ms: 0.0,
+                peer: None,
+                request_id,
+                referer: header(req.headers(), REFERER),
+                user_agent: header(req.headers(), USER_AGENT),
+                start,
+            },
+        }
+    }
+
+    /// Starts the entry of a session accepted from `client` just now and sent to `peer`.
+    pub fn begin_session(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        peer: Option<SocketAddr>,
+    ) -> PendingSession {
+        let start = SystemTime::now();
+        PendingSession {
+            log: self.clone(),
+            started: Instant::now(),
+            entry: SessionEntry {
+                timestamp: logger::timestamp(start),
+                client,
+                peer,
+                sent: 0,
+                received: 0,
+                duration_ms: 0.0,
+                end: SessionEnd::ClientClose,
+                start,
+            },
+        }
+    }
+
+    /// 16 random hex digits.
+    fn request_id(&self) -> String {
+        let mut bytes = [0; 8];
+        if self.random.fill(&mut bytes).is_err() {
+            // the system's randomness failing is not worth failing the request over
+            let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+            bytes = (nanos.unwrap_or_default().as_nanos() as u64).to_be_bytes();
+        }
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn write(&self, mut line: String) {
+        line.push('\n');
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Entries lost because the writer could not keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn protocol(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "HTTP/0.9",
+        http::Version::HTTP_10 => "HTTP/1.0",
+        http::Version::HTTP_2 => "HTTP/2.0",
+        http::Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// A request's entry, written once its response has been sent.
+pub struct PendingEntry {
+    log: Arc<AccessLog>,
+    started: Instant,
+    entry: AccessEntry,
+}
+
+impl PendingEntry {
+    /// Counts the bytes of `response`'s body as it is sent, writing the entry when it is done
+    /// or the client goes away.
+    pub fn finish(
+        mut self,
+        response: Response<ProxyBody>,
+        peer: Option<SocketAddr>,
+    ) -> Response<ProxyBody> {
+        self.entry.status = response.status().as_u16();
+        self.entry.peer = peer;
+        response.map(|inner| CountedBody { inner, entry: self }.boxed())
+    }
+}
+
+struct CountedBody {
+    inner: ProxyBody,
+    entry: PendingEntry,
+}
+
+impl Body for CountedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.entry.entry.bytes += data.len() as u64;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        let entry = &mut self.entry;
+        entry.entry.duration_ms = entry.started.elapsed().as_secs_f64() * 1000.0;
+        entry.log.write(entry.entry.line(entry.log.format));
+    }
+}
+
+/// A session's entry, written once the session is over.
+pub struct PendingSession {
+    log: Arc<AccessLog>,
+    started: Instant,
+    entry: SessionEntry,
+}
+
+impl PendingSession {
+    /// Writes the entry, `sent` and `received` counting the bytes from and to the client.
+    pub fn finish(mut self, (sent, received): (u64, u64), end: SessionEnd) {
+        self.entry.sent = sent;
+        self.entry.received = received;
+        self.entry.end = end;
+        self.entry.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.log.write(self.entry.line(self.log.format));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::*;
+    use crate::application::full;
+
+    #[tokio::test]
+    async fn test_access_log_lines() {
+        let path = std::env::temp_dir().join(format!("jalb-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config: AccessLogConfig =
+            toml::from_str(&format!("format = \"json\"\npath = {:?}", path)).unwrap();
+        assert!(config.validate().is_ok());
+        assert!(
+            toml::from_str::<AccessLogConfig>("capacity_mb = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        let log = AccessLog::open(&config).unwrap();
+
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut req = Request::get("/items?page=2")
+            .header(USER_AGENT, "curl/8.0")
+            .body(())
+            .unwrap();
+        let entry = log.begin(&mut req, client);
+        // peers are sent the id the entry is written with
+        let id = req.headers()[&REQUEST_ID].to_str().unwrap().to_string();
+        assert_eq!(id.len(), 16);
+        let peer = "10.0.1.1:8080".parse().unwrap();
+        let response = Response::new(full(Bytes::from("hello")));
+        let response = entry.finish(response, Some(peer));
+        response.into_body().collect().await.unwrap();
+
+        let mut contents = String::new();
+        for _ in 0..50 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if !contents.is_empty() {
+                break;
+            }
+        }
+        let line: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(line["client"], "10.0.0.1");
+        assert_eq!(line["path"], "/items?page=2");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 5);
+        assert_eq!(line["peer"], "10.0.1.1:8080");
+        assert_eq!(line["request_id"], id.as_str());
+        assert_eq!(log.dropped(), 0);
+        std::fs::remove_file(&path).unwrap();
+
+        // a client's own id is kept
+        let mut req = Request::get("/")
+            .header(&REQUEST_ID, "abc")
+            .body(())
+            .unwrap();
+        let mut entry = log.begin(&mut req, client);
+        assert_eq!(entry.entry.request_id, "abc");
+        entry.entry.start = std::time::UNIX_EPOCH + std::time::Duration::from_secs(971_211_336);
+        entry.entry.status = StatusCode::NOT_FOUND.as_u16();
+        entry.entry.user_agent = Some("say \"hi\"".to_string());
+        let line = entry.entry.line(AccessLogFormat::Combined);
+        assert!(
+            line.starts_with(concat!(
+                "10.0.0.1 - - [10/Oct/2000:20:55:36 +0000] \"GET / HTTP/1.1\" 404 - ",
+                "\"-\" \"say \\\"hi\\\"\" "
+            )),
+            "{}",
+            line
+        );
+        assert!(line.ends_with(" - abc"), "{}", line);
+        assert!(!entry.entry.line(AccessLogFormat::Common).contains("say"));
+
+        let client = "10.0.0.1:40000".parse().unwrap();
+        let mut session = log.begin_session(client, Some(peer));
+        session.entry.start = entry.entry.start;
+        session.entry.sent = 12;
+        session.entry.end = SessionEnd::UpstreamClose;
+        assert_eq!(
+            session.entry.line(AccessLogFormat::Combined),
+            concat!(
+                "10.0.0.1:40000 - - [10/Oct/2000:20:55:36 +0000] ",
+                "\"TCP 10.0.1.1:8080\" upstream_close 12 0 0.000"
+            )
+        );
+        let line: serde_json::Value =
+            serde_json::from_str(&session.entry.line(AccessLogFormat::Json)).unwrap();
+        assert_eq!(line["peer"], "10.0.1.1:8080");
+        assert_eq!(line["end"], "upstream_close");
+        assert_eq!(line["received"], 0);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    env, io,
+    ops::Range,
+    os::fd::{FromRawFd, RawFd},
+    process,
+};
+
+use socket2::{Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{config::Config, load_balancer::Listener};
+
+/// The first descriptor systemd passes, the rest follow on consecutively.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd under `Socket=` activation, `None` when jalb was started
+/// without any. They are made close-on-exec like `sd_listen_fds` does.
+pub fn inherited_sockets() -> Result<Option<Vec<Socket>>, io::Error> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let Some(range) = listen_fds(pid.as_deref(), fds.as_deref(), process::id())? else {
+        return Ok(None);
+    };
+
+    let mut sockets = Vec::with_capacity(range.len());
+    for fd in range {
+        // SAFETY: LISTEN_PID names this process, so systemd handed it these descriptors and
+        // nothing else in jalb has taken ownership of them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+        sockets.push(socket);
+    }
+
+    Ok(Some(sockets))
+}
+
+/// The descriptors announced by `LISTEN_PID` and `LISTEN_FDS`. Variables meant for another
+/// process, e.g. a parent that didn't clear them, are ignored.
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    own_pid: u32,
+) -> Result<Option<Range<RawFd>>, io::Error> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Ok(None);
+    }
+
+    let count = fds.parse::<RawFd>().ok().filter(|&count| count >= 0);
+    let count = count.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS={} is not a number of sockets", fds),
+        )
+    })?;
+
+    Ok((count > 0).then(|| SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count))
+}
+
+/// Turns inherited sockets into listeners. Each goes to the backend of the `[[listener]]` with
+/// its address, or the default backend when none matches.
+pub fn tcp_listeners(cfg: &Config, sockets: Vec<Socket>) -> Result<Vec<Listener>, io::Error> {
+    let configured = cfg.listeners();
+    let mut listeners = Vec::with_capacity(sockets.len());
+
+    for socket in sockets {
+        let addr = socket.local_addr()?.as_socket();
+        let Some(addr) = addr.filter(|_| socket.r#type().ok() == Some(Type::STREAM)) else {
+            return Err(not_inet("a tcp"));
+        };
+        if !socket.is_listener()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("socket on {} passed by systemd is not listening", addr),
+            ));
+        }
+
+        socket.set_nonblocking(true)?;
+        let backend = configured
+            .iter()
+            .find(|listener| listener.address == addr)
+            .and_then(|listener| listener.backend.clone());
+        let socket = TcpListener::from_std(socket.into())?;
+        listeners.push(Listener::new(socket, backend));
+    }
+
+    for listener in &configured {
+        if listener.backend.is_some()
+            && !listeners
+                .iter()
+                .any(|l| l.local_addr().ok() == Some(listener.address))
+        {
+            tracing::warn!(
+                "{} was not passed by systemd, its connections aren't served",
+                listener.address
+            );
+        }
+    }
+
+    Ok(listeners)
+}
+
+/// The single datagram socket udp serves, from inherited sockets.
+pub fn udp_socket(mut sockets: Vec<Socket>) -> Result<UdpSocket, io::Error> {
+    let socket = match sockets.pop() {
+        Some(socket) if sockets.is_empty() => socket,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp serves a single socket, systemd passed several",
+            ));
+        }
+    };
+
+    let inet = socket.local_addr()?.as_socket().is_some();
+    if !inet || socket.r#type()? != Type::DGRAM {
+        return Err(not_inet("a udp"));
+    }
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn not_inet(kind: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("a socket passed by systemd is not {} socket", kind),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), Some(3..5));
+        assert_eq!(listen_fds(None, None, 42).unwrap(), None);
+        // meant for another process
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), None);
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+        assert_eq!(listen_fds(Some("42"), Some("0"), 42).unwrap(), None);
+    }
+}
@@ -0,0 +1,999 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::Arc,
+};
+
+use http::{Method, Request, Response, StatusCode, header};
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{
+    bluegreen::BlueGreen,
+    clients::{ClientOrder, TopClient},
+    config::{AdminPermission, AdminToken, LoadBalancerStrategy},
+    connections::{ConnectionRegistry, LiveConnection},
+    dns::{self, NameLookups},
+    events::{EventKind, EventLog},
+    flows::FlowTrace,
+    health::HealthGuard,
+    histogram::HistogramSnapshot,
+    pages::Maintenance,
+    peer::Peer,
+    security::Security,
+    selector::SelectionDistribution,
+    split::{SplitArm, TrafficSplit},
+};
+
+const DEFAULT_EVENT_LIMIT: usize = 100;
+const DEFAULT_CONNECTION_LIMIT: usize = 100;
+const DEFAULT_CLIENT_LIMIT: usize = 10;
+const DEFAULT_FLOW_LIMIT: usize = 20;
+
+pub struct AdminState {
+    pub events: Arc<EventLog>,
+    /// the pool as of the latest reload
+    pub peers: watch::Receiver<Vec<Arc<Peer>>>,
+    /// the effective config as TOML, as of the latest reload
+    pub config: watch::Receiver<String>,
+    /// routes splitting their requests between backends, as of the latest reload
+    pub splits: watch::Receiver<Vec<Arc<TrafficSplit>>>,
+    /// routes with a standby pool, as of the latest reload
+    pub blue_greens: watch::Receiver<Vec<Arc<BlueGreen>>>,
+    /// routes with a maintenance page, as of the latest reload
+    pub maintenances: watch::Receiver<Vec<Arc<Maintenance>>>,
+    /// the security lists and policies, as of the latest reload
+    pub security: watch::Receiver<Security>,
+    pub connections: Arc<ConnectionRegistry>,
+    pub health: Arc<HealthGuard>,
+    /// true once the listeners are accepting
+    pub listening: watch::Receiver<bool>,
+    /// the pools `/readyz` needs a healthy peer in
+    pub required_pools: Vec<String>,
+    /// what `/pools` holds the selections against
+    pub strategy: LoadBalancerStrategy,
+    pub auth: AdminAuth,
+}
+
+/// Bearer token check in front of every admin request.
+#[derive(Debug, Clone, Default)]
+pub struct AdminAuth {
+    tokens: Vec<AdminToken>,
+}
+
+impl AdminAuth {
+    pub fn new(tokens: Vec<AdminToken>) -> Self {
+        Self { tokens }
+    }
+
+    /// Checks an `Authorization` header value against the configured tokens. Without any tokens
+    /// configured reads are open and mutations are refused.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        needed: AdminPermission,
+    ) -> Result<(), StatusCode> {
+        if self.tokens.is_empty() {
+            return match needed {
+                AdminPermission::Read => Ok(()),
+                AdminPermission::Write => Err(StatusCode::FORBIDDEN),
+            };
+        }
+
+        let presented = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let token = self
+            .tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.expose().as_bytes(), presented.as_bytes()))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if token.permission < needed {
+            tracing::warn!(
+                "admin token {} lacks {:?} permission",
+                token.name.as_deref().unwrap_or("<unnamed>"),
+                needed
+            );
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+pub(crate) struct PeerView {
+    pub address: String,
+    pub weight: u32,
+    pub live: bool,
+    pub ready: bool,
+    pub active_connections: u64,
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub errors: u64,
+    pub request_timeouts: u64,
+    pub connect_latency: LatencyView,
+    pub session_duration: LatencyView,
+}
+
+impl From<&Peer> for PeerView {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            address: peer.address.as_string(),
+            weight: peer.weight(),
+            live: peer.is_live(),
+            ready: peer.is_ready(),
+            active_connections: peer.stats().active_connections(),
+            connections: peer.stats().connections(),
+            bytes_sent: peer.stats().bytes_sent(),
+            bytes_received: peer.stats().bytes_received(),
+            errors: peer.stats().errors(),
+            request_timeouts: peer.request_timeouts(),
+            connect_latency: LatencyView::from(&peer.stats().connect_latency().snapshot()),
+            session_duration: LatencyView::from(&peer.stats().session_duration().snapshot()),
+        }
+    }
+}
+
+/// Quantiles of a histogram, in milliseconds.
+#[derive(Serialize)]
+pub(crate) struct LatencyView {
+    pub count: u64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl From<&HistogramSnapshot> for LatencyView {
+    fn from(snapshot: &HistogramSnapshot) -> Self {
+        let ms = |quantile| snapshot.quantile(quantile).as_secs_f64() * 1000.0;
+        Self {
+            count: snapshot.count(),
+            p50_ms: ms(0.5),
+            p90_ms: ms(0.9),
+            p99_ms: ms(0.99),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConnectionsView {
+    pub total: usize,
+    /// open sessions per peer address
+    pub by_peer: BTreeMap<String, usize>,
+    /// the oldest sessions, up to the requested limit
+    pub oldest: Vec<ConnectionView>,
+}
+
+#[derive(Serialize)]
+struct ClientsView {
+    /// clients with counters, at most `MAX_CLIENTS`
+    tracked: usize,
+    top: Vec<TopClient>,
+}
+
+#[derive(Serialize)]
+struct FlowsView {
+    /// 1 in how many tcp connections are traced, 0 while off
+    sample: u64,
+    flows: Vec<FlowTrace>,
+}
+
+#[derive(Serialize)]
+struct DnsView {
+    #[serde(flatten)]
+    lookups: NameLookups,
+    duration: LatencyView,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConnectionView {
+    /// what `PUT /connections/terminate` takes
+    pub id: u64,
+    pub client: String,
+    pub peer: String,
+    pub open_seconds: u64,
+    /// bytes from the client so far
+    pub bytes_sent: u64,
+    /// bytes to the client so far
+    pub bytes_received: u64,
+}
+
+impl ConnectionView {
+    fn of(connection: &LiveConnection) -> Self {
+        let (bytes_sent, bytes_received) = connection.transfer.copied();
+        Self {
+            id: connection.id,
+            client: connection.client.to_string(),
+            peer: connection.peer.address.as_string(),
+            open_seconds: connection.started.elapsed().as_secs(),
+            bytes_sent,
+            bytes_received,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct PoolView {
+    pub backend: String,
+    pub peers: Vec<String>,
+    pub connect_latency: LatencyView,
+    pub session_duration: LatencyView,
+    pub selections: SelectionDistribution,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SplitView {
+    pub route: usize,
+    pub backends: Vec<SplitArmView>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SplitArmView {
+    pub backend: String,
+    pub weight: u32,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+impl From<&TrafficSplit> for SplitView {
+    fn from(split: &TrafficSplit) -> Self {
+        Self {
+            route: split.route(),
+            backends: split
+                .arms()
+                .iter()
+                .map(|a| SplitArmView::from(a.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+impl From<&SplitArm> for SplitArmView {
+    fn from(arm: &SplitArm) -> Self {
+        Self {
+            backend: arm.backend().to_string(),
+            weight: arm.weight(),
+            requests: arm.requests(),
+            errors: arm.errors(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct BlueGreenView {
+    pub route: usize,
+    pub active: String,
+    pub standby: String,
+}
+
+impl From<&BlueGreen> for BlueGreenView {
+    fn from(blue_green: &BlueGreen) -> Self {
+        Self {
+            route: blue_green.route(),
+            active: blue_green.active().to_string(),
+            standby: blue_green.standby().to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MaintenanceView {
+    pub route: usize,
+    pub enabled: bool,
+}
+
+impl From<&Maintenance> for MaintenanceView {
+    fn from(maintenance: &Maintenance) -> Self {
+        Self {
+            route: maintenance.route(),
+            enabled: maintenance.enabled(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadinessView {
+    ready: bool,
+    listening: bool,
+    /// healthy peers in each required pool
+    pools: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+pub async fn serve(listener: TcpListener, state: Arc<AdminState>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, state.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::error!("admin connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    state: Arc<AdminState>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let query = parse_query(req.uri().query());
+
+    // orchestrators probe without a token, and neither answer says anything worth protecting
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => return Ok(json_response(StatusCode::OK, &"ok")),
+        (&Method::GET, "/readyz") => return Ok(readiness(&state)),
+        _ => {}
+    }
+
+    let needed = match *req.method() {
+        Method::GET | Method::HEAD => AdminPermission::Read,
+        _ => AdminPermission::Write,
+    };
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    if let Err(status) = state.auth.authorize(authorization, needed) {
+        let message = match status {
+            StatusCode::UNAUTHORIZED => "missing or invalid bearer token",
+            _ => "token does not permit this operation",
+        };
+        return Ok(error_response(status, message));
+    }
+
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/events") => events(&state, &query),
+        (&Method::GET, "/transitions") => transitions(&state, &query),
+        (&Method::GET, "/status") => status(&state),
+        (&Method::GET, "/peers") => peers(&state),
+        (&Method::GET, "/pools") => pools(&state),
+        (&Method::GET, "/config") => config(&state),
+        (&Method::PUT, "/peers/weight") => set_peer_weight(&state, &query),
+        (&Method::GET, "/splits") => splits(&state),
+        (&Method::PUT, "/splits/weight") => set_split_weight(&state, &query),
+        (&Method::GET, "/blue-green") => blue_greens(&state),
+        (&Method::PUT, "/blue-green/switch") => switch_pool(&state, &query),
+        (&Method::GET, "/maintenance") => maintenances(&state),
+        (&Method::PUT, "/maintenance") => set_maintenance(&state, &query),
+        (&Method::GET, "/rejections") => {
+            json_response(StatusCode::OK, &state.security.borrow().rejections())
+        }
+        (&Method::GET, "/security") => {
+            json_response(StatusCode::OK, &state.security.borrow().lists())
+        }
+        (&Method::GET, "/connections") => connections(&state, &query),
+        (&Method::PUT, "/connections/terminate") => terminate_connection(&state, &query),
+        (&Method::GET, "/clients") => clients(&state, &query),
+        (&Method::GET, "/flows") => flows(&state, &query),
+        (&Method::PUT, "/flows/sample") => set_flow_sample(&state, &query),
+        (&Method::GET, "/dns") => dns_lookups(),
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+
+    Ok(response)
+}
+
+/// `GET /events?limit=N&kind=reject`
+fn events(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_EVENT_LIMIT,
+    };
+
+    let kind = match query.get("kind") {
+        Some(name) => match EventKind::from_name(name) {
+            Some(kind) => Some(kind),
+            None => return error_response(StatusCode::BAD_REQUEST, "unknown event kind"),
+        },
+        None => None,
+    };
+
+    json_response(StatusCode::OK, &state.events.recent(limit, kind))
+}
+
+/// `GET /transitions?limit=N&peer=10.0.0.1:8080`, each peer's probes going up or down.
+fn transitions(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_EVENT_LIMIT,
+    };
+    let peer = query.get("peer").map(String::as_str);
+
+    json_response(StatusCode::OK, &state.events.transitions(limit, peer))
+}
+
+/// `GET /status`, answering 503 while below `min_healthy_peers` so orchestration can react.
+fn status(state: &AdminState) -> Response<Full<Bytes>> {
+    let status = state.health.status();
+    let code = if status.degraded {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    json_response(code, &status)
+}
+
+/// `GET /readyz`, answering 503 until the listeners accept and while any required pool has no
+/// peer passing its checks.
+fn readiness(state: &AdminState) -> Response<Full<Bytes>> {
+    let mut pools: BTreeMap<String, usize> = state
+        .required_pools
+        .iter()
+        .map(|backend| (backend.clone(), 0))
+        .collect();
+    for peer in state.peers.borrow().iter() {
+        if peer.is_live()
+            && peer.is_ready()
+            && let Some(healthy) = pools.get_mut(&peer.backend)
+        {
+            *healthy += 1;
+        }
+    }
+
+    let listening = *state.listening.borrow();
+    let ready = listening && pools.values().all(|healthy| *healthy > 0);
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = ReadinessView {
+        ready,
+        listening,
+        pools,
+    };
+    json_response(code, &readiness)
+}
+
+/// `GET /peers`
+fn peers(state: &AdminState) -> Response<Full<Bytes>> {
+    let peers: Vec<PeerView> = state
+        .peers
+        .borrow()
+        .iter()
+        .map(|p| PeerView::from(p.as_ref()))
+        .collect();
+    json_response(StatusCode::OK, &peers)
+}
+
+/// `GET /pools`, the latencies of each backend's peers summed up and how its selections spread
+/// over them.
+fn pools(state: &AdminState) -> Response<Full<Bytes>> {
+    let mut pools: BTreeMap<String, Vec<Arc<Peer>>> = BTreeMap::new();
+    for peer in state.peers.borrow().iter() {
+        pools.entry(peer.backend.clone()).or_default().push(peer.clone());
+    }
+    let pools: Vec<PoolView> = pools
+        .into_iter()
+        .map(|(backend, peers)| {
+            let mut connect = HistogramSnapshot::default();
+            let mut session = HistogramSnapshot::default();
+            for peer in &peers {
+                connect.merge(&peer.stats().connect_latency().snapshot());
+                session.merge(&peer.stats().session_duration().snapshot());
+            }
+            PoolView {
+                backend,
+                peers: peers.iter().map(|peer| peer.address.as_string()).collect(),
+                connect_latency: LatencyView::from(&connect),
+                session_duration: LatencyView::from(&session),
+                selections: SelectionDistribution::of(state.strategy, &peers),
+            }
+        })
+        .collect();
+    json_response(StatusCode::OK, &pools)
+}
+
+/// `GET /connections?limit=N`, the sessions open right now.
+fn connections(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_CONNECTION_LIMIT,
+    };
+
+    let live = state.connections.live();
+    let mut by_peer = BTreeMap::new();
+    for connection in &live {
+        *by_peer
+            .entry(connection.peer.address.as_string())
+            .or_default() += 1;
+    }
+    let view = ConnectionsView {
+        total: live.len(),
+        by_peer,
+        oldest: live
+            .iter()
+            .take(limit)
+            .map(ConnectionView::of)
+            .collect(),
+    };
+    json_response(StatusCode::OK, &view)
+}
+
+/// `PUT /connections/terminate?id=N`, closing the session with both its client and peer. It
+/// is answered with the session as it was just before.
+fn terminate_connection(
+    state: &AdminState,
+    query: &HashMap<String, String>,
+) -> Response<Full<Bytes>> {
+    let Some(Ok(id)) = query.get("id").map(|id| id.parse::<u64>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid id");
+    };
+    let Some(connection) = state.connections.terminate(id) else {
+        return error_response(StatusCode::NOT_FOUND, "unknown connection");
+    };
+
+    let view = ConnectionView::of(&connection);
+    state.events.record(
+        EventKind::Reject,
+        format!(
+            "terminated connection {} from {} to {}",
+            view.id, view.client, view.peer
+        ),
+    );
+    json_response(StatusCode::OK, &view)
+}
+
+/// `GET /clients?limit=N&by=bytes`, the clients with the most bytes, connections, rejections or
+/// rejections over a rate limit.
+fn clients(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_CLIENT_LIMIT,
+    };
+    let order = match query.get("by") {
+        Some(name) => match ClientOrder::from_name(name) {
+            Some(order) => order,
+            None => return error_response(StatusCode::BAD_REQUEST, "unknown client order"),
+        },
+        None => ClientOrder::default(),
+    };
+
+    let clients = state.connections.clients();
+    let view = ClientsView {
+        tracked: clients.len(),
+        top: clients.top(limit, order),
+    };
+    json_response(StatusCode::OK, &view)
+}
+
+/// `GET /flows?limit=N`, the latest sampled connections step by step, newest first.
+fn flows(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_FLOW_LIMIT,
+    };
+
+    let tracer = state.connections.flows();
+    let view = FlowsView {
+        sample: tracer.sample(),
+        flows: tracer.traces(limit),
+    };
+    json_response(StatusCode::OK, &view)
+}
+
+/// `GET /dns`, the lookups of every URL and `host:port` peer's name since start.
+fn dns_lookups() -> Response<Full<Bytes>> {
+    let view: Vec<DnsView> = dns::lookups()
+        .names()
+        .into_iter()
+        .map(|lookups| DnsView {
+            duration: LatencyView::from(&lookups.duration),
+            lookups,
+        })
+        .collect();
+    json_response(StatusCode::OK, &view)
+}
+
+/// `PUT /flows/sample?every=N`, tracing 1 in every N connections until the next reload, none
+/// with 0.
+fn set_flow_sample(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let Some(Ok(every)) = query.get("every").map(|n| n.parse::<u64>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid every");
+    };
+
+    state.connections.flows().set_sample(every);
+    flows(state, &HashMap::new())
+}
+
+/// `GET /config`, what `jalb config dump` prints. After a reload it shows the reloaded file, even
+/// for settings that only change on restart.
+fn config(state: &AdminState) -> Response<Full<Bytes>> {
+    let dump = state.config.borrow().clone();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/toml")
+        .body(Full::new(Bytes::from(dump)))
+        .unwrap()
+}
+
+/// `PUT /peers/weight?address=10.0.0.1:8080&weight=N`, applied from the next selection on.
+fn set_peer_weight(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let Some(address) = query.get("address") else {
+        return error_response(StatusCode::BAD_REQUEST, "missing address");
+    };
+    let Some(Ok(weight)) = query.get("weight").map(|w| w.parse::<u32>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid weight");
+    };
+    let peer = state
+        .peers
+        .borrow()
+        .iter()
+        .find(|p| p.address.as_string() == *address)
+        .cloned();
+    let Some(peer) = peer else {
+        return error_response(StatusCode::NOT_FOUND, "unknown peer");
+    };
+
+    let previous = peer.set_weight(weight);
+    if previous != weight {
+        state.events.record(
+            EventKind::PeerTransition,
+            format!(
+                "weight of {} changed from {} to {}",
+                address, previous, weight
+            ),
+        );
+    }
+
+    json_response(StatusCode::OK, &PeerView::from(peer.as_ref()))
+}
+
+/// `GET /splits`, each split route's backends with their weights and what they answered.
+fn splits(state: &AdminState) -> Response<Full<Bytes>> {
+    let splits: Vec<SplitView> = state
+        .splits
+        .borrow()
+        .iter()
+        .map(|s| SplitView::from(s.as_ref()))
+        .collect();
+    json_response(StatusCode::OK, &splits)
+}
+
+/// `PUT /splits/weight?route=N&backend=canary&weight=N`, applied from the next request on
+/// until the next reload.
+fn set_split_weight(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let Some(Ok(route)) = query.get("route").map(|r| r.parse::<usize>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid route");
+    };
+    let Some(backend) = query.get("backend") else {
+        return error_response(StatusCode::BAD_REQUEST, "missing backend");
+    };
+    let Some(Ok(weight)) = query.get("weight").map(|w| w.parse::<u32>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid weight");
+    };
+    let split = state
+        .splits
+        .borrow()
+        .iter()
+        .find(|s| s.route() == route)
+        .cloned();
+    let Some(split) = split else {
+        return error_response(StatusCode::NOT_FOUND, "no split route with that number");
+    };
+    let Some(arm) = split.arm(backend) else {
+        return error_response(StatusCode::NOT_FOUND, "backend is not part of the split");
+    };
+
+    let previous = arm.set_weight(weight);
+    if previous != weight {
+        state.events.record(
+            EventKind::PeerTransition,
+            format!(
+                "weight of {} in the split of route {} changed from {} to {}",
+                backend, route, previous, weight
+            ),
+        );
+    }
+
+    json_response(StatusCode::OK, &SplitView::from(split.as_ref()))
+}
+
+/// `GET /blue-green`, the active and standby pool of each blue-green route.
+fn blue_greens(state: &AdminState) -> Response<Full<Bytes>> {
+    let blue_greens: Vec<BlueGreenView> = state
+        .blue_greens
+        .borrow()
+        .iter()
+        .map(|b| BlueGreenView::from(b.as_ref()))
+        .collect();
+    json_response(StatusCode::OK, &blue_greens)
+}
+
+/// `PUT /blue-green/switch?route=N`, making the route's standby pool active until the next
+/// reload. With `&backend=name` it only switches when that pool isn't active already, so
+/// repeating the call is harmless.
+fn switch_pool(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let Some(Ok(route)) = query.get("route").map(|r| r.parse::<usize>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid route");
+    };
+    let blue_green = state
+        .blue_greens
+        .borrow()
+        .iter()
+        .find(|b| b.route() == route)
+        .cloned();
+    let Some(blue_green) = blue_green else {
+        return error_response(StatusCode::NOT_FOUND, "no blue-green route with that number");
+    };
+
+    let previous = blue_green.active().to_string();
+    match query.get("backend") {
+        Some(backend) if *backend == previous => {}
+        Some(backend) if *backend != blue_green.standby() => {
+            return error_response(StatusCode::NOT_FOUND, "backend is not one of the route's");
+        }
+        _ => {
+            let active = blue_green.switch();
+            state.events.record(
+                EventKind::PeerTransition,
+                format!("route {} switched from {} to {}", route, previous, active),
+            );
+        }
+    }
+
+    json_response(StatusCode::OK, &BlueGreenView::from(blue_green.as_ref()))
+}
+
+/// `GET /maintenance`, whether each route with a maintenance page is in maintenance.
+fn maintenances(state: &AdminState) -> Response<Full<Bytes>> {
+    let maintenances: Vec<MaintenanceView> = state
+        .maintenances
+        .borrow()
+        .iter()
+        .map(|m| MaintenanceView::from(m.as_ref()))
+        .collect();
+    json_response(StatusCode::OK, &maintenances)
+}
+
+/// `PUT /maintenance?route=N&enabled=true`, putting the route in maintenance or taking it out
+/// until the next reload.
+fn set_maintenance(state: &AdminState, query: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    let Some(Ok(route)) = query.get("route").map(|r| r.parse::<usize>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid route");
+    };
+    let Some(Ok(enabled)) = query.get("enabled").map(|e| e.parse::<bool>()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid enabled");
+    };
+    let maintenance = state
+        .maintenances
+        .borrow()
+        .iter()
+        .find(|m| m.route() == route)
+        .cloned();
+    let Some(maintenance) = maintenance else {
+        return error_response(StatusCode::NOT_FOUND, "no route with that number has maintenance");
+    };
+    if !enabled && !maintenance.has_backend() {
+        return error_response(StatusCode::CONFLICT, "the route has no backend to forward to");
+    }
+
+    if maintenance.set_enabled(enabled) != enabled {
+        let change = if enabled { "put in" } else { "taken out of" };
+        state.events.record(
+            EventKind::PeerTransition,
+            format!("route {} {} maintenance", route, change),
+        );
+    }
+
+    json_response(StatusCode::OK, &MaintenanceView::from(maintenance.as_ref()))
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .map(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    match serde_json::to_vec(body) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(json)))
+            .unwrap(),
+        Err(e) => {
+            tracing::error!("failed to serialize admin response: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        }
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(status, &ErrorBody { error: message })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::flows::FlowStep;
+
+    fn token(token: &str, permission: AdminPermission) -> AdminToken {
+        AdminToken {
+            name: None,
+            token: token.into(),
+            permission,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_introspection() {
+        let mut peer = Peer::new("10.0.0.1:80").unwrap();
+        peer.backend = "web".to_string();
+        let peer = Arc::new(peer);
+        let connections = Arc::new(ConnectionRegistry::new());
+        let connection = connections.register("10.1.0.1:5000".parse().unwrap(), peer.clone());
+        connections.clients().rejected("10.1.0.2".parse().unwrap());
+        connections.flows().set_sample(1);
+        let flow = connections.flows().start("10.1.0.3:5000".parse().unwrap());
+        flow.unwrap().finish(FlowStep::Rejected {
+            reason: "blacklisted".to_string(),
+        });
+        let mut security = Security::new();
+        security.add_to_blacklist("10.9.9.9".parse().unwrap());
+        security.add_to_blacklist_for("10.8.8.8".parse().unwrap(), Duration::from_secs(60));
+        let (_security, security) = watch::channel(security);
+        let (_peers, peers) = watch::channel(vec![peer.clone()]);
+        let listening = watch::Sender::new(false);
+        let state = Arc::new(AdminState {
+            events: Arc::new(EventLog::new(16)),
+            peers: peers.clone(),
+            config: watch::channel(String::new()).1,
+            splits: watch::channel(Vec::new()).1,
+            blue_greens: watch::channel(Vec::new()).1,
+            maintenances: watch::channel(Vec::new()).1,
+            security,
+            connections,
+            health: Arc::new(HealthGuard::new(0, false, peers)),
+            listening: listening.subscribe(),
+            required_pools: vec!["web".to_string()],
+            strategy: LoadBalancerStrategy::RoundRobin,
+            auth: AdminAuth::new(vec![
+                token("reader", AdminPermission::Read),
+                token("operator", AdminPermission::Write),
+            ]),
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state));
+        let call = |method: &'static str, path: &'static str, token: &'static str| async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "{} {} HTTP/1.1\r\nHost: jalb\r\nAuthorization: Bearer {}\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                method, path, token
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let get = |path, token| call("GET", path, token);
+
+        assert!(get("/security", "wrong").await.starts_with("HTTP/1.1 401"));
+        let response = get("/security", "reader").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(r#""blacklist":["10.9.9.9"]"#), "{}", response);
+        assert!(response.contains(r#""timed_bans":{"10.8.8.8":59"#), "{}", response);
+        let response = get("/connections", "reader").await;
+        assert!(response.contains(r#""total":1"#), "{}", response);
+        assert!(response.contains(r#""by_peer":{"10.0.0.1:80":1}"#), "{}", response);
+        assert!(response.contains(r#""client":"10.1.0.1:5000""#), "{}", response);
+        let response = get("/connections?limit=none", "reader").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        let terminate = "/connections/terminate?id=0";
+        let response = call("PUT", terminate, "reader").await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        let response = call("PUT", terminate, "operator").await;
+        assert!(response.contains(r#""id":0,"client":"10.1.0.1:5000""#), "{}", response);
+        connection.terminated().await;
+        let response = call("PUT", "/connections/terminate?id=7", "operator").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        let response = get("/clients?by=rejections", "reader").await;
+        let top = r#""top":[{"client":"10.1.0.2","connections":0"#;
+        assert!(response.contains(top), "{}", response);
+        assert!(get("/clients?by=age", "reader").await.starts_with("HTTP/1.1 400"));
+        let response = get("/flows", "reader").await;
+        assert!(response.contains(r#""sample":1"#), "{}", response);
+        assert!(response.contains(r#""step":"rejected","reason":"blacklisted""#), "{}", response);
+        dns::lookups().record("admin.test:80", Duration::from_millis(1), Ok(2));
+        let response = get("/dns", "reader").await;
+        let lookups = r#""name":"admin.test:80","lookups":1,"failures":0,"records":2"#;
+        assert!(response.contains(lookups), "{}", response);
+
+        // probes need no token
+        assert!(get("/healthz", "").await.starts_with("HTTP/1.1 200 OK"));
+        let response = get("/readyz", "").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.contains(r#""listening":false"#), "{}", response);
+        listening.send_replace(true);
+        let response = get("/readyz", "").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(r#""pools":{"web":1}"#), "{}", response);
+        peer.set_ready(false);
+        let response = get("/readyz", "").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    }
+
+    #[test]
+    fn test_admin_auth() {
+        let open = AdminAuth::default();
+        assert!(open.authorize(None, AdminPermission::Read).is_ok());
+        assert_eq!(
+            open.authorize(None, AdminPermission::Write),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        let auth = AdminAuth::new(vec![
+            token("reader", AdminPermission::Read),
+            token("operator", AdminPermission::Write),
+        ]);
+        assert_eq!(
+            auth.authorize(None, AdminPermission::Read),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer wrong"), AdminPermission::Read),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert!(
+            auth.authorize(Some("Bearer reader"), AdminPermission::Read)
+                .is_ok()
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer reader"), AdminPermission::Write),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert!(
+            auth.authorize(Some("Bearer operator"), AdminPermission::Write)
+                .is_ok()
+        );
+        assert!(
+            auth.authorize(Some("Bearer operator"), AdminPermission::Read)
+                .is_ok()
+        );
+    }
+}
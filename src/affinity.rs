@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use http::{HeaderMap, HeaderValue, header::COOKIE};
+use ring::{hmac, rand::SystemRandom};
+
+use crate::{config::AffinityConfig, peer::Peer};
+
+/// Pins clients of the application balancer to a peer with a cookie naming it. The cookie holds
+/// a MAC of the peer's address rather than the address, so it neither shows clients where
+/// their requests go nor can be made up to pick a peer.
+pub struct CookieAffinity {
+    config: AffinityConfig,
+    key: hmac::Key,
+}
+
+impl CookieAffinity {
+    pub fn from_config(config: &AffinityConfig) -> Self {
+        let key = match config.secret() {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .expect("the system's random source failed"),
+        };
+
+        Self {
+            config: config.clone(),
+            key,
+        }
+    }
+
+    /// Applies a reloaded `config`, keeping the key unless its secret changed so cookies
+    /// already handed out still pin their clients.
+    pub fn reconfigure(&mut self, config: &AffinityConfig) {
+        if config.secret() != self.config.secret() {
+            *self = Self::from_config(config);
+        } else {
+            self.config = config.clone();
+        }
+    }
+
+    /// The one of `peers` the cookie in `headers` names, if any.
+    pub fn peer(&self, headers: &HeaderMap, peers: &[Arc<Peer>]) -> Option<Arc<Peer>> {
+        let value = cookie(headers, self.config.cookie_name())?;
+        peers.iter().find(|peer| self.id(peer) == value).cloned()
+    }
+
+    /// A `Set-Cookie` value pinning the client to `peer`.
+    pub fn set_cookie(&self, peer: &Peer) -> HeaderValue {
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly",
+            self.config.cookie_name(),
+            self.id(peer)
+        );
+        if let Some(ttl) = self.config.ttl() {
+            cookie.push_str(&format!("; Max-Age={}", ttl.as_secs()));
+        }
+        HeaderValue::from_str(&cookie).expect("cookie names are checked when the config loads")
+    }
+
+    fn id(&self, peer: &Peer) -> String {
+        let tag = hmac::sign(&self.key, peer.address.as_string().as_bytes());
+        tag.as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// The value of the cookie called `name` in the `Cookie` headers.
+pub(crate) fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_names_peer() {
+        let peers: Vec<_> = ["10.0.0.1:80", "10.0.0.2:80"]
+            .iter()
+            .map(|address| Arc::new(Peer::new(address).unwrap()))
+            .collect();
+        let affinity = CookieAffinity::from_config(&AffinityConfig::cookie("pin"));
+
+        let set = affinity.set_cookie(&peers[1]);
+        let set = set.to_str().unwrap();
+        assert!(set.starts_with("pin=") && set.ends_with("; Path=/; HttpOnly"));
+        assert!(!set.contains("10.0.0.2"));
+
+        let value = set.split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("other=1; {}", value).parse().unwrap());
+        let pinned = affinity.peer(&headers, &peers).unwrap();
+        assert!(Arc::ptr_eq(&pinned, &peers[1]));
+
+        // another instance's key doesn't name any peer here
+        let other = CookieAffinity::from_config(&AffinityConfig::cookie("pin"));
+        assert!(other.peer(&headers, &peers).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{fmt, fs, net::IpAddr, path::Path};
+
+use ipnet::IpNet;
+use maxminddb::{Reader, geoip2};
+
+use crate::errors::SecurityError;
+
+/// Maps addresses to the autonomous system announcing them.
+///
+/// Reads either a MaxMind ASN database (`.mmdb`, e.g. GeoLite2-ASN) or a text file with one
+/// `<cidr> <asn>` pair per line, where blank lines and `#` comments are ignored. The most
+/// specific matching network wins.
+pub enum AsnDatabase {
+    MaxMind(Reader<Vec<u8>>),
+    Table(Vec<(IpNet, u32)>),
+}
+
+impl fmt::Debug for AsnDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsnDatabase::MaxMind(_) => f.write_str("AsnDatabase::MaxMind"),
+            AsnDatabase::Table(table) => write!(f, "AsnDatabase::Table({} networks)", table.len()),
+        }
+    }
+}
+
+impl AsnDatabase {
+    pub fn open(path: &Path) -> Result<Self, SecurityError> {
+        if path.extension().is_some_and(|ext| ext == "mmdb") {
+            let reader = Reader::open_readfile(path).map_err(|e| {
+                SecurityError::AsnDatabase(path.display().to_string(), e.to_string())
+            })?;
+            return Ok(AsnDatabase::MaxMind(reader));
+        }
+
+        let contents = fs::read_to_string(path)?;
+        Self::parse_table(path, &contents)
+    }
+
+    pub fn parse_table(path: &Path, contents: &str) -> Result<Self, SecurityError> {
+        let mut table = Vec::new();
+
+        for (idx, line) in contents.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            let mut fields = entry.split_whitespace();
+            let net = fields.next().and_then(|net| net.parse::<IpNet>().ok());
+            let asn = fields
+                .next()
+                .map(|asn| asn.trim_start_matches("AS"))
+                .and_then(|asn| asn.parse::<u32>().ok());
+
+            match (net, asn) {
+                (Some(net), Some(asn)) => table.push((net.trunc(), asn)),
+                _ => {
+                    return Err(SecurityError::InvalidListEntry(
+                        path.display().to_string(),
+                        idx + 1,
+                        entry.to_string(),
+                    ));
+                }
+            }
+        }
+
+        // longest prefix first, so the first match is the most specific
+        table.sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix_len()));
+
+        Ok(AsnDatabase::Table(table))
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<u32> {
+        match self {
+            AsnDatabase::MaxMind(reader) => {
+                reader
+                    .lookup::<geoip2::Asn>(*ip)
+                    .ok()?
+                    .autonomous_system_number
+            }
+            AsnDatabase::Table(table) => table
+                .iter()
+                .find(|(net, _)| net.contains(ip))
+                .map(|(_, asn)| *asn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asn_table_lookup() {
+        let contents = "
+            # provider      asn
+            203.0.113.0/24  64500
+            203.0.113.128/25 AS64501
+            2001:db8::/32   64502
+        ";
+        let db = AsnDatabase::parse_table(Path::new("asn.txt"), contents).unwrap();
+
+        assert_eq!(db.lookup(&"203.0.113.7".parse().unwrap()), Some(64500));
+        assert_eq!(db.lookup(&"203.0.113.200".parse().unwrap()), Some(64501));
+        assert_eq!(db.lookup(&"2001:db8::1".parse().unwrap()), Some(64502));
+        assert_eq!(db.lookup(&"192.0.2.1".parse().unwrap()), None);
+    }
+}
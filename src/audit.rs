@@ -0,0 +1,182 @@
+use std::{
+    io,
+    net::IpAddr,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use crate::config::{AuditTarget, Config};
+
+/// Records waiting to be written. Past this the audit log drops records rather than letting a
+/// flood of rejected connections grow memory or slow down the accept loop.
+const AUDIT_QUEUE_SIZE: usize = 4096;
+
+/// syslog facility `auth` (4) at severity `warning` (4)
+const SYSLOG_PRIORITY: u8 = 4 * 8 + 4;
+
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    /// milliseconds since the unix epoch
+    pub timestamp: u128,
+    pub client: IpAddr,
+    /// short machine-readable code, e.g. `blacklisted` or `rate_limit`
+    pub reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+enum Sink {
+    File(File),
+    Syslog(UnixDatagram),
+}
+
+impl Sink {
+    async fn open(target: AuditTarget, path: &Path) -> Result<Self, io::Error> {
+        match target {
+            AuditTarget::File => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                Ok(Sink::File(file))
+            }
+            AuditTarget::Syslog => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Sink::Syslog(socket))
+            }
+        }
+    }
+
+    async fn write(&mut self, record: &AuditRecord) -> Result<(), io::Error> {
+        let json = serde_json::to_string(record)?;
+        match self {
+            Sink::File(file) => {
+                file.write_all(json.as_bytes()).await?;
+                file.write_all(b"\n").await
+            }
+            Sink::Syslog(socket) => {
+                let line = format!(
+                    "<{}>jalb[{}]: {}",
+                    SYSLOG_PRIORITY,
+                    std::process::id(),
+                    json
+                );
+                socket.send(line.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Stream of rejected connections, written one JSON object per line to its own file or to
+/// syslog, apart from the application log.
+#[derive(Debug)]
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditRecord>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    /// Opens the sink configured under `[audit]`, or returns `None` if auditing is off.
+    pub async fn from_config(cfg: &Config) -> Result<Option<Arc<Self>>, io::Error> {
+        let Some(target) = cfg.audit_target() else {
+            return Ok(None);
+        };
+
+        Self::open(target, cfg.audit_path()).await.map(Some)
+    }
+
+    pub async fn open(target: AuditTarget, path: PathBuf) -> Result<Arc<Self>, io::Error> {
+        let mut sink = Sink::open(target, &path).await?;
+        let (sender, mut receiver) = mpsc::channel::<AuditRecord>(AUDIT_QUEUE_SIZE);
+
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                if let Err(e) = sink.write(&record).await {
+                    tracing::error!("failed to write audit record to {}: {}", path.display(), e);
+                }
+            }
+        });
+
+        Ok(Arc::new(Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn reject(&self, client: IpAddr, reason: &'static str, detail: Option<String>) {
+        let record = AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            client,
+            reason,
+            detail,
+        };
+
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records lost because the writer could not keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_log_writes_json_lines() {
+        let path = std::env::temp_dir().join(format!("jalb-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let audit = AuditLog::open(AuditTarget::File, path.clone())
+            .await
+            .unwrap();
+        audit.reject("10.0.0.1".parse().unwrap(), "blacklisted", None);
+        audit.reject(
+            "10.0.0.2".parse().unwrap(),
+            "rate_limit",
+            Some("max_accepts_per_second exceeded".to_string()),
+        );
+
+        let mut contents = String::new();
+        for _ in 0..50 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 2 {
+                break;
+            }
+        }
+
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["client"], "10.0.0.1");
+        assert_eq!(lines[0]["reason"], "blacklisted");
+        assert_eq!(lines[1]["detail"], "max_accepts_per_second exceeded");
+        assert_eq!(audit.dropped(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,8 @@
-use crate::config::{BackendOptions, NetworkTarget};
-use std::{str::FromStr, time::Duration};
+use crate::{
+    config::{BackendOptions, RequestTimeoutScope},
+    health::HealthCheck,
+};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Backend {
@@ -7,19 +10,30 @@ pub struct Backend {
     pub health_check_interval: Option<Duration>,
     pub health_check_timeout: Option<Duration>,
     pub request_timeout: Option<Duration>,
+    pub request_timeout_scope: RequestTimeoutScope,
     pub failed_request_threshold: Option<u32>,
     pub rate_limit: Option<u64>,
+    pub liveness: Option<HealthCheck>,
+    pub readiness: Option<HealthCheck>,
+    pub min_healthy_peers: Option<usize>,
+    /// Stop ejecting peers once that would leave fewer than `min_healthy_peers`
+    pub fail_open: bool,
 }
 
 impl Backend {
-    pub(crate) fn from_config(config: &BackendOptions) -> Self {
+    pub fn from_config(config: &BackendOptions) -> Self {
         Self {
             health_endpoint: config.health_endpoint.clone(),
             health_check_interval: config.get_health_check_interval(),
             health_check_timeout: config.get_health_check_timeout(),
             request_timeout: config.get_request_timeout(),
+            request_timeout_scope: config.request_timeout_scope,
             failed_request_threshold: config.failed_request_threshold,
             rate_limit: config.rate_limit,
+            liveness: config.liveness_check(),
+            readiness: config.readiness_check(),
+            min_healthy_peers: config.min_healthy_peers,
+            fail_open: config.fail_open,
         }
     }
 
@@ -43,6 +57,22 @@ impl Backend {
         self
     }
 
+    pub fn with_liveness_check(mut self, check: HealthCheck) -> Self {
+        self.liveness = Some(check);
+        self
+    }
+
+    pub fn with_readiness_check(mut self, check: HealthCheck) -> Self {
+        self.readiness = Some(check);
+        self
+    }
+
+    pub fn with_min_healthy_peers(mut self, min: usize, fail_open: bool) -> Self {
+        self.min_healthy_peers = Some(min);
+        self.fail_open = fail_open;
+        self
+    }
+
     pub fn with_rate_limit(mut self, max_requests_per_second: u64) -> Self {
         self.rate_limit = Some(max_requests_per_second);
         self
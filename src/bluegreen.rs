@@ -0,0 +1,194 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use http::Response;
+use serde::{Deserialize, Serialize};
+
+use crate::split::is_failure;
+
+/// `blue_green` of a `[[route]]`: its requests go to `backend` or `standby`, whichever is active,
+/// and an admin call switches between the two at once. With `rollback_error_percent` set, a
+/// pool switched to that fails more than that share of its requests within
+/// `rollback_window_seconds` is switched away from again.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlueGreenConfig {
+    pub standby: String,
+    pub rollback_error_percent: Option<u32>,
+    #[serde(default = "default_rollback_window_seconds")]
+    pub rollback_window_seconds: u64,
+    /// requests the pool switched to answers before its error rate counts
+    #[serde(default = "default_rollback_min_requests")]
+    pub rollback_min_requests: u64,
+}
+
+fn default_rollback_window_seconds() -> u64 {
+    60
+}
+
+fn default_rollback_min_requests() -> u64 {
+    20
+}
+
+impl BlueGreenConfig {
+    /// `standby` is a configured backend other than the route's own `backend`.
+    pub fn validate(&self, backend: &str, backends: &[&str]) -> Result<(), String> {
+        if !backends.contains(&self.standby.as_str()) {
+            return Err(format!("{} is not a configured backend", self.standby));
+        }
+        if self.standby == backend {
+            return Err("standby is the route's backend already".to_string());
+        }
+        if let Some(percent) = self.rollback_error_percent
+            && !(1..=100).contains(&percent)
+        {
+            return Err(format!(
+                "rollback_error_percent {} is not between 1 and 100",
+                percent
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The two pools of a blue-green route and which of them is active. Switches last until the
+/// next reload, which makes the route's `backend` active again.
+#[derive(Debug)]
+pub struct BlueGreen {
+    /// the route's position in the config, counting from 1
+    route: usize,
+    pools: [String; 2],
+    /// index into `pools`
+    active: AtomicUsize,
+    rollback: Option<Rollback>,
+    /// how the pool last switched to is doing, while within the rollback window
+    watching: Mutex<Option<Watch>>,
+}
+
+#[derive(Debug)]
+struct Rollback {
+    error_percent: u64,
+    window: Duration,
+    min_requests: u64,
+}
+
+#[derive(Debug)]
+struct Watch {
+    since: Instant,
+    requests: u64,
+    errors: u64,
+}
+
+impl BlueGreen {
+    /// `config` was checked by [`BlueGreenConfig::validate`] when the config was loaded.
+    pub fn from_config(route: usize, backend: &str, config: &BlueGreenConfig) -> Self {
+        Self {
+            route,
+            pools: [backend.to_string(), config.standby.clone()],
+            active: AtomicUsize::new(0),
+            rollback: config.rollback_error_percent.map(|percent| Rollback {
+                error_percent: u64::from(percent),
+                window: Duration::from_secs(config.rollback_window_seconds),
+                min_requests: config.rollback_min_requests,
+            }),
+            watching: Mutex::new(None),
+        }
+    }
+
+    pub fn route(&self) -> usize {
+        self.route
+    }
+
+    /// The pool requests go to.
+    pub fn active(&self) -> &str {
+        &self.pools[self.active.load(Ordering::Relaxed)]
+    }
+
+    /// The pool switched to by the next [`switch`](Self::switch).
+    pub fn standby(&self) -> &str {
+        &self.pools[1 - self.active.load(Ordering::Relaxed)]
+    }
+
+    /// Makes the standby pool active, returning its name, and starts watching its error rate.
+    pub fn switch(&self) -> &str {
+        let mut watching = self.watching.lock().unwrap();
+        let active = 1 - self.active.load(Ordering::Relaxed);
+        self.active.store(active, Ordering::Relaxed);
+        *watching = self.rollback.as_ref().map(|_| Watch {
+            since: Instant::now(),
+            requests: 0,
+            errors: 0,
+        });
+        &self.pools[active]
+    }
+
+    /// Counts a request `backend` answered with `response`. When that pushes the active pool
+    /// over its rollback error rate the route switches back, and why is returned.
+    pub fn record<B>(&self, backend: &str, response: &Response<B>) -> Option<String> {
+        let rollback = self.rollback.as_ref()?;
+        let mut watching = self.watching.lock().unwrap();
+        let watch = watching.as_mut()?;
+        if watch.since.elapsed() > rollback.window {
+            *watching = None;
+            return None;
+        }
+        // requests sent before the switch finish against the previous pool
+        if backend != self.active() {
+            return None;
+        }
+
+        watch.requests += 1;
+        if is_failure(response) {
+            watch.errors += 1;
+        }
+        let over = watch.errors * 100 > rollback.error_percent * watch.requests;
+        if watch.requests < rollback.min_requests || !over {
+            return None;
+        }
+
+        let (requests, errors) = (watch.requests, watch.errors);
+        let failing = self.active().to_string();
+        let active = 1 - self.active.load(Ordering::Relaxed);
+        self.active.store(active, Ordering::Relaxed);
+        *watching = None;
+        Some(format!(
+            "route {} rolled back from {} to {}: {} of {} requests failed",
+            self.route, failing, self.pools[active], errors, requests
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blue_green_rollback() {
+        let config: BlueGreenConfig = toml::from_str(
+            "standby = \"green\"\nrollback_error_percent = 50\nrollback_min_requests = 4",
+        )
+        .unwrap();
+        assert!(config.validate("blue", &["blue", "green"]).is_ok());
+        assert!(config.validate("green", &["blue", "green"]).is_err());
+
+        let blue_green = BlueGreen::from_config(1, "blue", &config);
+        assert_eq!(blue_green.active(), "blue");
+        assert_eq!(blue_green.switch(), "green");
+
+        let ok = Response::new(());
+        let failed = Response::builder().status(502).body(()).unwrap();
+        // answers from blue are no longer its business
+        assert!(blue_green.record("blue", &failed).is_none());
+        for response in [&ok, &failed, &failed] {
+            assert!(blue_green.record("green", response).is_none());
+        }
+        let rolled_back = blue_green.record("green", &failed).unwrap();
+        assert!(rolled_back.contains("3 of 4"));
+        assert_eq!(blue_green.active(), "blue");
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use http::{HeaderMap, header::CONTENT_LENGTH};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+
+use crate::errors::ProxyError;
+
+/// The length a request says its body has, `None` when it doesn't or it can't be read.
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// A request body passed on as it is read that fails once more than `limit` bytes have come
+/// through, so a client can't send a peer, or jalb's own buffers, more than it is allowed to.
+pub struct LimitedBody<B> {
+    inner: B,
+    limit: u64,
+    read: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<B> LimitedBody<B> {
+    /// Returns the body and a flag set once it went over `limit`, to tell the failure apart
+    /// from others when forwarding it fails.
+    pub fn new(inner: B, limit: u64) -> (Self, Arc<AtomicBool>) {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let body = Self {
+            inner,
+            limit,
+            read: 0,
+            exceeded: exceeded.clone(),
+        };
+        (body, exceeded)
+    }
+}
+
+impl<B> Body for LimitedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: From<ProxyError>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.read += data.len() as u64;
+            if self.read > self.limit {
+                self.exceeded.store(true, Ordering::Relaxed);
+                let limit = self.limit;
+                return Poll::Ready(Some(Err(ProxyError::BodyTooLarge(limit).into())));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let body =
+            || Full::new(Bytes::from("0123456789")).map_err(|never| -> BoxError { match never {} });
+
+        let (limited, exceeded) = LimitedBody::new(body(), 10);
+        assert_eq!(limited.collect().await.unwrap().to_bytes(), "0123456789");
+        assert!(!exceeded.load(Ordering::Relaxed));
+
+        let (limited, exceeded) = LimitedBody::new(body(), 9);
+        assert!(limited.collect().await.is_err());
+        assert!(exceeded.load(Ordering::Relaxed));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, "42".parse().unwrap());
+        assert_eq!(content_length(&headers), Some(42));
+    }
+}
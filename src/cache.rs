@@ -0,0 +1,466 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+    header::{AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, RANGE, SET_COOKIE, UPGRADE, VARY},
+};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+
+use crate::config::CacheConfig;
+
+/// Statuses whose responses may be kept, as long as nothing in their headers says otherwise.
+const CACHEABLE_STATUSES: [u16; 7] = [200, 203, 204, 300, 301, 404, 410];
+
+/// Keeps GET responses of the application balancer in memory to answer repeats of the request
+/// without asking a peer. Responses are keyed by host and path with query, and by the request
+/// headers they name in `Vary`. They stay fresh for their `s-maxage` or `max-age`, or else the
+/// configured `ttl_seconds`, and the least recently used are dropped to stay within
+/// `max_size_mb`. Requests and responses that are private, or ask not to be cached, pass by.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Option<Duration>,
+    max_entry: usize,
+    max_size: usize,
+    store: Mutex<Store>,
+}
+
+/// What [`ResponseCache::lookup`] found for a request.
+#[derive(Debug)]
+pub enum Lookup {
+    /// a fresh response to answer with
+    Hit(Response<Bytes>),
+    /// nothing yet, the response may be kept with [`ResponseCache::fill`]
+    Miss(Pending),
+    /// the request isn't one to answer from the cache
+    Bypass,
+}
+
+/// A missed request, remembered until its response arrives.
+#[derive(Debug)]
+pub struct Pending {
+    key: String,
+    headers: HeaderMap,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    /// the request headers responses of a key vary by, as of the latest response stored
+    vary: HashMap<String, Vec<HeaderName>>,
+    /// responses by key and the values of the headers they vary by
+    entries: HashMap<String, Entry>,
+    /// keys of `entries` by when they were last used
+    used: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    /// the age the peer gave the response
+    age: Duration,
+    fresh_for: Duration,
+    used: u64,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn from_config(cfg: &CacheConfig) -> Self {
+        Self {
+            ttl: cfg.ttl(),
+            max_entry: cfg.max_entry_bytes(),
+            max_size: cfg.max_size_bytes(),
+            store: Mutex::new(Store::default()),
+        }
+    }
+
+    /// Looks `req` up, with `host` as it arrived. Expired responses are dropped on the way.
+    pub fn lookup<B>(&self, req: &Request<B>, host: Option<&str>) -> Lookup {
+        self.lookup_as(req, host, &[])
+    }
+
+    /// [`lookup`](Self::lookup) for a request whose credentials jalb checked and took off. Its
+    /// responses are kept apart by the values of `identity`, the headers sent in their place.
+    pub fn lookup_as<B>(
+        &self,
+        req: &Request<B>,
+        host: Option<&str>,
+        identity: &[HeaderName],
+    ) -> Lookup {
+        let headers = req.headers();
+        let bypass = req.method() != Method::GET
+            || headers.contains_key(AUTHORIZATION)
+            || headers.contains_key(RANGE)
+            || headers.contains_key(UPGRADE)
+            || directives(headers).any(|d| d == "no-cache" || d == "no-store");
+        if bypass {
+            return Lookup::Bypass;
+        }
+
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let mut key = format!("{} {}", host.unwrap_or_default(), path);
+        for name in identity {
+            push_values(&mut key, headers, name);
+        }
+        let pending = Pending {
+            key,
+            headers: headers.clone(),
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let variant = store.variant(&pending);
+        let Some(entry) = store.entries.get(&variant) else {
+            return Lookup::Miss(pending);
+        };
+        let age = entry.age + entry.stored.elapsed();
+        if age >= entry.fresh_for {
+            store.remove(&variant);
+            return Lookup::Miss(pending);
+        }
+
+        let mut response = Response::new(entry.body.clone());
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+        response
+            .headers_mut()
+            .insert(AGE, HeaderValue::from(age.as_secs()));
+        store.touch(&variant);
+        Lookup::Hit(response)
+    }
+
+    /// Passes `response` to `pending` on, keeping a copy of it once its body has been read
+    /// when it may be cached and isn't too large.
+    pub fn fill<B>(self: &Arc<Self>, pending: Pending, response: Response<B>) -> Response<Fill<B>>
+    where
+        B: Body<Data = Bytes>,
+    {
+        let capture = self.fresh_for(&response).and_then(|fresh_for| {
+            let too_large = response
+                .body()
+                .size_hint()
+                .upper()
+                .is_some_and(|len| len > self.max_entry as u64);
+            (!too_large).then(|| Capture {
+                cache: self.clone(),
+                pending,
+                status: response.status(),
+                headers: response.headers().clone(),
+                fresh_for,
+                frames: Vec::new(),
+                len: 0,
+            })
+        });
+        response.map(|inner| {
+            let mut fill = Fill { inner, capture };
+            // an empty body may never be polled
+            if fill.inner.is_end_stream() {
+                fill.finish();
+            }
+            fill
+        })
+    }
+
+    /// How long `response` may be kept, `None` when it may not be.
+    fn fresh_for<B>(&self, response: &Response<B>) -> Option<Duration> {
+        let headers = response.headers();
+        if !CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            || headers.contains_key(SET_COOKIE)
+            || list(headers, &VARY).any(|name| name == "*")
+        {
+            return None;
+        }
+
+        let mut max_age = None;
+        let mut s_maxage = None;
+        for directive in directives(headers) {
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse().ok(),
+                Some(("s-maxage", secs)) => s_maxage = secs.trim_matches('"').parse().ok(),
+                _ if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => {
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        let fresh_for = match s_maxage.or(max_age) {
+            Some(secs) => Duration::from_secs(secs),
+            None => self.ttl?,
+        };
+        (!fresh_for.is_zero()).then_some(fresh_for)
+    }
+
+    fn keep(&self, capture: Capture) {
+        let body: Bytes = capture.frames.concat().into();
+        let headers_size: usize = capture
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = body.len() + headers_size;
+        if size > self.max_size {
+            return;
+        }
+        let age = capture
+            .headers
+            .get(AGE)
+            .and_then(|age| age.to_str().ok()?.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        if age >= capture.fresh_for {
+            return;
+        }
+        let vary: Vec<HeaderName> = list(&capture.headers, &VARY)
+            .filter_map(|name| HeaderName::try_from(name).ok())
+            .collect();
+
+        let mut store = self.store.lock().unwrap();
+        store.vary.insert(capture.pending.key.clone(), vary);
+        let variant = store.variant(&capture.pending);
+        store.remove(&variant);
+        while store.size + size > self.max_size {
+            let Some((_, oldest)) = store.used.pop_first() else {
+                break;
+            };
+            store.remove(&oldest);
+        }
+
+        store.tick += 1;
+        let used = store.tick;
+        store.used.insert(used, variant.clone());
+        store.size += size;
+        store.entries.insert(
+            variant,
+            Entry {
+                status: capture.status,
+                headers: capture.headers,
+                body,
+                stored: Instant::now(),
+                age,
+                fresh_for: capture.fresh_for,
+                used,
+                size,
+            },
+        );
+    }
+
+    /// Bytes of responses kept.
+    pub fn size(&self) -> usize {
+        self.store.lock().unwrap().size
+    }
+}
+
+impl Store {
+    /// The key of the response to `pending` among those its key varies by.
+    fn variant(&self, pending: &Pending) -> String {
+        let mut variant = pending.key.clone();
+        for name in self.vary.get(&pending.key).into_iter().flatten() {
+            push_values(&mut variant, &pending.headers, name);
+        }
+        variant
+    }
+
+    fn touch(&mut self, variant: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(variant) {
+            self.used.remove(&entry.used);
+            entry.used = tick;
+            self.used.insert(tick, variant.to_string());
+        }
+    }
+
+    fn remove(&mut self, variant: &str) {
+        if let Some(entry) = self.entries.remove(variant) {
+            self.used.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+}
+
+/// Adds the values of the `name` headers to a cache key, on a line of their own.
+fn push_values(key: &mut String, headers: &HeaderMap, name: &HeaderName) {
+    key.push('\n');
+    for value in headers.get_all(name) {
+        key.push_str(value.to_str().unwrap_or_default());
+        key.push(',');
+    }
+}
+
+/// The directives of a `Cache-Control` header, lowercased.
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    list(headers, &CACHE_CONTROL).map(|directive| directive.to_ascii_lowercase())
+}
+
+/// The items of a comma separated header, over all its lines.
+fn list<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// A response body on its way to the client, copied into the cache when it ends.
+pub struct Fill<B> {
+    inner: B,
+    capture: Option<Capture>,
+}
+
+struct Capture {
+    cache: Arc<ResponseCache>,
+    pending: Pending,
+    status: StatusCode,
+    headers: HeaderMap,
+    fresh_for: Duration,
+    frames: Vec<Bytes>,
+    len: usize,
+}
+
+impl<B> Fill<B> {
+    /// Keeps the body read, unless it is shorter than the peer said it would be.
+    fn finish(&mut self) {
+        let Some(capture) = self.capture.take() else {
+            return;
+        };
+        let expected = capture
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+        if expected.is_none_or(|len| len == capture.len) {
+            capture.cache.clone().keep(capture);
+        }
+    }
+}
+
+impl<B> Body for Fill<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => match (frame.data_ref(), &mut self.capture) {
+                (Some(data), Some(capture))
+                    if capture.len + data.len() <= capture.cache.max_entry =>
+                {
+                    capture.len += data.len();
+                    capture.frames.push(data.clone());
+                }
+                // too large, or with trailers that aren't kept
+                _ => self.capture = None,
+            },
+            Some(Err(_)) => self.capture = None,
+            None => self.finish(),
+        }
+        // the last frame may well be the last one polled
+        if self.inner.is_end_stream() {
+            self.finish();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        let cfg: CacheConfig = toml::from_str("max_entry_kb = 1\nmax_size_mb = 1").unwrap();
+        let cache = Arc::new(ResponseCache::from_config(&cfg));
+        let get = |path: &str, lang: &str| {
+            Request::get(path)
+                .header("accept-language", lang)
+                .body(())
+                .unwrap()
+        };
+        let respond = |cache_control: &str, body: &'static str| {
+            Response::builder()
+                .header(CACHE_CONTROL, cache_control)
+                .header(VARY, "Accept-Language")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap()
+        };
+
+        let Lookup::Miss(pending) = cache.lookup(&get("/a?x=1", "en"), Some("a.com")) else {
+            panic!("nothing is cached yet");
+        };
+        let response = cache.fill(pending, respond("max-age=60", "hello"));
+        assert_eq!(response.collect().await.unwrap().to_bytes(), "hello");
+
+        let Lookup::Hit(hit) = cache.lookup(&get("/a?x=1", "en"), Some("a.com")) else {
+            panic!("the response was cached");
+        };
+        assert_eq!(hit.body(), "hello");
+        assert_eq!(hit.headers()[AGE], "0");
+        // another language, query or host is another response
+        for (path, lang, host) in [("/a?x=1", "fr", "a.com"), ("/a", "en", "a.com")] {
+            assert!(matches!(
+                cache.lookup(&get(path, lang), Some(host)),
+                Lookup::Miss(_)
+            ));
+        }
+        assert!(matches!(
+            cache.lookup(&get("/a?x=1", "en"), None),
+            Lookup::Miss(_)
+        ));
+
+        // private responses, and those over max_entry_kb, pass by
+        let Lookup::Miss(pending) = cache.lookup(&get("/b", "en"), Some("a.com")) else {
+            panic!("nothing is cached yet");
+        };
+        let response = cache.fill(pending, respond("private, max-age=60", "secret"));
+        response.collect().await.unwrap();
+        let large = Response::builder()
+            .header(CACHE_CONTROL, "max-age=60")
+            .body(Full::new(Bytes::from(vec![b'x'; 2048])))
+            .unwrap();
+        let Lookup::Miss(pending) = cache.lookup(&get("/c", "en"), Some("a.com")) else {
+            panic!("nothing is cached yet");
+        };
+        cache.fill(pending, large).collect().await.unwrap();
+        assert!(matches!(
+            cache.lookup(&get("/b", "en"), Some("a.com")),
+            Lookup::Miss(_)
+        ));
+        assert!(matches!(
+            cache.lookup(&get("/c", "en"), Some("a.com")),
+            Lookup::Miss(_)
+        ));
+
+        let no_cache = Request::get("/a?x=1")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(())
+            .unwrap();
+        assert!(matches!(
+            cache.lookup(&no_cache, Some("a.com")),
+            Lookup::Bypass
+        ));
+    }
+}
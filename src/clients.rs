@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::Serialize;
+
+/// Shards the table is split into, so clients landing in different ones never wait on each
+/// other.
+const SHARDS: usize = 16;
+/// Clients kept across all shards. A full shard forgets its less recently seen half.
+pub const MAX_CLIENTS: usize = 65536;
+
+/// What a client has done since it was first seen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ClientCounters {
+    pub connections: u64,
+    /// bytes from the client
+    pub bytes_sent: u64,
+    /// bytes to the client
+    pub bytes_received: u64,
+    pub rejections: u64,
+    /// of the rejections, those over a rate limit
+    pub rate_limited: u64,
+}
+
+#[derive(Debug)]
+struct ClientEntry {
+    counters: ClientCounters,
+    /// the table's tick as of the client's latest update
+    last_seen: u64,
+}
+
+/// What the clients are ranked by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientOrder {
+    #[default]
+    Bytes,
+    Connections,
+    Rejections,
+    RateLimited,
+}
+
+impl ClientOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bytes" => Some(Self::Bytes),
+            "connections" => Some(Self::Connections),
+            "rejections" => Some(Self::Rejections),
+            "rate_limited" => Some(Self::RateLimited),
+            _ => None,
+        }
+    }
+
+    fn key(&self, counters: &ClientCounters) -> u64 {
+        match self {
+            Self::Bytes => counters.bytes_sent + counters.bytes_received,
+            Self::Connections => counters.connections,
+            Self::Rejections => counters.rejections,
+            Self::RateLimited => counters.rate_limited,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TopClient {
+    pub client: IpAddr,
+    #[serde(flatten)]
+    pub counters: ClientCounters,
+}
+
+/// Connection, byte and rejection counters per client address, bounded to [`MAX_CLIENTS`] so a
+/// flood of addresses can't grow it without end.
+#[derive(Debug)]
+pub struct ClientTable {
+    shards: Vec<Mutex<HashMap<IpAddr, ClientEntry>>>,
+    hasher: RandomState,
+    /// counts updates, telling which clients were seen last
+    ticks: AtomicU64,
+}
+
+impl Default for ClientTable {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            ticks: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ClientTable {
+    pub fn connected(&self, client: IpAddr) {
+        self.update(client, |counters| counters.connections += 1);
+    }
+
+    pub fn rejected(&self, client: IpAddr) {
+        self.update(client, |counters| counters.rejections += 1);
+    }
+
+    /// A rejection for going over a rate limit.
+    pub fn rate_limited(&self, client: IpAddr) {
+        self.update(client, |counters| {
+            counters.rejections += 1;
+            counters.rate_limited += 1;
+        });
+    }
+
+    /// Adds the bytes copied from `client` and to it.
+    pub fn transferred(&self, client: IpAddr, (sent, received): (u64, u64)) {
+        if sent == 0 && received == 0 {
+            return;
+        }
+        self.update(client, |counters| {
+            counters.bytes_sent += sent;
+            counters.bytes_received += received;
+        });
+    }
+
+    fn update(&self, client: IpAddr, change: impl FnOnce(&mut ClientCounters)) {
+        let shard = self.hasher.hash_one(client) as usize % SHARDS;
+        let mut shard = self.shards[shard].lock().unwrap();
+        let now = self.ticks.fetch_add(1, Ordering::Relaxed);
+        if !shard.contains_key(&client) && shard.len() >= MAX_CLIENTS / SHARDS {
+            forget_older_half(&mut shard);
+        }
+
+        let entry = shard.entry(client).or_insert_with(|| ClientEntry {
+            counters: ClientCounters::default(),
+            last_seen: now,
+        });
+        change(&mut entry.counters);
+        entry.last_seen = now;
+    }
+
+    /// Up to `limit` clients with the most of `order`, most first.
+    pub fn top(&self, limit: usize, order: ClientOrder) -> Vec<TopClient> {
+        let mut clients: Vec<TopClient> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .iter()
+                    .map(|(client, entry)| TopClient {
+                        client: *client,
+                        counters: entry.counters,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        clients.sort_by(|a, b| {
+            order
+                .key(&b.counters)
+                .cmp(&order.key(&a.counters))
+                .then(a.client.cmp(&b.client))
+        });
+        clients.truncate(limit);
+        clients
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Halves a full shard in one go, so evicting costs little spread over the inserts filling it.
+fn forget_older_half(shard: &mut HashMap<IpAddr, ClientEntry>) {
+    let mut seen: Vec<u64> = shard.values().map(|entry| entry.last_seen).collect();
+    let middle = seen.len() / 2;
+    let (_, cutoff, _) = seen.select_nth_unstable(middle);
+    let cutoff = *cutoff;
+    shard.retain(|_, entry| entry.last_seen >= cutoff);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_top_clients() {
+        let table = ClientTable::default();
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        table.connected(a);
+        table.transferred(a, (100, 4000));
+        for _ in 0..3 {
+            table.connected(b);
+            table.rejected(b);
+        }
+        table.rate_limited(a);
+        table.transferred(b, (10, 0));
+
+        let top = table.top(10, ClientOrder::Bytes);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].client, a);
+        assert_eq!(top[0].counters.bytes_received, 4000);
+        assert_eq!(table.top(1, ClientOrder::RateLimited)[0].client, a);
+        let top = table.top(1, ClientOrder::Rejections);
+        assert_eq!(
+            top,
+            vec![TopClient {
+                client: b,
+                counters: ClientCounters {
+                    connections: 3,
+                    bytes_sent: 10,
+                    bytes_received: 0,
+                    rejections: 3,
+                    rate_limited: 0,
+                },
+            }]
+        );
+
+        // a flood of addresses stays within the bound
+        for n in 0..(MAX_CLIENTS as u32 * 2) {
+            table.rejected(IpAddr::V4(Ipv4Addr::from(0x0b00_0000 + n)));
+        }
+        assert!(table.len() <= MAX_CLIENTS);
+        assert!(table.len() >= MAX_CLIENTS / 4);
+    }
+}
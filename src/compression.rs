@@ -0,0 +1,356 @@
+use http::{
+    HeaderMap, HeaderValue, Response, StatusCode,
+    header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+};
+use serde::{Deserialize, Serialize};
+
+/// `compression` of a `[[route]]`: responses of one of `content_types`, at least `min_size`
+/// bytes long, are compressed with gzip or brotli for clients that accept either, unless the
+/// peer compressed them already.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// media types without parameters, `text/*` for every text type
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+    /// responses of unknown length are compressed regardless
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+}
+
+fn default_content_types() -> Vec<String> {
+    [
+        "text/html",
+        "text/plain",
+        "text/css",
+        "text/javascript",
+        "application/javascript",
+        "application/json",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+fn default_min_size() -> u64 {
+    1024
+}
+
+impl CompressionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if cfg!(not(feature = "compression")) {
+            return Err("jalb was built without the compression feature".to_string());
+        }
+        if let Some(bad) = self.content_types.iter().find(|t| !t.contains('/')) {
+            return Err(format!("{} is not a media type like text/html", bad));
+        }
+        Ok(())
+    }
+}
+
+/// Content codings jalb compresses with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Decides which responses of a route are compressed, and how.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    content_types: Vec<String>,
+    min_size: u64,
+}
+
+impl Compression {
+    /// `config` was checked by [`CompressionConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &CompressionConfig) -> Self {
+        Self {
+            content_types: config
+                .content_types
+                .iter()
+                .map(|t| t.to_ascii_lowercase())
+                .collect(),
+            min_size: config.min_size,
+        }
+    }
+
+    /// The coding the client sending `headers` likes best, brotli on a tie.
+    pub fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let mut best: Option<(u16, Encoding)> = None;
+        let mut wildcard = None;
+        let mut refused = Vec::new();
+        let codings = headers
+            .get_all(ACCEPT_ENCODING)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for coding in codings {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(1000, parse_quality);
+            let encoding = match name.as_str() {
+                "br" => Encoding::Brotli,
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                "*" => {
+                    wildcard = Some(quality);
+                    continue;
+                }
+                _ => continue,
+            };
+            if quality == 0 {
+                refused.push(encoding);
+            } else if best.is_none_or(|(q, e)| {
+                quality > q || (quality == q && encoding == Encoding::Brotli && e != encoding)
+            }) {
+                best = Some((quality, encoding));
+            }
+        }
+
+        if let Some((_, encoding)) = best {
+            return Some(encoding);
+        }
+        // `*` stands for any coding not listed
+        [Encoding::Brotli, Encoding::Gzip]
+            .into_iter()
+            .find(|encoding| wildcard.is_some_and(|q| q > 0) && !refused.contains(encoding))
+    }
+
+    /// Whether `response` is worth compressing and hasn't been already.
+    pub fn applies<B>(&self, response: &Response<B>) -> bool {
+        let headers = response.headers();
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+        let small = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|len| len < self.min_size);
+        let media_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        let listed = media_type.is_some_and(|media_type| {
+            self.content_types
+                .iter()
+                .any(|listed| match listed.strip_suffix("/*") {
+                    Some(top) => media_type.split('/').next() == Some(top),
+                    None => *listed == media_type,
+                })
+        });
+
+        response.status() != StatusCode::NO_CONTENT
+            && response.status() != StatusCode::NOT_MODIFIED
+            && response.status() != StatusCode::PARTIAL_CONTENT
+            && !headers.contains_key(CONTENT_ENCODING)
+            && !no_transform
+            && !small
+            && listed
+    }
+}
+
+/// `q=0.8` in thousandths, the most precision it may have.
+fn parse_quality(q: &str) -> u16 {
+    let (whole, fraction) = q.split_once('.').unwrap_or((q, ""));
+    if whole != "0" {
+        return 1000;
+    }
+    let fraction = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+    fraction.parse().unwrap_or(0)
+}
+
+/// Marks `headers` as those of a response compressed with `encoding`: its length isn't known
+/// until the end, and a strong validator no longer matches the bytes sent.
+pub fn compressed_headers(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.append(
+        http::header::VARY,
+        HeaderValue::from_static("accept-encoding"),
+    );
+    if let Some(etag) = headers.get(http::header::ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+    {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+        if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+            headers.insert(http::header::ETAG, weak);
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+pub use encoder::{Compressed, compress};
+
+#[cfg(feature = "compression")]
+mod encoder {
+    use std::{
+        io::{self, Write},
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, ready},
+    };
+
+    use http::Response;
+    use hyper::body::{Body, Bytes, Frame, SizeHint};
+
+    use super::{Encoding, compressed_headers};
+
+    /// Compresses the body of `response` with `encoding` as it is passed on.
+    pub fn compress<B>(response: Response<B>, encoding: Encoding) -> Response<Compressed<B>> {
+        let (mut parts, body) = response.into_parts();
+        compressed_headers(&mut parts.headers, encoding);
+
+        let output = Sink::default();
+        let encoder: Box<dyn Write + Send> = match encoding {
+            Encoding::Gzip => Box::new(flate2::write::GzEncoder::new(
+                output.clone(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Brotli => {
+                Box::new(brotli::CompressorWriter::new(output.clone(), 4096, 5, 22))
+            }
+        };
+        let body = Compressed {
+            inner: body,
+            encoder: Some(Mutex::new(encoder)),
+            output,
+            trailers: None,
+        };
+        Response::from_parts(parts, body)
+    }
+
+    /// Where an encoder writes, taken from as it fills.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Sink {
+        fn take(&self) -> Bytes {
+            Bytes::from(std::mem::take(&mut *self.0.lock().unwrap()))
+        }
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A response body compressed as it is read.
+    pub struct Compressed<B> {
+        inner: B,
+        /// dropped to write the end of the compressed stream; behind a lock only for response
+        /// bodies to be `Sync`, it is never contended
+        encoder: Option<Mutex<Box<dyn Write + Send>>>,
+        output: Sink,
+        /// the peer's, held back until the compressed stream has ended
+        trailers: Option<Frame<Bytes>>,
+    }
+
+    impl<B> Body for Compressed<B>
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: From<io::Error>,
+    {
+        type Data = Bytes;
+        type Error = B::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+            let this = &mut *self;
+            loop {
+                let Some(encoder) = this.encoder.as_mut() else {
+                    return Poll::Ready(this.trailers.take().map(Ok));
+                };
+                let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    None => {
+                        this.encoder = None;
+                        return Poll::Ready(Some(Ok(Frame::data(this.output.take()))));
+                    }
+                };
+                match frame.into_data() {
+                    Ok(data) => encoder.get_mut().unwrap().write_all(&data)?,
+                    Err(frame) => this.trailers = frame.into_trailers().ok().map(Frame::trailers),
+                }
+                let compressed = this.output.take();
+                if !compressed.is_empty() {
+                    return Poll::Ready(Some(Ok(Frame::data(compressed))));
+                }
+            }
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.encoder.is_none() && self.trailers.is_none()
+        }
+
+        fn size_hint(&self) -> SizeHint {
+            SizeHint::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_choice() {
+        let config: CompressionConfig =
+            toml::from_str("content_types = [\"text/*\", \"application/json\"]").unwrap();
+        let compression = Compression::from_config(&config);
+        let accepting = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            compression.negotiate(&headers)
+        };
+        assert_eq!(accepting("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(accepting("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(accepting("*, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(accepting("identity"), None);
+
+        let response = |content_type: &str, len: u64| {
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, len)
+                .body(())
+                .unwrap()
+        };
+        assert!(compression.applies(&response("text/html; charset=utf-8", 4096)));
+        assert!(compression.applies(&response("application/json", 4096)));
+        assert!(!compression.applies(&response("image/png", 4096)));
+        assert!(!compression.applies(&response("text/html", 100)));
+        let mut gzipped = response("text/html", 4096);
+        gzipped
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(!compression.applies(&gzipped));
+    }
+}
@@ -1,30 +1,113 @@
 use crate::peer::Peer;
-use log;
 use serde::de::Error;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time;
-use std::{fs, io};
+use std::{env, fs, io};
+use ipnet::IpNet;
 use toml;
 use url::Url;
 
+use crate::access_log::AccessLogConfig;
+use crate::dns;
 use crate::errors::{ConfigError, NetworkTargetError};
-use crate::security::Security;
+use crate::include;
+use crate::logger::{LogFormat, LogTarget};
+use crate::events::DEFAULT_EVENT_BUFFER_SIZE;
+use crate::health::HealthCheck;
+use crate::http3::Http3Config;
+use crate::metrics::MetricsConfig;
+use crate::route::RouteConfig;
+use crate::secret::Secret;
+use crate::security::{LimitAction, PoolSecurity, Security};
+use crate::socket::{Keepalive, SocketOptions};
+use crate::tls::{TlsConfig, TlsPolicy, UpstreamTlsConfig};
+use crate::webhook::WebhookConfig;
 
 const LOG_FILE_SIZE_HARD_LIMIT_MB: usize = 10;
+const DEFAULT_LOG_ARCHIVES: usize = 5;
+const DEFAULT_DNS_REFRESH_SECONDS: u32 = 30;
+const DEFAULT_AFFINITY_COOKIE: &str = "jalb_affinity";
 
-#[derive(Debug, Deserialize, Default)]
-enum JalbConfigVersion {
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JalbConfigVersion {
     #[default]
     #[serde(rename = "1")]
     V1,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+impl JalbConfigVersion {
+    /// The version `Config` is laid out as, older files are migrated up to it.
+    pub const CURRENT: JalbConfigVersion = JalbConfigVersion::V1;
+    const ALL: [JalbConfigVersion; 1] = [JalbConfigVersion::V1];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JalbConfigVersion::V1 => "1",
+        }
+    }
+
+    /// The `version` key of `settings`, as a string or a bare number.
+    fn of(settings: &toml::Value) -> Result<Self, ConfigError> {
+        let valid = || {
+            Self::ALL
+                .iter()
+                .map(|version| version.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let version = match settings.get("version") {
+            Some(toml::Value::String(version)) => version.clone(),
+            Some(toml::Value::Integer(version)) => version.to_string(),
+            Some(other) => return Err(ConfigError::InvalidVersion(other.to_string(), valid())),
+            None => return Err(ConfigError::MissingVersion(valid())),
+        };
+
+        Self::ALL
+            .into_iter()
+            .find(|known| known.name() == version)
+            .ok_or_else(|| ConfigError::InvalidVersion(version, valid()))
+    }
+
+    /// Rewrites settings written for this version into the next one's layout, with a warning
+    /// for everything renamed or dropped on the way. `None` once at `CURRENT`.
+    fn upgrade(&self, _settings: &mut toml::Table) -> Option<(JalbConfigVersion, Vec<String>)> {
+        match self {
+            JalbConfigVersion::V1 => None,
+        }
+    }
+}
+
+/// Brings `settings` written for `version` up to `JalbConfigVersion::CURRENT` one version at a
+/// time, returning the deprecation warnings collected.
+fn migrate(settings: &mut toml::Value, mut version: JalbConfigVersion) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let Some(table) = settings.as_table_mut() else {
+        return warnings;
+    };
+
+    while let Some((next, step)) = version.upgrade(table) {
+        warnings.push(format!(
+            "config version {} is deprecated, read as version {}",
+            version.name(),
+            next.name()
+        ));
+        warnings.extend(step);
+        version = next;
+    }
+    table.insert("version".to_string(), version.name().into());
+
+    warnings
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
 pub enum LoadBalancerType {
     #[serde(rename = "application")]
     Application,
@@ -32,7 +115,16 @@ pub enum LoadBalancerType {
     Network,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TransportProtocol {
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    #[serde(rename = "udp")]
+    Udp,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
 pub enum LoadBalancerStrategy {
     #[serde(rename = "round_robin")]
     RoundRobin,
@@ -44,18 +136,135 @@ pub enum LoadBalancerStrategy {
     Geolocation,
 }
 
-#[derive(Debug, Deserialize)]
+impl LoadBalancerStrategy {
+    /// The name jalb.toml spells it with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RoundRobin => "round_robin",
+            Self::LeastUsed => "least_used",
+            Self::WeightedAverage => "weighted_average",
+            Self::Geolocation => "geo",
+        }
+    }
+}
+
+impl FromStr for LoadBalancerStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::RoundRobin, Self::LeastUsed, Self::WeightedAverage, Self::Geolocation]
+            .into_iter()
+            .find(|strategy| strategy.name() == s)
+            .ok_or_else(|| {
+                let known = "round_robin, least_used, weighted_average or geo";
+                format!("unknown strategy {}, expected {}", s, known)
+            })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct LoadBalancerConfig {
     #[serde(rename = "type")]
     load_balancer_type: LoadBalancerType,
     strategy: LoadBalancerStrategy,
+    #[serde(default)]
+    protocol: TransportProtocol,
     listener_address: Option<IpAddr>,
     port: Option<u16>,
+    /// Deprecated, read when `[runtime] worker_threads` is unset
+    worker_threads: Option<usize>,
     max_connections: u32,
     max_requests_per_connection: u32,
+    connection_reconcile_interval_seconds: Option<u32>,
+    connection_count_decay: Option<f64>,
+    first_byte_timeout_seconds: Option<u32>,
+    idle_timeout_seconds: Option<u32>,
+    /// `idle_timeout_seconds` for WebSockets proxied by the application balancer
+    websocket_idle_timeout_seconds: Option<u32>,
+    /// Largest request body the application balancer passes on, unlimited when unset
+    max_request_body_kb: Option<u64>,
+    listen_backlog: Option<u32>,
+    /// Sockets bound per listener with `SO_REUSEPORT`, each accepted on by a task of its own
+    acceptors: Option<usize>,
+    /// How large the buffers sessions are copied through are, one each way
+    relay_buffer: Option<RelayBuffer>,
+    /// `relay_buffer` as an exact size
+    relay_buffer_kb: Option<u32>,
+    max_accepts_per_second: Option<u64>,
+    /// New connections allowed from a single client address per second
+    max_client_accepts_per_second: Option<u64>,
+    /// Count `max_client_accepts_per_second` in the `[state]` store, shared between instances
+    #[serde(default)]
+    shared_rate_limit: bool,
+    /// Reload on changes to the config file as well as on SIGHUP
+    #[serde(default)]
+    watch_config: bool,
+    /// Name of the `[[backend]]` connections go to, the first one when unset
+    default_backend: Option<String>,
+    /// Answer requests on listeners without `[[tls.listener]]` with a redirect to https
+    #[serde(default)]
+    redirect_http_to_https: bool,
+    /// Port redirects to https point at, 443 when unset
+    https_port: Option<u16>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// One `[[listener]]`, an address connections are accepted on.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    /// `[[backend]]` connections accepted here go to, the default backend when unset
+    pub backend: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HealthCheckType {
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    #[serde(rename = "http")]
+    Http,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckOptions {
+    #[serde(rename = "type", default)]
+    check_type: HealthCheckType,
+    path: Option<String>,
+    interval_seconds: Option<u32>,
+    timeout_seconds: Option<u32>,
+    failure_threshold: Option<u32>,
+    success_threshold: Option<u32>,
+}
+
+impl HealthCheckOptions {
+    pub fn to_health_check(&self) -> HealthCheck {
+        let mut check = match self.check_type {
+            HealthCheckType::Tcp => HealthCheck::tcp(),
+            HealthCheckType::Http => HealthCheck::http(self.path.as_deref().unwrap_or("/")),
+        };
+
+        if let Some(interval) = self.interval_seconds {
+            check.interval = time::Duration::from_secs(interval.into());
+        }
+        if let Some(timeout) = self.timeout_seconds {
+            check.timeout = time::Duration::from_secs(timeout.into());
+        }
+        if let Some(threshold) = self.failure_threshold {
+            check.failure_threshold = threshold;
+        }
+        if let Some(threshold) = self.success_threshold {
+            check.success_threshold = threshold;
+        }
+
+        check
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct BackendOptions {
     pub name: String,
     pub health_endpoint: Option<String>,
@@ -63,11 +272,144 @@ pub struct BackendOptions {
     health_check_timeout_seconds: Option<u32>,
     pub failed_request_threshold: Option<u32>,
     request_timeout_seconds: Option<u32>,
+    /// Whether `request_timeout_seconds` limits connecting alone or the whole session
+    #[serde(default)]
+    pub request_timeout_scope: RequestTimeoutScope,
     pub rate_limit: Option<u64>,
+    liveness: Option<HealthCheckOptions>,
+    readiness: Option<HealthCheckOptions>,
+    pub min_healthy_peers: Option<usize>,
+    /// Overrides of the global `[security]` and `[tls]` settings for this pool
+    #[serde(default)]
+    pub security: PoolSecurity,
+    #[serde(default)]
+    pub fail_open: bool,
+    /// Leave this pool out of `/readyz`, e.g. a fallback jalb can serve without
+    #[serde(default)]
+    pub optional: bool,
+    /// Originate TLS to peers with this workload's SPIFFE identity
+    spiffe: Option<SpiffeConfig>,
+    #[serde(default)]
+    pub dns_strategy: DnsStrategy,
+    #[serde(default)]
+    pub dns_prefer: AddressFamily,
+    /// How often `host:port` peers are resolved again, 0 to resolve them only on load
+    dns_refresh_seconds: Option<u32>,
+    /// HTTP version the application balancer speaks to peers
+    #[serde(default)]
+    pub http_version: HttpVersion,
+    /// Keep sending a client's requests to the peer first picked for it
+    affinity: Option<AffinityConfig>,
     pub peers: Vec<PeerConfig>,
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+pub enum AffinityType {
+    /// a cookie set on the response names the peer
+    #[serde(rename = "cookie")]
+    Cookie,
+}
+
+/// `[backend.affinity]`, pinning clients of the application balancer to a peer.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AffinityConfig {
+    #[serde(rename = "type")]
+    pub affinity_type: AffinityType,
+    /// `jalb_affinity` when unset
+    cookie_name: Option<String>,
+    /// how long clients keep the cookie, until the browser is closed when unset
+    ttl_seconds: Option<u32>,
+    /// key the cookie is signed with, a random one per start when unset
+    secret: Option<Secret>,
+}
+
+impl AffinityConfig {
+    pub fn cookie(cookie_name: &str) -> Self {
+        Self {
+            affinity_type: AffinityType::Cookie,
+            cookie_name: Some(cookie_name.to_string()),
+            ttl_seconds: None,
+            secret: None,
+        }
+    }
+
+    pub fn cookie_name(&self) -> &str {
+        self.cookie_name.as_deref().unwrap_or(DEFAULT_AFFINITY_COOKIE)
+    }
+
+    pub fn ttl(&self) -> Option<time::Duration> {
+        self.ttl_seconds
+            .map(|seconds| time::Duration::from_secs(seconds.into()))
+    }
+
+    pub fn secret(&self) -> Option<&str> {
+        self.secret.as_ref().map(Secret::expose)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SpiffeConfig {
+    /// Workload API socket, `SPIFFE_ENDPOINT_SOCKET` or the SPIRE agent default when unset
+    socket: Option<PathBuf>,
+    /// SNI sent to peers instead of their address
+    server_name: Option<String>,
+}
+
 impl BackendOptions {
+    /// A pool without peers or health checks, for building a config in code.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            health_endpoint: None,
+            health_check_interval_seconds: None,
+            health_check_timeout_seconds: None,
+            failed_request_threshold: None,
+            request_timeout_seconds: None,
+            request_timeout_scope: RequestTimeoutScope::default(),
+            rate_limit: None,
+            liveness: None,
+            readiness: None,
+            min_healthy_peers: None,
+            security: PoolSecurity::default(),
+            fail_open: false,
+            optional: false,
+            spiffe: None,
+            dns_strategy: DnsStrategy::default(),
+            dns_prefer: AddressFamily::default(),
+            dns_refresh_seconds: None,
+            http_version: HttpVersion::default(),
+            affinity: None,
+            peers: Vec::new(),
+        }
+    }
+
+    pub fn with_peer(mut self, address: impl Into<NetworkTarget>, weight: u32) -> Self {
+        self.peers.push(PeerConfig::new(address.into(), weight));
+        self
+    }
+
+    /// Gates selection on an http check of `path` on every peer.
+    pub fn with_health_endpoint(mut self, path: &str) -> Self {
+        self.health_endpoint = Some(path.to_string());
+        self
+    }
+
+    pub fn with_security(mut self, security: PoolSecurity) -> Self {
+        self.security = security;
+        self
+    }
+
+    pub fn with_affinity(mut self, affinity: AffinityConfig) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    pub fn affinity(&self) -> Option<&AffinityConfig> {
+        self.affinity.as_ref()
+    }
+
     pub fn get_health_check_interval(&self) -> Option<time::Duration> {
         if let Some(interval) = self.health_check_interval_seconds {
             return Some(time::Duration::from_secs(interval.into()));
@@ -85,16 +427,74 @@ impl BackendOptions {
     }
 
     pub fn get_request_timeout(&self) -> Option<time::Duration> {
-        if let Some(timeout) = self.health_check_timeout_seconds {
+        if let Some(timeout) = self.request_timeout_seconds {
             return Some(time::Duration::from_secs(timeout.into()));
         }
 
         None
     }
 
+    pub fn liveness_check(&self) -> Option<HealthCheck> {
+        self.liveness.as_ref().map(HealthCheckOptions::to_health_check)
+    }
+
+    /// The readiness check gating peer selection. Falls back to an http check against
+    /// `health_endpoint` when no `[backend.readiness]` section is configured.
+    pub fn readiness_check(&self) -> Option<HealthCheck> {
+        if let Some(readiness) = &self.readiness {
+            return Some(readiness.to_health_check());
+        }
+
+        let mut check = HealthCheck::http(self.health_endpoint.as_deref()?);
+        if let Some(interval) = self.get_health_check_interval() {
+            check.interval = interval;
+        }
+        if let Some(timeout) = self.get_health_check_timeout() {
+            check.timeout = timeout;
+        }
+
+        Some(check)
+    }
+
+    /// The configured peers, with `host:port` peers, and URL peers under `all_as_peers`, expanded
+    /// into one peer per address they resolve to.
     pub fn peers(&self) -> Vec<Peer> {
+        self.expand_peers().0
+    }
+
+    /// Like `peers`, but `None` when a lookup came back empty, so a failing DNS server doesn't
+    /// empty the pool on a refresh.
+    pub fn resolved_peers(&self) -> Option<Vec<Peer>> {
+        let (peers, resolved) = self.expand_peers();
+        resolved.then_some(peers)
+    }
+
+    /// The peers, and whether every name resolved.
+    fn expand_peers(&self) -> (Vec<Peer>, bool) {
         let mut peers = Vec::with_capacity(self.peers.len());
+        let mut resolved = true;
         for option in self.peers.as_slice() {
+            if self.expands(&option.address) {
+                let addrs = option.address.resolve(self.dns_prefer);
+                if addrs.is_empty() {
+                    tracing::error!(
+                        "peer {} did not resolve to any address",
+                        option.address.as_string()
+                    );
+                    resolved = false;
+                }
+                if let Some(name) = option.address.lookup_name() {
+                    dns::lookups().keep_for(&name, self.dns_refresh_interval());
+                }
+
+                for addr in addrs {
+                    let mut resolved = option.clone();
+                    resolved.address = NetworkTarget::SocketAddr(addr);
+                    peers.push(Peer::from_config(&resolved, self).unwrap());
+                }
+                continue;
+            }
+
             match Peer::from_config(option, self) {
                 Ok(peer) => {
                     peers.push(peer);
@@ -105,7 +505,111 @@ impl BackendOptions {
             }
         }
 
-        peers
+        (peers, resolved)
+    }
+
+    fn expands(&self, address: &NetworkTarget) -> bool {
+        match address {
+            NetworkTarget::Host(..) => true,
+            NetworkTarget::Url(_) => self.dns_strategy == DnsStrategy::AllAsPeers,
+            NetworkTarget::SocketAddr(_) => false,
+        }
+    }
+
+    /// How often the peers expanded from DNS records are resolved again, `None` when there
+    /// are none or `dns_refresh_seconds = 0`.
+    pub fn dns_refresh_interval(&self) -> Option<time::Duration> {
+        if !self.peers.iter().any(|peer| self.expands(&peer.address)) {
+            return None;
+        }
+
+        let seconds = self.dns_refresh_seconds.unwrap_or(DEFAULT_DNS_REFRESH_SECONDS);
+        (seconds > 0).then(|| time::Duration::from_secs(seconds.into()))
+    }
+}
+
+/// Sizes of the buffers sessions are copied through, for how connections are used.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayBuffer {
+    /// 2 KiB, for many mostly idle connections
+    Small,
+    /// 8 KiB
+    #[default]
+    Default,
+    /// 64 KiB, for bulk transfers
+    Large,
+}
+
+impl RelayBuffer {
+    pub fn size(&self) -> usize {
+        match self {
+            RelayBuffer::Small => 2 * 1024,
+            RelayBuffer::Default => 8 * 1024,
+            RelayBuffer::Large => 64 * 1024,
+        }
+    }
+}
+
+/// What a backend's `request_timeout_seconds` limits.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestTimeoutScope {
+    /// Connecting to the peer, including the TLS handshake
+    #[default]
+    Connect,
+    /// Connecting, and then the whole session
+    Session,
+}
+
+/// HTTP version requests are forwarded to a backend's peers with.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HttpVersion {
+    /// a new connection per request
+    #[default]
+    #[serde(rename = "1.1")]
+    Http11,
+    /// HTTP/2 with prior knowledge (h2c on plaintext), one connection per peer carrying every
+    /// request sent to it as a stream of its own
+    #[serde(rename = "2")]
+    Http2,
+}
+
+/// How a URL peer that resolves to several addresses is turned into upstream connections.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DnsStrategy {
+    /// Always connect to the first (preferred) address
+    #[default]
+    #[serde(rename = "first")]
+    First,
+    /// Rotate through every resolved address, connection by connection
+    #[serde(rename = "round_robin_across_records")]
+    RoundRobinAcrossRecords,
+    /// Expand the peer into one peer per resolved address at load time
+    #[serde(rename = "all_as_peers")]
+    AllAsPeers,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum AddressFamily {
+    #[default]
+    #[serde(rename = "any")]
+    Any,
+    #[serde(rename = "ipv4")]
+    Ipv4,
+    #[serde(rename = "ipv6")]
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Orders `addrs` so the preferred family comes first, keeping resolver order otherwise.
+    /// Addresses of the other family are kept as a fallback.
+    pub fn order(&self, addrs: &mut [SocketAddr]) {
+        match self {
+            Self::Any => {}
+            Self::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            Self::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
     }
 }
 
@@ -113,6 +617,8 @@ impl BackendOptions {
 pub enum NetworkTarget {
     Url(url::Url),
     SocketAddr(std::net::SocketAddr),
+    /// `host:port`, lowercased
+    Host(String, u16),
 }
 
 impl NetworkTarget {
@@ -120,17 +626,47 @@ impl NetworkTarget {
         match self {
             Self::SocketAddr(addr) => addr.to_string(),
             Self::Url(url) => url.to_string(),
+            Self::Host(host, port) => format!("{}:{}", host, port),
         }
     }
 
     pub fn to_socket_addrs(&self) -> Option<SocketAddr> {
+        self.resolve(AddressFamily::Any).into_iter().next()
+    }
+
+    /// Every address the target resolves to, preferred family first. Empty if a URL or host
+    /// fails to resolve, which is recorded in [`dns::lookups`].
+    pub fn resolve(&self, prefer: AddressFamily) -> Vec<SocketAddr> {
+        let mut addrs = match self {
+            Self::SocketAddr(addr) => vec![*addr],
+            Self::Url(url) => match self.lookup_name() {
+                Some(name) => dns::lookup(&name, || {
+                    url.socket_addrs(|| url.port_or_known_default())
+                }),
+                None => url
+                    .socket_addrs(|| url.port_or_known_default())
+                    .unwrap_or_default(),
+            },
+            Self::Host(host, port) => dns::lookup(&self.as_string(), || {
+                (host.as_str(), *port).to_socket_addrs().map(Iterator::collect)
+            }),
+        };
+
+        prefer.order(&mut addrs);
+        addrs
+    }
+
+    /// The `host:port` looked up to resolve the target, `None` when it needs no lookup.
+    pub(crate) fn lookup_name(&self) -> Option<String> {
         match self {
-            Self::SocketAddr(addr) => Some(*addr),
-            Self::Url(url) => url
-                .socket_addrs(|| url.port_or_known_default())
-                .ok()?
-                .into_iter()
-                .next(),
+            Self::SocketAddr(_) => None,
+            Self::Url(url) => match url.host() {
+                Some(url::Host::Domain(host)) => {
+                    Some(format!("{}:{}", host, url.port_or_known_default()?))
+                }
+                _ => None,
+            },
+            Self::Host(..) => Some(self.as_string()),
         }
     }
 
@@ -142,9 +678,11 @@ impl NetworkTarget {
     ///
     /// # Example
     /// ```
+    /// # use std::str::FromStr;
+    /// # use jalb::config::NetworkTarget;
     /// let mut target = NetworkTarget::from_str("http://example.com").unwrap();
-    /// target.push("api/v1/users").unwrap();
-    /// assert_eq!(target.as_string(), "http://example.com/api/v1/users");
+    /// target.push("healthz").unwrap();
+    /// assert_eq!(target.as_string(), "http://example.com/healthz");
     /// ```
     pub fn push(&mut self, path: &str) -> Result<(), NetworkTargetError> {
         // TODO: fought the borrow checker for awhile on this one. This shouldn't harm performance much
@@ -159,22 +697,105 @@ impl NetworkTarget {
 
                 Err(_) => Err(NetworkTargetError::InvalidUrlBase(str)),
             },
-            Self::SocketAddr(_) => Err(NetworkTargetError::PushToSocketAddr),
+            Self::SocketAddr(_) | Self::Host(..) => Err(NetworkTargetError::PushToSocketAddr),
         }
     }
 }
 
+impl From<SocketAddr> for NetworkTarget {
+    fn from(addr: SocketAddr) -> Self {
+        NetworkTarget::SocketAddr(addr)
+    }
+}
+
 impl FromStr for NetworkTarget {
     type Err = NetworkTargetError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(NetworkTarget::SocketAddr(addr));
+        }
+        // checked before urls, which would take `example.com:80` for a scheme and a path
+        if let Some((host, port)) = host_and_port(s) {
+            return Ok(NetworkTarget::Host(host, port));
+        }
+
         Url::parse(s)
             .map(NetworkTarget::Url)
-            .or_else(|_| s.parse::<SocketAddr>().map(NetworkTarget::SocketAddr))
             .map_err(|_| NetworkTargetError::InvalidTargetError(s.to_owned()))
     }
 }
 
+/// Takes `[profiles]` out of `settings`, laying the one named `profile` over the rest first.
+/// Returns whether there was a `[profiles]` section.
+fn apply_profile(settings: &mut toml::Value, profile: Option<&str>) -> Result<bool, ConfigError> {
+    let profiles = settings
+        .as_table_mut()
+        .and_then(|settings| settings.remove("profiles"));
+    let Some(profile) = profile else {
+        return Ok(profiles.is_some());
+    };
+
+    let mut profiles = match profiles {
+        Some(toml::Value::Table(profiles)) => profiles,
+        _ => toml::Table::new(),
+    };
+    let Some(selected) = profiles.remove(profile) else {
+        let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        let known = if known.is_empty() {
+            "none".to_string()
+        } else {
+            known.join(", ")
+        };
+        return Err(ConfigError::UnknownProfile(profile.to_string(), known));
+    };
+
+    overlay(settings, selected);
+    Ok(true)
+}
+
+/// Lays `from` over `into`: tables merge key by key, entries of lists like `[[backend]]` merge
+/// with the entry of the same `name`, anything else is replaced.
+fn overlay(into: &mut toml::Value, from: toml::Value) {
+    match (into, from) {
+        (toml::Value::Table(into), toml::Value::Table(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => overlay(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(into), toml::Value::Array(from))
+            if from.iter().all(|item| item.get("name").is_some()) =>
+        {
+            for item in from {
+                let existing = into.iter_mut().find(|entry| entry.get("name") == item.get("name"));
+                match existing {
+                    Some(existing) => overlay(existing, item),
+                    None => into.push(item),
+                }
+            }
+        }
+        (into, from) => *into = from,
+    }
+}
+
+/// Splits `example.com:80` into a hostname and port, `None` for anything else.
+fn host_and_port(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let labels_valid = host.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    });
+
+    labels_valid.then(|| (host.to_ascii_lowercase(), port))
+}
+
 impl<'de> Deserialize<'de> for NetworkTarget {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -186,23 +807,73 @@ impl<'de> Deserialize<'de> for NetworkTarget {
     }
 }
 
+impl Serialize for NetworkTarget {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.as_string())
+    }
+}
+
 impl Hash for NetworkTarget {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             NetworkTarget::SocketAddr(socket_addr) => socket_addr.hash(state),
             NetworkTarget::Url(url) => url.hash(state),
+            NetworkTarget::Host(host, port) => (host, port).hash(state),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Accepts the `version` key, which [`JalbConfigVersion::of`] reads and checks before the rest of
+/// the file.
+fn checked_version<'de, D>(deserializer: D) -> Result<JalbConfigVersion, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    toml::Value::deserialize(deserializer)?;
+    Ok(JalbConfigVersion::default())
+}
+
+/// `[backend]` as a single table, or `[[backend]]` for several named pools.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<BackendOptions>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match toml::Value::deserialize(deserializer)? {
+        toml::Value::Array(backends) => backends
+            .into_iter()
+            .map(|backend| backend.try_into().map_err(D::Error::custom))
+            .collect(),
+        backend => Ok(vec![backend.try_into().map_err(D::Error::custom)?]),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PeerConfig {
     address: NetworkTarget,
     weight: Option<u32>,
     coordinates: Option<geo::Coord>,
+    /// Overrides of the backend's settings of the same name for this peer alone
+    request_timeout_seconds: Option<u32>,
+    failed_request_threshold: Option<u32>,
+    rate_limit: Option<u64>,
 }
 
 impl PeerConfig {
+    pub fn new(address: NetworkTarget, weight: u32) -> Self {
+        Self {
+            address,
+            weight: Some(weight),
+            coordinates: None,
+            request_timeout_seconds: None,
+            failed_request_threshold: None,
+            rate_limit: None,
+        }
+    }
+
     pub fn get_addr(&self) -> NetworkTarget {
         self.address.clone()
     }
@@ -214,12 +885,31 @@ impl PeerConfig {
     pub fn get_coordinates(&self) -> Option<geo::Coord> {
         self.coordinates
     }
+
+    /// The peer's request timeout, the backend's when it sets none.
+    pub fn get_request_timeout(&self, backend: &BackendOptions) -> Option<time::Duration> {
+        self.request_timeout_seconds
+            .map(|timeout| time::Duration::from_secs(timeout.into()))
+            .or_else(|| backend.get_request_timeout())
+    }
+
+    pub fn get_failed_request_threshold(&self, backend: &BackendOptions) -> Option<u32> {
+        self.failed_request_threshold.or(backend.failed_request_threshold)
+    }
+
+    pub fn get_rate_limit(&self, backend: &BackendOptions) -> Option<u64> {
+        self.rate_limit.or(backend.rate_limit)
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct LoggingPath(PathBuf);
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LoggingPath(PathBuf);
 
 impl LoggingPath {
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
     /// Returns the conventional default path for a log file for the given program name.
     ///
     /// This function uses conditional compilation to provide the correct path based on
@@ -236,7 +926,7 @@ impl LoggingPath {
     fn new_with_default_log_path() -> Result<Self, io::Error> {
         let mut path: PathBuf;
 
-        const PROGRAM_NAME: &'static str = "jalb";
+        const PROGRAM_NAME: &str = "jalb";
         // --- Windows ---
         #[cfg(target_os = "windows")]
         {
@@ -306,92 +996,1927 @@ impl Default for LoggingPath {
     fn default() -> Self {
         Self::new_with_default_log_path()
         .map_err(|e| {
-            log::error!("failed to create logfile at default path {:?}", e)
+            tracing::error!("failed to create logfile at default path {:?}", e)
         })
         .unwrap()
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct LoggingConfig {
     log_level: Option<log::Level>,
+    #[serde(default)]
+    log_target: LogTarget,
+    #[serde(default)]
+    format: LogFormat,
+    /// Levels for single modules in place of `log_level`, e.g. `"jalb::health" = "debug"`
+    #[serde(default)]
+    filters: BTreeMap<String, log::Level>,
     rotate_logs: bool,
     log_capacity_mb: Option<usize>,
+    /// Rotated files kept next to the log, the oldest is deleted past this
+    log_archives: Option<usize>,
     path: Option<LoggingPath>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    loadbalancer: LoadBalancerConfig,
-    logging: LoggingConfig,
-    pub security: Security,
-    pub backend: BackendOptions,
+/// `[tcp]`, socket options for both sides of every session.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Turns Nagle's algorithm off, or back on when false
+    nodelay: Option<bool>,
+    /// Idle time before keepalive probes are sent, none are unless set
+    keepalive_seconds: Option<u32>,
+    keepalive_interval_seconds: Option<u32>,
+    keepalive_retries: Option<u32>,
+    recv_buffer_kb: Option<u32>,
+    send_buffer_kb: Option<u32>,
 }
 
-impl Config {
-    pub fn load_from_file(path: &str) -> Result<Config, ConfigError> {
-        let toml_str = fs::read_to_string(path)?;
-
-        let config = toml::from_str::<Config>(&toml_str)?;
-
-        Ok(config)
+impl TcpConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let keepalive_tuned =
+            self.keepalive_interval_seconds.is_some() || self.keepalive_retries.is_some();
+        if keepalive_tuned && self.keepalive_seconds.is_none() {
+            return Err("keepalive interval and retries need keepalive_seconds".to_string());
+        }
+        let settings = [
+            ("keepalive_seconds", self.keepalive_seconds),
+            ("keepalive_interval_seconds", self.keepalive_interval_seconds),
+            ("keepalive_retries", self.keepalive_retries),
+            ("recv_buffer_kb", self.recv_buffer_kb),
+            ("send_buffer_kb", self.send_buffer_kb),
+        ];
+        for (name, value) in settings {
+            if value == Some(0) {
+                return Err(format!("{} must be at least 1", name));
+            }
+        }
+        Ok(())
     }
 
-    pub fn strategy(&self) -> LoadBalancerStrategy {
-        self.loadbalancer.strategy
+    pub fn to_socket_options(&self) -> SocketOptions {
+        let seconds = |seconds: u32| time::Duration::from_secs(seconds.into());
+        let kb = |kb: u32| kb as usize * 1024;
+        SocketOptions {
+            nodelay: self.nodelay,
+            keepalive: self.keepalive_seconds.map(|idle| Keepalive {
+                idle: seconds(idle),
+                interval: self.keepalive_interval_seconds.map(seconds),
+                retries: self.keepalive_retries,
+            }),
+            recv_buffer: self.recv_buffer_kb.map(kb),
+            send_buffer: self.send_buffer_kb.map(kb),
+        }
     }
+}
 
-    pub fn load_balancer_type(&self) -> LoadBalancerType {
-        self.loadbalancer.load_balancer_type
-    }
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    session_timeout_seconds: Option<u32>,
+    #[serde(default)]
+    quic: bool,
+    quic_connection_id_length: Option<u8>,
+}
 
-    pub fn ip(&self) -> IpAddr {
-        self.loadbalancer
-            .listener_address
-            .unwrap_or(IpAddr::from_str("127.0.0.1").unwrap())
-    }
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    listener_address: Option<IpAddr>,
+    port: Option<u16>,
+    grpc_port: Option<u16>,
+    event_buffer_size: Option<usize>,
+    /// trace 1 in every this many tcp connections, 0 for none
+    #[serde(default)]
+    flow_sample: u64,
+    #[serde(default)]
+    tokens: Vec<AdminToken>,
+}
 
-    pub fn port(&self) -> u16 {
-        self.loadbalancer.port.unwrap_or(9220)
-    }
+/// What an admin token may do. `Write` includes `Read`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminPermission {
+    /// Stats, events and peer listings
+    Read,
+    /// Operations that change state, like adjusting a peer's weight
+    Write,
+}
 
-    pub fn listener_address(&self) -> std::net::SocketAddr {
-        let ip = self.ip();
-        let port = self.port();
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    /// shown in logs instead of the token itself
+    pub name: Option<String>,
+    pub token: Secret,
+    pub permission: AdminPermission,
+}
 
-        std::net::SocketAddr::new(ip, port)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateBackend {
+    #[default]
+    File,
+    Redis,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    #[serde(default)]
+    backend: StateBackend,
+    path: Option<PathBuf>,
+    redis_url: Option<Secret>,
+}
+
+/// How the tokio runtime is built, tokio's defaults for anything unset. Read once at startup.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// one per core when unset
+    worker_threads: Option<usize>,
+    /// cap on the threads running blocking work such as DNS lookups and file writes
+    max_blocking_threads: Option<usize>,
+    /// scheduler ticks between checks for new io and timer events
+    event_interval: Option<u32>,
+    /// scheduler ticks between polls of the shared queue, ahead of a worker's own
+    global_queue_interval: Option<u32>,
+    /// threads relaying plain tcp sessions over io_uring, built with the `uring` feature
+    io_uring_threads: Option<usize>,
+}
+
+/// `[forwarded]`, the headers telling peers of the application balancer who the client is.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardedConfig {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Real-IP`, sent unless false
+    x_forwarded: Option<bool>,
+    /// an RFC 7239 `Forwarded` header
+    #[serde(default)]
+    rfc7239: bool,
+    /// addresses and networks whose forwarding headers are kept and appended to, those of
+    /// other clients are replaced
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+}
+
+impl ForwardedConfig {
+    pub fn x_forwarded(&self) -> bool {
+        self.x_forwarded.unwrap_or(true)
     }
 
-    pub fn rotate_logs(&self) -> bool {
-        self.logging.rotate_logs
+    pub fn rfc7239(&self) -> bool {
+        self.rfc7239
     }
 
-    pub fn log_file_max_size(&self) -> usize {
-        const BYTES_PER_MEGABYTE: usize = 1024 * 1024;
+    /// `trusted_proxies` as networks, a single address as a network of one.
+    pub fn trusted_proxies(&self) -> Result<Vec<IpNet>, ConfigError> {
+        self.trusted_proxies
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map(|net| net.trunc())
+                    .map_err(|_| {
+                        ConfigError::InvalidForwarded(format!(
+                            "{} in trusted_proxies is not an address or network",
+                            entry
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
 
-        if let Some(max_size) = self.logging.log_capacity_mb {
-            println!("max size req: {}", max_size);
-            return max_size * BYTES_PER_MEGABYTE;
-        }
+/// `[cache]`, GET responses of the application balancer kept in memory to answer repeats of
+/// the request without asking a peer.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// how long responses without a `max-age` stay fresh; unset, only those with one are kept
+    ttl_seconds: Option<u64>,
+    /// larger responses are passed on without being kept
+    #[serde(default = "default_cache_max_entry_kb")]
+    max_entry_kb: u64,
+    /// past this the least recently used responses are dropped
+    #[serde(default = "default_cache_max_size_mb")]
+    max_size_mb: u64,
+}
 
-        LOG_FILE_SIZE_HARD_LIMIT_MB * BYTES_PER_MEGABYTE
+fn default_cache_max_entry_kb() -> u64 {
+    1024
+}
+
+fn default_cache_max_size_mb() -> u64 {
+    64
+}
+
+impl CacheConfig {
+    pub fn ttl(&self) -> Option<time::Duration> {
+        self.ttl_seconds.map(time::Duration::from_secs)
     }
 
-    pub fn logfile_path(&self) -> LoggingPath {
-        self.logging.path.clone().unwrap_or_default()
+    pub fn max_entry_bytes(&self) -> usize {
+        (self.max_entry_kb * 1024) as usize
+    }
+
+    pub fn max_size_bytes(&self) -> usize {
+        (self.max_size_mb * 1024 * 1024) as usize
     }
 }
 
-#[cfg(test)]
-mod tests {
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditTarget {
+    #[default]
+    File,
+    Syslog,
+}
 
-    use super::*;
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    #[serde(default)]
+    target: AuditTarget,
+    /// audit file, or the syslog socket for `target = "syslog"`
+    path: Option<PathBuf>,
+}
 
-    #[test]
+const DEFAULT_CONFIG_PATH: &str = "./jalb.toml";
+
+/// Turns serde's error for an unknown key into [`ConfigError::UnknownKey`] when one of the keys
+/// it expected is close enough to be what was meant, e.g. `strategy` for `strategey`.
+fn suggest_key(error: ConfigError) -> ConfigError {
+    let message = match &error {
+        ConfigError::DeserializationError(message)
+        | ConfigError::YamlDeserializationError(message)
+        | ConfigError::JsonDeserializationError(message) => message.clone(),
+        _ => return error,
+    };
+
+    match closest_key(&message) {
+        Some(key) => ConfigError::UnknownKey(message.trim_end().to_string(), key),
+        None => error,
+    }
+}
+
+/// Picks from an "unknown field `strategey`, expected one of `type`, `strategy`, ..." message
+/// the expected key nearest the unknown one, if any is within a third of its length.
+fn closest_key(message: &str) -> Option<String> {
+    const UNKNOWN: &str = "unknown field `";
+
+    let rest = &message[message.find(UNKNOWN)? + UNKNOWN.len()..];
+    let (unknown, rest) = rest.split_once('`')?;
+    let (_, expected) = rest.split_once("expected")?;
+    let expected = expected.lines().next()?;
+
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|key| (edit_distance(unknown, key), key))
+        .filter(|(distance, _)| *distance <= (unknown.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, key)| key.to_string())
+}
+
+/// Edits, counting a swap of neighbouring characters as one, to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+/// Replaces every `${VAR}` in the config text with that variable from `lookup`, before the text
+/// is parsed as `format`, so a placeholder can stand for any value: `port = ${JALB_PORT}`,
+/// `path = "${LOG_DIR}/jalb.log"`. Inside a quoted string the value is escaped as that string
+/// needs; anywhere else it must be a plain number, word or address, so it can never add keys or
+/// tables. Comments are left alone and `$${` is a literal `${`.
+pub(crate) fn interpolate(
+    text: &str,
+    format: ConfigFormat,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut context = Context::Bare;
+    let mut line = 1;
+    let mut copied = 0;
+    let mut idx = 0;
+
+    while let Some(c) = text[idx..].chars().next() {
+        let rest = &text[idx..];
+        let invalid = |reason: String| ConfigError::Interpolation(line, reason);
+
+        if context != Context::Comment && rest.starts_with("$${") {
+            expanded.push_str(&text[copied..idx]);
+            expanded.push_str("${");
+            idx += 3;
+            copied = idx;
+            continue;
+        }
+        if context != Context::Comment && rest.starts_with("${") {
+            expanded.push_str(&text[copied..idx]);
+            let Some(len) = rest[2..].find('}') else {
+                return Err(invalid("unterminated ${".to_string()));
+            };
+            let name = &rest[2..2 + len];
+            let value = lookup(name)
+                .ok_or_else(|| invalid(format!("environment variable {} is not set", name)))?;
+            write_value(&mut expanded, &value, context, format)
+                .map_err(|reason| invalid(format!("{} {}", name, reason)))?;
+            idx += 2 + len + 1;
+            copied = idx;
+            continue;
+        }
+
+        if c == '\n' {
+            line += 1;
+        }
+        match context {
+            Context::Comment if c == '\n' => context = Context::Bare,
+            Context::Bare => {
+                if let Some((opened, len)) = Context::opened_at(text, idx, format) {
+                    context = opened;
+                    idx += len;
+                    continue;
+                }
+            }
+            Context::Escaped(_) if c == '\\' => {
+                // skips the escaped character, a line ending included
+                let escaped = rest[1..].chars().next();
+                if escaped == Some('\n') {
+                    line += 1;
+                }
+                idx += 1 + escaped.map_or(0, char::len_utf8);
+                continue;
+            }
+            Context::Literal(_) if format == ConfigFormat::Yaml && rest.starts_with("''") => {
+                idx += 2;
+                continue;
+            }
+            Context::Escaped(end) | Context::Literal(end) if rest.starts_with(end) => {
+                context = Context::Bare;
+                idx += end.len();
+                continue;
+            }
+            _ => {}
+        }
+        idx += c.len_utf8();
+    }
+
+    expanded.push_str(&text[copied..]);
+    Ok(expanded)
+}
+
+/// [`interpolate`] with the environment, returning the values placed in the text as well, for
+/// [`redact`] to keep them out of errors about it.
+pub(crate) fn interpolate_env(
+    text: &str,
+    format: ConfigFormat,
+) -> Result<(String, Vec<String>), ConfigError> {
+    let values = RefCell::new(Vec::new());
+    let text = interpolate(text, format, |name| {
+        let value = env::var(name).ok();
+        values.borrow_mut().extend(value.clone());
+        value
+    })?;
+    Ok((text, values.into_inner()))
+}
+
+/// `message` with every one of `values` in it replaced by `***`. Values shorter than four
+/// characters are left, they can't be told apart from the message's own words.
+pub(crate) fn redact(message: &str, values: &[String]) -> String {
+    values
+        .iter()
+        .filter(|value| value.chars().count() >= 4)
+        .fold(message.to_string(), |message, value| message.replace(value.as_str(), "***"))
+}
+
+/// `error` about interpolated config text, with the `values` placed in it redacted.
+fn redact_error(error: ConfigError, values: &[String]) -> ConfigError {
+    match error {
+        ConfigError::DeserializationError(message) => {
+            ConfigError::DeserializationError(redact(&message, values))
+        }
+        ConfigError::YamlDeserializationError(message) => {
+            ConfigError::YamlDeserializationError(redact(&message, values))
+        }
+        ConfigError::JsonDeserializationError(message) => {
+            ConfigError::JsonDeserializationError(redact(&message, values))
+        }
+        ConfigError::UnknownKey(message, key) => {
+            ConfigError::UnknownKey(redact(&message, values), key)
+        }
+        ConfigError::InvalidVersion(version, valid) => {
+            ConfigError::InvalidVersion(redact(&version, values), valid)
+        }
+        error => error,
+    }
+}
+
+/// What surrounds a spot in the config text, deciding how a placeholder's value is written
+/// there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Bare,
+    Comment,
+    /// A string with backslash escapes, until its closing quote
+    Escaped(&'static str),
+    /// A string taken as written, until its closing quote
+    Literal(&'static str),
+}
+
+impl Context {
+    /// The comment or string starting at `idx` of `text` in `format`, with the length of what
+    /// opens it.
+    fn opened_at(text: &str, idx: usize, format: ConfigFormat) -> Option<(Self, usize)> {
+        let rest = &text[idx..];
+        let line = &text[text[..idx].rfind('\n').map_or(0, |n| n + 1)..idx];
+        match format {
+            ConfigFormat::Toml => [
+                ("\"\"\"", Self::Escaped("\"\"\"")),
+                ("\"", Self::Escaped("\"")),
+                ("'''", Self::Literal("'''")),
+                ("'", Self::Literal("'")),
+                ("#", Self::Comment),
+            ]
+            .into_iter()
+            .find(|(opening, _)| rest.starts_with(opening))
+            .map(|(opening, context)| (context, opening.len())),
+            // quotes only open a string at the start of a value, as in `key: "..."`, not in
+            // the middle of one like `key: don't`, and comments follow a space
+            ConfigFormat::Yaml => {
+                let starts_value = line
+                    .trim_end()
+                    .chars()
+                    .last()
+                    .is_none_or(|c| matches!(c, ':' | '-' | '[' | '{' | ',' | '?'));
+                let after_space = line.chars().last().is_none_or(char::is_whitespace);
+                match rest.chars().next()? {
+                    '"' if starts_value => Some((Self::Escaped("\""), 1)),
+                    '\'' if starts_value => Some((Self::Literal("'"), 1)),
+                    '#' if after_space => Some((Self::Comment, 1)),
+                    _ => None,
+                }
+            }
+            ConfigFormat::Json => rest.starts_with('"').then_some((Self::Escaped("\""), 1)),
+        }
+    }
+}
+
+/// Writes `value` in place of a placeholder in `context`, escaped as the string around it needs,
+/// or says why it can't be written there.
+fn write_value(
+    expanded: &mut String,
+    value: &str,
+    context: Context,
+    format: ConfigFormat,
+) -> Result<(), String> {
+    match context {
+        Context::Escaped(_) => {
+            for c in value.chars() {
+                match c {
+                    '"' => expanded.push_str("\\\""),
+                    '\\' => expanded.push_str("\\\\"),
+                    '\n' => expanded.push_str("\\n"),
+                    '\r' => expanded.push_str("\\r"),
+                    '\t' => expanded.push_str("\\t"),
+                    c if c.is_control() => expanded.push_str(&format!("\\u{:04X}", c as u32)),
+                    c => expanded.push(c),
+                }
+            }
+        }
+        Context::Literal(_) if value.chars().any(|c| c.is_control() && c != '\t') => {
+            return Err("holds line breaks or other control characters, which need \"...\"".into());
+        }
+        Context::Literal(_) if format == ConfigFormat::Yaml => {
+            expanded.push_str(&value.replace('\'', "''"));
+        }
+        Context::Literal(_) if value.contains('\'') => {
+            return Err("holds a ', which needs a \"...\" string".into());
+        }
+        Context::Literal(_) => expanded.push_str(value),
+        Context::Bare => {
+            let plain = |c: char| c.is_ascii_alphanumeric() || "_.+-:/".contains(c);
+            if value.is_empty() || !value.chars().all(plain) {
+                return Err("is not a plain number or word, write the placeholder in quotes".into());
+            }
+            expanded.push_str(value);
+        }
+        Context::Comment => unreachable!("placeholders in comments are left alone"),
+    }
+    Ok(())
+}
+
+/// Syntax of the config file. All three describe the same settings, laid out as in jalb.toml.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Guesses the format from `path`'s extension, TOML unless it is .yaml, .yml or .json.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    pub fn parse<T: serde::de::DeserializeOwned>(&self, text: &str) -> Result<T, ConfigError> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml_ng::from_str(text)?,
+            ConfigFormat::Json => serde_json::from_str(text)?,
+        })
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!("unknown config format {}, expected toml, yaml or json", s)),
+        }
+    }
+}
+
+/// Settings that can be given on the command line or as `JALB_*` environment variables instead
+/// of editing jalb.toml. The command line wins over the environment, which wins over the file.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigOverrides {
+    pub config_path: Option<PathBuf>,
+    /// Format of `config_path`, from its extension when unset
+    pub config_format: Option<ConfigFormat>,
+    pub listener_address: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub worker_threads: Option<usize>,
+    /// `[profiles.<name>]` laid over the rest of the file
+    pub profile: Option<String>,
+}
+
+impl ConfigOverrides {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        fn parse<T: FromStr>(
+            lookup: &impl Fn(&str) -> Option<String>,
+            name: &str,
+        ) -> Result<Option<T>, ConfigError> {
+            lookup(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidOverride(name.to_string(), value))
+                })
+                .transpose()
+        }
+
+        Ok(Self {
+            config_path: lookup("JALB_CONFIG").map(PathBuf::from),
+            config_format: parse(&lookup, "JALB_CONFIG_FORMAT")?,
+            listener_address: parse(&lookup, "JALB_LISTENER_ADDR")?,
+            port: parse(&lookup, "JALB_PORT")?,
+            worker_threads: parse(&lookup, "JALB_WORKER_THREADS")?,
+            profile: lookup("JALB_PROFILE"),
+        })
+    }
+
+    /// `self` with anything unset taken from `fallback`.
+    pub fn or(self, fallback: ConfigOverrides) -> ConfigOverrides {
+        ConfigOverrides {
+            config_path: self.config_path.or(fallback.config_path),
+            config_format: self.config_format.or(fallback.config_format),
+            listener_address: self.listener_address.or(fallback.listener_address),
+            port: self.port.or(fallback.port),
+            worker_threads: self.worker_threads.or(fallback.worker_threads),
+            profile: self.profile.or(fallback.profile),
+        }
+    }
+}
+
+/// Puts a [`Config`] together in code, starting from the same defaults a minimal jalb.toml
+/// would have: round robin over TCP on 127.0.0.1:9220 with no security rules.
+#[derive(Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                loadbalancer: LoadBalancerConfig {
+                    load_balancer_type: LoadBalancerType::Network,
+                    strategy: LoadBalancerStrategy::RoundRobin,
+                    protocol: TransportProtocol::Tcp,
+                    listener_address: None,
+                    port: None,
+                    worker_threads: None,
+                    max_connections: 1000,
+                    max_requests_per_connection: 100,
+                    connection_reconcile_interval_seconds: None,
+                    connection_count_decay: None,
+                    first_byte_timeout_seconds: None,
+                    idle_timeout_seconds: None,
+                    websocket_idle_timeout_seconds: None,
+                    max_request_body_kb: None,
+                    listen_backlog: None,
+                    acceptors: None,
+                    relay_buffer: None,
+                    relay_buffer_kb: None,
+                    max_accepts_per_second: None,
+                    max_client_accepts_per_second: None,
+                    shared_rate_limit: false,
+                    watch_config: false,
+                    default_backend: None,
+                    redirect_http_to_https: false,
+                    https_port: None,
+                },
+                logging: LoggingConfig {
+                    log_level: None,
+                    log_target: LogTarget::default(),
+                    format: LogFormat::default(),
+                    filters: BTreeMap::new(),
+                    rotate_logs: false,
+                    log_capacity_mb: None,
+                    log_archives: None,
+                    path: None,
+                },
+                runtime: None,
+                admin: None,
+                udp: None,
+                tcp: None,
+                state: None,
+                tls: None,
+                audit: None,
+                forwarded: None,
+                cache: None,
+                http3: None,
+                access_log: None,
+                metrics: None,
+                webhooks: Vec::new(),
+                security: Security::new(),
+                backends: Vec::new(),
+                listeners: Vec::new(),
+                routes: Vec::new(),
+                version: JalbConfigVersion::CURRENT,
+                source: PathBuf::new(),
+                format: ConfigFormat::default(),
+                profile: None,
+            },
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn with_type(mut self, load_balancer_type: LoadBalancerType) -> Self {
+        self.config.loadbalancer.load_balancer_type = load_balancer_type;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: LoadBalancerStrategy) -> Self {
+        self.config.loadbalancer.strategy = strategy;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: TransportProtocol) -> Self {
+        self.config.loadbalancer.protocol = protocol;
+        self
+    }
+
+    pub fn with_listener(mut self, addr: SocketAddr) -> Self {
+        self.config.loadbalancer.listener_address = Some(addr.ip());
+        self.config.loadbalancer.port = Some(addr.port());
+        self
+    }
+
+    /// Adds a pool. The first one added takes connections unless `with_default_backend` says
+    /// otherwise.
+    pub fn with_backend(mut self, backend: BackendOptions) -> Self {
+        self.config.backends.push(backend);
+        self
+    }
+
+    /// Adds a peer to the most recently added pool, or to a new pool named `default`.
+    pub fn with_peer(mut self, address: impl Into<NetworkTarget>, weight: u32) -> Self {
+        if self.config.backends.is_empty() {
+            self.config.backends.push(BackendOptions::new("default"));
+        }
+        let backend = self.config.backends.last_mut().unwrap();
+        backend.peers.push(PeerConfig::new(address.into(), weight));
+        self
+    }
+
+    /// Adds a `[[route]]`, matched after those added before it on a tie.
+    pub fn with_route(mut self, route: RouteConfig) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: CacheConfig) -> Self {
+        self.config.cache = Some(cache);
+        self
+    }
+
+    pub fn with_http3(mut self, http3: Http3Config) -> Self {
+        self.config.http3 = Some(http3);
+        self
+    }
+
+    pub fn with_default_backend(mut self, name: &str) -> Self {
+        self.config.loadbalancer.default_backend = Some(name.to_string());
+        self
+    }
+
+    pub fn with_security(mut self, security: Security) -> Self {
+        self.config.security = security;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: u32) -> Self {
+        self.config.loadbalancer.max_connections = max;
+        self
+    }
+
+    pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+        self.config.loadbalancer.acceptors = Some(acceptors);
+        self
+    }
+
+    pub fn with_relay_buffer(mut self, buffer: RelayBuffer) -> Self {
+        self.config.loadbalancer.relay_buffer = Some(buffer);
+        self
+    }
+
+    /// Rounded down to whole seconds, like `first_byte_timeout_seconds`.
+    pub fn with_first_byte_timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.loadbalancer.first_byte_timeout_seconds = Some(timeout.as_secs() as u32);
+        self
+    }
+
+    /// Rounded down to whole seconds, like `idle_timeout_seconds`.
+    pub fn with_idle_timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.loadbalancer.idle_timeout_seconds = Some(timeout.as_secs() as u32);
+        self
+    }
+
+    /// Redirects requests on plaintext listeners to https on `port`.
+    pub fn with_https_redirect(mut self, port: u16) -> Self {
+        self.config.loadbalancer.redirect_http_to_https = true;
+        self.config.loadbalancer.https_port = Some(port);
+        self
+    }
+
+    /// Runs the checks a config file goes through when loaded.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let mut config = self.config;
+        config.finish()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    loadbalancer: LoadBalancerConfig,
+    logging: LoggingConfig,
+    runtime: Option<RuntimeConfig>,
+    admin: Option<AdminConfig>,
+    udp: Option<UdpConfig>,
+    tcp: Option<TcpConfig>,
+    state: Option<StateConfig>,
+    tls: Option<TlsConfig>,
+    audit: Option<AuditConfig>,
+    forwarded: Option<ForwardedConfig>,
+    cache: Option<CacheConfig>,
+    http3: Option<Http3Config>,
+    access_log: Option<AccessLogConfig>,
+    metrics: Option<MetricsConfig>,
+    /// URLs called when peers or whole backends go down or come back
+    #[serde(rename = "webhook", default, skip_serializing_if = "Vec::is_empty")]
+    webhooks: Vec<WebhookConfig>,
+    pub security: Security,
+    #[serde(rename = "backend", deserialize_with = "one_or_many")]
+    backends: Vec<BackendOptions>,
+    /// Addresses to accept connections on, in place of `listener_address` and `port`
+    #[serde(rename = "listener", default, skip_serializing_if = "Vec::is_empty")]
+    listeners: Vec<ListenerConfig>,
+    /// Backends picked by the request's host, ahead of the listener's
+    #[serde(rename = "route", default, skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<RouteConfig>,
+    /// Schema the file was written for, checked and migrated before the rest is read
+    #[serde(default, skip_serializing, deserialize_with = "checked_version")]
+    version: JalbConfigVersion,
+    /// File the config was read from
+    #[serde(skip)]
+    source: PathBuf,
+    #[serde(skip)]
+    format: ConfigFormat,
+    /// The `[profiles]` entry applied, if any
+    #[serde(skip)]
+    profile: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file named by `cli` or the environment, falling back to `./jalb.toml`,
+    /// with the command line and environment overrides applied on top.
+    pub fn load(cli: ConfigOverrides) -> Result<Config, ConfigError> {
+        let overrides = cli.or(ConfigOverrides::from_env()?);
+        let path = overrides
+            .config_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let format = overrides
+            .config_format
+            .unwrap_or_else(|| ConfigFormat::from_path(&path));
+
+        let mut config = Self::load_profile(&path, format, overrides.profile.as_deref())?;
+        config.apply_overrides(&overrides);
+        Ok(config)
+    }
+
+    fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(address) = overrides.listener_address {
+            self.loadbalancer.listener_address = Some(address);
+        }
+        if let Some(port) = overrides.port {
+            self.loadbalancer.port = Some(port);
+        }
+        if let Some(threads) = overrides.worker_threads {
+            self.runtime.get_or_insert_with(RuntimeConfig::default).worker_threads = Some(threads);
+        }
+    }
+
+    /// Reads `text` in `format`, merging in the files it includes from `dir`, laying `profile`
+    /// over the result and migrating it when written for an older schema version.
+    fn parse(
+        text: &str,
+        format: ConfigFormat,
+        dir: &Path,
+        profile: Option<&str>,
+    ) -> Result<Config, ConfigError> {
+        let mut settings = format.parse::<toml::Value>(text)?;
+        let version = JalbConfigVersion::of(&settings)?;
+        let included = include::merge_includes(&mut settings, dir)?;
+        let profiled = apply_profile(&mut settings, profile)?;
+
+        // parsed from the text when possible, errors then point at the line at fault
+        let config = if version == JalbConfigVersion::CURRENT && !included && !profiled {
+            format.parse::<Config>(text)
+        } else {
+            for warning in migrate(&mut settings, version) {
+                tracing::warn!("{}", warning);
+            }
+            settings.try_into::<Config>().map_err(ConfigError::from)
+        };
+        let mut config = config.map_err(suggest_key)?;
+        config.version = version;
+        config.profile = profile.map(str::to_string);
+
+        Ok(config)
+    }
+
+    /// The settings of `path` as a config is read from them, with placeholders expanded,
+    /// included files merged in and `profile` applied, but not yet checked.
+    pub fn settings(
+        path: &Path,
+        format: ConfigFormat,
+        profile: Option<&str>,
+    ) -> Result<toml::Value, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        let (text, values) = interpolate_env(&text, format)?;
+        let mut settings = format
+            .parse::<toml::Value>(&text)
+            .map_err(|e| redact_error(e, &values))?;
+        include::merge_includes(&mut settings, &include::base_dir(path))?;
+        apply_profile(&mut settings, profile)?;
+        Ok(settings)
+    }
+
+    /// Loads `path`, in the format its extension names.
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let format = ConfigFormat::from_path(path.as_ref());
+        Self::load_from_file_as(path, format)
+    }
+
+    pub fn load_from_file_as(
+        path: impl AsRef<Path>,
+        format: ConfigFormat,
+    ) -> Result<Config, ConfigError> {
+        Self::load_profile(path, format, None)
+    }
+
+    /// Loads `path` with the `[profiles]` entry named `profile` applied, or just the base
+    /// settings when `None`.
+    pub fn load_profile(
+        path: impl AsRef<Path>,
+        format: ConfigFormat,
+        profile: Option<&str>,
+    ) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(&path)?;
+        let (text, values) = interpolate_env(&text, format)?;
+        let dir = include::base_dir(path.as_ref());
+        let mut config =
+            Self::parse(&text, format, &dir, profile).map_err(|e| redact_error(e, &values))?;
+        config.source = path.as_ref().to_path_buf();
+        config.format = format;
+        config.finish()?;
+
+        Ok(config)
+    }
+
+    /// Checks and completes a config however it was put together, read from a file or built.
+    fn finish(&mut self) -> Result<(), ConfigError> {
+        self.validate_backends()?;
+        self.validate_listeners()?;
+        self.validate_routes()?;
+        self.validate_runtime()?;
+        self.forwarded().trusted_proxies()?;
+        self.validate_cache()?;
+        self.validate_http3()?;
+        self.validate_access_log()?;
+        self.validate_webhooks()?;
+        self.tcp().validate().map_err(ConfigError::InvalidTcp)?;
+        self.validate_consistency()?;
+        self.resolve_secrets()?;
+        TlsPolicy::from_config(&self.tls.clone().unwrap_or_default())?;
+        for backend in &self.backends {
+            self.tls_policy_for(backend)?;
+        }
+        self.validate_tls()?;
+
+        Ok(())
+    }
+
+    /// Starts a config built in code instead of read from a file.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    fn validate_backends(&self) -> Result<(), ConfigError> {
+        if self.backends.is_empty() {
+            return Err(ConfigError::InvalidBackends("none configured".to_string()));
+        }
+
+        for (idx, backend) in self.backends.iter().enumerate() {
+            if self.backends[..idx].iter().any(|b| b.name == backend.name) {
+                return Err(ConfigError::InvalidBackends(format!(
+                    "name {} is used more than once",
+                    backend.name
+                )));
+            }
+
+            // a cookie name is an http token
+            if let Some(affinity) = &backend.affinity {
+                let name = affinity.cookie_name();
+                let token = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+                if !token {
+                    return Err(ConfigError::InvalidBackends(format!(
+                        "{} is not a valid affinity cookie_name for {}",
+                        name, backend.name
+                    )));
+                }
+            }
+        }
+
+        if let Some(name) = &self.loadbalancer.default_backend
+            && !self.backends.iter().any(|b| &b.name == name)
+        {
+            return Err(ConfigError::InvalidBackends(format!(
+                "default_backend {} is not a configured backend",
+                name
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn validate_listeners(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::InvalidListeners(reason));
+
+        if self.protocol() == TransportProtocol::Udp && self.listeners.len() > 1 {
+            return invalid("udp serves a single listener".to_string());
+        }
+        match self.loadbalancer.acceptors {
+            Some(0) => return invalid("acceptors must be at least 1".to_string()),
+            Some(2..) if self.protocol() == TransportProtocol::Udp => {
+                return invalid("udp serves a single socket, acceptors must be 1".to_string());
+            }
+            _ => {}
+        }
+
+        for (idx, listener) in self.listeners.iter().enumerate() {
+            if self.listeners[..idx]
+                .iter()
+                .any(|l| l.address == listener.address)
+            {
+                return invalid(format!("{} is listed more than once", listener.address));
+            }
+
+            if let Some(name) = &listener.backend
+                && !self.backends.iter().any(|b| &b.name == name)
+            {
+                return invalid(format!(
+                    "{} sends connections to {}, which is not a configured backend",
+                    listener.address, name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_routes(&self) -> Result<(), ConfigError> {
+        let backends: Vec<&str> = self.backends.iter().map(|b| b.name.as_str()).collect();
+        RouteConfig::validate(&self.routes, &backends)
+    }
+
+    /// tokio panics on a zero for any of these, so they are caught while the config is loaded.
+    fn validate_cache(&self) -> Result<(), ConfigError> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+
+        if cache.max_entry_kb == 0 || cache.max_size_mb == 0 {
+            return Err(ConfigError::InvalidCache(
+                "max_entry_kb and max_size_mb must be at least 1".to_string(),
+            ));
+        }
+        if cache.max_entry_bytes() > cache.max_size_bytes() {
+            return Err(ConfigError::InvalidCache(
+                "max_entry_kb is larger than max_size_mb".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_http3(&self) -> Result<(), ConfigError> {
+        let Some(http3) = &self.http3 else {
+            return Ok(());
+        };
+        let backends: Vec<&str> = self.backends.iter().map(|b| b.name.as_str()).collect();
+        http3.validate(&backends).map_err(ConfigError::InvalidHttp3)
+    }
+
+    fn validate_access_log(&self) -> Result<(), ConfigError> {
+        let Some(access_log) = &self.access_log else {
+            return Ok(());
+        };
+        access_log.validate().map_err(ConfigError::InvalidAccessLog)
+    }
+
+    fn validate_webhooks(&self) -> Result<(), ConfigError> {
+        for webhook in &self.webhooks {
+            webhook.validate().map_err(ConfigError::InvalidWebhook)?;
+        }
+        Ok(())
+    }
+
+    fn validate_runtime(&self) -> Result<(), ConfigError> {
+        let Some(runtime) = &self.runtime else {
+            return Ok(());
+        };
+
+        let counts = [
+            ("worker_threads", runtime.worker_threads),
+            ("max_blocking_threads", runtime.max_blocking_threads),
+            ("event_interval", runtime.event_interval.map(|n| n as usize)),
+            (
+                "global_queue_interval",
+                runtime.global_queue_interval.map(|n| n as usize),
+            ),
+            ("io_uring_threads", runtime.io_uring_threads),
+        ];
+        for (key, value) in counts {
+            if value == Some(0) {
+                return Err(ConfigError::InvalidRuntime(format!(
+                    "{} must be at least 1",
+                    key
+                )));
+            }
+        }
+        if runtime.io_uring_threads.is_some() && !cfg!(all(feature = "uring", target_os = "linux"))
+        {
+            return Err(ConfigError::InvalidRuntime(
+                "io_uring_threads needs jalb built with --features uring on linux".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_tls(&self) -> Result<(), ConfigError> {
+        let global = self.tls.clone().unwrap_or_default();
+        let listeners: Vec<SocketAddr> = self.listeners().iter().map(|l| l.address).collect();
+        let backends: Vec<&str> = self.backends.iter().map(|b| b.name.as_str()).collect();
+        global.validate(&listeners, &backends)?;
+
+        for backend in &self.backends {
+            let effective = match &backend.security.tls {
+                Some(pool) => {
+                    pool.validate_override(&backend.name)?;
+                    pool.or(&global)
+                }
+                None => global.clone(),
+            };
+
+            if backend.spiffe.is_some() && effective.upstream().is_some() {
+                return Err(ConfigError::InvalidTls(format!(
+                    "backend {}: set either [tls.upstream] or [backend.spiffe], not both",
+                    backend.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Settings that are fine on their own but contradict each other. Every problem is
+    /// reported, not just the first.
+    fn validate_consistency(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let listeners = self.listeners();
+
+        if self.load_balancer_type() == LoadBalancerType::Application
+            && self.protocol() == TransportProtocol::Udp
+        {
+            problems.push(
+                "[loadbalancer]: type = \"application\" proxies http over tcp, not udp".to_string(),
+            );
+        }
+        if self.load_balancer_type() == LoadBalancerType::Network && !self.routes.is_empty() {
+            problems.push(
+                "[[route]]: routes need type = \"application\" to read requests".to_string(),
+            );
+        }
+        if self.load_balancer_type() == LoadBalancerType::Network
+            && self.loadbalancer.redirect_http_to_https
+        {
+            problems.push(
+                "[loadbalancer]: redirect_http_to_https needs type = \"application\"".to_string(),
+            );
+        }
+        if self.load_balancer_type() == LoadBalancerType::Network && self.cache.is_some() {
+            problems.push(
+                "[cache]: the cache needs type = \"application\" to read responses".to_string(),
+            );
+        }
+        if self.load_balancer_type() == LoadBalancerType::Network && self.http3.is_some() {
+            problems.push("[http3]: http3 needs type = \"application\"".to_string());
+        }
+        // every pool's selector is built from this one strategy, so this covers each backend
+        if matches!(self.strategy(), LoadBalancerStrategy::Geolocation) {
+            problems.push("[loadbalancer]: strategy = \"geo\" is not implemented yet".to_string());
+        }
+        if self.load_balancer_type() == LoadBalancerType::Application && self.acceptors() > 1 {
+            problems.push("[loadbalancer]: acceptors needs type = \"network\"".to_string());
+        }
+        if self.loadbalancer.relay_buffer.is_some() && self.loadbalancer.relay_buffer_kb.is_some() {
+            problems.push("[loadbalancer]: relay_buffer and relay_buffer_kb both set".to_string());
+        }
+        if let Some(kb) = self.loadbalancer.relay_buffer_kb
+            && !(1..=1024).contains(&kb)
+        {
+            problems.push("[loadbalancer]: relay_buffer_kb must be 1 to 1024".to_string());
+        }
+        if let Some(decay) = self.loadbalancer.connection_count_decay
+            && !(0.0..=1.0).contains(&decay)
+        {
+            problems.push(
+                "[loadbalancer]: connection_count_decay must be 0.0 to 1.0".to_string(),
+            );
+        }
+
+        let global_conflicts = self.security.whitelisted_and_blacklisted();
+        for ip in &global_conflicts {
+            problems.push(format!("[security]: {} is both whitelisted and blacklisted", ip));
+        }
+        // a network balancer doesn't know the client speaks http
+        let answers_429 = |security: &Security| {
+            self.load_balancer_type() == LoadBalancerType::Network
+                && security.on_limit() == LimitAction::TooManyRequests
+        };
+        if answers_429(&self.security) {
+            problems.push(
+                "[security]: on_limit = \"http_429\" needs type = \"application\"".to_string(),
+            );
+        }
+
+        for backend in &self.backends {
+            let name = &backend.name;
+
+            if self.load_balancer_type() == LoadBalancerType::Network && backend.affinity.is_some()
+            {
+                problems.push(format!(
+                    "backend {}: affinity needs type = \"application\" to set cookies",
+                    name
+                ));
+            }
+
+            for (idx, peer) in backend.peers.iter().enumerate() {
+                let address = peer.address.as_string();
+                if backend.peers[..idx]
+                    .iter()
+                    .any(|p| p.address.as_string() == address)
+                {
+                    problems.push(format!(
+                        "backend {}: peer {} is listed more than once",
+                        name, address
+                    ));
+                }
+
+                if peer.weight == Some(0) {
+                    problems.push(format!(
+                        "backend {}: peer {} has a weight of 0",
+                        name, address
+                    ));
+                }
+
+                if let NetworkTarget::SocketAddr(addr) = peer.address
+                    && listeners.iter().any(|l| same_socket(l.address, addr))
+                {
+                    problems.push(format!(
+                        "backend {}: peer {} is one of jalb's own listeners",
+                        name, address
+                    ));
+                }
+            }
+
+            let checks = [
+                ("liveness", backend.liveness_check()),
+                ("readiness", backend.readiness_check()),
+            ];
+            for (kind, check) in checks {
+                if let Some(check) = check
+                    && check.interval < check.timeout
+                {
+                    problems.push(format!(
+                        "backend {}: {} check interval {:?} is shorter than its timeout {:?}",
+                        name, kind, check.interval, check.timeout
+                    ));
+                }
+            }
+
+            let pool_security = self.security.for_pool(&backend.security);
+            if backend.security.on_limit.is_some() && answers_429(&pool_security) {
+                problems.push(format!(
+                    "backend {}: on_limit = \"http_429\" needs type = \"application\"",
+                    name
+                ));
+            }
+            for ip in pool_security
+                .whitelisted_and_blacklisted()
+                .iter()
+                .filter(|ip| !global_conflicts.contains(ip))
+            {
+                problems.push(format!(
+                    "backend {}: {} is both whitelisted and blacklisted",
+                    name, ip
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Inconsistent(problems))
+        }
+    }
+
+    /// The pool connections go to, `default_backend` or else the first `[[backend]]`.
+    pub fn backend(&self) -> &BackendOptions {
+        self.loadbalancer
+            .default_backend
+            .as_ref()
+            .and_then(|name| self.backends.iter().find(|b| &b.name == name))
+            .unwrap_or(&self.backends[0])
+    }
+
+    pub fn backends(&self) -> &[BackendOptions] {
+        &self.backends
+    }
+
+    /// The pools `/readyz` waits on a healthy peer in: every one not marked `optional`. With
+    /// udp only the default pool is served, so it is the only one.
+    pub fn required_pools(&self) -> Vec<String> {
+        let pools = match self.protocol() {
+            TransportProtocol::Udp => std::slice::from_ref(self.backend()),
+            _ => self.backends(),
+        };
+        pools
+            .iter()
+            .filter(|backend| !backend.optional)
+            .map(|backend| backend.name.clone())
+            .collect()
+    }
+
+    /// Swaps `${ENV_VAR}` and `file:` references in secret-bearing fields for their values.
+    fn resolve_secrets(&mut self) -> Result<(), ConfigError> {
+        // a file's placeholders were expanded with its text, expanding them again would take a
+        // `$${` escape or a value holding `${` for another placeholder
+        let from_file = !self.source.as_os_str().is_empty();
+        let resolve = |secret: &mut Secret, field: &str| match from_file {
+            true => secret.read_file(field),
+            false => secret.resolve(field),
+        };
+
+        if let Some(admin) = &mut self.admin {
+            for (idx, token) in admin.tokens.iter_mut().enumerate() {
+                resolve(&mut token.token, &format!("admin.tokens[{}].token", idx))?;
+            }
+        }
+
+        if let Some(url) = self.state.as_mut().and_then(|state| state.redis_url.as_mut()) {
+            resolve(url, "state.redis_url")?;
+        }
+
+        for backend in &mut self.backends {
+            if let Some(secret) = backend.affinity.as_mut().and_then(|a| a.secret.as_mut()) {
+                resolve(secret, &format!("backend {} affinity.secret", backend.name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// TLS versions and cipher suites for terminated and upstream TLS, validated against what
+    /// the TLS provider supports. The backend's `[backend.security.tls]` overrides `[tls]`.
+    pub fn tls_policy(&self) -> Result<TlsPolicy, ConfigError> {
+        self.tls_policy_for(self.backend())
+    }
+
+    /// [`tls_policy`](Self::tls_policy) for the pool `backend`.
+    pub fn tls_policy_for(&self, backend: &BackendOptions) -> Result<TlsPolicy, ConfigError> {
+        let global = self.tls.clone().unwrap_or_default();
+        let effective = match &backend.security.tls {
+            Some(pool) => pool.or(&global),
+            None => global,
+        };
+        TlsPolicy::from_config(&effective)
+    }
+
+    /// The global `[tls]` section: listener termination, sni routes and upstream defaults.
+    pub fn tls(&self) -> TlsConfig {
+        self.tls.clone().unwrap_or_default()
+    }
+
+    /// TLS opened to the backend's peers, `[backend.security.tls.upstream]` over
+    /// `[tls.upstream]`.
+    pub fn upstream_tls_config(&self) -> Option<UpstreamTlsConfig> {
+        self.upstream_tls_config_for(self.backend())
+    }
+
+    /// [`upstream_tls_config`](Self::upstream_tls_config) for the pool `backend`.
+    pub fn upstream_tls_config_for(&self, backend: &BackendOptions) -> Option<UpstreamTlsConfig> {
+        let global = self.tls();
+        match &backend.security.tls {
+            Some(pool) => pool.or(&global).upstream().cloned(),
+            None => global.upstream().cloned(),
+        }
+    }
+
+    /// The global `[security]` block with the backend's overrides applied.
+    pub fn pool_security(&self) -> Security {
+        self.security_for(self.backend())
+    }
+
+    /// [`pool_security`](Self::pool_security) for the pool `backend`.
+    pub fn security_for(&self, backend: &BackendOptions) -> Security {
+        self.security.for_pool(&backend.security)
+    }
+
+    /// Where state that survives restarts is kept, `None` when `[state]` is absent.
+    pub fn state_backend(&self) -> Option<StateBackend> {
+        self.state.as_ref().map(|state| state.backend)
+    }
+
+    pub fn state_path(&self) -> PathBuf {
+        self.state
+            .as_ref()
+            .and_then(|state| state.path.clone())
+            .unwrap_or_else(|| PathBuf::from("jalb-state.json"))
+    }
+
+    /// Where rejected connections are audited, `None` when `[audit]` is absent.
+    pub fn audit_target(&self) -> Option<AuditTarget> {
+        self.audit.as_ref().map(|audit| audit.target)
+    }
+
+    pub fn audit_path(&self) -> PathBuf {
+        let audit = self.audit.as_ref();
+        if let Some(path) = audit.and_then(|audit| audit.path.clone()) {
+            return path;
+        }
+
+        match audit.map(|audit| audit.target).unwrap_or_default() {
+            AuditTarget::File => PathBuf::from("jalb-audit.log"),
+            AuditTarget::Syslog => PathBuf::from("/dev/log"),
+        }
+    }
+
+    /// Workload API socket to fetch the upstream TLS identity from, `None` when `[backend.spiffe]`
+    /// is absent and peers are connected to in plaintext.
+    pub fn spiffe_socket(&self) -> Option<PathBuf> {
+        self.spiffe_socket_for(self.backend())
+    }
+
+    /// [`spiffe_socket`](Self::spiffe_socket) for the pool `backend`.
+    pub fn spiffe_socket_for(&self, backend: &BackendOptions) -> Option<PathBuf> {
+        let spiffe = backend.spiffe.as_ref()?;
+        if let Some(socket) = &spiffe.socket {
+            return Some(socket.clone());
+        }
+
+        let socket = env::var("SPIFFE_ENDPOINT_SOCKET")
+            .map(|endpoint| endpoint.trim_start_matches("unix://").to_string())
+            .unwrap_or_else(|_| "/tmp/spire-agent/public/api.sock".to_string());
+        Some(PathBuf::from(socket))
+    }
+
+    pub fn upstream_server_name(&self) -> Option<&str> {
+        self.upstream_server_name_for(self.backend())
+    }
+
+    pub fn upstream_server_name_for<'a>(&self, backend: &'a BackendOptions) -> Option<&'a str> {
+        backend.spiffe.as_ref()?.server_name.as_deref()
+    }
+
+    pub fn redis_url(&self) -> Option<&str> {
+        self.state.as_ref()?.redis_url.as_ref().map(Secret::expose)
+    }
+
+    pub fn strategy(&self) -> LoadBalancerStrategy {
+        self.loadbalancer.strategy
+    }
+
+    pub fn load_balancer_type(&self) -> LoadBalancerType {
+        self.loadbalancer.load_balancer_type
+    }
+
+    pub fn protocol(&self) -> TransportProtocol {
+        self.loadbalancer.protocol
+    }
+
+    /// How often per-peer connection counters are checked against the live connection registry.
+    pub fn connection_reconcile_interval(&self) -> time::Duration {
+        let seconds = self
+            .loadbalancer
+            .connection_reconcile_interval_seconds
+            .unwrap_or(30);
+
+        time::Duration::from_secs(seconds.into())
+    }
+
+    /// Fraction of a drifted counter's excess kept on each reconciliation pass. `None` resets
+    /// drifted counters outright.
+    pub fn connection_count_decay(&self) -> Option<f64> {
+        self.loadbalancer.connection_count_decay
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections_for(self.backend())
+    }
+
+    /// `max_connections` of the pool `backend`, its `[backend.security]` one or else the global
+    /// one. The other limits are taken the same way.
+    pub fn max_connections_for(&self, backend: &BackendOptions) -> usize {
+        backend
+            .security
+            .max_connections
+            .unwrap_or(self.loadbalancer.max_connections) as usize
+    }
+
+    pub fn listen_backlog(&self) -> u32 {
+        self.loadbalancer.listen_backlog.unwrap_or(1024)
+    }
+
+    /// How many sockets each listener is bound with, 1 unless `acceptors` is set.
+    pub fn acceptors(&self) -> usize {
+        self.loadbalancer.acceptors.unwrap_or(1)
+    }
+
+    /// Bytes each direction of a session is copied through at a time, `relay_buffer_kb` or else
+    /// the size of `relay_buffer`.
+    pub fn relay_buffer_size(&self) -> usize {
+        match self.loadbalancer.relay_buffer_kb {
+            Some(kb) => kb as usize * 1024,
+            None => self.loadbalancer.relay_buffer.unwrap_or_default().size(),
+        }
+    }
+
+    pub fn max_accepts_per_second(&self) -> Option<u64> {
+        self.max_accepts_per_second_for(self.backend())
+    }
+
+    pub fn max_accepts_per_second_for(&self, backend: &BackendOptions) -> Option<u64> {
+        backend
+            .security
+            .max_accepts_per_second
+            .or(self.loadbalancer.max_accepts_per_second)
+    }
+
+    pub fn max_client_accepts_per_second(&self) -> Option<u64> {
+        self.max_client_accepts_per_second_for(self.backend())
+    }
+
+    pub fn max_client_accepts_per_second_for(&self, backend: &BackendOptions) -> Option<u64> {
+        backend
+            .security
+            .max_client_accepts_per_second
+            .or(self.loadbalancer.max_client_accepts_per_second)
+    }
+
+    pub fn shared_rate_limit(&self) -> bool {
+        self.loadbalancer.shared_rate_limit
+    }
+
+    pub fn first_byte_timeout(&self) -> Option<time::Duration> {
+        self.loadbalancer
+            .first_byte_timeout_seconds
+            .map(|seconds| time::Duration::from_secs(seconds.into()))
+    }
+
+    pub fn idle_timeout(&self) -> Option<time::Duration> {
+        self.loadbalancer
+            .idle_timeout_seconds
+            .map(|seconds| time::Duration::from_secs(seconds.into()))
+    }
+
+    /// How long a WebSocket may go without traffic, `idle_timeout` when unset. Zero keeps it
+    /// open until either side closes it.
+    pub fn websocket_idle_timeout(&self) -> Option<time::Duration> {
+        match self.loadbalancer.websocket_idle_timeout_seconds {
+            Some(0) => None,
+            Some(seconds) => Some(time::Duration::from_secs(seconds.into())),
+            None => self.idle_timeout(),
+        }
+    }
+
+    /// The largest request body in bytes, `None` for no limit.
+    pub fn max_request_body(&self) -> Option<u64> {
+        self.loadbalancer
+            .max_request_body_kb
+            .map(|kb| kb.saturating_mul(1024))
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.loadbalancer
+            .listener_address
+            .unwrap_or(IpAddr::from_str("127.0.0.1").unwrap())
+    }
+
+    pub fn port(&self) -> u16 {
+        self.loadbalancer.port.unwrap_or(9220)
+    }
+
+    /// `[runtime] worker_threads`, or the older `[loadbalancer] worker_threads`.
+    pub fn worker_threads(&self) -> Option<usize> {
+        self.runtime
+            .as_ref()
+            .and_then(|runtime| runtime.worker_threads)
+            .or(self.loadbalancer.worker_threads)
+    }
+
+    pub fn max_blocking_threads(&self) -> Option<usize> {
+        self.runtime
+            .as_ref()
+            .and_then(|runtime| runtime.max_blocking_threads)
+    }
+
+    pub fn event_interval(&self) -> Option<u32> {
+        self.runtime.as_ref().and_then(|runtime| runtime.event_interval)
+    }
+
+    pub fn global_queue_interval(&self) -> Option<u32> {
+        self.runtime
+            .as_ref()
+            .and_then(|runtime| runtime.global_queue_interval)
+    }
+
+    /// How many io_uring threads relay plain tcp sessions, `None` to relay them on the runtime.
+    pub fn io_uring_threads(&self) -> Option<usize> {
+        self.runtime
+            .as_ref()
+            .and_then(|runtime| runtime.io_uring_threads)
+    }
+
+    /// The `[cache]` settings, `None` without a cache.
+    pub fn cache(&self) -> Option<&CacheConfig> {
+        self.cache.as_ref()
+    }
+
+    /// The `[http3]` settings, `None` when HTTP/3 isn't served.
+    pub fn http3(&self) -> Option<&Http3Config> {
+        self.http3.as_ref()
+    }
+
+    /// The `[access_log]` settings, `None` when requests and sessions aren't logged.
+    pub fn access_log(&self) -> Option<&AccessLogConfig> {
+        self.access_log.as_ref()
+    }
+
+    /// The `[[webhook]]`s to call on peer transitions.
+    pub fn webhooks(&self) -> &[WebhookConfig] {
+        &self.webhooks
+    }
+
+    /// Address `[metrics]` are served on, `None` without the section.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(|metrics| metrics.address)
+    }
+
+    /// The `[forwarded]` settings, the defaults when the section is left out.
+    pub fn forwarded(&self) -> ForwardedConfig {
+        self.forwarded.clone().unwrap_or_default()
+    }
+
+    /// The `[tcp]` settings, leaving every socket option alone when the section is left out.
+    pub fn tcp(&self) -> TcpConfig {
+        self.tcp.clone().unwrap_or_default()
+    }
+
+    pub fn watch_config(&self) -> bool {
+        self.loadbalancer.watch_config
+    }
+
+    /// The port plaintext requests are redirected to over https, `None` when they aren't.
+    pub fn https_redirect_port(&self) -> Option<u16> {
+        self.loadbalancer
+            .redirect_http_to_https
+            .then(|| self.loadbalancer.https_port.unwrap_or(443))
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn format(&self) -> ConfigFormat {
+        self.format
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Schema version the file was written in, before any migration.
+    pub fn version(&self) -> JalbConfigVersion {
+        self.version
+    }
+
+    pub fn listener_address(&self) -> std::net::SocketAddr {
+        let ip = self.ip();
+        let port = self.port();
+
+        std::net::SocketAddr::new(ip, port)
+    }
+
+    /// Every address to accept connections on: the `[[listener]]` entries, or when there are
+    /// none `listener_address` and `port` sending connections to the default backend.
+    /// `[[route]]` entries, in the order they were written.
+    pub fn routes(&self) -> &[RouteConfig] {
+        &self.routes
+    }
+
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![ListenerConfig {
+            address: self.listener_address(),
+            backend: None,
+        }]
+    }
+
+    /// How long a UDP flow may go without upstream replies before its session is dropped.
+    pub fn udp_session_timeout(&self) -> time::Duration {
+        let seconds = self
+            .udp
+            .as_ref()
+            .and_then(|u| u.session_timeout_seconds)
+            .unwrap_or(30);
+
+        time::Duration::from_secs(seconds.into())
+    }
+
+    /// Length of the connection IDs issued by upstream QUIC servers, used to read short header
+    /// packets. `None` when QUIC awareness is disabled.
+    pub fn quic_connection_id_length(&self) -> Option<usize> {
+        let udp = self.udp.as_ref().filter(|u| u.quic)?;
+        Some(udp.quic_connection_id_length.unwrap_or(8).into())
+    }
+
+    pub fn log_level(&self) -> log::Level {
+        self.logging.log_level.unwrap_or(log::Level::Info)
+    }
+
+    pub fn log_target(&self) -> LogTarget {
+        self.logging.log_target
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.logging.format
+    }
+
+    pub fn log_filters(&self) -> &BTreeMap<String, log::Level> {
+        &self.logging.filters
+    }
+
+    pub fn rotate_logs(&self) -> bool {
+        self.logging.rotate_logs
+    }
+
+    pub fn log_file_max_size(&self) -> usize {
+        const BYTES_PER_MEGABYTE: usize = 1024 * 1024;
+
+        if let Some(max_size) = self.logging.log_capacity_mb {
+            return max_size * BYTES_PER_MEGABYTE;
+        }
+
+        LOG_FILE_SIZE_HARD_LIMIT_MB * BYTES_PER_MEGABYTE
+    }
+
+    /// How many rotated log files are kept, `jalb.log.1` being the newest.
+    pub fn log_archives(&self) -> usize {
+        self.logging.log_archives.unwrap_or(DEFAULT_LOG_ARCHIVES)
+    }
+
+    pub fn logfile_path(&self) -> LoggingPath {
+        self.logging.path.clone().unwrap_or_default()
+    }
+
+    /// Address of the admin server. `None` when no `[admin]` section is configured.
+    pub fn admin_address(&self) -> Option<std::net::SocketAddr> {
+        let admin = self.admin.as_ref()?;
+        let ip = admin
+            .listener_address
+            .unwrap_or(IpAddr::from_str("127.0.0.1").unwrap());
+
+        Some(std::net::SocketAddr::new(ip, admin.port.unwrap_or(9221)))
+    }
+
+    /// Address of the gRPC admin service, sharing the admin listener address. `None` unless
+    /// `grpc_port` is set.
+    pub fn admin_grpc_address(&self) -> Option<std::net::SocketAddr> {
+        let port = self.admin.as_ref()?.grpc_port?;
+        self.admin_address()
+            .map(|addr| std::net::SocketAddr::new(addr.ip(), port))
+    }
+
+    /// Bearer tokens accepted by the admin API. Without any, the API is read-only.
+    pub fn admin_tokens(&self) -> Vec<AdminToken> {
+        self.admin
+            .as_ref()
+            .map(|a| a.tokens.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn event_buffer_size(&self) -> usize {
+        self.admin
+            .as_ref()
+            .and_then(|a| a.event_buffer_size)
+            .unwrap_or(DEFAULT_EVENT_BUFFER_SIZE)
+    }
+
+    /// How many tcp connections go by each one traced for `GET /flows`, 0 while off.
+    pub fn flow_sample(&self) -> u64 {
+        self.admin.as_ref().map_or(0, |a| a.flow_sample)
+    }
+
+    /// The settings in effect: the file after migration, includes and `${VAR}` expansion, with
+    /// the command line and environment overrides applied and defaults filled in for unset
+    /// settings. Secrets are shown as `***`.
+    pub fn effective(&self) -> Result<toml::Value, ConfigError> {
+        let mut settings = toml::Value::try_from(self)?;
+        let fill = |settings: &mut toml::Value, section: &str, key: &str, value: toml::Value| {
+            if let Some(table) = settings.get_mut(section).and_then(toml::Value::as_table_mut) {
+                table.entry(key).or_insert(value);
+            }
+        };
+
+        if let Some(table) = settings.as_table_mut() {
+            let version = JalbConfigVersion::CURRENT.name();
+            table.insert("version".to_string(), version.into());
+        }
+
+        let section = "loadbalancer";
+        fill(&mut settings, section, "listener_address", self.ip().to_string().into());
+        fill(&mut settings, section, "port", i64::from(self.port()).into());
+        fill(&mut settings, section, "listen_backlog", i64::from(self.listen_backlog()).into());
+        fill(&mut settings, section, "acceptors", (self.acceptors() as i64).into());
+        let relay_buffer_kb = (self.relay_buffer_size() / 1024) as i64;
+        fill(&mut settings, section, "relay_buffer_kb", relay_buffer_kb.into());
+        let interval = self.connection_reconcile_interval().as_secs() as i64;
+        fill(&mut settings, section, "connection_reconcile_interval_seconds", interval.into());
+        fill(&mut settings, section, "default_backend", self.backend().name.clone().into());
+
+        // `log::Level` serializes as INFO, jalb.toml spells it info
+        if let Some(logging) = settings.get_mut("logging").and_then(toml::Value::as_table_mut) {
+            let level = self.log_level().as_str().to_lowercase();
+            logging.insert("log_level".to_string(), level.into());
+            let filters: toml::Table = self
+                .log_filters()
+                .iter()
+                .map(|(module, level)| (module.clone(), level.as_str().to_lowercase().into()))
+                .collect();
+            logging.insert("filters".to_string(), filters.into());
+        }
+        fill(&mut settings, "logging", "log_archives", (self.log_archives() as i64).into());
+
+        if let Some(admin) = self.admin_address() {
+            fill(&mut settings, "admin", "listener_address", admin.ip().to_string().into());
+            fill(&mut settings, "admin", "port", i64::from(admin.port()).into());
+            let buffer = self.event_buffer_size() as i64;
+            fill(&mut settings, "admin", "event_buffer_size", buffer.into());
+        }
+
+        let timeout = self.udp_session_timeout().as_secs() as i64;
+        fill(&mut settings, "udp", "session_timeout_seconds", timeout.into());
+
+        Ok(settings)
+    }
+
+    /// [`Config::effective`] as TOML, for `jalb config dump` and the admin API.
+    pub fn dump(&self) -> Result<String, ConfigError> {
+        Ok(toml::to_string_pretty(&self.effective()?)?)
+    }
+}
+
+/// Whether a peer at `peer` would be reached through `listener`: same port and the same
+/// address, or a wildcard listener and a loopback peer.
+fn same_socket(listener: SocketAddr, peer: SocketAddr) -> bool {
+    listener.port() == peer.port()
+        && (listener.ip() == peer.ip()
+            || listener.ip().is_unspecified()
+                && (peer.ip().is_loopback() || peer.ip().is_unspecified()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_address_family_order() {
+        let mut addrs: Vec<SocketAddr> = vec![
+            "[::1]:80".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            "[::2]:80".parse().unwrap(),
+        ];
+
+        AddressFamily::Ipv4.order(&mut addrs);
+        assert_eq!(addrs[0], "127.0.0.1:80".parse().unwrap());
+        assert_eq!(addrs[1], "[::1]:80".parse().unwrap());
+
+        AddressFamily::Ipv6.order(&mut addrs);
+        assert_eq!(addrs[2], "127.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
     fn test_should_load_from_file() -> Result<(), ConfigError> {
         let config = Config::load_from_file("jalb.toml")?;
         config.load_balancer_type();
-        assert!(config.rotate_logs() == true);
+        assert!(config.rotate_logs());
         assert!(config.log_file_max_size() == 10485760);
         let ip = config.ip();
         assert!(ip.is_ipv4());
@@ -399,4 +2924,533 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_override_precedence() {
+        let env = ConfigOverrides::from_lookup(|name| match name {
+            "JALB_PORT" => Some("8000".to_string()),
+            "JALB_WORKER_THREADS" => Some("4".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let cli = ConfigOverrides {
+            port: Some(9000),
+            ..Default::default()
+        };
+
+        let mut config = Config::load_from_file("jalb.toml").unwrap();
+        config.apply_overrides(&cli.or(env));
+        assert_eq!(config.port(), 9000);
+        assert_eq!(config.worker_threads(), Some(4));
+        assert_eq!(config.ip().to_string(), "127.0.0.1");
+
+        let invalid = ConfigOverrides::from_lookup(|name| {
+            (name == "JALB_PORT").then(|| "http".to_string())
+        });
+        assert!(matches!(invalid, Err(ConfigError::InvalidOverride(..))));
+    }
+
+    #[test]
+    fn test_config_formats() {
+        assert_eq!(ConfigFormat::from_path(Path::new("jalb.yml")), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path(Path::new("/etc/jalb.json")), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path(Path::new("jalb.conf")), ConfigFormat::Toml);
+        assert_eq!("YAML".parse(), Ok(ConfigFormat::Yaml));
+
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let settings: toml::Value = toml::from_str(&file).unwrap();
+        let yaml = serde_yaml_ng::to_string(&settings).unwrap();
+        let json = serde_json::to_string(&settings).unwrap();
+
+        for (format, text) in [(ConfigFormat::Yaml, yaml), (ConfigFormat::Json, json)] {
+            let config = format.parse::<Config>(&text).unwrap();
+            assert_eq!(config.backend().name, "auth service");
+            assert_eq!(config.port(), 6331);
+        }
+    }
+
+    #[test]
+    fn test_profiles() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let profiles = r#"
+[profiles.prod.loadbalancer]
+port = 443
+
+[profiles.prod.logging]
+log_level = "warn"
+
+[[profiles.prod.backend]]
+name = "auth service"
+rate_limit = 1000
+
+[profiles.dev.loadbalancer]
+port = 8080
+"#;
+        let text = format!("{}{}", file, profiles);
+        let parse = |profile| Config::parse(&text, ConfigFormat::Toml, Path::new("."), profile);
+
+        let base = parse(None).unwrap();
+        assert_eq!(base.port(), 6331);
+        assert_eq!(base.profile(), None);
+
+        let prod = parse(Some("prod")).unwrap();
+        assert_eq!(prod.port(), 443);
+        assert_eq!(prod.log_level(), log::Level::Warn);
+        assert_eq!(prod.backend().rate_limit, Some(1000));
+        assert_eq!(prod.backend().peers.len(), base.backend().peers.len());
+
+        match parse(Some("staging")) {
+            Err(ConfigError::UnknownProfile(name, known)) => {
+                assert_eq!(name, "staging");
+                assert_eq!(known, "dev, prod");
+            }
+            other => panic!("expected an unknown profile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_config_version() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let config = Config::parse(&file, ConfigFormat::Toml, Path::new("."), None).unwrap();
+        assert_eq!(config.version(), JalbConfigVersion::CURRENT);
+
+        let bare = file.replacen("version = \"1\"", "version = 1", 1);
+        assert!(Config::parse(&bare, ConfigFormat::Toml, Path::new("."), None).is_ok());
+
+        let unknown = file.replacen("version = \"1\"", "version = \"7\"", 1);
+        let err = Config::parse(&unknown, ConfigFormat::Toml, Path::new("."), None).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidVersion(version, _) if version == "7"));
+
+        let missing = file.replacen("version = \"1\"", "", 1);
+        let err = Config::parse(&missing, ConfigFormat::Toml, Path::new("."), None).unwrap_err();
+        assert!(matches!(err, ConfigError::MissingVersion(_)));
+    }
+
+    #[test]
+    fn test_builder() {
+        let peer: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let config = Config::builder()
+            .with_listener("0.0.0.0:7000".parse().unwrap())
+            .with_peer(peer, 2)
+            .with_backend(BackendOptions::new("api").with_peer(peer, 1))
+            .with_default_backend("api")
+            .with_idle_timeout(time::Duration::from_secs(30))
+            .build()
+            .unwrap();
+
+        assert_eq!(config.listener_address(), "0.0.0.0:7000".parse().unwrap());
+        assert_eq!(config.backends().len(), 2);
+        assert_eq!(config.backends()[0].name, "default");
+        assert_eq!(config.backend().name, "api");
+        assert_eq!(config.idle_timeout(), Some(time::Duration::from_secs(30)));
+        assert_eq!(
+            config.websocket_idle_timeout(),
+            Some(time::Duration::from_secs(30))
+        );
+
+        assert!(matches!(
+            Config::builder().build(),
+            Err(ConfigError::InvalidBackends(_))
+        ));
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "PORT" => Some("8080".to_string()),
+            "DIR" => Some("/var/log".to_string()),
+            "QUOTED" => Some("a\"b'\\c\n[evil]\nd = 1".to_string()),
+            _ => None,
+        };
+        let (toml, yaml, json) = (ConfigFormat::Toml, ConfigFormat::Yaml, ConfigFormat::Json);
+
+        let text = "port = ${PORT}\npath = \"${DIR}/jalb.log\" # ${UNSET} in a comment\n";
+        assert_eq!(
+            interpolate(text, toml, lookup).unwrap(),
+            "port = 8080\npath = \"/var/log/jalb.log\" # ${UNSET} in a comment\n"
+        );
+        assert_eq!(
+            interpolate("token = \"#$${PORT}\"", toml, lookup).unwrap(),
+            "token = \"#${PORT}\""
+        );
+
+        let err = interpolate("a = 1\nport = ${UNSET}\n", toml, lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::Interpolation(2, _)));
+        assert!(err.to_string().contains("UNSET"));
+        assert!(interpolate("port = ${PORT", toml, lookup).is_err());
+
+        // values are escaped as the string around them needs, and can't add keys outside one
+        let quoted = lookup("QUOTED").unwrap();
+        let cases = [
+            (toml, "a = \"${QUOTED}\" # ${UNSET}\nb = \"\"\"\n${QUOTED}\"\"\"\n"),
+            (yaml, "a: \"${QUOTED}\" # ${UNSET}\nb: |\n  ${PORT}\n"),
+            (json, "{\"a\": \"${QUOTED}\", \"b\": \"\\n${QUOTED}\"}"),
+        ];
+        for (format, text) in cases {
+            let expanded = interpolate(text, format, lookup).unwrap();
+            let parsed = format.parse::<toml::Value>(&expanded).unwrap();
+            assert_eq!(parsed["a"].as_str(), Some(quoted.as_str()), "{}", expanded);
+            assert!(parsed.get("d").is_none(), "{}", expanded);
+        }
+        assert!(interpolate("a = ${QUOTED}", toml, lookup).is_err());
+        assert!(interpolate("a = '${QUOTED}'", toml, lookup).is_err());
+        assert!(interpolate("a: ${QUOTED}", yaml, lookup).is_err());
+        assert!(interpolate("{\"a\": ${QUOTED}}", json, lookup).is_err());
+
+        // a # only starts a yaml comment after a space, and json has none
+        assert!(interpolate("url: http://a/#${UNSET}", yaml, lookup).is_err());
+        assert!(interpolate("{\"a\": \"# ${UNSET}\"}", json, lookup).is_err());
+        let text = "a: it's ${DIR}/jalb.log\nb: 'it''s ${PORT}'\n";
+        let parsed = yaml.parse::<toml::Value>(&interpolate(text, yaml, lookup).unwrap());
+        assert_eq!(parsed.unwrap()["b"].as_str(), Some("it's 8080"));
+    }
+
+    #[test]
+    fn test_secrets_expand_once() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { env::set_var("JALB_TEST_ONCE", "x${JALB_TEST_ONCE_UNSET}y") };
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let path = env::temp_dir().join(format!("jalb-once-{}.toml", std::process::id()));
+        let token = |token: &str| {
+            let admin = format!(
+                "[admin]\ntokens = [{{ token = \"{}\", permission = \"read\" }}]\n",
+                token
+            );
+            fs::write(&path, file.replacen("[admin]\n", &admin, 1)).unwrap();
+            let config = Config::load_from_file(&path);
+            config.map(|config| config.admin_tokens()[0].token.expose().to_string())
+        };
+
+        // an escaped placeholder and a value holding one are each taken as written
+        assert_eq!(token("abc$${NOT_A_VAR}def").unwrap(), "abc${NOT_A_VAR}def");
+        assert_eq!(token("${JALB_TEST_ONCE}").unwrap(), "x${JALB_TEST_ONCE_UNSET}y");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors_hide_secrets() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { env::set_var("JALB_TEST_LEAKED", "s3cr3t-admin-token") };
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let path = env::temp_dir().join(format!("jalb-leaked-{}.toml", std::process::id()));
+        let broken = [
+            "[admin]\ntokens = [{ token = \"${JALB_TEST_LEAKED}\" permission = \"read\" }]\n",
+            "[admin]\ntokens = \"${JALB_TEST_LEAKED}\"\n",
+        ];
+        for admin in broken {
+            fs::write(&path, file.replacen("[admin]\n", admin, 1)).unwrap();
+            let err = Config::load_from_file(&path).unwrap_err();
+            assert!(!format!("{:?}", err).contains("s3cr3t"), "{:?}", err);
+            assert!(!err.to_string().contains("s3cr3t"), "{}", err);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backend_pools() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |toml: &str| {
+            let config = toml::from_str::<Config>(toml).unwrap();
+            config.validate_backends().map(|_| config)
+        };
+
+        let single = parse(&file.replacen("[[backend]]", "[backend]", 1)).unwrap();
+        assert_eq!(single.backends().len(), 1);
+
+        let second = r#"
+[[backend]]
+name = "api"
+peers = [{ address = "127.0.0.1:5000" }]
+"#;
+        let pools = parse(&format!("{}{}", file, second)).unwrap();
+        assert_eq!(pools.backends().len(), 2);
+        assert_eq!(pools.backend().name, "auth service");
+
+        let routed = file.replacen(
+            "[loadbalancer]\n",
+            "[loadbalancer]\ndefault_backend = \"api\"\n",
+            1,
+        );
+        let pools = parse(&format!("{}{}", routed, second)).unwrap();
+        assert_eq!(pools.backend().name, "api");
+        assert!(parse(&routed).is_err());
+
+        let duplicate = second.replace("api", "auth service");
+        assert!(parse(&format!("{}{}", file, duplicate)).is_err());
+    }
+
+    #[test]
+    fn test_effective_config() {
+        let mut config = Config::load_from_file("jalb.toml").unwrap();
+        config.apply_overrides(&ConfigOverrides {
+            port: Some(7000),
+            ..Default::default()
+        });
+
+        let effective = config.effective().unwrap();
+        assert_eq!(effective["version"].as_str(), Some(JalbConfigVersion::CURRENT.name()));
+        assert_eq!(effective["loadbalancer"]["port"].as_integer(), Some(7000));
+        assert_eq!(effective["loadbalancer"]["listen_backlog"].as_integer(), Some(1024));
+        assert_eq!(
+            effective["loadbalancer"]["default_backend"].as_str(),
+            Some("auth service")
+        );
+
+        // the dump reads back as the same config
+        let dump = config.dump().unwrap();
+        let reread = toml::from_str::<Config>(&dump).unwrap();
+        assert_eq!(reread.listener_address(), config.listener_address());
+        assert_eq!(reread.backends().len(), config.backends().len());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |toml: &str| Config::parse(toml, ConfigFormat::Toml, Path::new("."), None);
+        assert!(parse(&file).is_ok());
+
+        let typo = file.replacen("strategy = ", "strategey = ", 1);
+        match parse(&typo) {
+            Err(ConfigError::UnknownKey(message, key)) => {
+                assert!(message.contains("strategey"));
+                assert_eq!(key, "strategy");
+            }
+            other => panic!("expected an unknown key error, got {:?}", other),
+        }
+
+        let backend = file.replacen("dns_prefer = ", "dns_perfer = ", 1);
+        assert!(matches!(
+            parse(&backend),
+            Err(ConfigError::UnknownKey(_, key)) if key == "dns_prefer"
+        ));
+
+        let unrelated = file.replacen("[loadbalancer]\n", "[loadbalancer]\ncolour = 1\n", 1);
+        assert!(matches!(parse(&unrelated), Err(ConfigError::DeserializationError(_))));
+
+        assert_eq!(edit_distance("prot", "port"), 1);
+        assert_eq!(edit_distance("strategy", "strategy"), 0);
+    }
+
+    #[test]
+    fn test_listeners() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |toml: &str| {
+            let config = toml::from_str::<Config>(toml).unwrap();
+            config.validate_listeners().map(|_| config)
+        };
+
+        let single = parse(&file).unwrap();
+        assert_eq!(single.listeners().len(), 1);
+        assert_eq!(single.listeners()[0].address, single.listener_address());
+
+        let listeners = r#"
+[[listener]]
+address = "0.0.0.0:443"
+
+[[listener]]
+address = "[::]:443"
+
+[[listener]]
+address = "10.0.0.1:8443"
+backend = "auth service"
+"#;
+        let config = parse(&format!("{}{}", file, listeners)).unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 3);
+        assert_eq!(listeners[1].address, "[::]:443".parse().unwrap());
+        assert_eq!(listeners[2].backend.as_deref(), Some("auth service"));
+
+        let unknown = "[[listener]]\naddress = \"0.0.0.0:443\"\nbackend = \"api\"\n";
+        assert!(parse(&format!("{}{}", file, unknown)).is_err());
+
+        let duplicate = "[[listener]]\naddress = \"0.0.0.0:443\"\n".repeat(2);
+        assert!(parse(&format!("{}{}", file, duplicate)).is_err());
+    }
+
+    #[test]
+    fn test_consistency() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |toml: &str| toml::from_str::<Config>(toml).unwrap().validate_consistency();
+        assert!(parse(&file).is_ok());
+        let geo = file.replacen("strategy = \"round_robin\"", "strategy = \"geo\"", 1);
+        assert!(parse(&geo).is_err());
+        let answers_429 = file.replacen("on_limit = \"reset\"", "on_limit = \"http_429\"", 1);
+        assert!(parse(&answers_429).is_err());
+        let application = answers_429.replacen("type = \"network\"", "type = \"application\"", 1);
+        assert!(parse(&application).is_ok());
+
+        let inconsistent = file
+            .replacen("127.0.0.1:4001", "127.0.0.1:4000", 1)
+            .replacen("127.0.0.1:4002\", weight = 1", "127.0.0.1:4002\", weight = 0", 1)
+            .replacen("port = 6331", "port = 4003", 1)
+            .replacen("interval_seconds = 5", "interval_seconds = 1", 1)
+            .replacen("ip_whitelist = []", "ip_whitelist = [\"10.0.0.1\"]", 1)
+            .replacen("ip_blacklist = []", "ip_blacklist = [\"10.0.0.1\"]", 1);
+        match parse(&inconsistent) {
+            Err(ConfigError::Inconsistent(problems)) => {
+                assert_eq!(problems.len(), 5, "{:?}", problems);
+                assert!(problems[0].contains("10.0.0.1 is both whitelisted and blacklisted"));
+                assert!(problems[1].contains("127.0.0.1:4000 is listed more than once"));
+            }
+            other => panic!("expected every inconsistency, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_relay_buffer() {
+        let peer: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let config = Config::builder().with_peer(peer, 1).build().unwrap();
+        assert_eq!(config.relay_buffer_size(), 8 * 1024);
+        let bulk = Config::builder()
+            .with_peer(peer, 1)
+            .with_relay_buffer(RelayBuffer::Large)
+            .build()
+            .unwrap();
+        assert_eq!(bulk.relay_buffer_size(), 64 * 1024);
+
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |setting: &str| {
+            let toml = file.replacen("listen_backlog = 1024", setting, 1);
+            toml::from_str::<Config>(&toml).unwrap()
+        };
+        let exact = parse("relay_buffer_kb = 32");
+        assert!(exact.validate_consistency().is_ok());
+        assert_eq!(exact.relay_buffer_size(), 32 * 1024);
+        assert_eq!(parse("relay_buffer = \"small\"").relay_buffer_size(), 2 * 1024);
+        assert!(parse("relay_buffer_kb = 0").validate_consistency().is_err());
+        let both = parse("relay_buffer = \"large\"\nrelay_buffer_kb = 32");
+        assert!(both.validate_consistency().is_err());
+    }
+
+    #[test]
+    fn test_connection_count_decay() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |setting: &str| {
+            let toml = file.replacen("listen_backlog = 1024", setting, 1);
+            toml::from_str::<Config>(&toml).unwrap()
+        };
+        let fading = parse("connection_count_decay = 0.5");
+        assert!(fading.validate_consistency().is_ok());
+        assert_eq!(fading.connection_count_decay(), Some(0.5));
+        assert!(parse("connection_count_decay = 1.0").validate_consistency().is_ok());
+
+        for decay in ["-0.1", "1.5", "nan"] {
+            let setting = format!("connection_count_decay = {}", decay);
+            match parse(&setting).validate_consistency() {
+                Err(ConfigError::Inconsistent(problems)) => {
+                    assert!(problems[0].contains("connection_count_decay"), "{:?}", problems);
+                }
+                other => panic!("expected decay {} to be rejected, got {:?}", decay, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tcp() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |tcp: &str| toml::from_str::<Config>(&format!("{}\n[tcp]\n{}", file, tcp));
+
+        let config = parse("nodelay = true\nkeepalive_seconds = 60\nrecv_buffer_kb = 64").unwrap();
+        assert!(config.tcp().validate().is_ok());
+        let options = config.tcp().to_socket_options();
+        assert_eq!(options.nodelay, Some(true));
+        assert_eq!(options.keepalive.map(|k| k.idle), Some(time::Duration::from_secs(60)));
+        assert_eq!((options.recv_buffer, options.send_buffer), (Some(64 * 1024), None));
+
+        let untuned = Config::load_from_file("jalb.toml").unwrap();
+        assert_eq!(untuned.tcp().to_socket_options(), SocketOptions::default());
+        let orphaned = parse("keepalive_retries = 3").unwrap();
+        assert!(orphaned.tcp().validate().is_err());
+        assert!(parse("send_buffer_kb = 0").unwrap().tcp().validate().is_err());
+    }
+
+    #[test]
+    fn test_runtime() {
+        let file = fs::read_to_string("jalb.toml").unwrap();
+        let parse = |toml: &str| toml::from_str::<Config>(toml).unwrap();
+
+        // the older [loadbalancer] key is still read, [runtime] wins over it
+        let legacy = file.replacen("port = 6331", "port = 6331\nworker_threads = 8", 1);
+        assert_eq!(parse(&legacy).worker_threads(), Some(8));
+
+        let tuned = legacy.replacen(
+            "[logging]",
+            "[runtime]\nworker_threads = 2\nmax_blocking_threads = 4\nevent_interval = 31\n\n\
+             [logging]",
+            1,
+        );
+        let config = parse(&tuned);
+        assert!(config.validate_runtime().is_ok());
+        assert_eq!(config.worker_threads(), Some(2));
+        assert_eq!(config.max_blocking_threads(), Some(4));
+        assert_eq!(config.event_interval(), Some(31));
+        assert_eq!(config.global_queue_interval(), None);
+
+        let zero = tuned.replacen("max_blocking_threads = 4", "max_blocking_threads = 0", 1);
+        assert!(matches!(
+            parse(&zero).validate_runtime(),
+            Err(ConfigError::InvalidRuntime(_))
+        ));
+
+        let uring = tuned.replacen("event_interval = 31", "io_uring_threads = 2", 1);
+        let uring = parse(&uring);
+        assert_eq!(uring.io_uring_threads(), Some(2));
+        let built_with_uring = cfg!(all(feature = "uring", target_os = "linux"));
+        assert_eq!(uring.validate_runtime().is_ok(), built_with_uring);
+    }
+
+    #[test]
+    fn test_host_peers() {
+        let host = NetworkTarget::from_str("Example.com:8080").unwrap();
+        assert_eq!(host, NetworkTarget::Host("example.com".to_string(), 8080));
+        assert!(matches!(
+            NetworkTarget::from_str("127.0.0.1:80"),
+            Ok(NetworkTarget::SocketAddr(_))
+        ));
+        assert!(matches!(
+            NetworkTarget::from_str("http://example.com"),
+            Ok(NetworkTarget::Url(_))
+        ));
+
+        let backend: BackendOptions =
+            toml::from_str("name = \"api\"\npeers = [{ address = \"localhost:4000\" }]").unwrap();
+        let peers = backend.peers();
+        assert!(!peers.is_empty());
+        assert!(peers.iter().all(|peer| matches!(
+            peer.address,
+            NetworkTarget::SocketAddr(addr) if addr.ip().is_loopback() && addr.port() == 4000
+        )));
+        assert_eq!(backend.dns_refresh_interval(), Some(time::Duration::from_secs(30)));
+
+        let resolve_once: BackendOptions = toml::from_str(
+            "name = \"api\"\ndns_refresh_seconds = 0\npeers = [{ address = \"localhost:4000\" }]",
+        )
+        .unwrap();
+        assert_eq!(resolve_once.dns_refresh_interval(), None);
+    }
+
+    #[test]
+    fn test_peer_overrides() {
+        let backend: BackendOptions = toml::from_str(
+            r#"
+name = "api"
+request_timeout_seconds = 5
+failed_request_threshold = 3
+peers = [
+    { address = "127.0.0.1:5000" },
+    { address = "127.0.0.1:5001", request_timeout_seconds = 30, rate_limit = 50 },
+]
+"#,
+        )
+        .unwrap();
+
+        let [default, slow] = backend.peers.as_slice() else {
+            panic!("expected two peers");
+        };
+        assert_eq!(default.get_request_timeout(&backend), Some(time::Duration::from_secs(5)));
+        assert_eq!(default.get_rate_limit(&backend), None);
+        assert_eq!(slow.get_request_timeout(&backend), Some(time::Duration::from_secs(30)));
+        assert_eq!(slow.get_failed_request_threshold(&backend), Some(3));
+        assert_eq!(slow.get_rate_limit(&backend), Some(50));
+    }
 }
@@ -0,0 +1,367 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{Notify, watch},
+    task::JoinHandle,
+};
+
+use crate::{clients::ClientTable, flows::FlowTracer, peer::Peer, relay::Transfer};
+
+#[derive(Debug, Clone)]
+pub struct LiveConnection {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub peer: Arc<Peer>,
+    pub started: Instant,
+    /// bytes from the client as `a` and from the peer as `b`, counted as they are copied
+    pub transfer: Arc<Transfer>,
+    terminate: Arc<Notify>,
+}
+
+/// How often [`spawn_throughput_meter`] works out each pool's throughput.
+pub const THROUGHPUT_EVERY: Duration = Duration::from_secs(5);
+
+/// Bytes per second copied for the sessions of a pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    pub from_client: f64,
+    pub to_client: f64,
+}
+
+/// The bytes copied per pool as of the latest sample, and the throughput since the one before.
+#[derive(Debug, Default)]
+struct Meter {
+    sampled_at: Option<Instant>,
+    transferred: HashMap<String, (u64, u64)>,
+    rates: HashMap<String, Throughput>,
+}
+
+/// Every proxied session currently open, used as the source of truth for the per-peer
+/// connection counters, along with what each client has done and the connections traced.
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, LiveConnection>>,
+    /// bytes from and to the client of the sessions already closed, per pool; only ever locked
+    /// while holding `live`, so a closing session is never counted twice or not at all
+    closed: Mutex<HashMap<String, (u64, u64)>>,
+    meter: Mutex<Meter>,
+    clients: ClientTable,
+    flows: Arc<FlowTracer>,
+}
+
+/// Keeps a connection registered and counted against its peer until dropped.
+#[derive(Debug)]
+pub struct ConnectionHandle {
+    id: u64,
+    registry: Arc<ConnectionRegistry>,
+    transfer: Arc<Transfer>,
+    terminate: Arc<Notify>,
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Where the session counts what it copies, for listing it while open.
+    pub fn transfer(&self) -> &Arc<Transfer> {
+        &self.transfer
+    }
+
+    /// Completes once [`ConnectionRegistry::terminate`] is called for the connection, even
+    /// when that was before this was.
+    pub async fn terminated(&self) {
+        self.terminate.notified().await
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let mut live = self.registry.live.lock().unwrap();
+        let Some(connection) = live.remove(&self.id) else {
+            return;
+        };
+        let (sent, received) = connection.transfer.copied();
+        let mut closed = self.registry.closed.lock().unwrap();
+        let pool = closed.entry(connection.peer.backend.clone()).or_default();
+        pool.0 += sent;
+        pool.1 += received;
+        drop(closed);
+        drop(live);
+
+        let stats = connection.peer.stats();
+        stats.session_sent().record_value(sent);
+        stats.session_received().record_value(received);
+        connection.peer.connection_closed();
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(self: &Arc<Self>, client: SocketAddr, peer: Arc<Peer>) -> ConnectionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        peer.connection_opened();
+
+        let connection = LiveConnection {
+            id,
+            client,
+            peer,
+            started: Instant::now(),
+            transfer: Arc::new(Transfer::default()),
+            terminate: Arc::new(Notify::new()),
+        };
+        let handle = ConnectionHandle {
+            id,
+            registry: self.clone(),
+            transfer: connection.transfer.clone(),
+            terminate: connection.terminate.clone(),
+        };
+        self.live.lock().unwrap().insert(id, connection);
+        handle
+    }
+
+    /// Asks the open session `id` to close, returning it, or `None` when there's no such
+    /// session. It is gone from [`ConnectionRegistry::live`] once it has.
+    pub fn terminate(&self, id: u64) -> Option<LiveConnection> {
+        let connection = self.live.lock().unwrap().get(&id).cloned()?;
+        connection.terminate.notify_one();
+        Some(connection)
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.lock().unwrap().is_empty()
+    }
+
+    /// Counters per client address, kept by the balancers as they admit, reject and relay.
+    pub fn clients(&self) -> &ClientTable {
+        &self.clients
+    }
+
+    /// The sampled connections followed step by step, off until a sample rate is set.
+    pub fn flows(&self) -> &Arc<FlowTracer> {
+        &self.flows
+    }
+
+    /// The sessions open right now, oldest first.
+    pub fn live(&self) -> Vec<LiveConnection> {
+        let mut live: Vec<LiveConnection> = self.live.lock().unwrap().values().cloned().collect();
+        live.sort_by_key(|connection| connection.started);
+        live
+    }
+
+    /// The bytes copied from and to the clients of each pool so far, open sessions included.
+    pub fn transferred(&self) -> HashMap<String, (u64, u64)> {
+        let live = self.live.lock().unwrap();
+        let mut transferred = self.closed.lock().unwrap().clone();
+        for connection in live.values() {
+            let (sent, received) = connection.transfer.copied();
+            let pool = transferred
+                .entry(connection.peer.backend.clone())
+                .or_default();
+            pool.0 += sent;
+            pool.1 += received;
+        }
+        transferred
+    }
+
+    /// Works out each pool's throughput since the previous call.
+    pub fn sample_throughput(&self) {
+        let now = Instant::now();
+        let transferred = self.transferred();
+        let mut meter = self.meter.lock().unwrap();
+        if let Some(at) = meter.sampled_at {
+            let seconds = now.duration_since(at).as_secs_f64();
+            let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+            meter.rates = transferred
+                .iter()
+                .map(|(pool, &(sent, received))| {
+                    let (sent_before, received_before) =
+                        meter.transferred.get(pool).copied().unwrap_or_default();
+                    let throughput = Throughput {
+                        from_client: rate(sent, sent_before),
+                        to_client: rate(received, received_before),
+                    };
+                    (pool.clone(), throughput)
+                })
+                .collect();
+        }
+        meter.sampled_at = Some(now);
+        meter.transferred = transferred;
+    }
+
+    /// Each pool's throughput as of the latest sample, empty before there were two.
+    pub fn throughput(&self) -> HashMap<String, Throughput> {
+        self.meter.lock().unwrap().rates.clone()
+    }
+
+    /// Corrects each peer's connection counter against the registry, returning how many counters
+    /// had drifted.
+    ///
+    /// Without `decay` a drifted counter is reset to the live count. With `decay` only that
+    /// fraction of an excess is kept each pass, so a leak fades out exponentially while
+    /// connections racing with the pass are not undercounted.
+    pub fn reconcile(&self, peers: &[Arc<Peer>], decay: Option<f64>) -> usize {
+        let mut live_counts: HashMap<*const Peer, u64> = HashMap::new();
+        for connection in self.live.lock().unwrap().values() {
+            *live_counts
+                .entry(Arc::as_ptr(&connection.peer))
+                .or_default() += 1;
+        }
+
+        let mut drifted = 0;
+        for peer in peers {
+            let live = live_counts
+                .get(&Arc::as_ptr(peer))
+                .copied()
+                .unwrap_or_default();
+            let counted = peer.active_connections();
+            if counted == live {
+                continue;
+            }
+
+            let corrected = match decay {
+                Some(factor) if counted > live => live + ((counted - live) as f64 * factor) as u64,
+                _ => live,
+            };
+
+            tracing::warn!(
+                "connection counter for {} drifted: counted {}, live {}, corrected to {}",
+                peer.address.as_string(),
+                counted,
+                live,
+                corrected
+            );
+            peer.set_active_connections(corrected);
+            drifted += 1;
+        }
+
+        drifted
+    }
+}
+
+pub fn spawn_reconciler(
+    registry: Arc<ConnectionRegistry>,
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+    interval: Duration,
+    decay: Option<f64>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let peers = peers.borrow().clone();
+            registry.reconcile(&peers, decay);
+        }
+    })
+}
+
+/// Samples `registry`'s throughput every [`THROUGHPUT_EVERY`].
+pub fn spawn_throughput_meter(registry: Arc<ConnectionRegistry>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(THROUGHPUT_EVERY);
+        loop {
+            interval.tick().await;
+            registry.sample_throughput();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::Side;
+
+    #[test]
+    fn test_handle_drop_releases_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let peer = Arc::new(Peer::new("127.0.0.1:8080").unwrap());
+        let client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+
+        let handle = registry.register(client, peer.clone());
+        assert_eq!(peer.active_connections(), 1);
+        assert_eq!(registry.len(), 1);
+
+        drop(handle);
+        assert_eq!(peer.active_connections(), 0);
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let peer = Arc::new(Peer::new("127.0.0.1:8080").unwrap());
+        let handle = registry.register("10.0.0.1:50000".parse().unwrap(), peer);
+        assert!(registry.terminate(handle.id() + 1).is_none());
+
+        let terminated = registry.terminate(handle.id()).unwrap();
+        assert_eq!(terminated.id, handle.id());
+        // asked before the session got to wait for it
+        tokio::time::timeout(Duration::from_secs(1), handle.terminated())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_session_bytes_and_throughput() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let mut peer = Peer::new("127.0.0.1:8080").unwrap();
+        peer.backend = "web".to_string();
+        let peer = Arc::new(peer);
+        let client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let (open, closing) = (
+            registry.register(client, peer.clone()),
+            registry.register(client, peer.clone()),
+        );
+
+        registry.sample_throughput();
+        assert!(registry.throughput().is_empty());
+        open.transfer().read(Side::A, 100);
+        closing.transfer().read(Side::B, 4000);
+        drop(closing);
+        assert_eq!(registry.transferred()["web"], (100, 4000));
+        let received = peer.stats().session_received().snapshot();
+        assert_eq!(received.count(), 1);
+        assert!(received.value_quantile(0.5) >= 4000);
+
+        std::thread::sleep(Duration::from_millis(20));
+        registry.sample_throughput();
+        let throughput = registry.throughput()["web"];
+        assert!(throughput.from_client > 0.0 && throughput.from_client <= 5000.0);
+        assert!(throughput.to_client > throughput.from_client);
+    }
+
+    #[test]
+    fn test_reconcile_with_and_without_decay() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let peer = Arc::new(Peer::new("127.0.0.1:8080").unwrap());
+        let client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let _handle = registry.register(client, peer.clone());
+
+        // simulate nine leaked decrements
+        peer.set_active_connections(10);
+
+        let peers = vec![peer.clone()];
+        assert_eq!(registry.reconcile(&peers, Some(0.5)), 1);
+        assert_eq!(peer.active_connections(), 5);
+
+        assert_eq!(registry.reconcile(&peers, None), 1);
+        assert_eq!(peer.active_connections(), 1);
+        assert_eq!(registry.reconcile(&peers, None), 0);
+    }
+}
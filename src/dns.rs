@@ -0,0 +1,184 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::histogram::{Histogram, HistogramSnapshot};
+
+static LOOKUPS: OnceLock<DnsStats> = OnceLock::new();
+
+/// The lookups of every URL and `host:port` peer since start. Peers are resolved from the
+/// config as well as on connecting, so these are kept for the whole process.
+pub fn lookups() -> &'static DnsStats {
+    LOOKUPS.get_or_init(DnsStats::default)
+}
+
+/// Resolves `name` with `resolve`, recording how long it took and what it came back with.
+pub(crate) fn lookup(
+    name: &str,
+    resolve: impl FnOnce() -> io::Result<Vec<SocketAddr>>,
+) -> Vec<SocketAddr> {
+    let started = Instant::now();
+    let resolved = resolve();
+    let took = started.elapsed();
+    match resolved {
+        Ok(addrs) if !addrs.is_empty() => {
+            lookups().record(name, took, Ok(addrs.len()));
+            addrs
+        }
+        Ok(_) => {
+            lookups().record(name, took, Err("no records".to_string()));
+            Vec::new()
+        }
+        Err(e) => {
+            lookups().record(name, took, Err(e.to_string()));
+            Vec::new()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NameEntry {
+    lookups: u64,
+    failures: u64,
+    records: usize,
+    last_error: Option<String>,
+    last_lookup: u128,
+    last_failure: Option<u128>,
+    ttl: Option<Duration>,
+    duration: Histogram,
+}
+
+/// What the lookups of one name came to.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameLookups {
+    /// `host:port`
+    pub name: String,
+    pub lookups: u64,
+    pub failures: u64,
+    /// addresses the latest lookup returned, 0 when it failed
+    pub records: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// milliseconds since the epoch
+    pub last_lookup: u128,
+    pub last_failure: Option<u128>,
+    /// How long the records are used before the name is looked up again: 0 when it is for
+    /// every connection, leaving caching to the system resolver, and the backend's
+    /// `dns_refresh_seconds` for peers expanded from the records. `None` when they are kept
+    /// until the config is reloaded.
+    pub ttl_seconds: Option<u64>,
+    #[serde(skip)]
+    pub duration: HistogramSnapshot,
+}
+
+/// Lookups per name, so a name that stopped resolving shows up before its peer is picked.
+#[derive(Debug, Default)]
+pub struct DnsStats {
+    names: Mutex<BTreeMap<String, NameEntry>>,
+}
+
+impl DnsStats {
+    /// A lookup of `name` that took `took`, with the records it returned or why it failed.
+    pub fn record(&self, name: &str, took: Duration, outcome: Result<usize, String>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut names = self.names.lock().unwrap();
+        let entry = names.entry(name.to_string()).or_insert_with(|| NameEntry {
+            lookups: 0,
+            failures: 0,
+            records: 0,
+            last_error: None,
+            last_lookup: now,
+            last_failure: None,
+            ttl: Some(Duration::ZERO),
+            duration: Histogram::default(),
+        });
+        entry.lookups += 1;
+        entry.last_lookup = now;
+        entry.duration.record(took);
+        match outcome {
+            Ok(records) => {
+                entry.records = records;
+                entry.last_error = None;
+            }
+            Err(e) => {
+                tracing::warn!("looking up {} failed: {}", name, e);
+                entry.failures += 1;
+                entry.records = 0;
+                entry.last_error = Some(e);
+                entry.last_failure = Some(now);
+            }
+        }
+    }
+
+    /// Notes that `name`'s records are used for `ttl` before it is looked up again, until the
+    /// next reload with `None`.
+    pub fn keep_for(&self, name: &str, ttl: Option<Duration>) {
+        if let Some(entry) = self.names.lock().unwrap().get_mut(name) {
+            entry.ttl = ttl;
+        }
+    }
+
+    /// Every name looked up so far, in order.
+    pub fn names(&self) -> Vec<NameLookups> {
+        let names = self.names.lock().unwrap();
+        names
+            .iter()
+            .map(|(name, entry)| NameLookups {
+                name: name.clone(),
+                lookups: entry.lookups,
+                failures: entry.failures,
+                records: entry.records,
+                last_error: entry.last_error.clone(),
+                last_lookup: entry.last_lookup,
+                last_failure: entry.last_failure,
+                ttl_seconds: entry.ttl.map(|ttl| ttl.as_secs()),
+                duration: entry.duration.snapshot(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookups_recorded() {
+        let stats = DnsStats::default();
+        stats.record("db.internal:5432", Duration::from_millis(3), Ok(2));
+        stats.record(
+            "db.internal:5432",
+            Duration::from_millis(5),
+            Err("timed out".to_string()),
+        );
+        stats.keep_for("db.internal:5432", Some(Duration::from_secs(30)));
+        stats.keep_for("never.looked.up:80", None);
+
+        let names = stats.names();
+        assert_eq!(names.len(), 1);
+        let db = &names[0];
+        assert_eq!((db.lookups, db.failures, db.records), (2, 1, 0));
+        assert_eq!(db.last_error.as_deref(), Some("timed out"));
+        assert!(db.last_failure.is_some());
+        assert_eq!(db.ttl_seconds, Some(30));
+        assert_eq!(db.duration.count(), 2);
+
+        let addrs = lookup("localhost:1", || Ok(vec!["127.0.0.1:1".parse().unwrap()]));
+        assert_eq!(addrs.len(), 1);
+        let localhost = lookups()
+            .names()
+            .into_iter()
+            .find(|name| name.name == "localhost:1")
+            .unwrap();
+        assert_eq!((localhost.records, localhost.ttl_seconds), (1, Some(0)));
+    }
+}
@@ -1,17 +1,89 @@
-use std::{error, io, net::AddrParseError};
-use thiserror;
-use toml;
+use std::{io, time::Duration};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
     #[error("could not open config file")]
     IOError(#[from] io::Error),
-    #[error("failed to deserialize from config file")]
-    DeserializationError(#[from] toml::de::Error),
+    #[error("failed to deserialize from config file: {0}")]
+    DeserializationError(String),
+    #[error("failed to deserialize from yaml config file: {0}")]
+    YamlDeserializationError(String),
+    #[error("failed to deserialize from json config file: {0}")]
+    JsonDeserializationError(String),
+    #[error("{0}\ndid you mean `{1}`?")]
+    UnknownKey(String, String),
+    #[error("failed to serialize the config")]
+    SerializationError(#[from] toml::ser::Error),
     #[error("unknown load balancer strategy specified {0}")]
     InvalidStrategy(String),
     #[error("unknown jalb config version specified {0}. Valid versions are {1}")]
     InvalidVersion(String, String),
+    #[error("config has no version key. Valid versions are {0}")]
+    MissingVersion(String),
+    #[error("invalid [tls] section: {0}")]
+    InvalidTls(String),
+    #[error("invalid [runtime] section: {0}")]
+    InvalidRuntime(String),
+    #[error("cannot resolve secret {0}: {1}")]
+    UnresolvedSecret(String, String),
+    #[error("invalid value for {0}: {1}")]
+    InvalidOverride(String, String),
+    #[error("invalid [[backend]] pools: {0}")]
+    InvalidBackends(String),
+    #[error("invalid [[listener]] addresses: {0}")]
+    InvalidListeners(String),
+    #[error("invalid [[route]]: {0}")]
+    InvalidRoutes(String),
+    #[error("invalid [forwarded] section: {0}")]
+    InvalidForwarded(String),
+    #[error("invalid [cache] section: {0}")]
+    InvalidCache(String),
+    #[error("invalid [http3] section: {0}")]
+    InvalidHttp3(String),
+    #[error("invalid [access_log] section: {0}")]
+    InvalidAccessLog(String),
+    #[error("invalid [[webhook]] section: {0}")]
+    InvalidWebhook(String),
+    #[error("invalid [tcp] section: {0}")]
+    InvalidTcp(String),
+    #[error("inconsistent config: {}", .0.join("; "))]
+    Inconsistent(Vec<String>),
+    #[error("no profile {0} in the config, it has {1}")]
+    UnknownProfile(String, String),
+    #[error("strategy {0} is not implemented yet")]
+    UnimplementedStrategy(String),
+    #[error("cannot expand line {0} of the config: {1}")]
+    Interpolation(usize, String),
+    #[error("cannot include {0}: {1}")]
+    Include(String, String),
+    #[error("{0} is set again by {1} with a different value")]
+    IncludeConflict(String, String),
+}
+
+/// Keeps where the parser stopped and why, not the line of config text it quotes, which may hold
+/// an expanded secret.
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        let shown = e.to_string();
+        let message = e.message().trim_end();
+        ConfigError::DeserializationError(match shown.lines().next() {
+            Some(at) if at.starts_with("TOML parse error at ") => format!("{}: {}", at, message),
+            _ => message.to_string(),
+        })
+    }
+}
+
+/// yaml and json errors don't quote the text around them, they are kept as shown.
+impl From<serde_yaml_ng::Error> for ConfigError {
+    fn from(e: serde_yaml_ng::Error) -> Self {
+        ConfigError::YamlDeserializationError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::JsonDeserializationError(e.to_string())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -22,6 +94,64 @@ pub enum LoadBalancerError {
     SocketOpenError(String),
 }
 
+/// Why a proxied connection was cut short, carried inside an `io::Error` of kind `TimedOut`.
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    #[error("connecting to the peer took longer than {0:?}")]
+    ConnectTimeout(Duration),
+    #[error("session lasted longer than {0:?}")]
+    SessionTimeout(Duration),
+    #[error("the peer sent no response within {0:?} of getting the request")]
+    FirstByteTimeout(Duration),
+    #[error("the request took longer than {0:?} in all")]
+    TotalTimeout(Duration),
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(u64),
+}
+
+/// What a session fails with once terminated through the admin api.
+#[derive(Debug, thiserror::Error)]
+#[error("terminated through the admin api")]
+pub struct Terminated;
+
+impl From<Terminated> for std::io::Error {
+    fn from(terminated: Terminated) -> Self {
+        std::io::Error::new(io::ErrorKind::ConnectionAborted, terminated)
+    }
+}
+
+/// Why a request's bearer token wasn't accepted by a `[[route]]`'s `jwt`.
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("no bearer token")]
+    Missing,
+    #[error("malformed token: {0}")]
+    Malformed(String),
+    #[error("token signed with {0}, which isn't accepted")]
+    UnsupportedAlgorithm(String),
+    #[error("no key {0} in the jwks")]
+    UnknownKey(String),
+    #[error("signature doesn't verify")]
+    BadSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("token not valid yet")]
+    NotYetValid,
+    #[error("token issued by {0}")]
+    WrongIssuer(String),
+    #[error("token not meant for this audience")]
+    WrongAudience,
+    #[error("cannot fetch the jwks: {0}")]
+    Jwks(String),
+}
+
+/// Why the copy of a request sent to a `[[route]]`'s mirror was cut short.
+#[derive(Debug, thiserror::Error)]
+pub enum MirrorError {
+    #[error("the mirror fell behind the request body, or the body failed")]
+    Abandoned,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum NetworkTargetError {
     #[error("The provided string cannot be parsed as either a url or socket address {0}")]
@@ -37,3 +167,44 @@ pub enum PeerError {
     #[error("The provided health endpoint cannot be represented as a Url or socket address")]
     InvalidHealthEndpointError(NetworkTargetError),
 }
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecurityError {
+    #[error("could not read ip list file")]
+    IOError(#[from] io::Error),
+    #[error("invalid entry in ip list {0} on line {1}: {2}")]
+    InvalidListEntry(String, usize, String),
+    #[error("could not open asn database {0}: {1}")]
+    AsnDatabase(String, String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("could not access state file")]
+    IOError(#[from] io::Error),
+    #[error("state file is corrupt")]
+    SerializationError(#[from] serde_json::Error),
+    #[cfg(feature = "redis")]
+    #[error("redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("state backend redis requires redis_url")]
+    MissingRedisUrl,
+    #[error("state backend redis requires jalb to be built with the redis feature")]
+    RedisDisabled,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpiffeError {
+    #[error("workload api unavailable: {0}")]
+    WorkloadApi(String),
+    #[error("workload api returned no svid")]
+    NoSvid,
+    #[error("malformed svid: {0}")]
+    MalformedSvid(String),
+    #[error("invalid upstream server_name {0}")]
+    InvalidServerName(String),
+    #[error("cannot use svid for tls")]
+    Tls(#[from] rustls::Error),
+    #[error("jalb was built without the spiffe feature")]
+    SpiffeDisabled,
+}
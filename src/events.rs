@@ -0,0 +1,282 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 1024;
+const SUBSCRIBER_BACKLOG: usize = 256;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    PeerTransition,
+    Reload,
+    Reject,
+    Error,
+    Critical,
+}
+
+impl EventKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "peer_transition" => Some(Self::PeerTransition),
+            "reload" => Some(Self::Reload),
+            "reject" => Some(Self::Reject),
+            "error" => Some(Self::Error),
+            "critical" => Some(Self::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PeerTransition => "peer_transition",
+            Self::Reload => "reload",
+            Self::Reject => "reject",
+            Self::Error => "error",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Whether a peer's probe is passing.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    Up,
+    Down,
+}
+
+/// A peer's liveness or readiness flipping, as the health checks saw it.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub peer: String,
+    pub backend: String,
+    /// `liveness` or `readiness`
+    pub probe: &'static str,
+    /// the check run, e.g. `tcp` or `http /healthz`
+    pub check: String,
+    pub from: PeerState,
+    pub to: PeerState,
+    /// why the check failed, for transitions down
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TransitionRecord {
+    /// the id of the `peer_transition` event recorded with it
+    pub id: u64,
+    /// milliseconds since the unix epoch
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub transition: Transition,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Event {
+    pub id: u64,
+    /// milliseconds since the unix epoch
+    pub timestamp: u128,
+    pub kind: EventKind,
+    pub message: String,
+}
+
+/// Fixed-size buffer of the most recent internal events. Once full, recording a new event evicts
+/// the oldest one. Peer transitions are also kept in a buffer of their own, so a flood of other
+/// events can't push out the history of a flapping peer.
+#[derive(Debug)]
+pub struct EventLog {
+    capacity: usize,
+    next_id: AtomicU64,
+    events: Mutex<VecDeque<Event>>,
+    transitions: Mutex<VecDeque<TransitionRecord>>,
+    subscribers: broadcast::Sender<Event>,
+    transition_subscribers: broadcast::Sender<TransitionRecord>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next_id: AtomicU64::new(0),
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            transitions: Mutex::new(VecDeque::new()),
+            subscribers: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+            transition_subscribers: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        }
+    }
+
+    pub fn record(&self, kind: EventKind, message: impl Into<String>) {
+        self.push(kind, message.into());
+    }
+
+    /// Records `transition` as a `peer_transition` event reading `message`, and keeps it apart
+    /// for [`EventLog::transitions`].
+    pub fn record_transition(&self, transition: Transition, message: impl Into<String>) {
+        let Some(event) = self.push(EventKind::PeerTransition, message.into()) else {
+            return;
+        };
+
+        let record = TransitionRecord {
+            id: event.id,
+            timestamp: event.timestamp,
+            transition,
+        };
+        let _ = self.transition_subscribers.send(record.clone());
+
+        let mut transitions = self.transitions.lock().unwrap();
+        if transitions.len() == self.capacity {
+            transitions.pop_front();
+        }
+        transitions.push_back(record);
+    }
+
+    /// Returns `None` without recording anything when the capacity is 0.
+    fn push(&self, kind: EventKind, message: String) -> Option<Event> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        let event = Event {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp,
+            kind,
+            message,
+        };
+
+        // an error only means nobody is watching
+        let _ = self.subscribers.send(event.clone());
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        Some(event)
+    }
+
+    /// Receives every event recorded after the call. Slow receivers skip events rather than
+    /// holding up the recorder.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.subscribers.subscribe()
+    }
+
+    /// Receives every peer transition recorded after the call, like [`EventLog::subscribe`].
+    pub fn subscribe_transitions(&self) -> broadcast::Receiver<TransitionRecord> {
+        self.transition_subscribers.subscribe()
+    }
+
+    /// Returns up to `limit` of the newest events, optionally filtered by kind, oldest first.
+    pub fn recent(&self, limit: usize, kind: Option<EventKind>) -> Vec<Event> {
+        let events = self.events.lock().unwrap();
+        let mut recent: Vec<Event> = events
+            .iter()
+            .rev()
+            .filter(|e| kind.is_none_or(|k| e.kind == k))
+            .take(limit)
+            .cloned()
+            .collect();
+
+        recent.reverse();
+        recent
+    }
+
+    /// Returns up to `limit` of the newest peer transitions, optionally of one peer only, oldest
+    /// first.
+    pub fn transitions(&self, limit: usize, peer: Option<&str>) -> Vec<TransitionRecord> {
+        let transitions = self.transitions.lock().unwrap();
+        let mut recent: Vec<TransitionRecord> = transitions
+            .iter()
+            .rev()
+            .filter(|t| peer.is_none_or(|p| t.transition.peer == p))
+            .take(limit)
+            .cloned()
+            .collect();
+
+        recent.reverse();
+        recent
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_BUFFER_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_evicts_oldest() {
+        let log = EventLog::new(3);
+        for i in 0..5 {
+            log.record(EventKind::Error, format!("event {}", i));
+        }
+
+        let events = log.recent(10, None);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].message, "event 2");
+        assert_eq!(events[2].message, "event 4");
+    }
+
+    #[test]
+    fn test_event_log_filter_by_kind() {
+        let log = EventLog::new(10);
+        log.record(EventKind::Reject, "rejected 10.0.0.1");
+        log.record(EventKind::Error, "upstream refused");
+        log.record(EventKind::Reject, "rejected 10.0.0.2");
+
+        let rejects = log.recent(1, Some(EventKind::Reject));
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].message, "rejected 10.0.0.2");
+    }
+
+    #[test]
+    fn test_transitions_outlast_other_events() {
+        let log = EventLog::new(4);
+        let transition = |peer: &str, to| Transition {
+            peer: peer.to_string(),
+            backend: "web".to_string(),
+            probe: "readiness",
+            check: "tcp".to_string(),
+            from: PeerState::Up,
+            to,
+            detail: None,
+        };
+        log.record_transition(transition("10.0.0.1:80", PeerState::Down), "peer down");
+        log.record_transition(transition("10.0.0.2:80", PeerState::Down), "peer down");
+        log.record_transition(transition("10.0.0.1:80", PeerState::Up), "peer up");
+        for i in 0..10 {
+            log.record(EventKind::Reject, format!("rejected {}", i));
+        }
+
+        assert!(log.recent(10, Some(EventKind::PeerTransition)).is_empty());
+        let flaps = log.transitions(10, Some("10.0.0.1:80"));
+        assert_eq!(flaps.len(), 2);
+        assert_eq!(flaps[0].transition.to, PeerState::Down);
+        assert_eq!(flaps[1].transition.to, PeerState::Up);
+        assert_eq!(flaps[1].id, 2);
+        assert_eq!(
+            log.transitions(1, None)[0].transition,
+            transition("10.0.0.1:80", PeerState::Up)
+        );
+    }
+}
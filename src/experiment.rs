@@ -0,0 +1,229 @@
+use std::net::IpAddr;
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::affinity::cookie;
+
+/// `experiment` of a `[[route]]`: clients are put in one of the `variants` by a hash of what
+/// `by` names, each variant getting a share of them by weight, and their requests go to that
+/// variant's backend. The same client lands in the same variant on every instance, and the
+/// variant it is in is sent back in `response_header`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    /// part of the hash, so clients are shuffled afresh for each experiment
+    pub name: String,
+    #[serde(default)]
+    pub by: ExperimentKey,
+    /// the cookie or header `by` names, clients without it are told apart by address
+    pub key: Option<String>,
+    pub variants: Vec<VariantConfig>,
+    #[serde(default = "default_response_header")]
+    pub response_header: String,
+}
+
+/// What puts a client in a variant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExperimentKey {
+    #[default]
+    ClientIp,
+    Cookie,
+    Header,
+}
+
+/// One of an experiment's `variants`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    pub backend: String,
+    pub weight: u32,
+}
+
+fn default_response_header() -> String {
+    "X-Experiment-Variant".to_string()
+}
+
+impl ExperimentConfig {
+    pub fn validate(&self, backends: &[&str]) -> Result<(), String> {
+        match (self.by, &self.key) {
+            (ExperimentKey::ClientIp, Some(_)) => {
+                return Err("key is only for by = \"cookie\" or \"header\"".to_string());
+            }
+            (ExperimentKey::Cookie | ExperimentKey::Header, None) => {
+                return Err("name the cookie or header in key".to_string());
+            }
+            (ExperimentKey::Header, Some(key)) if HeaderName::try_from(key.as_str()).is_err() => {
+                return Err(format!("{} is not a header name", key));
+            }
+            _ => {}
+        }
+        for (idx, variant) in self.variants.iter().enumerate() {
+            if !backends.contains(&variant.backend.as_str()) {
+                return Err(format!("{} is not a configured backend", variant.backend));
+            }
+            if HeaderValue::from_str(&variant.name).is_err() {
+                return Err(format!("{:?} can't be sent in a header", variant.name));
+            }
+            if self.variants[..idx]
+                .iter()
+                .any(|other| other.name == variant.name)
+            {
+                return Err(format!("{} is listed more than once", variant.name));
+            }
+        }
+        if self.variants.iter().all(|variant| variant.weight == 0) {
+            return Err("no variant has a weight".to_string());
+        }
+        if HeaderName::try_from(self.response_header.as_str()).is_err() {
+            return Err(format!("{} is not a header name", self.response_header));
+        }
+        Ok(())
+    }
+}
+
+/// A route's [`ExperimentConfig`], ready to put clients in variants.
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    name: String,
+    by: ExperimentKey,
+    key: Option<String>,
+    variants: Vec<Variant>,
+    total_weight: u64,
+    response_header: HeaderName,
+}
+
+/// A variant clients can be put in.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    name: HeaderValue,
+    backend: String,
+    weight: u32,
+}
+
+impl Experiment {
+    /// `config` was checked by [`ExperimentConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &ExperimentConfig) -> Self {
+        let variants = config
+            .variants
+            .iter()
+            .map(|variant| Variant {
+                name: HeaderValue::from_str(&variant.name).expect("validated variant name"),
+                backend: variant.backend.clone(),
+                weight: variant.weight,
+            })
+            .collect();
+        Self {
+            name: config.name.clone(),
+            by: config.by,
+            key: config.key.clone(),
+            variants,
+            total_weight: config.variants.iter().map(|v| u64::from(v.weight)).sum(),
+            response_header: HeaderName::try_from(config.response_header.as_str())
+                .expect("validated header"),
+        }
+    }
+
+    /// The variant of the client at `client` sending `headers`.
+    pub fn assign(&self, client: IpAddr, headers: &HeaderMap) -> &Variant {
+        let key = self.key.as_deref();
+        let value = match self.by {
+            ExperimentKey::ClientIp => None,
+            ExperimentKey::Cookie => key.and_then(|name| cookie(headers, name)),
+            ExperimentKey::Header => key
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok()),
+        };
+        let client = client.to_string();
+        let value = value.unwrap_or(&client);
+
+        // a hash that stays the same across instances and releases, unlike std's
+        let input = format!("{}\n{}", self.name, value);
+        let hash = digest::digest(&digest::SHA256, input.as_bytes());
+        let bytes: [u8; 8] = hash.as_ref()[..8].try_into().expect("sha256 is 32 bytes");
+        let mut bucket = u64::from_be_bytes(bytes) % self.total_weight;
+        for variant in &self.variants {
+            let weight = u64::from(variant.weight);
+            if bucket < weight {
+                return variant;
+            }
+            bucket -= weight;
+        }
+        unreachable!("buckets are below the sum of the weights")
+    }
+
+    /// The header telling clients their variant.
+    pub fn response_header(&self) -> &HeaderName {
+        &self.response_header
+    }
+}
+
+impl Variant {
+    pub fn name(&self) -> &HeaderValue {
+        &self.name
+    }
+
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::COOKIE;
+
+    use super::*;
+
+    #[test]
+    fn test_experiment_assignment() {
+        let config: ExperimentConfig = toml::from_str(
+            r#"
+            name = "checkout"
+            by = "cookie"
+            key = "session"
+            variants = [
+                { name = "control", backend = "stable", weight = 3 },
+                { name = "redesign", backend = "v2", weight = 1 },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate(&["stable", "v2"]).is_ok());
+        assert!(config.validate(&["stable"]).is_err());
+        let experiment = Experiment::from_config(&config);
+        assert_eq!(experiment.response_header(), "x-experiment-variant");
+
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut counts = [0; 2];
+        for session in 0..1000 {
+            let mut headers = HeaderMap::new();
+            let value = format!("theme=dark; session={}", session);
+            headers.insert(COOKIE, HeaderValue::from_str(&value).unwrap());
+            let variant = experiment.assign(client, &headers);
+            // the same client always gets the same variant
+            assert_eq!(experiment.assign(client, &headers).name(), variant.name());
+            counts[usize::from(variant.backend() == "v2")] += 1;
+        }
+        assert!((650..850).contains(&counts[0]), "{:?}", counts);
+        // without the cookie the address decides
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let headers = HeaderMap::new();
+        assert_eq!(
+            experiment.assign(other, &headers).name(),
+            experiment.assign(other, &headers).name()
+        );
+
+        let invalid = |toml: &str| {
+            toml::from_str::<ExperimentConfig>(toml)
+                .unwrap()
+                .validate(&["stable"])
+        };
+        let variants = r#"variants = [{ name = "a", backend = "stable", weight = 1 }]"#;
+        assert!(invalid(&format!("name = \"x\"\n{}", variants)).is_ok());
+        assert!(invalid(&format!("name = \"x\"\nby = \"header\"\n{}", variants)).is_err());
+        assert!(invalid(&format!("name = \"x\"\nkey = \"k\"\n{}", variants)).is_err());
+    }
+}
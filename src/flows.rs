@@ -0,0 +1,240 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::relay::Transfer;
+
+/// Traced connections kept, the oldest dropped first.
+pub const MAX_FLOWS: usize = 100;
+/// Steps kept per flow. Byte counts stop being sampled past it, the close is always kept.
+const MAX_STEPS: usize = 64;
+/// How often the bytes copied so far are noted while a traced session is open.
+const BYTES_EVERY: Duration = Duration::from_secs(1);
+
+/// What happened to a traced connection, in order.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum FlowStep {
+    Accepted,
+    /// passed the security rules
+    Admitted,
+    Rejected {
+        reason: String,
+    },
+    Selected {
+        peer: String,
+    },
+    /// the connection to the peer is open, and with upstream TLS its handshake done
+    Connected {
+        connect_ms: f64,
+    },
+    Transferred {
+        sent: u64,
+        received: u64,
+    },
+    Closed {
+        end: &'static str,
+        sent: u64,
+        received: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlowEntry {
+    /// since the connection was accepted
+    pub at_ms: f64,
+    #[serde(flatten)]
+    pub step: FlowStep,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlowTrace {
+    pub id: u64,
+    pub client: SocketAddr,
+    /// milliseconds since the epoch the connection was accepted at
+    pub timestamp: u128,
+    pub steps: Vec<FlowEntry>,
+}
+
+/// Picks 1 in every `sample` connections to follow step by step, keeping the latest
+/// [`MAX_FLOWS`] traces for diagnosing slow or failing sessions. Off while `sample` is 0.
+#[derive(Debug, Default)]
+pub struct FlowTracer {
+    sample: AtomicU64,
+    seen: AtomicU64,
+    next_id: AtomicU64,
+    traces: Mutex<VecDeque<FlowTrace>>,
+}
+
+impl FlowTracer {
+    pub fn sample(&self) -> u64 {
+        self.sample.load(Ordering::Relaxed)
+    }
+
+    /// Traces 1 in every `sample` connections from now on, none with 0.
+    pub fn set_sample(&self, sample: u64) {
+        self.sample.store(sample, Ordering::Relaxed);
+    }
+
+    /// A flow for the connection just accepted from `client`, when it is one to trace.
+    pub fn start(self: &Arc<Self>, client: SocketAddr) -> Option<Flow> {
+        let sample = self.sample();
+        let nth = self.seen.fetch_add(1, Ordering::Relaxed);
+        if sample == 0 || !nth.is_multiple_of(sample) {
+            return None;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut flow = Flow {
+            tracer: self.clone(),
+            started: Instant::now(),
+            trace: FlowTrace {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                client,
+                timestamp,
+                steps: Vec::new(),
+            },
+        };
+        flow.step(FlowStep::Accepted);
+        Some(flow)
+    }
+
+    /// Up to `limit` finished traces, the newest first.
+    pub fn traces(&self, limit: usize) -> Vec<FlowTrace> {
+        let traces = self.traces.lock().unwrap();
+        traces.iter().rev().take(limit).cloned().collect()
+    }
+
+    fn keep(&self, trace: FlowTrace) {
+        let mut traces = self.traces.lock().unwrap();
+        if traces.len() >= MAX_FLOWS {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+    }
+}
+
+/// One traced connection, kept by its tracer once [`Flow::finish`]ed.
+#[derive(Debug)]
+pub struct Flow {
+    tracer: Arc<FlowTracer>,
+    started: Instant,
+    trace: FlowTrace,
+}
+
+impl Flow {
+    pub fn step(&mut self, step: FlowStep) {
+        self.step_at(Instant::now(), step);
+    }
+
+    fn step_at(&mut self, at: Instant, step: FlowStep) {
+        let at_ms = at.saturating_duration_since(self.started).as_secs_f64() * 1000.0;
+        self.trace.steps.push(FlowEntry { at_ms, step });
+    }
+
+    /// Runs `session`, a proxy counting into `transfer`, noting when it got connected and the
+    /// bytes copied every [`BYTES_EVERY`] along the way.
+    pub async fn follow<T>(&mut self, session: impl Future<Output = T>, transfer: &Transfer) -> T {
+        let selected = Instant::now();
+        let mut connected = false;
+        let mut ticks =
+            tokio::time::interval_at(tokio::time::Instant::now() + BYTES_EVERY, BYTES_EVERY);
+        tokio::pin!(session);
+
+        let done = loop {
+            tokio::select! {
+                done = &mut session => break done,
+                _ = ticks.tick() => {
+                    connected = connected || self.connected(selected, transfer);
+                    if self.trace.steps.len() < MAX_STEPS - 1 {
+                        let (sent, received) = transfer.copied();
+                        self.step(FlowStep::Transferred { sent, received });
+                    }
+                }
+            }
+        };
+        if !connected {
+            self.connected(selected, transfer);
+        }
+        done
+    }
+
+    /// Notes when the relay in `transfer` started, once it has.
+    fn connected(&mut self, selected: Instant, transfer: &Transfer) -> bool {
+        let Some(at) = transfer.started() else {
+            return false;
+        };
+        let connect_ms = at.saturating_duration_since(selected).as_secs_f64() * 1000.0;
+        self.step_at(at, FlowStep::Connected { connect_ms });
+        true
+    }
+
+    /// Notes `step` as the last one and hands the trace to the tracer.
+    pub fn finish(mut self, step: FlowStep) {
+        self.step(step);
+        self.tracer.keep(self.trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sampled_flow() {
+        let tracer = Arc::new(FlowTracer::default());
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert!(tracer.start(client).is_none());
+
+        tracer.set_sample(3);
+        let sampled: Vec<Flow> = (0..6).filter_map(|_| tracer.start(client)).collect();
+        assert_eq!(sampled.len(), 2);
+
+        let mut flow = sampled.into_iter().next().unwrap();
+        flow.step(FlowStep::Admitted);
+        let transfer = Transfer::default();
+        let copied = flow
+            .follow(
+                async {
+                    transfer.start();
+                    tokio::time::sleep(Duration::from_millis(1200)).await;
+                    7
+                },
+                &transfer,
+            )
+            .await;
+        assert_eq!(copied, 7);
+        flow.finish(FlowStep::Closed {
+            end: "client_close",
+            sent: 0,
+            received: 0,
+            error: None,
+        });
+
+        let traces = tracer.traces(10);
+        assert_eq!(traces.len(), 1);
+        let steps: Vec<&FlowStep> = traces[0].steps.iter().map(|entry| &entry.step).collect();
+        assert_eq!(steps[0], &FlowStep::Accepted);
+        assert!(matches!(steps[2], FlowStep::Connected { .. }));
+        let transferred = steps
+            .iter()
+            .filter(|step| matches!(step, FlowStep::Transferred { .. }))
+            .count();
+        assert_eq!(transferred, 1);
+        assert!(matches!(steps.last(), Some(FlowStep::Closed { .. })));
+    }
+}
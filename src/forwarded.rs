@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+
+use http::{HeaderMap, HeaderName, HeaderValue, header::FORWARDED};
+use ipnet::IpNet;
+
+use crate::config::ForwardedConfig;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// Tells peers of the application balancer who the client is. A client that is itself one of
+/// the trusted proxies has its forwarding headers kept and the hop appended; anyone else could
+/// be making them up, so theirs are replaced.
+#[derive(Debug, Clone)]
+pub struct Forwarding {
+    x_forwarded: bool,
+    rfc7239: bool,
+    trusted: Vec<IpNet>,
+}
+
+impl Forwarding {
+    /// `cfg` was checked when the config was loaded.
+    pub fn from_config(cfg: &ForwardedConfig) -> Self {
+        Self {
+            x_forwarded: cfg.x_forwarded(),
+            rfc7239: cfg.rfc7239(),
+            trusted: cfg.trusted_proxies().unwrap_or_default(),
+        }
+    }
+
+    /// Adds the forwarding headers for a request from `client` over `proto`, for `host`.
+    pub fn apply(&self, headers: &mut HeaderMap, client: IpAddr, proto: &str, host: Option<&str>) {
+        if !self.x_forwarded && !self.rfc7239 {
+            return;
+        }
+
+        let trusted = self.trusted.iter().any(|net| net.contains(&client));
+        if !trusted {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_REAL_IP, FORWARDED] {
+                headers.remove(name);
+            }
+        }
+
+        if self.x_forwarded {
+            append(headers, X_FORWARDED_FOR, &client.to_string());
+            if !headers.contains_key(X_FORWARDED_PROTO)
+                && let Ok(proto) = HeaderValue::from_str(proto)
+            {
+                headers.insert(X_FORWARDED_PROTO, proto);
+            }
+            // the first hop's, when a trusted proxy passed it on
+            if !headers.contains_key(X_REAL_IP)
+                && let Ok(ip) = HeaderValue::from_str(&client.to_string())
+            {
+                headers.insert(X_REAL_IP, ip);
+            }
+        }
+
+        if self.rfc7239 {
+            let mut element = match client {
+                IpAddr::V4(ip) => format!("for={}", ip),
+                IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+            };
+            element.push_str(&format!(";proto={}", proto));
+            if let Some(host) = host.filter(|host| !host.contains(['"', '\\'])) {
+                element.push_str(&format!(";host=\"{}\"", host));
+            }
+            append(headers, FORWARDED, &element);
+        }
+    }
+
+    /// Whether `client` is a trusted proxy that received the request over https, per the
+    /// `X-Forwarded-Proto` it sent.
+    pub fn forwarded_https(&self, client: IpAddr, headers: &HeaderMap) -> bool {
+        self.trusted.iter().any(|net| net.contains(&client))
+            && headers
+                .get(X_FORWARDED_PROTO)
+                .and_then(|proto| proto.to_str().ok())
+                .and_then(|proto| proto.split(',').next())
+                .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+    }
+}
+
+/// Adds `value` to the end of the list in `name`, joining repeated headers into one.
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut list: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    list.push(value);
+
+    if let Ok(joined) = HeaderValue::from_str(&list.join(", ")) {
+        headers.insert(name, joined);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarding_headers() {
+        let cfg: ForwardedConfig =
+            toml::from_str("rfc7239 = true\ntrusted_proxies = [\"10.0.0.0/8\"]").unwrap();
+        let forwarding = Forwarding::from_config(&cfg);
+        let inbound = || {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.7"));
+            headers.insert(X_REAL_IP, HeaderValue::from_static("203.0.113.7"));
+            headers.insert(FORWARDED, HeaderValue::from_static("for=203.0.113.7"));
+            headers
+        };
+
+        // a trusted proxy's headers are kept and added to
+        let mut headers = inbound();
+        forwarding.apply(
+            &mut headers,
+            "10.1.2.3".parse().unwrap(),
+            "http",
+            Some("a.com"),
+        );
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.7, 10.1.2.3");
+        assert_eq!(headers[X_REAL_IP], "203.0.113.7");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=203.0.113.7, for=10.1.2.3;proto=http;host=\"a.com\""
+        );
+
+        // anyone else's are replaced
+        let mut headers = inbound();
+        forwarding.apply(&mut headers, "2001:db8::1".parse().unwrap(), "http", None);
+        assert_eq!(headers[X_FORWARDED_FOR], "2001:db8::1");
+        assert_eq!(headers[X_REAL_IP], "2001:db8::1");
+        assert_eq!(headers[FORWARDED], "for=\"[2001:db8::1]\";proto=http");
+
+        let bad: ForwardedConfig = toml::from_str("trusted_proxies = [\"10.0.0/8\"]").unwrap();
+        assert!(bad.trusted_proxies().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
+
+use http::StatusCode;
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    admin::{AdminState, PeerView},
+    config::AdminPermission,
+    events::{self, EventKind},
+};
+
+pub mod proto {
+    tonic::include_proto!("jalb.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+const DEFAULT_EVENT_LIMIT: u32 = 100;
+
+pub struct GrpcAdmin {
+    state: Arc<AdminState>,
+}
+
+impl From<events::Event> for proto::Event {
+    fn from(event: events::Event) -> Self {
+        Self {
+            id: event.id,
+            timestamp: event.timestamp as u64,
+            kind: event.kind.name().to_string(),
+            message: event.message,
+        }
+    }
+}
+
+impl From<PeerView> for proto::Peer {
+    fn from(peer: PeerView) -> Self {
+        Self {
+            address: peer.address,
+            weight: peer.weight,
+            live: peer.live,
+            ready: peer.ready,
+            request_timeouts: peer.request_timeouts,
+            active_connections: peer.active_connections,
+            connections: peer.connections,
+            bytes_sent: peer.bytes_sent,
+            bytes_received: peer.bytes_received,
+            errors: peer.errors,
+        }
+    }
+}
+
+fn parse_kind(kind: Option<&str>) -> Result<Option<EventKind>, Status> {
+    match kind {
+        Some(name) => EventKind::from_name(name)
+            .map(Some)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown event kind {}", name))),
+        None => Ok(None),
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for GrpcAdmin {
+    async fn list_events(
+        &self,
+        request: Request<proto::ListEventsRequest>,
+    ) -> Result<Response<proto::ListEventsResponse>, Status> {
+        let request = request.into_inner();
+        let kind = parse_kind(request.kind.as_deref())?;
+        let limit = request.limit.unwrap_or(DEFAULT_EVENT_LIMIT) as usize;
+
+        let events = self
+            .state
+            .events
+            .recent(limit, kind)
+            .into_iter()
+            .map(proto::Event::from)
+            .collect();
+
+        Ok(Response::new(proto::ListEventsResponse { events }))
+    }
+
+    async fn list_peers(
+        &self,
+        _request: Request<proto::ListPeersRequest>,
+    ) -> Result<Response<proto::ListPeersResponse>, Status> {
+        let peers = self
+            .state
+            .peers
+            .borrow()
+            .iter()
+            .map(|p| proto::Peer::from(PeerView::from(p.as_ref())))
+            .collect();
+
+        Ok(Response::new(proto::ListPeersResponse { peers }))
+    }
+
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<proto::WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let kind = parse_kind(request.into_inner().kind.as_deref())?;
+
+        // lagged receivers silently skip the events they missed
+        let stream =
+            BroadcastStream::new(self.state.events.subscribe()).filter_map(
+                move |event| match event {
+                    Ok(event) if kind.is_none_or(|k| event.kind == k) => {
+                        Some(Ok(proto::Event::from(event)))
+                    }
+                    _ => None,
+                },
+            );
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+pub async fn serve(addr: SocketAddr, state: Arc<AdminState>) {
+    // every rpc here only reads
+    let auth = state.auth.clone();
+    let service = AdminServer::with_interceptor(GrpcAdmin { state }, move |request: Request<()>| {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        match auth.authorize(authorization, AdminPermission::Read) {
+            Ok(()) => Ok(request),
+            Err(StatusCode::UNAUTHORIZED) => {
+                Err(Status::unauthenticated("missing or invalid bearer token"))
+            }
+            Err(_) => Err(Status::permission_denied(
+                "token does not permit this operation",
+            )),
+        }
+    });
+
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(addr)
+        .await
+    {
+        tracing::error!("admin grpc server error: {}", e);
+    }
+}
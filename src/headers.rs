@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue, Request};
+use serde::{Deserialize, Serialize};
+
+/// `request_headers` or `response_headers` of a `[[route]]`. Headers are removed first, then
+/// set, replacing any values they had, then added alongside those already there. Values are
+/// templates: `{client_ip}`, `{host}`, `{method}`, `{path}`, `{query}`, with its `?` when there
+/// is one, `{peer}`, `{backend}` and `{request_start}`, in microseconds since the epoch, are
+/// filled in per request, and `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.set.is_empty() && self.add.is_empty()
+    }
+
+    /// Every name is a header name and every value a template that renders to a header value.
+    pub fn validate(&self) -> Result<(), String> {
+        let names = self
+            .remove
+            .iter()
+            .chain(self.set.keys())
+            .chain(self.add.keys());
+        for name in names {
+            HeaderName::try_from(name.as_str())
+                .map_err(|_| format!("{} is not a header name", name))?;
+        }
+
+        for (name, value) in self.set.iter().chain(&self.add) {
+            let template = Template::parse(value).map_err(|e| format!("{}: {}", name, e))?;
+            HeaderValue::from_str(&template.literal())
+                .map_err(|_| format!("{}: {:?} is not a header value", name, value))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// [`HeaderRules`] ready to apply, names parsed and templates split up.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrite {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, Template)>,
+    add: Vec<(HeaderName, Template)>,
+}
+
+impl HeaderRewrite {
+    /// `rules` were checked by [`HeaderRules::validate`] when the config was loaded.
+    pub fn from_rules(rules: &HeaderRules) -> Self {
+        let name = |name: &String| HeaderName::try_from(name.as_str()).expect("validated name");
+        let templates = |values: &BTreeMap<String, String>| {
+            values
+                .iter()
+                .map(|(n, value)| (name(n), Template::parse(value).expect("validated template")))
+                .collect()
+        };
+
+        Self {
+            remove: rules.remove.iter().map(name).collect(),
+            set: templates(&rules.set),
+            add: templates(&rules.add),
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, context: &RequestContext) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        // a value that renders to something a header can't carry, e.g. a path that isn't
+        // valid UTF-8, is left out rather than failing the request
+        for (name, template) in &self.set {
+            if let Ok(value) = HeaderValue::from_str(&template.render(context)) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        for (name, template) in &self.add {
+            if let Ok(value) = HeaderValue::from_str(&template.render(context)) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+}
+
+/// What header templates are filled in with.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub client: SocketAddr,
+    pub host: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// `?` and the query string, empty without one
+    pub query: String,
+    /// the peer the request went to, once picked
+    pub peer: Option<SocketAddr>,
+    pub backend: Option<String>,
+    pub start: SystemTime,
+}
+
+impl RequestContext {
+    /// The context of `req` from `client`, as it arrived.
+    pub fn new<B>(client: SocketAddr, req: &Request<B>) -> Self {
+        let host = req.uri().host().map(str::to_string).or_else(|| {
+            let host = req.headers().get(http::header::HOST)?;
+            host.to_str().ok().map(str::to_string)
+        });
+
+        Self {
+            client,
+            host,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            query: req
+                .uri()
+                .query()
+                .map_or(String::new(), |query| format!("?{}", query)),
+            peer: None,
+            backend: None,
+            start: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    ClientIp,
+    Host,
+    Method,
+    Path,
+    Query,
+    Peer,
+    Backend,
+    RequestStart,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Var(Var),
+}
+
+/// A value with `{var}`s filled in per request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Template(Vec<Part>);
+
+impl Template {
+    pub(crate) fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(idx) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+            if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+                literal.push_str(&rest[..1]);
+                rest = after;
+                continue;
+            }
+            if rest.starts_with('}') {
+                return Err("unmatched }, write }} for a literal one".to_string());
+            }
+
+            let Some(end) = rest.find('}') else {
+                return Err("unterminated {".to_string());
+            };
+            let var = match &rest[1..end] {
+                "client_ip" => Var::ClientIp,
+                "host" => Var::Host,
+                "method" => Var::Method,
+                "path" => Var::Path,
+                "query" => Var::Query,
+                "peer" => Var::Peer,
+                "backend" => Var::Backend,
+                "request_start" => Var::RequestStart,
+                unknown => return Err(format!("{{{}}} is not a template variable", unknown)),
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Var(var));
+            rest = &rest[end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self(parts))
+    }
+
+    /// The literal text alone, to check it is fit for where the rendered value goes.
+    pub(crate) fn literal(&self) -> String {
+        self.0
+            .iter()
+            .filter_map(|part| match part {
+                Part::Literal(literal) => Some(literal.as_str()),
+                Part::Var(_) => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn render(&self, context: &RequestContext) -> String {
+        let mut rendered = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Var(Var::ClientIp) => rendered.push_str(&context.client.ip().to_string()),
+                Part::Var(Var::Host) => rendered.push_str(context.host.as_deref().unwrap_or("")),
+                Part::Var(Var::Method) => rendered.push_str(&context.method),
+                Part::Var(Var::Path) => rendered.push_str(&context.path),
+                Part::Var(Var::Query) => rendered.push_str(&context.query),
+                Part::Var(Var::Peer) => {
+                    if let Some(peer) = context.peer {
+                        rendered.push_str(&peer.to_string());
+                    }
+                }
+                Part::Var(Var::Backend) => {
+                    rendered.push_str(context.backend.as_deref().unwrap_or(""))
+                }
+                Part::Var(Var::RequestStart) => {
+                    let since_epoch = context.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    rendered.push_str(&since_epoch.as_micros().to_string());
+                }
+            }
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_header_rules() {
+        let rules: HeaderRules = toml::from_str(
+            r#"
+            remove = ["x-debug"]
+            set = { x-request-start = "t={request_start}", x-served-by = "{backend}" }
+            add = { via = "jalb {{{peer}}}" }
+            "#,
+        )
+        .unwrap();
+        assert!(rules.validate().is_ok());
+
+        let req = Request::get("/a?b")
+            .header("host", "example.com")
+            .body(())
+            .unwrap();
+        let mut context = RequestContext::new("10.0.0.9:5000".parse().unwrap(), &req);
+        context.peer = Some("10.0.0.1:80".parse().unwrap());
+        context.backend = Some("api".to_string());
+        context.start = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.insert("x-served-by", HeaderValue::from_static("someone"));
+        headers.insert("via", HeaderValue::from_static("1.1 cdn"));
+        HeaderRewrite::from_rules(&rules).apply(&mut headers, &context);
+
+        assert!(!headers.contains_key("x-debug"));
+        assert_eq!(headers["x-request-start"], "t=1700000000123456");
+        assert_eq!(headers["x-served-by"], "api");
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, ["1.1 cdn", "jalb {10.0.0.1:80}"]);
+
+        let invalid = |set: &str| HeaderRules {
+            set: BTreeMap::from([("x-a".to_string(), set.to_string())]),
+            ..HeaderRules::default()
+        };
+        assert!(invalid("{client}").validate().is_err());
+        assert!(invalid("{host").validate().is_err());
+        assert!(invalid("a}").validate().is_err());
+        assert!(invalid("a\nb").validate().is_err());
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tracing::error;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{
+    backend::Backend,
+    events::{EventKind, EventLog, PeerState, Transition},
+    peer::Peer,
+};
+
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+pub const DEFAULT_SUCCESS_THRESHOLD: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// The peer accepts a TCP connection
+    Tcp,
+    /// The peer answers a GET on `path` with a 2xx status
+    Http { path: String },
+}
+
+impl fmt::Display for HealthCheckKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Http { path } => write!(f, "http {}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub kind: HealthCheckKind,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// consecutive failures before the signal goes down
+    pub failure_threshold: u32,
+    /// consecutive successes before the signal comes back up
+    pub success_threshold: u32,
+}
+
+impl HealthCheck {
+    pub fn tcp() -> Self {
+        Self {
+            kind: HealthCheckKind::Tcp,
+            interval: DEFAULT_CHECK_INTERVAL,
+            timeout: DEFAULT_CHECK_TIMEOUT,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            success_threshold: DEFAULT_SUCCESS_THRESHOLD,
+        }
+    }
+
+    pub fn http(path: &str) -> Self {
+        Self {
+            kind: HealthCheckKind::Http {
+                path: path.to_string(),
+            },
+            ..Self::tcp()
+        }
+    }
+}
+
+/// Liveness answers "is the process reachable", readiness answers "should it receive traffic".
+/// Only readiness gates peer selection; liveness failures are surfaced as events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Liveness,
+    Readiness,
+}
+
+impl Probe {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Liveness => "liveness",
+            Self::Readiness => "readiness",
+        }
+    }
+
+    fn get(&self, peer: &Peer) -> bool {
+        match self {
+            Self::Liveness => peer.is_live(),
+            Self::Readiness => peer.is_ready(),
+        }
+    }
+
+    fn set(&self, peer: &Peer, up: bool) {
+        match self {
+            Self::Liveness => peer.set_live(up),
+            Self::Readiness => peer.set_ready(up),
+        }
+    }
+}
+
+/// Tracks consecutive results of a probe and decides when the signal flips.
+#[derive(Debug, Default)]
+struct ProbeState {
+    failures: u32,
+    successes: u32,
+}
+
+impl ProbeState {
+    /// Returns the new signal value when `passed` causes a transition away from `current`.
+    fn observe(&mut self, check: &HealthCheck, current: bool, passed: bool) -> Option<bool> {
+        if passed {
+            self.failures = 0;
+            self.successes = self.successes.saturating_add(1);
+            if !current && self.successes >= check.success_threshold {
+                return Some(true);
+            }
+        } else {
+            self.successes = 0;
+            self.failures = self.failures.saturating_add(1);
+            if current && self.failures >= check.failure_threshold {
+                return Some(false);
+            }
+        }
+
+        None
+    }
+}
+
+/// Watches how many peers are left in rotation against the backend's `min_healthy_peers`.
+/// In fail-open mode it also refuses to eject a peer once that would leave too few.
+#[derive(Debug)]
+pub struct HealthGuard {
+    min_healthy: usize,
+    fail_open: bool,
+    /// the pool as of the latest reload
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+    /// addresses of failing peers kept in rotation by fail-open
+    held: Mutex<HashSet<String>>,
+    degraded: AtomicBool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthStatus {
+    pub healthy_peers: usize,
+    pub total_peers: usize,
+    pub min_healthy_peers: usize,
+    pub degraded: bool,
+    pub held_ejections: Vec<String>,
+}
+
+impl HealthGuard {
+    pub fn new(
+        min_healthy: usize,
+        fail_open: bool,
+        peers: watch::Receiver<Vec<Arc<Peer>>>,
+    ) -> Self {
+        Self {
+            min_healthy,
+            fail_open,
+            peers,
+            held: Mutex::new(HashSet::new()),
+            degraded: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn from_backend(backend: &Backend, peers: watch::Receiver<Vec<Arc<Peer>>>) -> Self {
+        Self::new(
+            backend.min_healthy_peers.unwrap_or(0),
+            backend.fail_open,
+            peers,
+        )
+    }
+
+    /// Peers in rotation and actually passing their checks.
+    pub fn healthy_count(&self) -> usize {
+        let held = self.held.lock().unwrap();
+        self.peers
+            .borrow()
+            .iter()
+            .filter(|p| p.is_live() && p.is_ready())
+            .filter(|p| !held.contains(&p.address.as_string()))
+            .count()
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.healthy_count() < self.min_healthy
+    }
+
+    /// Whether `peer`, which just failed, should stay in rotation anyway.
+    fn hold_ejection(&self, peer: &Peer) -> bool {
+        if !self.fail_open || self.healthy_count() > self.min_healthy {
+            return false;
+        }
+
+        self.held.lock().unwrap().insert(peer.address.as_string());
+        true
+    }
+
+    fn release(&self, peer: &Peer) -> bool {
+        self.held.lock().unwrap().remove(&peer.address.as_string())
+    }
+
+    /// Records a critical event when the healthy count drops below the minimum, and a
+    /// transition back once it recovers.
+    fn update(&self, events: &EventLog) {
+        let degraded = self.is_degraded();
+        if self.degraded.swap(degraded, Ordering::Relaxed) == degraded {
+            return;
+        }
+
+        let healthy = self.healthy_count();
+        if degraded {
+            let message = format!(
+                "only {} of {} peers healthy, below min_healthy_peers {}{}",
+                healthy,
+                self.peers.borrow().len(),
+                self.min_healthy,
+                if self.fail_open {
+                    ", no longer ejecting failing peers"
+                } else {
+                    ""
+                }
+            );
+            error!("{}", message);
+            events.record(EventKind::Critical, message);
+        } else {
+            events.record(
+                EventKind::PeerTransition,
+                format!(
+                    "{} of {} peers healthy, back at or above min_healthy_peers {}",
+                    healthy,
+                    self.peers.borrow().len(),
+                    self.min_healthy
+                ),
+            );
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        let mut held_ejections: Vec<String> = self.held.lock().unwrap().iter().cloned().collect();
+        held_ejections.sort();
+
+        HealthStatus {
+            healthy_peers: self.healthy_count(),
+            total_peers: self.peers.borrow().len(),
+            min_healthy_peers: self.min_healthy,
+            degraded: self.is_degraded(),
+            held_ejections,
+        }
+    }
+}
+
+/// Spawns one task per configured probe per peer. Probes run for the lifetime of the process.
+pub fn spawn_health_checks(
+    backend: &Backend,
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+    events: &Arc<EventLog>,
+) -> Arc<HealthGuard> {
+    let initial = peers.borrow().clone();
+    let guard = Arc::new(HealthGuard::from_backend(backend, peers));
+    guard.update(events);
+
+    for peer in &initial {
+        spawn_peer_checks(backend, peer, events, &guard);
+    }
+
+    guard
+}
+
+/// Starts probing a single peer, e.g. one added by a reload. The probes stop once the peer is
+/// retired.
+pub fn spawn_peer_checks(
+    backend: &Backend,
+    peer: &Arc<Peer>,
+    events: &Arc<EventLog>,
+    guard: &Arc<HealthGuard>,
+) {
+    if let Some(check) = &backend.liveness {
+        tokio::spawn(run_probe(
+            peer.clone(),
+            check.clone(),
+            Probe::Liveness,
+            events.clone(),
+            guard.clone(),
+        ));
+    }
+
+    if let Some(check) = &backend.readiness {
+        tokio::spawn(run_probe(
+            peer.clone(),
+            check.clone(),
+            Probe::Readiness,
+            events.clone(),
+            guard.clone(),
+        ));
+    }
+}
+
+async fn run_probe(
+    peer: Arc<Peer>,
+    check: HealthCheck,
+    probe: Probe,
+    events: Arc<EventLog>,
+    guard: Arc<HealthGuard>,
+) {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(check.interval);
+    let mut state = ProbeState::default();
+
+    loop {
+        interval.tick().await;
+        if peer.is_retired() {
+            return;
+        }
+
+        let (passed, detail) = match peer.health_check(&check, &client).await {
+            Ok(true) => (true, None),
+            Ok(false) => (false, Some("check did not pass".to_string())),
+            Err(e) => (false, Some(e.to_string())),
+        };
+
+        if passed && guard.release(&peer) {
+            guard.update(&events);
+        }
+
+        let Some(up) = state.observe(&check, probe.get(&peer), passed) else {
+            continue;
+        };
+
+        let address = peer.address.as_string();
+        if !up && guard.hold_ejection(&peer) {
+            tracing::warn!(
+                "peer {} {} check failing, kept in rotation to stay at min_healthy_peers",
+                address,
+                probe.name()
+            );
+            guard.update(&events);
+            continue;
+        }
+
+        probe.set(&peer, up);
+
+        let message = match (up, &detail) {
+            (true, _) => format!("peer {} {} check passing", address, probe.name()),
+            (false, Some(detail)) => {
+                format!(
+                    "peer {} {} check failing: {}",
+                    address,
+                    probe.name(),
+                    detail
+                )
+            }
+            (false, None) => format!("peer {} {} check failing", address, probe.name()),
+        };
+
+        if !up && probe == Probe::Liveness {
+            error!("{}", message);
+        }
+
+        let (from, to) = if up {
+            (PeerState::Down, PeerState::Up)
+        } else {
+            (PeerState::Up, PeerState::Down)
+        };
+        let transition = Transition {
+            peer: address,
+            backend: peer.backend.clone(),
+            probe: probe.name(),
+            check: check.kind.to_string(),
+            from,
+            to,
+            detail: detail.filter(|_| !up),
+        };
+        events.record_transition(transition, message);
+        guard.update(&events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_state_thresholds() {
+        let check = HealthCheck {
+            failure_threshold: 3,
+            success_threshold: 2,
+            ..HealthCheck::tcp()
+        };
+        let mut state = ProbeState::default();
+
+        assert_eq!(state.observe(&check, true, false), None);
+        assert_eq!(state.observe(&check, true, false), None);
+        assert_eq!(state.observe(&check, true, false), Some(false));
+
+        assert_eq!(state.observe(&check, false, true), None);
+        assert_eq!(state.observe(&check, false, false), None);
+        assert_eq!(state.observe(&check, false, true), None);
+        assert_eq!(state.observe(&check, false, true), Some(true));
+    }
+
+    #[test]
+    fn test_guard_holds_ejections_when_failing_open() {
+        let peers: Vec<Arc<Peer>> = (0..3)
+            .map(|i| Arc::new(Peer::new(&format!("127.0.0.1:800{}", i)).unwrap()))
+            .collect();
+        let events = EventLog::new(16);
+        let guard = HealthGuard::new(2, true, watch::channel(peers.clone()).1);
+
+        assert!(!guard.hold_ejection(&peers[0]));
+        peers[0].set_live(false);
+        guard.update(&events);
+        assert!(!guard.is_degraded());
+
+        // ejecting a second peer would leave one healthy, below the minimum of two
+        assert!(guard.hold_ejection(&peers[1]));
+        guard.update(&events);
+        assert!(peers[1].is_live());
+        assert!(guard.is_degraded());
+        assert_eq!(events.recent(1, Some(EventKind::Critical)).len(), 1);
+
+        assert!(guard.release(&peers[1]));
+        guard.update(&events);
+        assert!(!guard.is_degraded());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The quantiles reported for every histogram.
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Values below this get a bucket each.
+const LINEAR: u64 = 64;
+/// Buckets per doubling past [`LINEAR`], keeping every bucket within about 3% of its values.
+const SUB_BUCKETS: u64 = 32;
+/// Larger values are counted as this, for durations in microseconds over 19 hours and for byte
+/// counts 64 GiB.
+const MAX_VALUE: u64 = (1 << 36) - 1;
+const BUCKETS: usize = 1024;
+
+/// Durations counted into buckets of microseconds, or other values such as byte counts into
+/// buckets of their own, that widen as they go up the way an HDR histogram's do, so high
+/// quantiles stay accurate without keeping every value. Recording is lock free.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        self.record_value(u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX));
+    }
+
+    /// Records a value other than a duration, read back with [`HistogramSnapshot::value_quantile`].
+    pub fn record_value(&self, value: u64) {
+        self.buckets[bucket(value.min(MAX_VALUE))].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// The counts so far, to read quantiles from or add up with other histograms.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A [`Histogram`] as of one moment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+}
+
+impl Default for HistogramSnapshot {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum)
+    }
+
+    pub fn value_sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Adds `other`'s counts to these, e.g. to sum up the peers of a pool.
+    pub fn merge(&mut self, other: &HistogramSnapshot) {
+        for (count, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// The duration `quantile` of the recorded ones are at most, rounded up to the top of its
+    /// bucket. Zero while nothing was recorded.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        Duration::from_micros(self.value_quantile(quantile))
+    }
+
+    /// [`HistogramSnapshot::quantile`] of values recorded with [`Histogram::record_value`].
+    pub fn value_quantile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest(idx);
+            }
+        }
+        MAX_VALUE
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < LINEAR {
+        return value as usize;
+    }
+    let magnitude = u64::from(63 - value.leading_zeros());
+    let shift = magnitude - 5;
+    (shift * SUB_BUCKETS + (value >> shift)) as usize
+}
+
+/// The largest value counted into bucket `idx`.
+fn highest(idx: usize) -> u64 {
+    let idx = idx as u64;
+    if idx < LINEAR {
+        return idx;
+    }
+    let shift = idx / SUB_BUCKETS - 1;
+    let sub = idx % SUB_BUCKETS + SUB_BUCKETS;
+    ((sub + 1) << shift) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_quantiles() {
+        for micros in [0, 63, 64, 65, 127, 128, 1000, 123_456, MAX_VALUE] {
+            let idx = bucket(micros);
+            assert!(idx < BUCKETS);
+            assert!(highest(idx) >= micros, "{}", micros);
+            assert!(idx == 0 || highest(idx - 1) < micros, "{}", micros);
+        }
+
+        let histogram = Histogram::default();
+        assert_eq!(histogram.snapshot().quantile(0.99), Duration::ZERO);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 100);
+        assert_eq!(snapshot.sum(), Duration::from_millis(5050));
+        let within = |quantile: f64, ms: u64| {
+            let value = snapshot.quantile(quantile).as_micros() as f64;
+            let expected = (ms * 1000) as f64;
+            assert!(
+                value >= expected && value < expected * 1.04,
+                "{} {}",
+                quantile,
+                value
+            );
+        };
+        within(0.5, 50);
+        within(0.99, 99);
+        within(1.0, 100);
+
+        let other = Histogram::default();
+        other.record(Duration::from_secs(10));
+        let mut merged = snapshot.clone();
+        merged.merge(&other.snapshot());
+        assert_eq!(merged.count(), 101);
+        assert!(merged.quantile(1.0) >= Duration::from_secs(10));
+        assert_eq!(merged.sum(), Duration::from_millis(15_050));
+
+        let sizes = Histogram::default();
+        sizes.record_value(512);
+        sizes.record_value(1 << 40);
+        let sizes = sizes.snapshot();
+        assert!((512..530).contains(&sizes.value_quantile(0.5)));
+        assert_eq!(sizes.value_quantile(1.0), MAX_VALUE);
+        assert_eq!(sizes.value_sum(), 512 + (1 << 40));
+    }
+}
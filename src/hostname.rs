@@ -0,0 +1,298 @@
+use std::{io, time::Duration};
+
+use tokio::{net::TcpStream, time::Instant};
+
+/// Most of a connection's opening bytes looked at for a hostname. A ClientHello or request head
+/// that doesn't fit is treated as naming no host.
+const MAX_PEEK: usize = 16 * 1024;
+/// How long to wait between peeks when the opening bytes are still arriving.
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME_TYPE: u8 = 0x00;
+
+/// What the opening bytes of a connection say about the host it is for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Requested {
+    /// SNI of a TLS ClientHello, or the `Host` header of an HTTP/1 request
+    Host(String),
+    /// Complete, but without a hostname, or not TLS or HTTP at all
+    NoHost,
+    /// Not enough bytes yet to tell
+    Incomplete,
+}
+
+/// Reads the hostname a connection asks for from its opening bytes without consuming them, so
+/// the stream can still be relayed as is.
+pub async fn peek_hostname(stream: &TcpStream, deadline: Duration) -> io::Result<Option<String>> {
+    let give_up = Instant::now() + deadline;
+    let mut buf = vec![0u8; MAX_PEEK];
+
+    loop {
+        let n = tokio::time::timeout_at(give_up, stream.peek(&mut buf))
+            .await
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, "no hostname within deadline")
+            })??;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client closed before naming a host",
+            ));
+        }
+
+        match requested_host(&buf[..n]) {
+            Requested::Host(host) => return Ok(Some(host)),
+            Requested::NoHost => return Ok(None),
+            Requested::Incomplete if n == MAX_PEEK => return Ok(None),
+            Requested::Incomplete => {}
+        }
+
+        // peek returns whatever is buffered straight away, so wait for more to arrive
+        if Instant::now() + PEEK_RETRY > give_up {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no hostname within deadline",
+            ));
+        }
+        tokio::time::sleep(PEEK_RETRY).await;
+    }
+}
+
+pub fn requested_host(buf: &[u8]) -> Requested {
+    match buf.first() {
+        None => Requested::Incomplete,
+        Some(&TLS_HANDSHAKE) => client_hello_server_name(buf),
+        Some(b) if b.is_ascii_uppercase() => http_host(buf),
+        Some(_) => Requested::NoHost,
+    }
+}
+
+/// Cursor over a ClientHello. Every read returns `None` past the end of the buffer.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    /// A block prefixed with its length in `len_bytes` bytes.
+    fn block(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = match len_bytes {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(|buf| Reader { buf })
+    }
+}
+
+fn client_hello_server_name(buf: &[u8]) -> Requested {
+    let mut record = Reader { buf };
+    // content type and legacy version, then the fragment length
+    let (Some(_), Some(record_len)) = (record.take(3), record.u16()) else {
+        return Requested::Incomplete;
+    };
+    let Some(fragment) = record.take(record_len as usize) else {
+        return Requested::Incomplete;
+    };
+
+    // only the first record is read, a ClientHello split across records names no host here
+    let mut hello = Reader { buf: fragment };
+    if hello.u8() != Some(CLIENT_HELLO) {
+        return Requested::NoHost;
+    }
+
+    server_name(&mut hello)
+        .map(Requested::Host)
+        .unwrap_or(Requested::NoHost)
+}
+
+fn server_name(hello: &mut Reader) -> Option<String> {
+    let mut body = hello.block(3)?;
+    body.take(2 + 32)?; // legacy version and random
+    body.block(1)?; // session id
+    body.block(2)?; // cipher suites
+    body.block(1)?; // compression methods
+
+    let mut extensions = body.block(2)?;
+    while let Some(kind) = extensions.u16() {
+        let mut data = extensions.block(2)?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let mut names = data.block(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.block(2)?;
+            if name_type == HOST_NAME_TYPE {
+                return std::str::from_utf8(name.buf).ok().map(normalize);
+            }
+        }
+    }
+
+    None
+}
+
+fn http_host(buf: &[u8]) -> Requested {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Requested::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Requested::NoHost;
+    };
+
+    let mut lines = head.split("\r\n");
+    let is_request = lines
+        .next()
+        .is_some_and(|line| line.ends_with("HTTP/1.1") || line.ends_with("HTTP/1.0"));
+    if !is_request {
+        return Requested::NoHost;
+    }
+
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| Requested::Host(normalize(strip_port(value.trim()))))
+        .unwrap_or(Requested::NoHost)
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// The host a `Host` header value or URI authority names: lowercase, without the port or a
+/// trailing dot, as [`HostPattern::matches`] expects.
+pub fn normalize_host(value: &str) -> String {
+    normalize(strip_port(value.trim()))
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// A configured hostname, either exact or `*.example.com` for any single label below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Exact(String),
+    /// the `.example.com` suffix of a wildcard
+    Wildcard(String),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let pattern = normalize(pattern);
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_string()),
+            _ => HostPattern::Exact(pattern),
+        }
+    }
+
+    /// `host` must already be lowercase without a trailing dot, as returned by `peek_hostname`.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Wildcard(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal ClientHello carrying `server_name` and one unrelated extension before it.
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(HOST_NAME_TYPE);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]; // ec_point_formats
+        extensions.extend_from_slice(&SERVER_NAME_EXTENSION.to_be_bytes());
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_requested_host() {
+        let hello = client_hello("API.Example.com.");
+        assert_eq!(
+            requested_host(&hello),
+            Requested::Host("api.example.com".to_string())
+        );
+        assert_eq!(requested_host(&hello[..20]), Requested::Incomplete);
+
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: example.com:8080\r\n\r\n";
+        assert_eq!(
+            requested_host(request),
+            Requested::Host("example.com".to_string())
+        );
+        assert_eq!(requested_host(&request[..30]), Requested::Incomplete);
+        assert_eq!(requested_host(b"GET / HTTP/1.0\r\n\r\n"), Requested::NoHost);
+        assert_eq!(requested_host(b"\x00\x01binary"), Requested::NoHost);
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        let wildcard = HostPattern::parse("*.Example.com");
+        assert!(wildcard.matches("api.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("evilexample.com"));
+
+        let exact = HostPattern::parse("example.com.");
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+    }
+}
@@ -0,0 +1,391 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use http::HeaderValue;
+use serde::{Deserialize, Serialize};
+
+/// `[http3]`: the application balancer also answers HTTP/3 over QUIC on a UDP address, with
+/// the certificate clients are shown there. Responses on the tcp listeners advertise it with
+/// `Alt-Svc`, so clients that can switch over on their next request; peers are sent the
+/// requests over HTTP/1.1 or HTTP/2 as their backend's `http_version` says.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// usually the address of the tcp listener on 443, QUIC being UDP they can share it
+    pub address: SocketAddr,
+    /// PEM certificate chain and private key
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// the `[[backend]]` requests no `[[route]]` matches go to, the default one when unset
+    pub backend: Option<String>,
+    /// the port clients are told to reach, when it isn't that of `address`, e.g. behind NAT
+    pub advertised_port: Option<u16>,
+    /// how long clients may remember the advertisement
+    #[serde(default = "default_alt_svc_max_age")]
+    pub alt_svc_max_age_seconds: u64,
+}
+
+fn default_alt_svc_max_age() -> u64 {
+    86400
+}
+
+impl Http3Config {
+    pub fn validate(&self, backends: &[&str]) -> Result<(), String> {
+        if cfg!(not(feature = "http3")) {
+            return Err("jalb was built without the http3 feature".to_string());
+        }
+        if let Some(backend) = &self.backend
+            && !backends.contains(&backend.as_str())
+        {
+            return Err(format!("{} is not a configured backend", backend));
+        }
+        for file in [&self.certificate, &self.private_key] {
+            std::fs::metadata(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// The `Alt-Svc` header advertising the listener, e.g. `h3=":443"; ma=86400`.
+    pub fn alt_svc(&self) -> HeaderValue {
+        let port = self.advertised_port.unwrap_or(self.address.port());
+        let value = format!("h3=\":{}\"; ma={}", port, self.alt_svc_max_age_seconds);
+        HeaderValue::from_str(&value).expect("alt-svc is ascii")
+    }
+}
+
+#[cfg(feature = "http3")]
+pub use quic::{Http3Listener, Incoming, accept, client_address, refuse, serve_connection};
+
+#[cfg(not(feature = "http3"))]
+pub use disabled::{Http3Listener, Incoming, accept, client_address, refuse, serve_connection};
+
+#[cfg(feature = "http3")]
+mod quic {
+    use std::{
+        convert::Infallible,
+        future::Future,
+        io,
+        net::SocketAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, ready},
+    };
+
+    use h3::server::RequestStream;
+    use http::{HeaderMap, Request, Response};
+    use http_body_util::BodyExt;
+    use hyper::body::{Body, Buf, Bytes, Frame, SizeHint};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+    use super::Http3Config;
+    use crate::{
+        application::{BoxError, ProxyBody},
+        body_limit::content_length,
+    };
+
+    pub type Incoming = quinn::Incoming;
+
+    /// The QUIC endpoint of `[http3]`.
+    pub struct Http3Listener {
+        endpoint: quinn::Endpoint,
+        backend: Option<String>,
+    }
+
+    impl Http3Listener {
+        /// Binds `config.address` with its certificate, offering only `h3`.
+        pub fn bind(config: &Http3Config) -> io::Result<Self> {
+            let certs = CertificateDer::pem_file_iter(&config.certificate)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| pem_error(&config.certificate, e))?;
+            let key = PrivateKeyDer::from_pem_file(&config.private_key)
+                .map_err(|e| pem_error(&config.private_key, e))?;
+
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .map_err(io::Error::other)?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(io::Error::other)?;
+            tls.alpn_protocols = vec![b"h3".to_vec()];
+            let crypto =
+                quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+            let server = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+            Ok(Self {
+                endpoint: quinn::Endpoint::server(server, config.address)?,
+                backend: config.backend.clone(),
+            })
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.endpoint.local_addr()
+        }
+
+        pub fn backend(&self) -> Option<&str> {
+            self.backend.as_deref()
+        }
+    }
+
+    fn pem_error(path: &std::path::Path, e: rustls::pki_types::pem::Error) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    }
+
+    /// The next connection attempt, never resolving without a listener.
+    pub async fn accept(listener: Option<&Http3Listener>) -> Option<Incoming> {
+        match listener {
+            Some(listener) => listener.endpoint.accept().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn client_address(incoming: &Incoming) -> SocketAddr {
+        incoming.remote_address()
+    }
+
+    /// Turns a client away before the handshake.
+    pub fn refuse(incoming: Incoming) {
+        incoming.refuse();
+    }
+
+    /// Completes the handshake and answers every request on the connection with `service`,
+    /// each on a task of its own.
+    pub async fn serve_connection<S, F>(incoming: Incoming, service: S)
+    where
+        S: Fn(Request<ProxyBody>) -> F + Clone + Send + 'static,
+        F: Future<Output = Result<Response<ProxyBody>, Infallible>> + Send,
+    {
+        let client = incoming.remote_address();
+        let connection = match incoming.await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::debug!("quic handshake with {} failed: {}", client, e);
+                return;
+            }
+        };
+        let mut connection =
+            match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::debug!("http3 connection from {} failed: {}", client, e);
+                    return;
+                }
+            };
+
+        loop {
+            let resolver = match connection.accept().await {
+                Ok(Some(resolver)) => resolver,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::debug!("http3 connection from {} closed: {}", client, e);
+                    break;
+                }
+            };
+            let service = service.clone();
+            tokio::spawn(async move {
+                let result = match resolver.resolve_request().await {
+                    Ok((req, stream)) => serve_request(req, stream, service).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    tracing::debug!("http3 request from {} failed: {}", client, e);
+                }
+            });
+        }
+    }
+
+    type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+    async fn serve_request<S, F>(
+        req: Request<()>,
+        stream: Stream,
+        service: S,
+    ) -> Result<(), BoxError>
+    where
+        S: Fn(Request<ProxyBody>) -> F,
+        F: Future<Output = Result<Response<ProxyBody>, Infallible>>,
+    {
+        let (mut send, mut recv) = stream.split();
+        // a request the client ended with its headers has nothing left to read, which lets it
+        // be retried like one without a body over tcp
+        let body = match content_length(req.headers()) {
+            Some(0) | None => match recv.recv_data().await? {
+                Some(mut data) => {
+                    let first = data.copy_to_bytes(data.remaining());
+                    RecvBody::new(recv, Some(first))
+                }
+                None => RecvBody::ended(recv.recv_trailers().await?),
+            },
+            Some(_) => RecvBody::new(recv, None),
+        };
+
+        let Ok(response) = service(req.map(|()| body.boxed())).await;
+        let (parts, mut body) = response.into_parts();
+        send.send_response(Response::from_parts(parts, ())).await?;
+        while let Some(frame) = body.frame().await {
+            match frame?.into_data() {
+                Ok(data) => send.send_data(data).await?,
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        send.send_trailers(trailers).await?;
+                    }
+                }
+            }
+        }
+        send.finish().await?;
+        Ok(())
+    }
+
+    type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+    /// A request body read from its QUIC stream as the peer's connection asks for it.
+    struct RecvBody {
+        /// behind a lock only for request bodies to be `Sync`, it is never contended
+        stream: Option<Mutex<RecvStream>>,
+        /// read ahead to find out whether there was any body at all
+        first: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+        data_done: bool,
+    }
+
+    impl RecvBody {
+        fn new(stream: RecvStream, first: Option<Bytes>) -> Self {
+            Self {
+                stream: Some(Mutex::new(stream)),
+                first,
+                trailers: None,
+                data_done: false,
+            }
+        }
+
+        fn ended(trailers: Option<HeaderMap>) -> Self {
+            Self {
+                stream: None,
+                first: None,
+                trailers,
+                data_done: true,
+            }
+        }
+    }
+
+    impl Body for RecvBody {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            let this = &mut *self;
+            if let Some(first) = this.first.take() {
+                return Poll::Ready(Some(Ok(Frame::data(first))));
+            }
+            let Some(stream) = this.stream.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t))));
+            };
+            let stream = stream.get_mut().unwrap();
+            if !this.data_done {
+                match ready!(stream.poll_recv_data(cx)) {
+                    Ok(Some(mut data)) => {
+                        let data = data.copy_to_bytes(data.remaining());
+                        return Poll::Ready(Some(Ok(Frame::data(data))));
+                    }
+                    Ok(None) => this.data_done = true,
+                    Err(e) => {
+                        this.stream = None;
+                        return Poll::Ready(Some(Err(e.into())));
+                    }
+                }
+            }
+            let trailers = ready!(stream.poll_recv_trailers(cx));
+            this.stream = None;
+            Poll::Ready(
+                trailers
+                    .map_err(BoxError::from)
+                    .transpose()
+                    .map(|t| t.map(Frame::trailers)),
+            )
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.stream.is_none() && self.first.is_none() && self.trailers.is_none()
+        }
+
+        fn size_hint(&self) -> SizeHint {
+            SizeHint::default()
+        }
+    }
+}
+
+/// Stand-ins keeping the application balancer the same without the http3 feature, when no
+/// listener can be bound.
+#[cfg(not(feature = "http3"))]
+mod disabled {
+    use std::{convert::Infallible, future::Future, io, net::SocketAddr};
+
+    use http::{Request, Response};
+
+    use crate::application::ProxyBody;
+
+    pub enum Incoming {}
+
+    pub enum Http3Listener {}
+
+    impl Http3Listener {
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            match *self {}
+        }
+
+        pub fn backend(&self) -> Option<&str> {
+            match *self {}
+        }
+    }
+
+    pub async fn accept(_: Option<&Http3Listener>) -> Option<Incoming> {
+        std::future::pending().await
+    }
+
+    pub fn client_address(incoming: &Incoming) -> SocketAddr {
+        match *incoming {}
+    }
+
+    pub fn refuse(incoming: Incoming) {
+        match incoming {}
+    }
+
+    pub async fn serve_connection<S, F>(incoming: Incoming, _: S)
+    where
+        S: Fn(Request<ProxyBody>) -> F + Clone + Send + 'static,
+        F: Future<Output = Result<Response<ProxyBody>, Infallible>> + Send,
+    {
+        match incoming {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http3_config() {
+        let config: Http3Config = toml::from_str(
+            r#"
+            address = "0.0.0.0:443"
+            certificate = "/etc/jalb/cert.pem"
+            private_key = "/etc/jalb/key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.alt_svc(), "h3=\":443\"; ma=86400");
+        let advertised = Http3Config {
+            advertised_port: Some(8443),
+            alt_svc_max_age_seconds: 60,
+            ..config.clone()
+        };
+        assert_eq!(advertised.alt_svc(), "h3=\":8443\"; ma=60");
+        // the files don't exist, or the feature is missing
+        assert!(config.validate(&["default"]).is_err());
+    }
+}
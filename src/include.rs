@@ -0,0 +1,233 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use toml::Value;
+
+use crate::{
+    config::{ConfigFormat, interpolate_env, redact},
+    errors::ConfigError,
+};
+
+/// Directory relative `include` patterns are resolved against, the config file's own.
+pub fn base_dir(config: &Path) -> PathBuf {
+    match config.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Merges every file named by the top level `include = ["conf.d/*.toml", ...]` into `settings`,
+/// so parts of the config can live in fragment files owned by different teams. Patterns are
+/// taken in order and the files each matches in name order, so the result doesn't depend on the
+/// directory listing.
+///
+/// Tables are merged key by key and arrays appended to, except entries with a `name`, such as
+/// `[[backend]]` pools, which are merged with the entry of the same name. A setting given two
+/// different values is a conflict and fails the load. Returns whether anything was included.
+pub fn merge_includes(settings: &mut Value, dir: &Path) -> Result<bool, ConfigError> {
+    let Some(table) = settings.as_table_mut() else {
+        return Ok(false);
+    };
+    let Some(patterns) = table.remove("include") else {
+        return Ok(false);
+    };
+
+    let invalid = || {
+        ConfigError::Include(
+            "include".to_string(),
+            "expected a list of paths".to_string(),
+        )
+    };
+    let patterns = patterns.as_array().ok_or_else(invalid)?;
+    normalize_backends(settings);
+
+    for pattern in patterns {
+        let pattern = pattern.as_str().ok_or_else(invalid)?;
+        for path in expand(&dir.join(pattern))? {
+            let mut fragment = read_fragment(&path)?;
+            normalize_backends(&mut fragment);
+            merge("", settings, fragment, &path)?;
+        }
+    }
+
+    Ok(true)
+}
+
+fn read_fragment(path: &Path) -> Result<Value, ConfigError> {
+    let failed = |reason: String| ConfigError::Include(path.display().to_string(), reason);
+
+    let format = ConfigFormat::from_path(path);
+    let text = fs::read_to_string(path).map_err(|e| failed(e.to_string()))?;
+    let (text, values) = interpolate_env(&text, format).map_err(|e| failed(e.to_string()))?;
+    let fragment = format
+        .parse::<Value>(&text)
+        .map_err(|e| failed(redact(&e.to_string(), &values)))?;
+
+    if fragment.get("include").is_some() {
+        return Err(failed("included files cannot include others".to_string()));
+    }
+    Ok(fragment)
+}
+
+/// `[backend]` as a one element `[[backend]]`, so a single pool merges with a list of them.
+fn normalize_backends(settings: &mut Value) {
+    if let Some(table) = settings.as_table_mut()
+        && let Some(backend @ Value::Table(_)) = table.get_mut("backend")
+    {
+        *backend = Value::Array(vec![backend.clone()]);
+    }
+}
+
+fn merge(key: &str, into: &mut Value, from: Value, source: &Path) -> Result<(), ConfigError> {
+    let child = |name: &str| match key {
+        "" => name.to_string(),
+        _ => format!("{}.{}", key, name),
+    };
+
+    match (into, from) {
+        (Value::Table(into), Value::Table(from)) => {
+            for (name, value) in from {
+                match into.get_mut(&name) {
+                    Some(existing) => merge(&child(&name), existing, value, source)?,
+                    None => {
+                        into.insert(name, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(into), Value::Array(from)) => {
+            for item in from {
+                let name = item.get("name").and_then(Value::as_str).map(str::to_string);
+                let existing = name.as_deref().and_then(|name| {
+                    into.iter_mut()
+                        .find(|entry| entry.get("name").and_then(Value::as_str) == Some(name))
+                });
+                match existing {
+                    Some(existing) => {
+                        let key = format!("{}[{}]", key, name.unwrap_or_default());
+                        merge(&key, existing, item, source)?;
+                    }
+                    None => into.push(item),
+                }
+            }
+        }
+        (into, from) if *into == from => {}
+        _ => {
+            return Err(ConfigError::IncludeConflict(
+                key.to_string(),
+                source.display().to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The files `pattern` names. `*` and `?` are allowed in the file name, not in directories. A
+/// pattern in a missing directory matches nothing, a plain path has to exist.
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    let Some(name) = pattern.file_name().and_then(|name| name.to_str()) else {
+        return Ok(vec![pattern.to_path_buf()]);
+    };
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let dir = base_dir(pattern);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ConfigError::Include(
+                dir.display().to_string(),
+                e.to_string(),
+            ));
+        }
+    };
+
+    let mut matches: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|file| wildcard(name.as_bytes(), file.as_bytes()))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    matches.sort();
+
+    Ok(matches)
+}
+
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => wildcard(rest, name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_merge_includes() {
+        let dir = env::temp_dir().join(format!("jalb-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("conf.d/20-api.toml"),
+            "[[backend]]\nname = \"api\"\npeers = [{ address = \"10.0.0.2:80\" }]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/10-web.toml"),
+            "[security]\nip_blacklist = [\"192.0.2.1\"]\n\
+             [[backend]]\nname = \"web\"\npeers = [{ address = \"10.0.0.3:80\" }]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/notes.txt"), "not = \"included\"").unwrap();
+
+        let mut settings: Value = toml::from_str(
+            "include = [\"conf.d/*.toml\"]\n[security]\nip_blacklist = []\n\
+             [backend]\nname = \"web\"\npeers = [{ address = \"10.0.0.1:80\" }]\n",
+        )
+        .unwrap();
+        assert!(merge_includes(&mut settings, &dir).unwrap());
+
+        let backends = settings["backend"].as_array().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0]["name"].as_str(), Some("web"));
+        assert_eq!(backends[0]["peers"].as_array().unwrap().len(), 2);
+        assert_eq!(backends[1]["name"].as_str(), Some("api"));
+        assert_eq!(
+            settings["security"]["ip_blacklist"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(settings.get("not").is_none());
+
+        fs::write(
+            dir.join("conf.d/30-port.toml"),
+            "[loadbalancer]\nport = 80\n",
+        )
+        .unwrap();
+        let mut conflicting: Value =
+            toml::from_str("include = [\"conf.d/*.toml\"]\n[loadbalancer]\nport = 8080\n").unwrap();
+        let err = merge_includes(&mut conflicting, &dir).unwrap_err();
+        assert!(matches!(err, ConfigError::IncludeConflict(key, _) if key == "loadbalancer.port"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    config::{LoadBalancerStrategy, NetworkTarget},
+    errors::ConfigError,
+};
+
+/// Peer written into a sample config when none are given.
+const SAMPLE_PEER: &str = "127.0.0.1:8080";
+
+/// A commented starter jalb.toml balancing over `peers` with `strategy`. It loads as is, with
+/// the less common settings left commented out.
+pub fn sample_config(
+    strategy: LoadBalancerStrategy,
+    peers: &[NetworkTarget],
+) -> Result<String, ConfigError> {
+    if matches!(strategy, LoadBalancerStrategy::Geolocation) {
+        return Err(ConfigError::UnimplementedStrategy(
+            strategy.name().to_string(),
+        ));
+    }
+
+    let peers = if peers.is_empty() {
+        format!("    {{ address = \"{}\", weight = 1 }},\n", SAMPLE_PEER)
+    } else {
+        peers
+            .iter()
+            .map(|peer| {
+                format!(
+                    "    {{ address = \"{}\", weight = 1 }},\n",
+                    peer.as_string()
+                )
+            })
+            .collect()
+    };
+
+    Ok(format!(
+        r#"version = "1"                     # the config schema version
+
+[loadbalancer]
+type = "network"
+strategy = "{strategy}"{padding}# round_robin | least_used | weighted_average
+protocol = "tcp"                  # udp
+listener_address = "127.0.0.1"    # 0.0.0.0 to accept connections from other hosts
+port = 6331
+max_connections = 1000            # new connections past this are dropped on accept
+max_requests_per_connection = 100
+# first_byte_timeout_seconds = 10 # close clients that send nothing for this long
+# idle_timeout_seconds = 300      # close sessions without traffic for this long
+
+[logging]
+log_level = "info"                # debug | info | warn | error
+rotate_logs = true
+log_capacity_mb = 10              # rotated past this size, keeping log_archives old files
+# path = "/var/log/jalb/jalb.log" # a per-user default when unset
+
+# [runtime]
+# worker_threads = 2              # one per core when unset, fewer in a small container
+# max_blocking_threads = 16       # threads for blocking work such as dns lookups
+
+# the admin api: peers, stats and events over http, read-only without tokens
+[admin]
+listener_address = "127.0.0.1"
+port = 9221
+
+[security]
+ip_whitelist = []                 # only these addresses may connect, when not empty
+ip_blacklist = []                 # these never may
+default_policy = "allow"          # allow | deny, for addresses on neither list
+
+[[backend]]
+name = "default"
+# request_timeout_seconds = 5     # for connecting to a peer
+# a peer is an ip:port, a host:port resolved to one peer per record, or a url
+peers = [
+{peers}]
+
+# peers are only picked while this check passes
+[backend.readiness]
+type = "tcp"                      # http, with path = "/healthz"
+interval_seconds = 5
+timeout_seconds = 2
+"#,
+        strategy = strategy.name(),
+        padding = " ".repeat(21usize.saturating_sub(strategy.name().len()).max(1)),
+        peers = peers,
+    ))
+}
+
+/// Writes `contents` to `path`, leaving an existing file alone unless `force` is set.
+pub fn write_sample(path: &Path, contents: &str, force: bool) -> Result<(), io::Error> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(
+            e.kind(),
+            format!(
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            ),
+        ),
+        _ => e,
+    })?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_sample_config_loads() {
+        let peers = [
+            NetworkTarget::from_str("10.0.0.1:80").unwrap(),
+            NetworkTarget::from_str("10.0.0.2:80").unwrap(),
+        ];
+        let sample = sample_config(LoadBalancerStrategy::LeastUsed, &peers).unwrap();
+
+        let path = std::env::temp_dir().join(format!("jalb-init-{}.toml", std::process::id()));
+        write_sample(&path, &sample, false).unwrap();
+        assert!(write_sample(&path, &sample, false).is_err());
+
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.strategy().name(), "least_used");
+        assert_eq!(config.backend().peers.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(sample_config(LoadBalancerStrategy::Geolocation, &[]).is_err());
+    }
+}
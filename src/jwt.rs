@@ -0,0 +1,495 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{HeaderMap, HeaderName, HeaderValue, header::AUTHORIZATION};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::errors::JwtError;
+
+/// How old the keys have to be before a token naming a key they lack fetches them again; keys
+/// get rotated, but tokens with made up ids shouldn't hammer the issuer.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `jwt` of a `[[route]]`: requests are only forwarded with an `Authorization: Bearer` token
+/// signed by a key of `jwks_url`, from `issuer` and for one of `audiences` when set, and are
+/// answered with a 401 otherwise. `claims_to_headers` sends claims of the token on to the peer,
+/// e.g. `{ sub = "X-User-Id" }`; clients can't set those headers themselves.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub jwks_url: Url,
+    pub issuer: Option<String>,
+    /// the token's `aud` has to name one of them, any audience when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audiences: Vec<String>,
+    /// how long the keys are used before they are fetched again
+    #[serde(default = "default_jwks_cache_seconds")]
+    pub jwks_cache_seconds: u64,
+    /// clock skew tolerated on `exp` and `nbf`
+    #[serde(default = "default_leeway_seconds")]
+    pub leeway_seconds: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub claims_to_headers: BTreeMap<String, String>,
+}
+
+fn default_jwks_cache_seconds() -> u64 {
+    300
+}
+
+fn default_leeway_seconds() -> u64 {
+    60
+}
+
+impl JwtConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !matches!(self.jwks_url.scheme(), "http" | "https") {
+            return Err(format!("{} is not an http url", self.jwks_url));
+        }
+        if self.jwks_cache_seconds == 0 {
+            return Err("jwks_cache_seconds must be at least 1".to_string());
+        }
+        if let Some(header) = self
+            .claims_to_headers
+            .values()
+            .find(|header| HeaderName::try_from(header.as_str()).is_err())
+        {
+            return Err(format!("{} is not a header name", header));
+        }
+        Ok(())
+    }
+}
+
+/// A route's [`JwtConfig`], with the keys last fetched from its `jwks_url`.
+#[derive(Debug)]
+pub struct JwtAuth {
+    jwks_url: Url,
+    issuer: Option<String>,
+    audiences: Vec<String>,
+    cache_for: Duration,
+    leeway: u64,
+    claims_to_headers: Vec<(String, HeaderName)>,
+    client: reqwest::Client,
+    keys: Mutex<Option<(Instant, KeySet)>>,
+}
+
+impl JwtAuth {
+    /// `config` was checked by [`JwtConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &JwtConfig) -> Self {
+        Self {
+            jwks_url: config.jwks_url.clone(),
+            issuer: config.issuer.clone(),
+            audiences: config.audiences.clone(),
+            cache_for: Duration::from_secs(config.jwks_cache_seconds),
+            leeway: config.leeway_seconds,
+            claims_to_headers: config
+                .claims_to_headers
+                .iter()
+                .map(|(claim, header)| {
+                    let header = HeaderName::try_from(header.as_str()).expect("validated header");
+                    (claim.clone(), header)
+                })
+                .collect(),
+            client: reqwest::Client::new(),
+            keys: Mutex::new(None),
+        }
+    }
+
+    /// The headers claims are sent to the peer in.
+    pub fn identity_headers(&self) -> Vec<HeaderName> {
+        self.claims_to_headers
+            .iter()
+            .map(|(_, header)| header.clone())
+            .collect()
+    }
+
+    /// Checks the bearer token of a request with `headers`, then replaces the headers claims
+    /// are sent in with the token's claims.
+    pub async fn authenticate(&self, headers: &mut HeaderMap) -> Result<(), JwtError> {
+        for (_, header) in &self.claims_to_headers {
+            headers.remove(header);
+        }
+        let token = bearer_token(headers).ok_or(JwtError::Missing)?;
+        let token = Token::parse(token)?;
+        let claims = {
+            let keys = self.keys_for(token.kid.as_deref()).await?;
+            token.verify(&keys)?
+        };
+        self.check_claims(&claims, unix_now())?;
+
+        for (claim, header) in &self.claims_to_headers {
+            let value = match claims.get(claim) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Array(values)) => values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .map_or_else(|| value.to_string(), str::to_string)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Some(Value::Null) | None => continue,
+                Some(value) => value.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(header.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_claims(&self, claims: &Claims, now: u64) -> Result<(), JwtError> {
+        let time = |name: &str| claims.get(name).and_then(Value::as_u64);
+        if time("exp").is_some_and(|exp| exp.saturating_add(self.leeway) <= now) {
+            return Err(JwtError::Expired);
+        }
+        if time("nbf").is_some_and(|nbf| nbf > now.saturating_add(self.leeway)) {
+            return Err(JwtError::NotYetValid);
+        }
+        if let Some(issuer) = &self.issuer {
+            let iss = claims
+                .get("iss")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if iss != issuer {
+                return Err(JwtError::WrongIssuer(iss.to_string()));
+            }
+        }
+        if !self.audiences.is_empty() {
+            let for_us = |aud: &Value| {
+                aud.as_str()
+                    .is_some_and(|aud| self.audiences.iter().any(|a| a == aud))
+            };
+            let accepted = match claims.get("aud") {
+                Some(Value::Array(auds)) => auds.iter().any(for_us),
+                Some(aud) => for_us(aud),
+                None => false,
+            };
+            if !accepted {
+                return Err(JwtError::WrongAudience);
+            }
+        }
+        Ok(())
+    }
+
+    /// The key set, fetched again once it is older than `jwks_cache_seconds`, or when it
+    /// lacks `kid` and wasn't just fetched. Keys that can't be fetched again are kept in use.
+    async fn keys_for(&self, kid: Option<&str>) -> Result<KeySet, JwtError> {
+        let mut cached = self.keys.lock().await;
+        let refetch = match cached.as_ref() {
+            None => true,
+            Some((fetched, keys)) => {
+                fetched.elapsed() >= self.cache_for
+                    || (keys.find(kid).is_none() && fetched.elapsed() >= MIN_REFETCH_INTERVAL)
+            }
+        };
+        if refetch {
+            match self.fetch().await {
+                Ok(keys) => *cached = Some((Instant::now(), keys)),
+                Err(e) if cached.is_some() => {
+                    tracing::warn!("keeping the keys of {}: {}", self.jwks_url, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(cached
+            .as_ref()
+            .map(|(_, keys)| keys.clone())
+            .unwrap_or_default())
+    }
+
+    async fn fetch(&self) -> Result<KeySet, JwtError> {
+        let jwks = |e: reqwest::Error| JwtError::Jwks(e.to_string());
+        let response = self
+            .client
+            .get(self.jwks_url.clone())
+            .timeout(JWKS_TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(jwks)?;
+        let body = response.bytes().await.map_err(jwks)?;
+        KeySet::parse(&body)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+type Claims = serde_json::Map<String, Value>;
+
+/// A compact JWS token, split up but not verified yet.
+struct Token<'a> {
+    alg: String,
+    kid: Option<String>,
+    /// `header.payload`, what the signature is over
+    signed: &'a str,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct TokenHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+impl<'a> Token<'a> {
+    fn parse(token: &'a str) -> Result<Self, JwtError> {
+        let malformed = |reason: &str| JwtError::Malformed(reason.to_string());
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(malformed("not three parts"));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| malformed("not base64url"))
+        };
+        let header: TokenHeader = serde_json::from_slice(&decode(header)?)
+            .map_err(|e| JwtError::Malformed(e.to_string()))?;
+
+        Ok(Self {
+            alg: header.alg,
+            kid: header.kid,
+            signed: &token[..token.rfind('.').expect("three parts")],
+            payload: decode(payload)?,
+            signature: decode(signature)?,
+        })
+    }
+
+    /// The token's claims, when a key of `keys` meant for its algorithm signed it.
+    fn verify(&self, keys: &KeySet) -> Result<Claims, JwtError> {
+        let key = keys
+            .find(self.kid.as_deref())
+            .ok_or_else(|| JwtError::UnknownKey(self.kid.clone().unwrap_or_default()))?;
+        if key.alg.as_deref().is_some_and(|alg| alg != self.alg) {
+            return Err(JwtError::UnsupportedAlgorithm(self.alg.clone()));
+        }
+
+        let message = self.signed.as_bytes();
+        let verified = match (&key.key, self.alg.as_str()) {
+            (Key::Rsa { n, e }, alg) => {
+                let params = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => return Err(JwtError::UnsupportedAlgorithm(self.alg.clone())),
+                };
+                RsaPublicKeyComponents { n, e }.verify(params, message, &self.signature)
+            }
+            (Key::P256(point), "ES256") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, &self.signature)
+            }
+            (Key::P384(point), "ES384") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                    .verify(message, &self.signature)
+            }
+            (Key::Ed25519(x), "EdDSA") => {
+                UnparsedPublicKey::new(&signature::ED25519, x).verify(message, &self.signature)
+            }
+            _ => return Err(JwtError::UnsupportedAlgorithm(self.alg.clone())),
+        };
+        verified.map_err(|_| JwtError::BadSignature)?;
+
+        serde_json::from_slice(&self.payload).map_err(|e| JwtError::Malformed(e.to_string()))
+    }
+}
+
+/// The keys of a JWKS document jalb can verify with, others are left out.
+#[derive(Debug, Clone, Default)]
+struct KeySet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone)]
+struct Jwk {
+    kid: Option<String>,
+    alg: Option<String>,
+    key: Key,
+}
+
+#[derive(Debug, Clone)]
+enum Key {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// uncompressed points, `04 || x || y`
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<RawJwk>,
+}
+
+#[derive(Deserialize)]
+struct RawJwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl KeySet {
+    fn parse(json: &[u8]) -> Result<Self, JwtError> {
+        let set: JwkSet =
+            serde_json::from_slice(json).map_err(|e| JwtError::Jwks(e.to_string()))?;
+        let keys = set
+            .keys
+            .into_iter()
+            .filter(|jwk| jwk.usage.as_deref().is_none_or(|usage| usage == "sig"))
+            .filter_map(|jwk| {
+                let decode = |part: &Option<String>| URL_SAFE_NO_PAD.decode(part.as_deref()?).ok();
+                let point = || {
+                    let mut point = vec![4];
+                    point.extend(decode(&jwk.x)?);
+                    point.extend(decode(&jwk.y)?);
+                    Some(point)
+                };
+                let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                    ("RSA", _) => Key::Rsa {
+                        n: decode(&jwk.n)?,
+                        e: decode(&jwk.e)?,
+                    },
+                    ("EC", Some("P-256")) => Key::P256(point()?),
+                    ("EC", Some("P-384")) => Key::P384(point()?),
+                    ("OKP", Some("Ed25519")) => Key::Ed25519(decode(&jwk.x)?),
+                    _ => return None,
+                };
+                Some(Jwk {
+                    kid: jwk.kid,
+                    alg: jwk.alg,
+                    key,
+                })
+            })
+            .collect();
+        Ok(Self { keys })
+    }
+
+    /// The key with id `kid`, or the only key when the token names none.
+    fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::*;
+
+    fn sign(key: &Ed25519KeyPair, kid: &str, claims: Value) -> String {
+        let header = serde_json::json!({ "alg": "EdDSA", "kid": kid, "typ": "JWT" });
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = key.sign(signed.as_bytes());
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    #[test]
+    fn test_jwt_verification() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwks = serde_json::json!({ "keys": [
+            { "kty": "OKP", "crv": "Ed25519", "kid": "k1",
+              "x": URL_SAFE_NO_PAD.encode(key.public_key().as_ref()) },
+            { "kty": "oct", "kid": "secret", "k": "c2VjcmV0" },
+        ]});
+        let keys = KeySet::parse(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(keys.keys.len(), 1);
+
+        let config: JwtConfig = toml::from_str(
+            r#"
+            jwks_url = "https://issuer.example.com/.well-known/jwks.json"
+            issuer = "https://issuer.example.com"
+            audiences = ["api"]
+            claims_to_headers = { sub = "X-User-Id" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let auth = JwtAuth::from_config(&config);
+        let now = 1_700_000_000;
+        let check = |token: &str| {
+            let claims = Token::parse(token)?.verify(&keys)?;
+            auth.check_claims(&claims, now)
+        };
+        let claims = |aud: Value, exp: u64| {
+            serde_json::json!({
+                "iss": "https://issuer.example.com", "aud": aud, "sub": "alice", "exp": exp
+            })
+        };
+
+        assert!(check(&sign(&key, "k1", claims("api".into(), now + 60))).is_ok());
+        assert!(
+            check(&sign(
+                &key,
+                "k1",
+                claims(serde_json::json!(["web", "api"]), now + 60)
+            ))
+            .is_ok()
+        );
+        assert!(matches!(
+            check(&sign(&key, "k1", claims("api".into(), now - 120))),
+            Err(JwtError::Expired)
+        ));
+        assert!(matches!(
+            check(&sign(&key, "k1", claims("web".into(), now + 60))),
+            Err(JwtError::WrongAudience)
+        ));
+        assert!(matches!(
+            check(&sign(&key, "k2", claims("api".into(), now + 60))),
+            Err(JwtError::UnknownKey(_))
+        ));
+        let mut forged = sign(&key, "k1", claims("api".into(), now + 60));
+        forged.truncate(forged.len() - 4);
+        forged.push_str("AAAA");
+        assert!(matches!(check(&forged), Err(JwtError::BadSignature)));
+        assert!(matches!(check("not.a-token"), Err(JwtError::Malformed(_))));
+    }
+}
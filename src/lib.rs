@@ -0,0 +1,68 @@
+//! jalb as a library, for embedding the balancer in another binary. Build a [`config::Config`]
+//! with [`config::Config::builder`] or load one from a file, then hand it to
+//! [`load_balancer::NetworkLoadBalancer`], or to [`application::ApplicationLoadBalancer`] to
+//! balance HTTP requests rather than connections.
+
+pub mod access_log;
+pub mod activation;
+pub mod admin;
+pub mod affinity;
+pub mod application;
+pub mod asn;
+pub mod audit;
+pub mod backend;
+pub mod body_limit;
+pub mod bluegreen;
+pub mod cache;
+pub mod clients;
+pub mod compression;
+pub mod config;
+pub mod connections;
+pub mod dns;
+pub mod errors;
+pub mod events;
+pub mod experiment;
+pub mod flows;
+pub mod forwarded;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod headers;
+pub mod health;
+pub mod histogram;
+pub mod hostname;
+pub mod http3;
+pub mod include;
+pub mod init;
+pub mod jwt;
+pub mod load_balancer;
+pub mod logger;
+pub mod metrics;
+pub mod mirror;
+pub mod pages;
+pub mod peer;
+pub mod pool;
+pub mod ratelimit;
+pub mod redirect;
+pub mod relay;
+pub mod reload;
+pub mod retry;
+pub mod rewrite;
+pub mod route;
+pub mod secret;
+pub mod security;
+pub mod selector;
+pub mod selftest;
+pub mod socket;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+pub mod splice;
+pub mod split;
+#[cfg(feature = "spiffe")]
+pub mod spiffe;
+pub mod store;
+pub mod timeouts;
+pub mod tls;
+pub mod udp;
+pub mod upstream_tls;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+pub mod webhook;
@@ -1,112 +1,1557 @@
-use std::{net::IpAddr, os::unix::net::SocketAddr, sync::Arc, time::{self, Duration, Instant}};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
 use tokio::{
-    io::{self, copy_bidirectional},
-    net::TcpStream,
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    task::JoinSet,
 };
 
 use crate::{
-    backend::Backend,
-    config::{Config, LoadBalancerStrategy},
-    peer::{Peer, tcpsocket_from_address},
-    security::Security,
-    selector::{RoundRobin, Selector},
+    access_log::{AccessLog, SessionEnd},
+    audit::AuditLog,
+    config::{BackendOptions, Config, ConfigBuilder, LoadBalancerStrategy, NetworkTarget},
+    errors::{ConfigError, ProxyError, Terminated},
+    connections::ConnectionRegistry,
+    events::{EventKind, EventLog},
+    flows::FlowStep,
+    health::HealthGuard,
+    hostname::peek_hostname,
+    metrics::{Direction, Metrics},
+    peer::{Peer, PeerStats, tcpsocket_from_address},
+    pool::{self, Pool},
+    ratelimit::{AtomicTokenBucket, ClientRateLimiter},
+    relay::{
+        DEFAULT_BUFFER_SIZE, RelayPath, Side, Transfer, await_first_byte, relay_tcp, relay_tracked,
+    },
+    security::{HostFilter, LimitAction, RejectReason, Security},
+    selector::{LeastUsed, RoundRobin, Selector, Weighted},
+    socket::SocketOptions,
+    upstream_tls::UpstreamTls,
 };
 
+pub(crate) fn selector_from_config(cfg: &Config) -> Box<dyn Selector> {
+    match cfg.strategy() {
+        LoadBalancerStrategy::RoundRobin => Box::new(RoundRobin::new()),
+        LoadBalancerStrategy::WeightedAverage => Box::new(Weighted::new()),
+        LoadBalancerStrategy::LeastUsed => Box::new(LeastUsed::new()),
+        LoadBalancerStrategy::Geolocation => unreachable!("geo is rejected by validation"),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyOptions {
+    /// Close connections that send nothing within this window after being accepted
+    pub first_byte_timeout: Option<Duration>,
+    /// Close sessions where neither side has sent anything for this long
+    pub idle_timeout: Option<Duration>,
+    /// Give up on a peer that hasn't accepted the connection, and finished the TLS handshake,
+    /// within this long
+    pub connect_timeout: Option<Duration>,
+    /// Close sessions lasting longer than this, however active
+    pub session_timeout: Option<Duration>,
+    /// Bytes copied each way at a time
+    pub buffer_size: usize,
+    /// Set on the client's socket and the one to the peer
+    pub socket: SocketOptions,
+    /// How plain tcp sessions are relayed
+    pub relay: RelayPath,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            first_byte_timeout: None,
+            idle_timeout: None,
+            connect_timeout: None,
+            session_timeout: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            socket: SocketOptions::default(),
+            relay: RelayPath::Copy,
+        }
+    }
+}
+
+impl ProxyOptions {
+    pub(crate) fn from_config(cfg: &Config) -> Self {
+        Self {
+            first_byte_timeout: cfg.first_byte_timeout(),
+            idle_timeout: cfg.idle_timeout(),
+            connect_timeout: None,
+            session_timeout: None,
+            buffer_size: cfg.relay_buffer_size(),
+            socket: cfg.tcp().to_socket_options(),
+            relay: RelayPath::fastest(cfg.io_uring_threads().is_some()),
+        }
+    }
+}
+
+/// How long the accept loop waits after an accept error (e.g. out of file descriptors) before
+/// accepting again, rather than spinning on the error.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How long a tarpitted connection is held before it is dropped.
+const TARPIT_DURATION: Duration = Duration::from_secs(30);
+/// Tarpitted connections each hold a file descriptor, past this many they are reset instead.
+const MAX_TARPITTED: usize = 1024;
+/// With `on_limit = "delay"`, the limits are rechecked this often, this many times, before
+/// the connection is reset.
+const LIMIT_DELAY: Duration = Duration::from_millis(100);
+const LIMIT_DELAY_ATTEMPTS: u32 = 50;
+
+/// How long a client has to name a host when `hostnames` is set and there is no
+/// `first_byte_timeout`.
+const HOST_PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TOO_MANY_REQUESTS: &[u8] =
+    b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Why a connection was shed before any other work was done on it.
+#[derive(Debug, Clone, Copy)]
+enum Overload {
+    MaxConnections,
+    AcceptRate,
+    ClientRate,
+}
+
+impl Overload {
+    fn name(&self) -> &'static str {
+        match self {
+            Overload::MaxConnections => "max_connections",
+            Overload::AcceptRate => "rate_limit",
+            Overload::ClientRate => "client_rate_limit",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Overload::MaxConnections => "max_connections reached",
+            Overload::AcceptRate => "accept rate limit exceeded",
+            Overload::ClientRate => "per-client accept rate limit exceeded",
+        }
+    }
+}
+
+/// What woke the accept loop.
+pub(crate) enum Wake {
+    /// a connection, with the index of the listener it came in on
+    Accepted(TcpStream, std::net::SocketAddr, usize),
+    Reload(Box<Config>),
+    /// a pool's `host:port` peers are due to be resolved again
+    DnsRefresh,
+    /// a pool's peers as they resolved on a refresh
+    Resolved(String, Vec<Peer>),
+    /// a client connecting to the application balancer's `[http3]` listener
+    Http3(Box<crate::http3::Incoming>),
+}
+
+pub(crate) async fn next_reload(reloads: &mut Option<mpsc::Receiver<Config>>) -> Option<Config> {
+    match reloads {
+        Some(reloads) => reloads.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs `future`, failing with `TimedOut` and `error(limit)` inside once `limit` has passed. Both
+/// streams are closed when the proxy returns.
+pub(crate) async fn within<T>(
+    limit: Option<Duration>,
+    future: impl Future<Output = io::Result<T>>,
+    error: fn(Duration) -> ProxyError,
+) -> io::Result<T> {
+    let Some(limit) = limit else {
+        return future.await;
+    };
+
+    tokio::time::timeout(limit, future)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, error(limit))))
+}
+
+/// Whether `e` came from the peer's `request_timeout`, rather than e.g. the idle timeout.
+pub(crate) fn is_request_timeout(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<ProxyError>())
+}
+
+/// Whether `e` is the session being terminated through the admin api.
+pub(crate) fn is_terminated(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Terminated>())
+}
+
+/// How a session relayed by [`relay_tracked`] with the client as `a` ended.
+fn session_end(proxied: &io::Result<(u64, u64)>, transfer: &Transfer) -> SessionEnd {
+    match proxied {
+        Ok(_) if transfer.closed_first() == Some(Side::B) => SessionEnd::UpstreamClose,
+        Ok(_) => SessionEnd::ClientClose,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => SessionEnd::Timeout,
+        Err(e) if is_terminated(e) => SessionEnd::Terminated,
+        Err(_) => SessionEnd::Error,
+    }
+}
+
+/// `cfg.dump()`, or a comment saying why it can't be shown.
+pub(crate) fn dump_config(cfg: &Config) -> String {
+    cfg.dump()
+        .unwrap_or_else(|e| format!("# cannot show the config: {}\n", e))
+}
+
+fn client_limiter(
+    cfg: &Config,
+    backend: &BackendOptions,
+    security: &Security,
+) -> Option<ClientRateLimiter> {
+    let rate = cfg.max_client_accepts_per_second_for(backend)?;
+    let limiter = match (cfg.shared_rate_limit(), security.state_store()) {
+        (true, Some(store)) => ClientRateLimiter::shared(rate, store),
+        (true, None) => {
+            tracing::warn!("shared_rate_limit needs a [state] store, limiting per instance");
+            ClientRateLimiter::local(rate)
+        }
+        (false, _) => ClientRateLimiter::local(rate),
+    };
+    Some(limiter)
+}
+
+/// Closes `stream` with a RST rather than a graceful FIN.
+pub(crate) fn reset(stream: TcpStream) {
+    let _ = stream.set_linger(Some(Duration::ZERO));
+    drop(stream);
+}
+
+/// Binds a listener with an explicit backlog instead of the platform default.
+pub fn bind_tcp_listener(
+    addr: std::net::SocketAddr,
+    backlog: u32,
+) -> Result<TcpListener, io::Error> {
+    let socket = tcpsocket_from_address(&addr)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// A socket connections are accepted on, and the pool they are sent to.
+pub struct Listener {
+    socket: TcpListener,
+    /// name of the `[[backend]]` its connections go to, the default one when unset
+    backend: Option<String>,
+    /// which of the `acceptors` sharing the address through `SO_REUSEPORT` accepts on it
+    acceptor: usize,
+}
+
+impl Listener {
+    pub fn new(socket: TcpListener, backend: Option<String>) -> Self {
+        Self {
+            socket,
+            backend,
+            acceptor: 0,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    /// Name of the `[[backend]]` its connections go to, `None` for the default one.
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(socket: TcpListener) -> Self {
+        Self::new(socket, None)
+    }
+}
+
+/// Binds every listener in `cfg`, `acceptors` times over with `SO_REUSEPORT` so the kernel
+/// spreads its connections across them. An IPv6 wildcard on the same port as an IPv4 wildcard
+/// is made v6 only, otherwise it would also claim the IPv4 port and the second bind would fail.
+pub fn bind_tcp_listeners(cfg: &Config) -> Result<Vec<Listener>, io::Error> {
+    let listeners = cfg.listeners();
+    let shares_port = |addr: std::net::SocketAddr| {
+        listeners.iter().any(|other| {
+            other.address.is_ipv4()
+                && other.address.ip().is_unspecified()
+                && other.address.port() == addr.port()
+        })
+    };
+
+    let acceptors = cfg.acceptors();
+    let mut bound = Vec::with_capacity(listeners.len() * acceptors);
+    for listener in &listeners {
+        let mut addr = listener.address;
+        let bind = |addr: std::net::SocketAddr| {
+            let socket = tcpsocket_from_address(&addr)?;
+            if addr.is_ipv6() && addr.ip().is_unspecified() && shares_port(addr) {
+                socket2::SockRef::from(&socket).set_only_v6(true)?;
+            }
+            socket.set_reuseaddr(true)?;
+            if acceptors > 1 {
+                socket.set_reuseport(true)?;
+            }
+            socket.bind(addr)?;
+            socket.listen(cfg.listen_backlog())
+        };
+
+        for acceptor in 0..acceptors {
+            let socket = bind(addr).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot listen on {}: {}", addr, e))
+            })?;
+            // with port 0 the rest share the port the first one was given
+            addr = socket.local_addr()?;
+            bound.push(Listener {
+                socket,
+                backend: listener.backend.clone(),
+                acceptor,
+            });
+        }
+    }
+
+    Ok(bound)
+}
+
+/// Waits for a connection on any of `listeners`, returning the index of the one it came in
+/// on too. Polling starts at `first`, so one busy listener can't starve the others.
+pub(crate) async fn accept(
+    listeners: &[Listener],
+    first: usize,
+) -> (TcpStream, std::net::SocketAddr, usize) {
+    loop {
+        let accepted = std::future::poll_fn(|cx| {
+            for offset in 0..listeners.len() {
+                let idx = (first + offset) % listeners.len();
+                if let Poll::Ready(accepted) = listeners[idx].socket.poll_accept(cx) {
+                    return Poll::Ready(accepted.map(|(stream, addr)| (stream, addr, idx)));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+
+        match accepted {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                tracing::error!("failed to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
+
+// only implemented within jalb, so the returned future's bounds don't need spelling out
+#[allow(async_fn_in_trait)]
 pub trait TcpProxy {
+    /// Returns the bytes copied from the client and to it, which are also counted into
+    /// `transfer` as they go. How long connecting and the whole session took go into `stats`.
     async fn proxy_connection(
         incoming: TcpStream,
         upstream: std::net::SocketAddr,
-    ) -> Result<(), io::Error>;
+        options: ProxyOptions,
+        stats: &PeerStats,
+        transfer: &Arc<Transfer>,
+    ) -> Result<(u64, u64), io::Error>;
 }
 
 pub struct NetworkLoadBalancer {
-    pub security: Security,
-    backend: Backend,
-    selector: Box<dyn Selector>,
-    balancer_task: Option<tokio::task::JoinHandle<()>>,
+    dispatcher: Dispatcher,
+    /// every pool's peers, republished whenever a reload changes them
+    peer_list: watch::Sender<Vec<Arc<Peer>>>,
+    /// `Config::dump` of the config as of the latest reload
+    config_dump: watch::Sender<String>,
+    /// `security` as of the latest reload
+    security_rules: watch::Sender<Security>,
+    /// what the dispatcher checks connections against, replaced on reloads
+    rules: watch::Sender<Arc<Rules>>,
+    /// taken by the accept loop once it runs
+    reloads: Mutex<Option<mpsc::Receiver<Config>>>,
 }
 
-impl NetworkLoadBalancer {
-    pub(crate) fn new_from_config(cfg: &Config) -> Self {
-        let backend = Backend::from_config(&cfg.backend);
-
-        let mut selector = match cfg.strategy() {
-            LoadBalancerStrategy::RoundRobin => RoundRobin::new(),
-            LoadBalancerStrategy::WeightedAverage => todo!(),
-            LoadBalancerStrategy::LeastUsed => todo!(),
-            LoadBalancerStrategy::Geolocation => todo!(),
-            _ => todo!(),
-        };
+/// What accepted connections are checked against and sent on with. Tasks handling them read
+/// the latest one without waiting on each other, reloads replace it whole.
+#[derive(Clone)]
+pub(crate) struct Rules {
+    /// the global `[security]`, which every pool's is built on
+    security: Security,
+    /// what connections to each pool are checked against, in the order of the pools
+    pools: Vec<PoolRules>,
+    /// index into the pools of the pool connections go to
+    default_pool: usize,
+    proxy_options: ProxyOptions,
+}
 
-        cfg.backend.peers().drain(0..).for_each(|p| {
-            selector.add_peer(p);
-        });
+impl Rules {
+    /// Rules for the pools named `pools`, with the timed bans and list files of `security`.
+    pub(crate) fn from_config(
+        cfg: &Config,
+        security: Security,
+        pools: &[String],
+        default_pool: usize,
+    ) -> Self {
+        let pools = pools
+            .iter()
+            .map(|name| {
+                let backend = cfg.backends().iter().find(|b| b.name == *name);
+                let backend = backend.unwrap_or(cfg.backend());
+                PoolRules::from_config(cfg, backend, security.for_pool(&backend.security))
+            })
+            .collect();
+        Self {
+            security,
+            pools,
+            default_pool,
+            proxy_options: ProxyOptions::from_config(cfg),
+        }
+    }
+
+    /// The global `[security]`, with the timed bans and list files every pool's shares.
+    pub(crate) fn security(&self) -> &Security {
+        &self.security
+    }
+
+    /// What connections to `pool` are checked against.
+    pub(crate) fn pool_security(&self, pool: usize) -> &Security {
+        &self.pools[pool].security
+    }
+}
+
+/// The security rules and limits of one pool, its `[backend.security]` over the global ones.
+#[derive(Clone)]
+pub(crate) struct PoolRules {
+    security: Security,
+    host_filter: Option<Arc<HostFilter>>,
+    max_connections: usize,
+    accept_limiter: Option<Arc<AtomicTokenBucket>>,
+    client_limiter: Option<Arc<ClientRateLimiter>>,
+}
 
+impl PoolRules {
+    fn from_config(cfg: &Config, backend: &BackendOptions, security: Security) -> Self {
         Self {
-            security: cfg.security.to_owned(),
-            backend: backend,
-            balancer_task: None,
-            selector: Box::new(selector),
+            host_filter: security.host_filter().map(Arc::new),
+            client_limiter: client_limiter(cfg, backend, &security).map(Arc::new),
+            security,
+            max_connections: cfg.max_connections_for(backend),
+            accept_limiter: cfg
+                .max_accepts_per_second_for(backend)
+                .map(|rate| Arc::new(AtomicTokenBucket::per_second(rate))),
         }
     }
 
-    fn is_allowed(&self, ip: &IpAddr) -> bool {
-        !self.security.is_blacklisted(ip) && self.security.is_whitelisted(ip)
+    /// Returns why a freshly accepted connection should be dropped before doing any other work
+    /// on it, with `open` sessions to the pool already, or `None` to admit it.
+    fn overload_reason(&self, open: usize) -> Option<Overload> {
+        if open >= self.max_connections {
+            return Some(Overload::MaxConnections);
+        }
+
+        if let Some(limiter) = &self.accept_limiter
+            && !limiter.try_acquire()
+        {
+            return Some(Overload::AcceptRate);
+        }
+
+        None
     }
+}
 
-    fn listener_task(&mut self, stream: TcpStream, downstream: std::net::SocketAddr) {
-        let ip = downstream.ip();
+/// Admits connections accepted for a pool within its limits, applying its `on_limit` to the
+/// rest. Both balancers go through it, clones share everything.
+#[derive(Clone)]
+pub(crate) struct Admission {
+    rules: watch::Receiver<Arc<Rules>>,
+    /// connections admitted to each pool and not closed yet
+    open: Arc<[AtomicUsize]>,
+    /// connections dropped since shedding began, 0 while not shedding
+    shed_count: Arc<AtomicU64>,
+    tarpitted: Arc<AtomicUsize>,
+    events: Arc<EventLog>,
+    connections: Arc<ConnectionRegistry>,
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<AuditLog>>,
+}
 
-        if !self.is_allowed(&ip) {
-            return;
+/// Counts a connection against the pool it was admitted to until dropped.
+pub(crate) struct Open {
+    open: Arc<[AtomicUsize]>,
+    pool: usize,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.open[self.pool].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Admission {
+    pub(crate) fn new(
+        rules: watch::Receiver<Arc<Rules>>,
+        events: Arc<EventLog>,
+        connections: Arc<ConnectionRegistry>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let pools = rules.borrow().pools.len();
+        Self {
+            rules,
+            open: (0..pools).map(|_| AtomicUsize::new(0)).collect(),
+            shed_count: Arc::new(AtomicU64::new(0)),
+            tarpitted: Arc::new(AtomicUsize::new(0)),
+            events,
+            connections,
+            metrics,
+            audit: None,
         }
+    }
 
-        if let Some(peer) = self.selector.next() {
-            tokio::spawn(async move {
-                let socket_addr = peer 
-                    .address
-                    .to_socket_addrs()
-                    .expect("peer does not contain valid socket address");
+    /// Writes every rejected connection to `audit`.
+    pub(crate) fn set_audit_log(&mut self, audit: Arc<AuditLog>) {
+        self.audit = Some(audit);
+    }
 
-                match NetworkLoadBalancer::proxy_connection(stream, socket_addr).await {
-                    Err(e) => {
-                        println!("Error proxying {:?}", e)
+    pub(crate) fn audit(&self, client: IpAddr, reason: &'static str, detail: Option<String>) {
+        if let Some(audit) = &self.audit {
+            audit.reject(client, reason, detail);
+        }
+    }
+
+    /// The pool rules as of the latest reload.
+    pub(crate) fn rules(&self, pool: usize) -> PoolRules {
+        self.rules.borrow().pools[pool].clone()
+    }
+
+    /// Checks `client` against the security rules of `pool`, recording why it was turned away.
+    pub(crate) fn check(&self, client: IpAddr, pool: usize) -> Result<(), RejectReason> {
+        let checked = self.rules(pool).security.check(&client);
+        if let Err(reason) = checked {
+            self.events.record(
+                EventKind::Reject,
+                format!("rejected connection from {}: {}", client, reason.name()),
+            );
+            self.audit(client, reason.name(), None);
+            self.connections.clients().rejected(client);
+        }
+        checked
+    }
+
+    fn opened(&self, pool: usize) -> Open {
+        self.open[pool].fetch_add(1, Ordering::Relaxed);
+        Open {
+            open: self.open.clone(),
+            pool,
+        }
+    }
+
+    fn overload_reason(&self, pool: usize) -> Option<Overload> {
+        let open = self.open[pool].load(Ordering::Relaxed);
+        self.rules(pool).overload_reason(open)
+    }
+
+    /// Decides whether to drop the connection just accepted for `pool`. Only the transitions
+    /// into and out of shedding are recorded, so a flood can't also flood the event log.
+    fn shed_reason(&self, pool: usize) -> Option<Overload> {
+        let Some(reason) = self.overload_reason(pool) else {
+            // only written to while shedding, so admitting doesn't contend on it
+            if self.shed_count.load(Ordering::Relaxed) > 0 {
+                let count = self.shed_count.swap(0, Ordering::Relaxed);
+                if count > 0 {
+                    self.events.record(
+                        EventKind::Reject,
+                        format!("stopped shedding load after dropping {} connections", count),
+                    );
+                }
+            }
+            return None;
+        };
+
+        if self.shed_count.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.events.record(
+                EventKind::Reject,
+                format!("shedding new connections: {}", reason.description()),
+            );
+        }
+
+        Some(reason)
+    }
+
+    /// Whether `client` is within `max_client_accepts_per_second`. Unlike the global limits this
+    /// doesn't count as shedding, one noisy client shouldn't mark the balancer as overloaded.
+    async fn client_allowed(&self, client: IpAddr, pool: usize) -> bool {
+        let limiter = self.rules(pool).client_limiter;
+        match limiter {
+            Some(limiter) => limiter.try_acquire(client).await,
+            None => true,
+        }
+    }
+
+    /// Why the connection just accepted from `client` for `pool` is over a limit, `None` when
+    /// it isn't.
+    async fn limit_reason(&self, client: IpAddr, pool: usize) -> Option<Overload> {
+        match self.shed_reason(pool) {
+            Some(reason) => Some(reason),
+            None if self.client_allowed(client, pool).await => None,
+            None => Some(Overload::ClientRate),
+        }
+    }
+
+    /// Applies the `on_limit` action of `pool` when the connection just accepted for it is over
+    /// a limit. Hands the stream back, counted against the pool, if it should be served after
+    /// all.
+    pub(crate) async fn admit(
+        &self,
+        stream: TcpStream,
+        client: IpAddr,
+        pool: usize,
+    ) -> Option<(TcpStream, Open)> {
+        self.metrics.accepted();
+        let Some(reason) = self.limit_reason(client, pool).await else {
+            return Some((stream, self.opened(pool)));
+        };
+
+        let action = self.rules(pool).security.on_limit();
+        match action {
+            LimitAction::Reset => reset(stream),
+            LimitAction::Tarpit => self.tarpit(stream),
+            LimitAction::Delay => {
+                // only this connection waits, the accept loop carries on
+                for _ in 0..LIMIT_DELAY_ATTEMPTS {
+                    tokio::time::sleep(LIMIT_DELAY).await;
+                    let cleared = match reason {
+                        Overload::ClientRate => self.client_allowed(client, pool).await,
+                        _ => self.overload_reason(pool).is_none(),
+                    };
+                    if cleared {
+                        return Some((stream, self.opened(pool)));
                     }
-                    _ => {}
                 }
-            });
+                reset(stream);
+            }
+            LimitAction::TooManyRequests => {
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let _ = stream.write_all(TOO_MANY_REQUESTS).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        }
+
+        let detail = format!("{}, on_limit {}", reason.description(), action.name());
+        self.rejected(client, reason, detail);
+        None
+    }
+
+    /// [`admit`](Self::admit) for a client connecting over QUIC, which is refused when it is
+    /// over a limit whatever the `on_limit`.
+    pub(crate) async fn admit_quic(&self, client: IpAddr, pool: usize) -> Option<Open> {
+        self.metrics.accepted();
+        let Some(reason) = self.limit_reason(client, pool).await else {
+            return Some(self.opened(pool));
+        };
+        self.rejected(client, reason, reason.description().to_string());
+        None
+    }
+
+    fn rejected(&self, client: IpAddr, reason: Overload, detail: String) {
+        self.metrics.rejected(reason.name());
+        match reason {
+            Overload::ClientRate => self.connections.clients().rate_limited(client),
+            _ => self.connections.clients().rejected(client),
+        }
+        self.audit(client, reason.name(), Some(detail));
+    }
+
+    fn tarpit(&self, stream: TcpStream) {
+        if self.tarpitted.fetch_add(1, Ordering::Relaxed) >= MAX_TARPITTED {
+            self.tarpitted.fetch_sub(1, Ordering::Relaxed);
+            reset(stream);
+            return;
+        }
+
+        let tarpitted = self.tarpitted.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(TARPIT_DURATION).await;
+            drop(stream);
+            tarpitted.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Admits and proxies accepted connections, each on a task of its own so the accept loop does
+/// nothing but accept. Clones share everything.
+#[derive(Clone)]
+struct Dispatcher {
+    rules: watch::Receiver<Arc<Rules>>,
+    /// one per `[[backend]]`, locked on its own only while a peer is picked or it is reloaded
+    pools: Arc<[Mutex<Pool>]>,
+    /// the names of `pools`, which only change on restart
+    pool_names: Arc<[String]>,
+    admission: Admission,
+    events: Arc<EventLog>,
+    connections: Arc<ConnectionRegistry>,
+    metrics: Arc<Metrics>,
+    /// by the name of the pool whose peers are connected to over it
+    upstream_tls: Arc<HashMap<String, UpstreamTls>>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+/// Sets up a [`NetworkLoadBalancer`] in code, through the same settings as the config file.
+///
+/// ```ignore
+/// let balancer = NetworkLoadBalancer::builder()
+///     .with_strategy(LoadBalancerStrategy::LeastUsed)
+///     .with_peer("10.0.0.1:8080".parse::<SocketAddr>()?, 1)
+///     .with_peer("10.0.0.2:8080".parse::<SocketAddr>()?, 2)
+///     .with_idle_timeout(Duration::from_secs(300))
+///     .build()?;
+/// ```
+#[derive(Debug, Default)]
+pub struct NetworkLoadBalancerBuilder {
+    config: ConfigBuilder,
+}
+
+impl NetworkLoadBalancerBuilder {
+    pub fn with_strategy(mut self, strategy: LoadBalancerStrategy) -> Self {
+        self.config = self.config.with_strategy(strategy);
+        self
+    }
+
+    pub fn with_backend(mut self, backend: BackendOptions) -> Self {
+        self.config = self.config.with_backend(backend);
+        self
+    }
+
+    pub fn with_peer(mut self, address: impl Into<NetworkTarget>, weight: u32) -> Self {
+        self.config = self.config.with_peer(address, weight);
+        self
+    }
+
+    pub fn with_security(mut self, security: Security) -> Self {
+        self.config = self.config.with_security(security);
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: u32) -> Self {
+        self.config = self.config.with_max_connections(max);
+        self
+    }
+
+    pub fn with_first_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.config = self.config.with_first_byte_timeout(timeout);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config = self.config.with_idle_timeout(timeout);
+        self
+    }
+
+    pub fn build(self) -> Result<NetworkLoadBalancer, ConfigError> {
+        Ok(NetworkLoadBalancer::new_from_config(&self.config.build()?))
+    }
+}
+
+impl NetworkLoadBalancer {
+    /// Starts a balancer set up in code instead of from a config file.
+    pub fn builder() -> NetworkLoadBalancerBuilder {
+        NetworkLoadBalancerBuilder::default()
+    }
+
+    /// Builds one pool per `[[backend]]`, sending connections to the default one.
+    pub fn new_from_config(cfg: &Config) -> Self {
+        let pools: Vec<Pool> = cfg
+            .backends()
+            .iter()
+            .map(|options| Pool::from_config(cfg, options))
+            .collect();
+        let default_pool = pools
+            .iter()
+            .position(|pool| pool.name == cfg.backend().name)
+            .unwrap_or(0);
+
+        Self::with_pools(cfg, pools, default_pool)
+    }
+
+    /// Builds the balancer from `cfg` but with only the default pool, in front of `peers`
+    /// instead of the configured ones.
+    pub(crate) fn with_peers(cfg: &Config, peers: Vec<Peer>) -> Self {
+        Self::with_pools(cfg, vec![Pool::with_peers(cfg, cfg.backend(), peers)], 0)
+    }
+
+    fn with_pools(cfg: &Config, pools: Vec<Pool>, default_pool: usize) -> Self {
+        let peers = pools.iter().flat_map(Pool::peers).collect();
+        let pool_names: Vec<String> = pools.iter().map(|pool| pool.name.clone()).collect();
+        let connections = Arc::new(ConnectionRegistry::new());
+        connections.flows().set_sample(cfg.flow_sample());
+        let rules = Rules::from_config(cfg, cfg.security.clone(), &pool_names, default_pool);
+        let security = rules.pool_security(default_pool).clone();
+        let rules = watch::Sender::new(Arc::new(rules));
+        let events = Arc::new(EventLog::new(cfg.event_buffer_size()));
+        let metrics = Arc::new(Metrics::new());
+        let admission = Admission::new(
+            rules.subscribe(),
+            events.clone(),
+            connections.clone(),
+            metrics.clone(),
+        );
+
+        Self {
+            security_rules: watch::Sender::new(security),
+            peer_list: watch::Sender::new(peers),
+            config_dump: watch::Sender::new(dump_config(cfg)),
+            reloads: Mutex::new(None),
+            dispatcher: Dispatcher {
+                rules: rules.subscribe(),
+                pool_names: pool_names.into(),
+                pools: pools.into_iter().map(Mutex::new).collect(),
+                admission,
+                events,
+                connections,
+                metrics,
+                upstream_tls: Arc::new(HashMap::new()),
+                access_log: None,
+            },
+            rules,
+        }
+    }
+
+    /// Checks clients of every pool against `security` instead of the config's rules, until
+    /// the next reload.
+    pub fn with_security(self, security: Security) -> Self {
+        self.security_rules.send_replace(security.clone());
+        let mut rules = Rules::clone(&self.dispatcher.rules());
+        for pool in &mut rules.pools {
+            pool.host_filter = security.host_filter().map(Arc::new);
+            pool.security = security.clone();
+        }
+        rules.security = security;
+        self.rules.send_replace(Arc::new(rules));
+        self
+    }
+
+    /// Writes every rejected connection to `audit`.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.dispatcher.admission.set_audit_log(audit);
+        self
+    }
+
+    /// Writes every proxied session to `access_log` once it is over.
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.dispatcher.access_log = Some(access_log);
+        self
+    }
+
+    /// Connects to the peers of `pool` over TLS instead of plaintext.
+    pub fn with_upstream_tls(mut self, pool: &str, tls: UpstreamTls) -> Self {
+        Arc::make_mut(&mut self.dispatcher.upstream_tls).insert(pool.to_string(), tls);
+        self
+    }
+
+    /// Starts health checking every pool, returning the default pool's guard.
+    pub fn spawn_health_checks(&self) -> Arc<HealthGuard> {
+        let events = &self.dispatcher.events;
+        let guards: Vec<_> = self
+            .dispatcher
+            .pools
+            .iter()
+            .map(|pool| pool.lock().unwrap().spawn_health_checks(events))
+            .collect();
+        guards[self.dispatcher.rules().default_pool].clone()
+    }
+
+    /// Applies every config received on `reloads` between accepts.
+    pub fn with_reloads(mut self, reloads: mpsc::Receiver<Config>) -> Self {
+        self.reloads = Mutex::new(Some(reloads));
+        self
+    }
+
+    /// The peers of every pool, following reloads.
+    pub fn watch_peers(&self) -> watch::Receiver<Vec<Arc<Peer>>> {
+        self.peer_list.subscribe()
+    }
+
+    /// Applies a reloaded config without touching established connections. Each pool's peers
+    /// are added, removed or reweighted, and the security rules and limits rebuilt. The
+    /// listeners, protocol, strategy and set of pools only change on restart.
+    pub fn apply_config(&self, cfg: &Config) {
+        let events = &self.dispatcher.events;
+        let changes = pool::apply_config(self.dispatcher.locked_pools(), cfg, events);
+        self.peer_list.send_replace(self.dispatcher.peers());
+        self.config_dump.send_replace(dump_config(cfg));
+
+        let current = self.dispatcher.rules();
+        let default_pool = self
+            .dispatcher
+            .pool_names
+            .iter()
+            .position(|name| *name == cfg.backend().name)
+            .unwrap_or(current.default_pool);
+        let mut security = current.security.clone();
+        security.reload_rules(&cfg.security);
+        let rules = Rules::from_config(cfg, security, &self.dispatcher.pool_names, default_pool);
+        self.security_rules
+            .send_replace(rules.pool_security(default_pool).clone());
+        self.rules.send_replace(Arc::new(rules));
+        self.dispatcher.connections.flows().set_sample(cfg.flow_sample());
+
+        pool::record_reload(events, &changes);
+    }
+
+    fn apply_resolved(&self, pool: &str, peers: Vec<Peer>) {
+        let pools = self.dispatcher.locked_pools();
+        if pool::apply_resolved(pools, pool, peers, &self.dispatcher.events) {
+            self.peer_list.send_replace(self.dispatcher.peers());
         }
     }
 
-    pub async fn run_forever(&mut self, listener: tokio::net::TcpListener) {
-        while let Ok((stream, addr)) = listener.accept().await {
-            self.listener_task(stream, addr);
+    /// The effective config as TOML, following reloads.
+    pub fn watch_config_dump(&self) -> watch::Receiver<String> {
+        self.config_dump.subscribe()
+    }
+
+    /// The security lists and policies, following reloads.
+    pub fn watch_security(&self) -> watch::Receiver<Security> {
+        self.security_rules.subscribe()
+    }
+
+    pub fn events(&self) -> Arc<EventLog> {
+        self.dispatcher.events.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.dispatcher.metrics.clone()
+    }
+
+    pub fn connections(&self) -> Arc<ConnectionRegistry> {
+        self.dispatcher.connections.clone()
+    }
+
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.peer_list.borrow().clone()
+    }
+
+    /// Accepts connections on every listener, sending each to its listener's pool from a task
+    /// of its own, and applies reloads and DNS refreshes between accepts. With `acceptors`, the
+    /// sockets of the first acceptor are accepted on here and those of the others on a task
+    /// per acceptor, aborted when this returns or is dropped.
+    pub async fn run_forever(&self, listeners: Vec<Listener>) {
+        let mut acceptors = shard(listeners).into_iter();
+        let listeners = acceptors.next().unwrap_or_default();
+        let mut others = JoinSet::new();
+        for listeners in acceptors {
+            others.spawn(self.dispatcher.clone().accept_forever(listeners));
+        }
+
+        let mut reloads = self.reloads.lock().unwrap().take();
+        let pools = self.dispatcher.pools.len();
+        let (resolved_tx, mut resolved) = mpsc::channel(pools.max(1));
+        let mut first = 0;
+        let mut refresh_at = self.dispatcher.next_dns_refresh();
+        loop {
+            let wake = tokio::select! {
+                (stream, addr, idx) = accept(&listeners, first) => {
+                    Wake::Accepted(stream, addr, idx)
+                }
+                Some(cfg) = next_reload(&mut reloads) => Wake::Reload(Box::new(cfg)),
+                _ = sleep_until(refresh_at) => Wake::DnsRefresh,
+                Some((pool, peers)) = resolved.recv() => Wake::Resolved(pool, peers),
+            };
+
+            match wake {
+                Wake::Accepted(stream, addr, idx) => {
+                    first = idx + 1;
+                    self.dispatcher.dispatch(stream, addr, &listeners[idx]);
+                    // accepting doesn't move the next refresh, so the pools aren't locked for it
+                    continue;
+                }
+                Wake::Reload(cfg) => self.apply_config(&cfg),
+                Wake::DnsRefresh => {
+                    pool::refresh_dns(self.dispatcher.locked_pools(), &resolved_tx);
+                }
+                Wake::Resolved(pool, peers) => self.apply_resolved(&pool, peers),
+                // connections are only ever accepted over QUIC by the application balancer
+                Wake::Http3(_) => {}
+            }
+            refresh_at = self.dispatcher.next_dns_refresh();
         }
     }
 
-    pub async fn run_until(&mut self, listener: tokio::net::TcpListener, duration: Duration) {
+    pub async fn run_until(&self, listeners: Vec<Listener>, duration: Duration) {
         let now = Instant::now();
-        while let Ok((stream, addr)) = listener.accept().await {
-            
+        let mut first = 0;
+        loop {
+            let (stream, addr, idx) = accept(&listeners, first).await;
+            first = idx + 1;
+
             if now.elapsed() > duration {
                 break;
             }
 
-            self.listener_task(stream, addr);
-        } 
+            self.dispatcher.dispatch(stream, addr, &listeners[idx]);
+        }
+    }
+}
+
+/// `listeners` split by the acceptor they were bound for, in order.
+fn shard(listeners: Vec<Listener>) -> Vec<Vec<Listener>> {
+    let mut acceptors: Vec<Vec<Listener>> = Vec::new();
+    for listener in listeners {
+        if acceptors.len() <= listener.acceptor {
+            acceptors.resize_with(listener.acceptor + 1, Vec::new);
+        }
+        acceptors[listener.acceptor].push(listener);
+    }
+    acceptors
+}
+
+impl Dispatcher {
+    /// The rules as of the latest reload.
+    fn rules(&self) -> Arc<Rules> {
+        self.rules.borrow().clone()
+    }
+
+    /// Every pool, each locked as the iterator reaches it and unlocked as it moves on.
+    fn locked_pools(&self) -> impl Iterator<Item = MutexGuard<'_, Pool>> {
+        self.pools.iter().map(|pool| pool.lock().unwrap())
+    }
+
+    fn peers(&self) -> Vec<Arc<Peer>> {
+        self.locked_pools().flat_map(|pool| pool.peers()).collect()
+    }
+
+    fn next_dns_refresh(&self) -> Option<tokio::time::Instant> {
+        self.locked_pools().filter_map(|pool| pool.next_dns_refresh()).min()
+    }
+
+    /// Index of the pool connections on a listener sending to `backend` go to.
+    fn pool_index(&self, backend: Option<&str>) -> usize {
+        let names = self.pool_names.iter().map(String::as_str);
+        pool::pool_for(names, backend, self.rules().default_pool)
+    }
+
+    /// The next peer of `pool` and how to proxy to it. Only that pool is locked while its
+    /// selector picks.
+    fn pick(&self, rules: &Rules, pool: usize) -> Option<(Arc<Peer>, ProxyOptions)> {
+        let mut pool = self.pools[pool].lock().unwrap();
+        let peer = pool.pick()?;
+        let options = pool.proxy_options(&peer, rules.proxy_options);
+        Some((peer, options))
+    }
+
+    /// Hands a connection accepted on `listener` to a task of its own.
+    fn dispatch(&self, stream: TcpStream, downstream: std::net::SocketAddr, listener: &Listener) {
+        let backend = listener.backend().map(str::to_string);
+        tokio::spawn(self.clone().handle(stream, downstream, backend));
+    }
+
+    /// Accepts on the sockets of one of the acceptors past the first, for as long as
+    /// [`NetworkLoadBalancer::run_forever`] runs.
+    async fn accept_forever(self, listeners: Vec<Listener>) {
+        let mut first = 0;
+        loop {
+            let (stream, addr, idx) = accept(&listeners, first).await;
+            first = idx + 1;
+            self.dispatch(stream, addr, &listeners[idx]);
+        }
+    }
+
+    /// Admits a connection accepted on a listener sending to `backend`, then proxies it.
+    async fn handle(
+        self,
+        stream: TcpStream,
+        downstream: std::net::SocketAddr,
+        backend: Option<String>,
+    ) {
+        let pool = self.pool_index(backend.as_deref());
+        let admitted = self.admission.admit(stream, downstream.ip(), pool).await;
+        let Some((stream, _open)) = admitted else {
+            return;
+        };
+        self.serve(stream, downstream, pool).await;
+    }
+
+    /// Checks the client against the security rules of `pool`, then proxies it to a peer of
+    /// the pool.
+    async fn serve(&self, stream: TcpStream, downstream: std::net::SocketAddr, pool: usize) {
+        let ip = downstream.ip();
+        let mut flow = self.connections.flows().start(downstream);
+
+        let rules = self.rules();
+        if let Err(reason) = self.admission.check(ip, pool) {
+            if let Some(flow) = flow {
+                flow.finish(FlowStep::Rejected {
+                    reason: reason.name().to_string(),
+                });
+            }
+            reset(stream);
+            return;
+        }
+        if let Some(flow) = &mut flow {
+            flow.step(FlowStep::Admitted);
+        }
+
+        self.connections.clients().connected(ip);
+        if let Some((peer, options)) = self.pick(&rules, pool) {
+            if let Some(flow) = &mut flow {
+                flow.step(FlowStep::Selected {
+                    peer: peer.address.as_string(),
+                });
+            }
+            let events = self.events.clone();
+            let audit = self.admission.audit.clone();
+            let metrics = self.metrics.clone();
+            let connections = self.connections.clone();
+            let connection = self.connections.register(downstream, peer.clone());
+            let host_filter = rules.pools[pool].host_filter.clone();
+            let upstream_tls = self.upstream_tls.get(&self.pool_names[pool]).cloned();
+            let access_entry = self
+                .access_log
+                .as_ref()
+                .map(|log| log.begin_session(downstream, peer.socket_addr()));
+            // every event of the session carries who it is between
+            let span = tracing::info_span!(
+                "connection",
+                id = connection.id(),
+                client = %downstream,
+                peer = %peer.address.as_string(),
+            );
+            let session = async move {
+                if let Some(deadline) = options.first_byte_timeout
+                    && let Err(e) = await_first_byte(&stream, deadline).await
+                {
+                    events.record(
+                        EventKind::Reject,
+                        format!("closed connection from {}: {}", downstream, e),
+                    );
+                    metrics.rejected("first_byte_timeout");
+                    connections.clients().rejected(ip);
+                    if let Some(audit) = audit {
+                        audit.reject(ip, "first_byte_timeout", Some(e.to_string()));
+                    }
+                    if let Some(flow) = flow {
+                        flow.finish(FlowStep::Rejected {
+                            reason: "first_byte_timeout".to_string(),
+                        });
+                    }
+                    return;
+                }
+
+                if let Some(filter) = host_filter {
+                    let deadline = options.first_byte_timeout.unwrap_or(HOST_PEEK_TIMEOUT);
+                    let host = match peek_hostname(&stream, deadline).await {
+                        Ok(host) => host,
+                        Err(e) => {
+                            events.record(
+                                EventKind::Reject,
+                                format!("closed connection from {}: {}", downstream, e),
+                            );
+                            // a client that left before naming a host wasn't turned away
+                            if e.kind() == io::ErrorKind::TimedOut {
+                                metrics.rejected("handshake_timeout");
+                                connections.clients().rejected(ip);
+                                if let Some(audit) = audit {
+                                    audit.reject(ip, "handshake_timeout", Some(e.to_string()));
+                                }
+                            }
+                            if let Some(flow) = flow {
+                                flow.finish(FlowStep::Rejected {
+                                    reason: e.to_string(),
+                                });
+                            }
+                            return;
+                        }
+                    };
+
+                    if let Err(reason) = filter.check(host.as_deref()) {
+                        let host = host.unwrap_or_else(|| "no host".to_string());
+                        events.record(
+                            EventKind::Reject,
+                            format!(
+                                "rejected connection from {}: {} ({})",
+                                downstream,
+                                reason.name(),
+                                host
+                            ),
+                        );
+                        connections.clients().rejected(ip);
+                        if let Some(audit) = audit {
+                            audit.reject(ip, reason.name(), Some(host));
+                        }
+                        if let Some(flow) = flow {
+                            flow.finish(FlowStep::Rejected {
+                                reason: reason.name().to_string(),
+                            });
+                        }
+                        reset(stream);
+                        return;
+                    }
+                }
+
+                // a name that stopped resolving fails the session, not the process
+                let Some(socket_addr) = peer.socket_addr() else {
+                    let error = format!(
+                        "peer {} has no socket address for {}, see GET /dns",
+                        peer.address.as_string(),
+                        downstream
+                    );
+                    peer.stats().failed();
+                    tracing::error!("{}", error);
+                    events.record(EventKind::Error, error.clone());
+                    if let Some(entry) = access_entry {
+                        entry.finish((0, 0), SessionEnd::Error);
+                    }
+                    if let Some(flow) = flow {
+                        flow.finish(FlowStep::Closed {
+                            end: SessionEnd::Error.name(),
+                            sent: 0,
+                            received: 0,
+                            error: Some(error),
+                        });
+                    }
+                    return;
+                };
+
+                let stats = peer.stats();
+                let transfer = connection.transfer();
+                let proxy = async {
+                    match &upstream_tls {
+                        Some(tls) => {
+                            NetworkLoadBalancer::proxy_tls_connection(
+                                stream,
+                                socket_addr,
+                                options,
+                                tls,
+                                stats,
+                                transfer,
+                            )
+                            .await
+                        }
+                        None => {
+                            NetworkLoadBalancer::proxy_connection(
+                                stream,
+                                socket_addr,
+                                options,
+                                stats,
+                                transfer,
+                            )
+                            .await
+                        }
+                    }
+                };
+                let proxy = async {
+                    tokio::select! {
+                        proxied = proxy => proxied,
+                        _ = connection.terminated() => Err(Terminated.into()),
+                    }
+                };
+                let proxied = match &mut flow {
+                    Some(flow) => flow.follow(proxy, transfer).await,
+                    None => proxy.await,
+                };
+                let (sent, received) = transfer.copied();
+                let end = session_end(&proxied, transfer);
+                connections.clients().transferred(ip, (sent, received));
+                if let Some(entry) = access_entry {
+                    entry.finish((sent, received), end);
+                }
+                if let Some(flow) = flow {
+                    flow.finish(FlowStep::Closed {
+                        end: end.name(),
+                        sent,
+                        received,
+                        error: proxied.as_ref().err().map(|e| e.to_string()),
+                    });
+                }
+
+                match proxied {
+                    Ok((from_client, to_client)) => {
+                        peer.stats().proxied(from_client, to_client);
+                        metrics.proxied(Direction::FromClient, from_client);
+                        metrics.proxied(Direction::ToClient, to_client);
+                    }
+                    Err(e) if is_request_timeout(&e) => {
+                        peer.request_timed_out();
+                        events.record(
+                            EventKind::Error,
+                            format!("closed {} -> {}: {}", downstream, socket_addr, e),
+                        );
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        tracing::info!("closed idle session {} -> {}", downstream, socket_addr);
+                    }
+                    Err(e) if is_terminated(&e) => {
+                        tracing::info!("terminated session {} -> {}", downstream, socket_addr);
+                    }
+                    Err(e) => {
+                        peer.stats().failed();
+                        tracing::error!("error proxying {} to {}: {}", downstream, socket_addr, e);
+                        events.record(
+                            EventKind::Error,
+                            format!("error proxying {} to {}: {}", downstream, socket_addr, e),
+                        );
+                    }
+                }
+            };
+            session.instrument(span).await;
+        } else if let Some(flow) = flow {
+            flow.finish(FlowStep::Rejected {
+                reason: "no_ready_peer".to_string(),
+            });
+        }
+    }
+}
+
+impl NetworkLoadBalancer {
+    async fn proxy_tls_connection(
+        mut incoming: TcpStream,
+        upstream: std::net::SocketAddr,
+        options: ProxyOptions,
+        tls: &UpstreamTls,
+        stats: &PeerStats,
+        transfer: &Transfer,
+    ) -> Result<(u64, u64), io::Error> {
+        let started = Instant::now();
+        options.socket.apply(&incoming)?;
+        let connect = async {
+            let socket = tcpsocket_from_address(&upstream)?;
+            options.socket.apply(&socket)?;
+            let outgoing = socket.connect(upstream).await?;
+            tls.connect(outgoing, upstream).await
+        };
+        let mut outgoing =
+            within(options.connect_timeout, connect, ProxyError::ConnectTimeout).await?;
+        stats.connect_latency().record(started.elapsed());
+
+        let (idle_timeout, buffer_size) = (options.idle_timeout, options.buffer_size);
+        let session =
+            relay_tracked(&mut incoming, &mut outgoing, idle_timeout, transfer, buffer_size);
+        let proxied = within(options.session_timeout, session, ProxyError::SessionTimeout).await;
+        stats.session_duration().record(started.elapsed());
+        proxied
     }
 }
 
 impl TcpProxy for NetworkLoadBalancer {
     async fn proxy_connection(
-        mut incoming: TcpStream,
+        incoming: TcpStream,
         upstream: std::net::SocketAddr,
-    ) -> Result<(), io::Error> {
+        options: ProxyOptions,
+        stats: &PeerStats,
+        transfer: &Arc<Transfer>,
+    ) -> Result<(u64, u64), io::Error> {
+        let started = Instant::now();
+        options.socket.apply(&incoming)?;
         let socket = tcpsocket_from_address(&upstream)?;
-        let mut outgoing = socket.connect(upstream).await?;
+        options.socket.apply(&socket)?;
+        let connect = socket.connect(upstream);
+        let outgoing =
+            within(options.connect_timeout, connect, ProxyError::ConnectTimeout).await?;
+        stats.connect_latency().record(started.elapsed());
+
+        let (idle_timeout, buffer_size) = (options.idle_timeout, options.buffer_size);
+        let session = relay_tcp(
+            incoming,
+            outgoing,
+            idle_timeout,
+            transfer,
+            buffer_size,
+            options.relay,
+        );
+        let proxied = within(options.session_timeout, session, ProxyError::SessionTimeout).await;
+        stats.session_duration().record(started.elapsed());
+        proxied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::PoolSecurity;
+    use tokio::io::AsyncReadExt;
 
-        let (_, _) = copy_bidirectional(&mut incoming, &mut outgoing).await?;
+    /// A peer sending back whatever it is sent.
+    async fn echo_peer() -> std::net::SocketAddr {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_accept_loop_runs_while_a_connection_waits() {
+        let upstream_addr = echo_peer().await;
+        let delay = PoolSecurity {
+            on_limit: Some(LimitAction::Delay),
+            ..Default::default()
+        };
+        let backend = BackendOptions::new("default")
+            .with_security(delay)
+            .with_peer(upstream_addr, 1);
+        let config = |max| {
+            Config::builder()
+                .with_backend(backend.clone())
+                .with_max_connections(max)
+                .build()
+                .unwrap()
+        };
+        let (reload, reloads) = mpsc::channel(1);
+        let balancer = NetworkLoadBalancer::new_from_config(&config(1)).with_reloads(reloads);
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = front.local_addr().unwrap();
+        tokio::spawn(async move { balancer.run_forever(vec![front.into()]).await });
+
+        let mut buf = [0u8; 3];
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"one").await.unwrap();
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"one");
+
+        // held back by max_connections, while the accept loop carries on and takes the reload
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(b"two").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        reload.send(config(2)).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_secs(2), second.read_exact(&mut buf));
+        echoed.await.unwrap().unwrap();
+        assert_eq!(&buf, b"two");
+    }
+
+    #[tokio::test]
+    async fn test_pool_security_applies_on_its_listeners() {
+        let upstream_addr = echo_peer().await;
+        let whitelist = PoolSecurity {
+            ip_whitelist: Some(["10.0.0.1".parse().unwrap()].into()),
+            ..Default::default()
+        };
+        let cfg = Config::builder()
+            .with_backend(BackendOptions::new("open").with_peer(upstream_addr, 1))
+            .with_backend(
+                BackendOptions::new("locked")
+                    .with_security(whitelist)
+                    .with_peer(upstream_addr, 1),
+            )
+            .build()
+            .unwrap();
+        let balancer = NetworkLoadBalancer::new_from_config(&cfg);
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let locked = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (open_addr, locked_addr) = (open.local_addr().unwrap(), locked.local_addr().unwrap());
+        let listeners = vec![open.into(), Listener::new(locked, Some("locked".to_string()))];
+        tokio::spawn(async move { balancer.run_forever(listeners).await });
+
+        let mut client = TcpStream::connect(open_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // 127.0.0.1 isn't on the locked pool's whitelist
+        let mut client = TcpStream::connect(locked_addr).await.unwrap();
+        let _ = client.write_all(b"ping").await;
+        let read = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf));
+        assert!(!matches!(read.await.unwrap(), Ok(n) if n > 0));
+    }
+
+    #[tokio::test]
+    async fn test_acceptors_share_the_listener_address() {
+        assert!(Config::builder().with_acceptors(0).build().is_err());
+        let cfg = Config::builder()
+            .with_listener("127.0.0.1:0".parse().unwrap())
+            .with_peer(echo_peer().await, 1)
+            .with_acceptors(3)
+            .build()
+            .unwrap();
+        let listeners = bind_tcp_listeners(&cfg).unwrap();
+        assert_eq!(listeners.len(), 3);
+        let addr = listeners[0].local_addr().unwrap();
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap() == addr));
+        let acceptors: Vec<usize> = listeners.iter().map(|l| l.acceptor).collect();
+        assert_eq!(acceptors, vec![0, 1, 2]);
+
+        let balancer = NetworkLoadBalancer::new_from_config(&cfg);
+        let running = tokio::spawn(async move { balancer.run_forever(listeners).await });
+        for _ in 0..12 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+
+        // the other acceptors stop with it and close their sockets too
+        running.abort();
+        let _ = running.await;
+        let closed = async {
+            while TcpStream::connect(addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), closed).await.unwrap();
+        for _ in 0..12 {
+            assert!(TcpStream::connect(addr).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_timeout_closes_both_sides() {
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        let (client, accepted) = tokio::join!(
+            TcpStream::connect(front.local_addr().unwrap()),
+            front.accept()
+        );
+        let (mut client, (incoming, _)) = (client.unwrap(), accepted.unwrap());
+
+        let options = ProxyOptions {
+            session_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let stats = PeerStats::default();
+        let transfer = Arc::new(Transfer::default());
+        let (proxied, accepted) = tokio::join!(
+            NetworkLoadBalancer::proxy_connection(
+                incoming,
+                upstream_addr,
+                options,
+                &stats,
+                &transfer,
+            ),
+            upstream.accept()
+        );
+        let err = proxied.unwrap_err();
+        assert!(is_request_timeout(&err));
+        assert_eq!(session_end(&Err(err), &transfer), SessionEnd::Timeout);
+        assert_eq!(stats.connect_latency().snapshot().count(), 1);
+        let session = stats.session_duration().snapshot();
+        assert_eq!(session.count(), 1);
+        assert!(session.quantile(1.0) >= Duration::from_millis(100));
 
-        Ok(())
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        let (mut upstream_side, _) = accepted.unwrap();
+        assert_eq!(upstream_side.read(&mut buf).await.unwrap(), 0);
     }
 }
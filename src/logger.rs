@@ -0,0 +1,559 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::{
+    Layer, Registry,
+    filter::Targets,
+    fmt::{MakeWriter, format, time::FormatTime},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+};
+
+use crate::config::Config;
+
+/// `[logging] format`: `text` is a line of level, target and message, preceded by the spans
+/// the event happened in with their fields; `json` is one object per line, the fields of the
+/// innermost span under `span`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `[logging] log_target`: where events go, `file` being `path`. `syslog` and `journald` send
+/// each event to the local daemon with the priority of its level, leaving the time to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTarget {
+    #[default]
+    File,
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// `daemon`, the syslog facility events are filed under.
+const SYSLOG_FACILITY: u8 = 3;
+const IDENTIFIER: &str = "jalb";
+
+/// What a reload changing `log_level` or `[logging.filters]` swaps in.
+type LevelHandle = reload::Handle<Targets, Registry>;
+
+static LEVELS: OnceLock<LevelHandle> = OnceLock::new();
+
+/// Writes log events to `[logging] path`, one line each. With `rotate_logs` the file is moved
+/// to `jalb.log.1` once it would grow past `log_capacity_mb`, shifting older archives up and
+/// deleting the one past `log_archives`.
+pub struct FileLogger {
+    file: Mutex<LogFile>,
+}
+
+/// A log file rotated the way [`FileLogger`] describes, shared with the access log.
+pub(crate) struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// `None` when rotation is off
+    max_size: Option<u64>,
+    archives: usize,
+}
+
+impl FileLogger {
+    pub fn open(path: &Path, max_size: Option<u64>, archives: usize) -> Result<Self, io::Error> {
+        Ok(Self {
+            file: Mutex::new(LogFile::open(path, max_size, archives)?),
+        })
+    }
+
+    pub fn from_config(cfg: &Config) -> Result<Self, io::Error> {
+        let max_size = cfg.rotate_logs().then_some(cfg.log_file_max_size() as u64);
+        Self::open(cfg.logfile_path().as_path(), max_size, cfg.log_archives())
+    }
+}
+
+/// One event's line, written to the file as a whole.
+pub struct LogWriter<'a> {
+    file: &'a Mutex<LogFile>,
+}
+
+impl Write for LogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for FileLogger {
+    type Writer = LogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter { file: &self.file }
+    }
+}
+
+/// Sends each event to syslog or journald as a datagram of its own.
+pub struct SystemLogger {
+    socket: UnixDatagram,
+    journald: bool,
+}
+
+impl SystemLogger {
+    /// Connects to the local daemon of `target`, `Syslog` or `Journald`.
+    pub fn connect(target: LogTarget) -> Result<Self, io::Error> {
+        let journald = target == LogTarget::Journald;
+        let path = if journald {
+            JOURNALD_SOCKET
+        } else {
+            SYSLOG_SOCKET
+        };
+        Self::connect_to(Path::new(path), journald)
+    }
+
+    fn connect_to(path: &Path, journald: bool) -> Result<Self, io::Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self { socket, journald })
+    }
+
+    /// `message` as syslog's `<priority>jalb[pid]: message`, or as journald's fields.
+    fn datagram(&self, level: Level, message: &[u8]) -> Vec<u8> {
+        let severity = severity(level);
+        if !self.journald {
+            let priority = SYSLOG_FACILITY * 8 + severity;
+            let mut datagram =
+                format!("<{}>{}[{}]: ", priority, IDENTIFIER, std::process::id()).into_bytes();
+            datagram.extend_from_slice(message);
+            return datagram;
+        }
+
+        let mut datagram =
+            format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\n", severity, IDENTIFIER).into_bytes();
+        if message.contains(&b'\n') {
+            // a value spanning lines goes after the name as its length and its bytes
+            datagram.extend_from_slice(b"MESSAGE\n");
+            datagram.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        } else {
+            datagram.extend_from_slice(b"MESSAGE=");
+        }
+        datagram.extend_from_slice(message);
+        datagram.push(b'\n');
+        datagram
+    }
+
+    fn writer(&self, level: Level) -> SystemLogWriter<'_> {
+        SystemLogWriter {
+            logger: self,
+            level,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// syslog's severity for `level`: err, warning, info or debug.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// One event, sent once all of it has been written.
+pub struct SystemLogWriter<'a> {
+    logger: &'a SystemLogger,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for SystemLogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SystemLogWriter<'_> {
+    fn drop(&mut self) {
+        let message = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        if message.is_empty() {
+            return;
+        }
+        // a daemon that went away leaves nowhere to say so
+        let _ = self
+            .logger
+            .socket
+            .send(&self.logger.datagram(self.level, message));
+    }
+}
+
+impl<'a> MakeWriter<'a> for SystemLogger {
+    type Writer = SystemLogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.writer(*meta.level())
+    }
+}
+
+/// Installs the logger for `[logging] log_target` as the global subscriber. Crates still
+/// logging through `log` are passed on to it.
+pub fn init(cfg: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    match cfg.log_target() {
+        LogTarget::File => install(cfg, FileLogger::from_config(cfg)?, true),
+        LogTarget::Stdout => install(cfg, io::stdout, true),
+        target => install(cfg, SystemLogger::connect(target)?, false),
+    }
+}
+
+fn install<W>(cfg: &Config, writer: W, timestamps: bool) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let levels = targets(cfg.log_level(), cfg.log_filters());
+    let (subscriber, handle) = subscriber(cfg.log_format(), timestamps, levels, writer);
+    subscriber.try_init()?;
+    let _ = LEVELS.set(handle);
+    set_log_max_level(cfg);
+    Ok(())
+}
+
+/// Applies a reloaded `log_level` and `[logging.filters]` from the next event on.
+pub fn reload_levels(cfg: &Config) {
+    if let Some(levels) = LEVELS.get()
+        && let Err(e) = levels.reload(targets(cfg.log_level(), cfg.log_filters()))
+    {
+        tracing::error!("failed to apply reloaded log levels: {}", e);
+    }
+    set_log_max_level(cfg);
+}
+
+/// Records from crates on `log` are dropped before reaching the filters unless `log` lets
+/// through the most verbose level any module is set to.
+fn set_log_max_level(cfg: &Config) {
+    let most_verbose = cfg.log_filters().values().copied().max();
+    let level = most_verbose.map_or(cfg.log_level(), |filter| filter.max(cfg.log_level()));
+    log::set_max_level(level.to_level_filter());
+}
+
+/// `default` for every event, but the level set for the module in `filters` for events from
+/// it or its submodules.
+fn targets(default: log::Level, filters: &BTreeMap<String, log::Level>) -> Targets {
+    Targets::new().with_default(level(default)).with_targets(
+        filters
+            .iter()
+            .map(|(module, filter)| (module.clone(), level(*filter))),
+    )
+}
+
+fn level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::ERROR,
+        log::Level::Warn => Level::WARN,
+        log::Level::Info => Level::INFO,
+        log::Level::Debug => Level::DEBUG,
+        log::Level::Trace => Level::TRACE,
+    }
+}
+
+/// Events filtered by `levels` and written to `writer` in `format`, with the handle to change
+/// the levels by. Without `timestamps` the time is left to whatever receives the events.
+fn subscriber<W>(
+    format: LogFormat,
+    timestamps: bool,
+    levels: Targets,
+    writer: W,
+) -> (impl Subscriber + Send + Sync, LevelHandle)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (levels, handle) = reload::Layer::new(levels);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(writer);
+    let json = format::json().flatten_event(true).with_span_list(false);
+    let layer = match format {
+        LogFormat::Text if timestamps => layer.with_timer(Timestamp).boxed(),
+        LogFormat::Text => layer.without_time().boxed(),
+        LogFormat::Json if timestamps => layer
+            .event_format(json.with_timer(Timestamp))
+            .fmt_fields(format::JsonFields::new())
+            .boxed(),
+        LogFormat::Json => layer
+            .event_format(json.without_time())
+            .fmt_fields(format::JsonFields::new())
+            .boxed(),
+    };
+    (Registry::default().with(levels).with(layer), handle)
+}
+
+/// Stamps events the way the access log does.
+struct Timestamp;
+
+impl FormatTime for Timestamp {
+    fn format_time(&self, w: &mut format::Writer<'_>) -> fmt::Result {
+        write!(w, "{}", timestamp(SystemTime::now()))
+    }
+}
+
+impl LogFile {
+    pub(crate) fn open(
+        path: &Path,
+        max_size: Option<u64>,
+        archives: usize,
+    ) -> Result<Self, io::Error> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            archives,
+        })
+    }
+
+    pub(crate) fn write(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        if let Some(max_size) = self.max_size
+            && self.size > 0
+            && self.size + line.len() as u64 > max_size
+        {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `jalb.log.N-1` becomes `jalb.log.N` down to `jalb.log` becoming `jalb.log.1`, then a new
+    /// `jalb.log` is started. Without archives the log is truncated instead.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        if self.archives > 0 {
+            let _ = fs::remove_file(self.archive(self.archives));
+            for n in (1..self.archives).rev() {
+                let from = self.archive(n);
+                if from.exists() {
+                    fs::rename(from, self.archive(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.archive(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn archive(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+/// `time` as UTC in RFC 3339 with milliseconds, e.g. `2024-05-01T12:00:00.000Z`.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let utc = Utc::from(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        utc.year, utc.month, utc.day, utc.hour, utc.minute, utc.second, utc.millis
+    )
+}
+
+/// A point in time as a UTC date and time of day.
+pub(crate) struct Utc {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    pub millis: u32,
+}
+
+impl From<SystemTime> for Utc {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        // civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil inverse
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3_600,
+            minute: secs_of_day % 3_600 / 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rotation_keeps_archives() {
+        let dir = std::env::temp_dir().join(format!("jalb-logger-{}", std::process::id()));
+        let path = dir.join("jalb.log");
+        let logger = FileLogger::open(&path, Some(64), 2).unwrap();
+
+        for line in ["a", "b", "c", "d"] {
+            logger
+                .file
+                .lock()
+                .unwrap()
+                .write(format!("{}\n", line.repeat(40)).as_bytes())
+                .unwrap();
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&path).starts_with('d'));
+        assert!(read(&dir.join("jalb.log.1")).starts_with('c'));
+        assert!(read(&dir.join("jalb.log.2")).starts_with('b'));
+        assert!(!dir.join("jalb.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let time = UNIX_EPOCH + Duration::from_millis(951_782_400_123);
+        assert_eq!(timestamp(time), "2000-02-29T00:00:00.123Z");
+    }
+
+    #[test]
+    fn test_structured_events() {
+        let dir = std::env::temp_dir().join(format!("jalb-events-{}", std::process::id()));
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        let filters = BTreeMap::from([("jalb::logger".to_string(), log::Level::Debug)]);
+
+        let path = dir.join("json.log");
+        let logger = FileLogger::open(&path, None, 0).unwrap();
+        let levels = targets(log::Level::Warn, &filters);
+        let (json, _) = subscriber(LogFormat::Json, true, levels, logger);
+        tracing::subscriber::with_default(json, || {
+            let span = tracing::info_span!("connection", id = 7, peer = "10.0.0.1:80");
+            let _entered = span.enter();
+            tracing::debug!("relayed {} bytes", 12);
+            tracing::debug!(target: "jalb::health", "left out");
+        });
+        let line = read(&path);
+        assert_eq!(line.lines().count(), 1, "{}", line);
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["level"], "DEBUG");
+        assert_eq!(event["message"], "relayed 12 bytes");
+        assert_eq!(event["span"]["id"], 7);
+        assert_eq!(event["span"]["peer"], "10.0.0.1:80");
+
+        let path = dir.join("text.log");
+        let logger = FileLogger::open(&path, None, 0).unwrap();
+        let (text, levels) = subscriber(
+            LogFormat::Text,
+            true,
+            targets(log::Level::Warn, &filters),
+            logger,
+        );
+        tracing::subscriber::with_default(text, || {
+            let span = tracing::info_span!("connection", id = 7);
+            let _entered = span.enter();
+            tracing::info!(target: "jalb::health", "left out");
+            levels.reload(targets(log::Level::Info, &filters)).unwrap();
+            tracing::info!(target: "jalb::health", "peer down");
+        });
+        let line = read(&path);
+        assert_eq!(line.lines().count(), 1, "{}", line);
+        assert!(
+            line.contains(" INFO connection{id=7}: jalb::health: peer down"),
+            "{}",
+            line
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_system_log_priorities() {
+        let dir = std::env::temp_dir().join(format!("jalb-syslog-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.sock");
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let mut buf = [0u8; 1024];
+        let mut receive = || {
+            let len = daemon.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+
+        let syslog = SystemLogger::connect_to(&path, false).unwrap();
+        let levels = targets(log::Level::Info, &BTreeMap::new());
+        let (text, _) = subscriber(LogFormat::Text, false, levels, syslog);
+        tracing::subscriber::with_default(text, || {
+            tracing::warn!("peer down");
+            tracing::debug!("left out");
+            tracing::error!("no peers left");
+        });
+        let pid = std::process::id();
+        assert_eq!(
+            receive(),
+            format!("<28>jalb[{}]:  WARN jalb::logger::tests: peer down", pid)
+        );
+        assert!(receive().starts_with(&format!("<27>jalb[{}]: ", pid)));
+
+        let journald = SystemLogger::connect_to(&path, true).unwrap();
+        let levels = targets(log::Level::Info, &BTreeMap::new());
+        let (text, _) = subscriber(LogFormat::Text, false, levels, journald);
+        tracing::subscriber::with_default(text, || tracing::info!("two\nlines"));
+        let datagram = receive();
+        assert!(
+            datagram.starts_with("PRIORITY=6\nSYSLOG_IDENTIFIER=jalb\nMESSAGE\n"),
+            "{:?}",
+            datagram
+        );
+        assert!(datagram.ends_with("two\nlines\n"), "{:?}", datagram);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,26 +1,45 @@
-use std::{
-    io,
-    sync::{
-        atomic::{AtomicI32, AtomicI64, Ordering}, Arc
-    },
-    time::{Duration, Instant},
-};
-
-use tokio::{self, net::TcpListener};
-
-use config::Config;
-
-use load_balancer::NetworkLoadBalancer;
+use std::{io, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use crate::load_balancer::TcpProxy;
+use clap::Parser;
+use tokio::{
+    self,
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+};
 
-mod backend;
-mod config;
-mod errors;
-mod load_balancer;
-mod peer;
-mod security;
-mod selector;
+#[cfg(feature = "grpc")]
+use jalb::grpc;
+#[cfg(feature = "spiffe")]
+use jalb::spiffe;
+use jalb::{
+    access_log::AccessLog,
+    activation, admin,
+    application::ApplicationLoadBalancer,
+    audit::AuditLog,
+    backend::Backend,
+    bluegreen::BlueGreen,
+    config::{
+        BackendOptions, Config, ConfigFormat, ConfigOverrides, LoadBalancerStrategy,
+        LoadBalancerType, NetworkTarget, TransportProtocol,
+    },
+    connections::{self, ConnectionRegistry},
+    events::EventLog,
+    health::{self, HealthGuard},
+    init,
+    load_balancer::{self, Listener, NetworkLoadBalancer},
+    logger,
+    metrics::{Metrics, MetricsState},
+    pages::Maintenance,
+    peer::Peer,
+    reload,
+    security::Security,
+    selftest,
+    split::TrafficSplit,
+    store,
+    udp::UdpLoadBalancer,
+    upstream_tls::UpstreamTls,
+    webhook,
+};
 
 // make a load balancer with the following requirements:
 // 1. Multi-strategy (e.g. Round Robin, Least Connections, Weighted Round Robin, Geo-based, etc.)
@@ -32,41 +51,511 @@ mod selector;
 #[derive(clap::Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Config file, also read from JALB_CONFIG [default: ./jalb.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Format of the config file: toml, yaml or json, also read from JALB_CONFIG_FORMAT
+    /// [default: from the file extension, toml otherwise]
+    #[arg(long)]
+    config_format: Option<ConfigFormat>,
+
+    /// Overrides [loadbalancer] listener_address, also read from JALB_LISTENER_ADDR
+    #[arg(long)]
+    listener_addr: Option<IpAddr>,
+
+    /// Overrides [loadbalancer] port, also read from JALB_PORT
     #[arg(long)]
-    listener_addr: String,
+    port: Option<u16>,
 
+    /// Overrides [runtime] worker_threads, also read from JALB_WORKER_THREADS
     #[arg(long)]
-    port: u16,
+    worker_threads: Option<usize>, // log_level: LogLevel
 
+    /// Applies the config's [profiles.<name>] over the rest of it, also read from JALB_PROFILE
     #[arg(long)]
-    worker_threads: usize, // log_level: LogLevel
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the configured balancer against stub peers on an ephemeral port and report
+    /// pass/fail per subsystem
+    SelfTest {
+        /// Number of synthetic connections to push through the balancer
+        #[arg(long, default_value_t = 100)]
+        connections: usize,
+    },
+    /// Write a commented starter config that loads as is
+    Init {
+        /// Where to write it
+        #[arg(long, default_value = "jalb.toml")]
+        output: PathBuf,
+        /// round_robin, least_used or weighted_average
+        #[arg(long, default_value = "round_robin")]
+        strategy: LoadBalancerStrategy,
+        /// Comma separated peers, e.g. a:80,b:80 [default: 127.0.0.1:8080]
+        #[arg(long, value_delimiter = ',')]
+        peers: Vec<NetworkTarget>,
+        /// Overwrite the file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration as TOML: the file with includes, ${VAR} expansion and
+    /// the command line and environment overrides applied, defaults filled in and secrets
+    /// shown as ***
+    Dump,
+}
+
+const BLACKLIST_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const LIST_FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the admin api shows and changes of the security rules and routes, following reloads,
+/// and whether the listeners are serving yet.
+struct AdminWatches {
+    listening: watch::Receiver<bool>,
+    security: watch::Receiver<Security>,
+    splits: watch::Receiver<Vec<Arc<TrafficSplit>>>,
+    blue_greens: watch::Receiver<Vec<Arc<BlueGreen>>>,
+    maintenances: watch::Receiver<Vec<Arc<Maintenance>>>,
+}
+
+impl AdminWatches {
+    /// Routes, split ones included, need type = "application".
+    fn without_routes(
+        listening: watch::Receiver<bool>,
+        security: watch::Receiver<Security>,
+    ) -> Self {
+        Self {
+            listening,
+            security,
+            splits: watch::channel(Vec::new()).1,
+            blue_greens: watch::channel(Vec::new()).1,
+            maintenances: watch::channel(Vec::new()).1,
+        }
+    }
+
+    fn of(listening: watch::Receiver<bool>, load_balancer: &ApplicationLoadBalancer) -> Self {
+        Self {
+            listening,
+            security: load_balancer.watch_security(),
+            splits: load_balancer.watch_splits(),
+            blue_greens: load_balancer.watch_blue_greens(),
+            maintenances: load_balancer.watch_maintenances(),
+        }
+    }
+}
+
+async fn start_admin(
+    cfg: &Config,
+    events: Arc<EventLog>,
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+    config: watch::Receiver<String>,
+    watches: AdminWatches,
+    connections: Arc<ConnectionRegistry>,
+    health: Arc<HealthGuard>,
+) -> Result<(), io::Error> {
+    let Some(admin_addr) = cfg.admin_address() else {
+        return Ok(());
+    };
+
+    let admin_listener = TcpListener::bind(admin_addr).await?;
+    let state = Arc::new(admin::AdminState {
+        events,
+        peers,
+        config,
+        splits: watches.splits,
+        blue_greens: watches.blue_greens,
+        maintenances: watches.maintenances,
+        security: watches.security,
+        connections,
+        health,
+        listening: watches.listening,
+        required_pools: cfg.required_pools(),
+        strategy: cfg.strategy(),
+        auth: admin::AdminAuth::new(cfg.admin_tokens()),
+    });
+
+    if cfg.admin_tokens().is_empty() {
+        tracing::warn!("no admin tokens configured, admin api on {} is read-only", admin_addr);
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = cfg.admin_grpc_address() {
+        tokio::spawn(grpc::serve(grpc_addr, state.clone()));
+        tracing::info!("admin grpc server listening on {}", grpc_addr);
+    }
+
+    tokio::spawn(admin::serve(admin_listener, state));
+    tracing::info!("admin server listening on {}", admin_addr);
+
+    Ok(())
 }
 
-pub async fn listen_and_serve(
-    listener: tokio::net::TcpListener,
-    load_balancer: NetworkLoadBalancer,
+/// Serves `[metrics]`, when configured, from the balancer's counters.
+async fn start_metrics(
+    cfg: &Config,
+    metrics: Arc<Metrics>,
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+    connections: Arc<ConnectionRegistry>,
 ) -> Result<(), io::Error> {
-    while let Ok((stream, addr)) = listener.accept().await {}
+    let Some(metrics_addr) = cfg.metrics_address() else {
+        return Ok(());
+    };
+
+    let listener = TcpListener::bind(metrics_addr).await?;
+    let state = Arc::new(MetricsState {
+        metrics,
+        peers,
+        connections,
+        security: cfg.pool_security(),
+        strategy: cfg.strategy(),
+    });
+    connections::spawn_throughput_meter(state.connections.clone());
+    tokio::spawn(jalb::metrics::serve(listener, state));
+    tracing::info!("metrics server listening on {}", metrics_addr);
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cfg = Config::load_from_file("./jalb.toml")?;
-    let listener_addr = cfg.listener_address();
-    let listener = TcpListener::bind(listener_addr).await?;
+/// TLS to the peers of `backend` with the workload's SPIFFE identity, `None` unless its
+/// `[backend.spiffe]` is set.
+fn upstream_tls(
+    cfg: &Config,
+    backend: &BackendOptions,
+) -> Result<Option<UpstreamTls>, Box<dyn std::error::Error>> {
+    let Some(socket) = cfg.spiffe_socket_for(backend) else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "spiffe")]
+    {
+        tracing::info!(
+            "fetching upstream tls identity for backend {} from {}",
+            backend.name,
+            socket.display()
+        );
+        let svids = spiffe::spawn_svid_watcher(socket, cfg.tls_policy_for(backend)?);
+        let server_name = cfg.upstream_server_name_for(backend);
+        Ok(Some(UpstreamTls::new(svids, server_name)?))
+    }
+
+    #[cfg(not(feature = "spiffe"))]
+    {
+        let _ = socket;
+        Err(jalb::errors::SpiffeError::SpiffeDisabled.into())
+    }
+}
+
+/// `[tls]` listeners, routes and upstream re-encryption are validated but not acted on yet.
+fn warn_unimplemented_tls(cfg: &Config) {
+    let tls = cfg.tls();
+    for listener in tls.listeners() {
+        tracing::warn!(
+            "tls on {} is not implemented yet, connections are proxied as plain tcp",
+            listener.address
+        );
+    }
+    if !tls.routes().is_empty() {
+        tracing::warn!(
+            "[[tls.route]] is not implemented yet, connections go to the default backend"
+        );
+    }
+    if cfg
+        .backends()
+        .iter()
+        .any(|backend| cfg.upstream_tls_config_for(backend).is_some())
+    {
+        tracing::warn!(
+            "[tls.upstream] is not implemented yet, peers are connected to in plain tcp"
+        );
+    }
+}
+
+impl From<&Args> for ConfigOverrides {
+    fn from(args: &Args) -> Self {
+        ConfigOverrides {
+            config_path: args.config.clone(),
+            config_format: args.config_format,
+            listener_address: args.listener_addr,
+            port: args.port,
+            worker_threads: args.worker_threads,
+            profile: args.profile.clone(),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    // before loading, there is no config yet to load
+    if let Some(Command::Init {
+        output,
+        strategy,
+        peers,
+        force,
+    }) = &args.command
+    {
+        init::write_sample(output, &init::sample_config(*strategy, peers)?, *force)?;
+        println!("wrote {}", output.display());
+        return Ok(());
+    }
+
+    let cfg = Config::load(ConfigOverrides::from(&args))?;
+
+    if let Some(Command::Config {
+        command: ConfigCommand::Dump,
+    }) = &args.command
+    {
+        print!("{}", cfg.dump()?);
+        return Ok(());
+    }
+
+    // built by hand rather than with #[tokio::main] so [runtime] can tune it
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = cfg.worker_threads() {
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = cfg.max_blocking_threads() {
+        runtime.max_blocking_threads(threads);
+    }
+    if let Some(ticks) = cfg.event_interval() {
+        runtime.event_interval(ticks);
+    }
+    if let Some(ticks) = cfg.global_queue_interval() {
+        runtime.global_queue_interval(ticks);
+    }
+    runtime.enable_all().build()?.block_on(run(args, cfg))
+}
+
+async fn run(args: Args, mut cfg: Config) -> Result<(), Box<dyn std::error::Error>> {
+    logger::init(&cfg)?;
+
+    if let Some(Command::SelfTest { connections }) = args.command {
+        if !selftest::run(&cfg, connections).await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(store) = store::from_config(&cfg)? {
+        cfg.security.set_state_store(store);
+        let restored = cfg.security.restore_bans()?;
+        tracing::info!("restored {} timed bans from the state store", restored);
+    }
+
+    // both balancers clone `cfg.security`, clones share their timed bans
+    cfg.security.spawn_blacklist_sweeper(BLACKLIST_SWEEP_INTERVAL);
+    cfg.security.load_list_files()?;
+    cfg.security.spawn_list_file_watcher(LIST_FILE_POLL_INTERVAL);
+
+    let audit_log = AuditLog::from_config(&cfg).await?;
+
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if let Some(threads) = cfg.io_uring_threads() {
+        jalb::uring::start(threads);
+    }
+
+    if cfg.protocol() == TransportProtocol::Udp {
+        // udp is limited to one listener when the config is loaded
+        let socket = match activation::inherited_sockets()? {
+            Some(sockets) => activation::udp_socket(sockets)?,
+            None => UdpSocket::bind(cfg.listeners()[0].address).await?,
+        };
+        let listener_addr = socket.local_addr()?;
+        let mut load_balancer = UdpLoadBalancer::new_from_config(&cfg, socket);
+        if let Some(audit) = audit_log.clone() {
+            load_balancer = load_balancer.with_audit_log(audit);
+        }
+        // config reloads only reach the tcp balancer, the udp peers stay as started. udp
+        // serves the default backend alone
+        let (_, peers) = watch::channel(load_balancer.peers());
+        let health = health::spawn_health_checks(
+            &Backend::from_config(cfg.backend()),
+            peers.clone(),
+            &load_balancer.events(),
+        );
+        webhook::spawn_notifier(cfg.webhooks().to_vec(), load_balancer.events(), peers.clone());
+        connections::spawn_reconciler(
+            load_balancer.connections(),
+            peers.clone(),
+            cfg.connection_reconcile_interval(),
+            cfg.connection_count_decay(),
+        );
+        let (_, config) = watch::channel(cfg.dump()?);
+        let listening = watch::Sender::new(false);
+        start_admin(
+            &cfg,
+            load_balancer.events(),
+            peers.clone(),
+            config,
+            AdminWatches::without_routes(
+                listening.subscribe(),
+                watch::channel(cfg.pool_security()).1,
+            ),
+            load_balancer.connections(),
+            health,
+        )
+        .await?;
+        start_metrics(
+            &cfg,
+            load_balancer.metrics(),
+            peers,
+            load_balancer.connections(),
+        )
+        .await?;
+
+        tracing::info!("udp load balancer listening on {}", listener_addr);
+        listening.send_replace(true);
+        load_balancer.run_forever().await;
+
+        return Ok(());
+    }
+
+    // under systemd socket activation the unit's sockets replace binding our own
+    let listeners = match activation::inherited_sockets()? {
+        Some(sockets) => activation::tcp_listeners(&cfg, sockets)?,
+        None => load_balancer::bind_tcp_listeners(&cfg)?,
+    };
+    warn_unimplemented_tls(&cfg);
+
+    if cfg.load_balancer_type() == LoadBalancerType::Application {
+        return serve_application(&args, &cfg, listeners, audit_log).await;
+    }
 
     let mut load_balancer = NetworkLoadBalancer::new_from_config(&cfg);
+    if let Some(audit) = audit_log {
+        load_balancer = load_balancer.with_audit_log(audit);
+    }
+    if let Some(access_log) = cfg.access_log() {
+        load_balancer = load_balancer.with_access_log(AccessLog::open(access_log)?);
+    }
+    for backend in cfg.backends() {
+        if let Some(tls) = upstream_tls(&cfg, backend)? {
+            load_balancer = load_balancer.with_upstream_tls(&backend.name, tls);
+        }
+    }
+    let health = load_balancer.spawn_health_checks();
+    webhook::spawn_notifier(
+        cfg.webhooks().to_vec(),
+        load_balancer.events(),
+        load_balancer.watch_peers(),
+    );
+    let reloads =
+        reload::spawn_config_reload(ConfigOverrides::from(&args), &cfg, load_balancer.events());
+    load_balancer = load_balancer.with_reloads(reloads);
+    connections::spawn_reconciler(
+        load_balancer.connections(),
+        load_balancer.watch_peers(),
+        cfg.connection_reconcile_interval(),
+        cfg.connection_count_decay(),
+    );
+    let listening = watch::Sender::new(false);
+    start_admin(
+        &cfg,
+        load_balancer.events(),
+        load_balancer.watch_peers(),
+        load_balancer.watch_config_dump(),
+        AdminWatches::without_routes(listening.subscribe(), load_balancer.watch_security()),
+        load_balancer.connections(),
+        health,
+    )
+    .await?;
+    start_metrics(
+        &cfg,
+        load_balancer.metrics(),
+        load_balancer.watch_peers(),
+        load_balancer.connections(),
+    )
+    .await?;
+
+    for listener in &listeners {
+        tracing::info!("load balancer listening on {}", listener.local_addr()?);
+    }
+    listening.send_replace(true);
+
+    load_balancer.run_forever(listeners).await;
 
-    println!(
-        "load balancer listening on {}:{}",
-        listener_addr.ip(),
-        listener_addr.port()
+    Ok(())
+}
+
+/// Serves `listeners` with the application balancer, set up the same way as the network one.
+async fn serve_application(
+    args: &Args,
+    cfg: &Config,
+    listeners: Vec<Listener>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut load_balancer = ApplicationLoadBalancer::new_from_config(cfg);
+    if let Some(audit) = audit_log {
+        load_balancer = load_balancer.with_audit_log(audit);
+    }
+    if let Some(access_log) = cfg.access_log() {
+        load_balancer = load_balancer.with_access_log(AccessLog::open(access_log)?);
+    }
+    for backend in cfg.backends() {
+        if let Some(tls) = upstream_tls(cfg, backend)? {
+            load_balancer = load_balancer.with_upstream_tls(&backend.name, tls);
+        }
+    }
+    #[cfg(feature = "http3")]
+    if let Some(http3) = cfg.http3() {
+        let listener = jalb::http3::Http3Listener::bind(http3)?;
+        tracing::info!("application load balancer answering http3 on {}", listener.local_addr()?);
+        load_balancer = load_balancer.with_http3(listener);
+    }
+    let health = load_balancer.spawn_health_checks();
+    webhook::spawn_notifier(
+        cfg.webhooks().to_vec(),
+        load_balancer.events(),
+        load_balancer.watch_peers(),
+    );
+    let reloads =
+        reload::spawn_config_reload(ConfigOverrides::from(args), cfg, load_balancer.events());
+    load_balancer = load_balancer.with_reloads(reloads);
+    connections::spawn_reconciler(
+        load_balancer.connections(),
+        load_balancer.watch_peers(),
+        cfg.connection_reconcile_interval(),
+        cfg.connection_count_decay(),
     );
+    let listening = watch::Sender::new(false);
+    start_admin(
+        cfg,
+        load_balancer.events(),
+        load_balancer.watch_peers(),
+        load_balancer.watch_config_dump(),
+        AdminWatches::of(listening.subscribe(), &load_balancer),
+        load_balancer.connections(),
+        health,
+    )
+    .await?;
+    start_metrics(
+        cfg,
+        load_balancer.metrics(),
+        load_balancer.watch_peers(),
+        load_balancer.connections(),
+    )
+    .await?;
 
+    for listener in &listeners {
+        tracing::info!("application load balancer listening on {}", listener.local_addr()?);
+    }
+    listening.send_replace(true);
 
-    load_balancer.run_forever(listener).await;
+    load_balancer.run_forever(listeners).await;
 
     Ok(())
 }
@@ -0,0 +1,703 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::{Display, Write},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use http::{Method, Request, Response, StatusCode, header};
+use http_body_util::Full;
+use hyper::{
+    body::{Body, Bytes, Frame, Incoming, SizeHint},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{
+    application::{BoxError, ProxyBody},
+    config::LoadBalancerStrategy,
+    connections::{ConnectionRegistry, Throughput},
+    dns,
+    histogram::{Histogram, HistogramSnapshot, QUANTILES},
+    peer::{Peer, PeerStats},
+    security::Security,
+    selector::SelectionDistribution,
+};
+
+/// `[metrics]`: counters and gauges in the Prometheus text format on `GET /metrics` at
+/// `address`, a listener of its own apart from the admin api so it can be scraped without a
+/// token.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub address: SocketAddr,
+}
+
+/// Which way proxied bytes went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    FromClient,
+    ToClient,
+}
+
+/// Counters kept by the balancers whether or not `[metrics]` serves them.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    accepted: AtomicU64,
+    /// connections shed over a limit, by reason; rejections by the security rules are counted
+    /// by [`Security`]
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+    /// requests a route turned away, by reason
+    requests_rejected: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_from_clients: AtomicU64,
+    bytes_to_clients: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A client connected, or with udp a new flow started.
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, reason: &'static str) {
+        *self.rejected.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn request_rejected(&self, reason: &'static str) {
+        let mut rejected = self.requests_rejected.lock().unwrap();
+        *rejected.entry(reason).or_default() += 1;
+    }
+
+    pub fn proxied(&self, direction: Direction, bytes: u64) {
+        let counter = match direction {
+            Direction::FromClient => &self.bytes_from_clients,
+            Direction::ToClient => &self.bytes_to_clients,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// What `GET /metrics` reads.
+pub struct MetricsState {
+    pub metrics: Arc<Metrics>,
+    /// every pool's peers, as of the latest reload
+    pub peers: watch::Receiver<Vec<Arc<Peer>>>,
+    pub connections: Arc<ConnectionRegistry>,
+    pub security: Security,
+    /// what the pools' selections are held against
+    pub strategy: LoadBalancerStrategy,
+}
+
+impl MetricsState {
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "jalb_connections_accepted_total",
+            "counter",
+            "Connections accepted, or with udp flows started.",
+        );
+        sample(
+            &mut out,
+            "jalb_connections_accepted_total",
+            &[],
+            metrics.accepted.load(Ordering::Relaxed),
+        );
+
+        family(
+            &mut out,
+            "jalb_connections_rejected_total",
+            "counter",
+            "Connections refused by the security rules or shed over a limit, by reason.",
+        );
+        let mut rejected = self.security.rejections();
+        rejected.extend(metrics.rejected.lock().unwrap().iter());
+        for (reason, count) in rejected {
+            sample(
+                &mut out,
+                "jalb_connections_rejected_total",
+                &[("reason", reason)],
+                count,
+            );
+        }
+
+        family(
+            &mut out,
+            "jalb_requests_rejected_total",
+            "counter",
+            "Requests turned away by a route's rate_limit or jwt, by reason.",
+        );
+        for (reason, count) in metrics.requests_rejected.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "jalb_requests_rejected_total",
+                &[("reason", reason)],
+                *count,
+            );
+        }
+
+        family(
+            &mut out,
+            "jalb_active_sessions",
+            "gauge",
+            "Connections, or with type = \"application\" requests, open to a peer.",
+        );
+        sample(
+            &mut out,
+            "jalb_active_sessions",
+            &[],
+            self.connections.len() as u64,
+        );
+
+        family(
+            &mut out,
+            "jalb_proxied_bytes_total",
+            "counter",
+            "Bytes proxied, by direction.",
+        );
+        let bytes = [
+            ("from_client", &metrics.bytes_from_clients),
+            ("to_client", &metrics.bytes_to_clients),
+        ];
+        for (direction, counter) in bytes {
+            let count = counter.load(Ordering::Relaxed);
+            sample(
+                &mut out,
+                "jalb_proxied_bytes_total",
+                &[("direction", direction)],
+                count,
+            );
+        }
+
+        let peers = self.peers.borrow().clone();
+        let per_peer: [(&str, &str, &str, PeerCounter); 4] = [
+            (
+                "jalb_peer_selections_total",
+                "counter",
+                "Times each peer was picked for a connection or request.",
+                PeerStats::connections,
+            ),
+            (
+                "jalb_peer_active_connections",
+                "gauge",
+                "Connections, or with type = \"application\" requests, open to each peer.",
+                PeerStats::active_connections,
+            ),
+            (
+                "jalb_peer_errors_total",
+                "counter",
+                "Connections and requests to each peer that failed other than by timing out.",
+                PeerStats::errors,
+            ),
+            (
+                "jalb_peer_request_timeouts_total",
+                "counter",
+                "Connections and requests to each peer cut off by its request timeout.",
+                PeerStats::request_timeouts,
+            ),
+        ];
+        for (name, kind, help, value) in per_peer {
+            family(&mut out, name, kind, help);
+            for peer in &peers {
+                let address = peer.address.as_string();
+                sample(&mut out, name, &[("peer", &address)], value(peer.stats()));
+            }
+        }
+        family(
+            &mut out,
+            "jalb_peer_bytes_total",
+            "counter",
+            "Bytes sent to and received from each peer.",
+        );
+        for peer in &peers {
+            let address = peer.address.as_string();
+            let stats = peer.stats();
+            for (direction, bytes) in [
+                ("sent", stats.bytes_sent()),
+                ("received", stats.bytes_received()),
+            ] {
+                sample(
+                    &mut out,
+                    "jalb_peer_bytes_total",
+                    &[("peer", &address), ("direction", direction)],
+                    bytes,
+                );
+            }
+        }
+        let latencies: [(&str, &str, &str, PeerHistogram); 2] = [
+            (
+                "connect",
+                "jalb_peer_connect_seconds",
+                "Time taken to open connections to each peer.",
+                PeerStats::connect_latency,
+            ),
+            (
+                "session",
+                "jalb_peer_session_seconds",
+                "Time taken by tcp sessions, or requests and their response, with each peer.",
+                PeerStats::session_duration,
+            ),
+        ];
+        for (kind, name, help, histogram) in latencies {
+            family(&mut out, name, "summary", help);
+            // pools are summed up from their peers, by the backend each is in
+            let mut pools: BTreeMap<&str, HistogramSnapshot> = BTreeMap::new();
+            for peer in peers.iter() {
+                let snapshot = histogram(peer.stats()).snapshot();
+                summary(
+                    &mut out,
+                    name,
+                    ("peer", &peer.address.as_string()),
+                    &snapshot,
+                );
+                if !peer.backend.is_empty() {
+                    pools.entry(&peer.backend).or_default().merge(&snapshot);
+                }
+            }
+            let name = format!("jalb_pool_{}_seconds", kind);
+            family(
+                &mut out,
+                &name,
+                "summary",
+                &help.replace("each peer", "the peers of each pool"),
+            );
+            for (pool, snapshot) in pools {
+                summary(&mut out, &name, ("pool", pool), &snapshot);
+            }
+        }
+        family(
+            &mut out,
+            "jalb_pool_session_bytes",
+            "summary",
+            "Bytes each tcp session, udp flow, or request and its response copied, by pool and \
+             direction.",
+        );
+        let mut sizes: BTreeMap<(&str, &str), HistogramSnapshot> = BTreeMap::new();
+        for peer in peers.iter().filter(|peer| !peer.backend.is_empty()) {
+            let stats = peer.stats();
+            for (direction, histogram) in [
+                ("from_client", stats.session_sent()),
+                ("to_client", stats.session_received()),
+            ] {
+                let size = sizes.entry((&peer.backend, direction)).or_default();
+                size.merge(&histogram.snapshot());
+            }
+        }
+        for ((pool, direction), snapshot) in sizes {
+            let labels = [("pool", pool), ("direction", direction)];
+            byte_summary(&mut out, "jalb_pool_session_bytes", &labels, &snapshot);
+        }
+        family(
+            &mut out,
+            "jalb_pool_throughput_bytes_per_second",
+            "gauge",
+            "Bytes per second copied for each pool's sessions over 5 seconds, by direction.",
+        );
+        let throughput: BTreeMap<String, Throughput> =
+            self.connections.throughput().into_iter().collect();
+        for (pool, rate) in throughput.iter().filter(|(pool, _)| !pool.is_empty()) {
+            for (direction, value) in [
+                ("from_client", rate.from_client),
+                ("to_client", rate.to_client),
+            ] {
+                sample(
+                    &mut out,
+                    "jalb_pool_throughput_bytes_per_second",
+                    &[("pool", pool), ("direction", direction)],
+                    value,
+                );
+            }
+        }
+        family(
+            &mut out,
+            "jalb_pool_selection_skew",
+            "gauge",
+            "Selections since start of each pool's most picked peer over its due, 1 when even.",
+        );
+        let mut pools: BTreeMap<&str, Vec<Arc<Peer>>> = BTreeMap::new();
+        for peer in peers.iter().filter(|peer| !peer.backend.is_empty()) {
+            pools.entry(&peer.backend).or_default().push(peer.clone());
+        }
+        for (pool, peers) in &pools {
+            let distribution = SelectionDistribution::of(self.strategy, peers);
+            if let Some(skew) = distribution.skew {
+                sample(
+                    &mut out,
+                    "jalb_pool_selection_skew",
+                    &[("pool", pool)],
+                    skew,
+                );
+            }
+        }
+        family(
+            &mut out,
+            "jalb_peer_up",
+            "gauge",
+            "1 while the peer passes its health checks and is in rotation.",
+        );
+        for peer in &peers {
+            let address = peer.address.as_string();
+            let labels = [("peer", address.as_str())];
+            let up = peer.is_live() && peer.is_ready();
+            sample(&mut out, "jalb_peer_up", &labels, u64::from(up));
+        }
+
+        let rollups: [(&str, &str, &str, PoolRollup); 5] = [
+            ("jalb_pool_peers", "gauge", "Peers in each pool.", |peers| {
+                peers.len() as f64
+            }),
+            (
+                "jalb_pool_healthy_peers",
+                "gauge",
+                "Peers of each pool passing their health checks and in rotation.",
+                |peers| {
+                    let healthy = peers.iter().filter(|p| p.is_live() && p.is_ready());
+                    healthy.count() as f64
+                },
+            ),
+            (
+                "jalb_pool_active_connections",
+                "gauge",
+                "Connections, or with type = \"application\" requests, open to the peers of each \
+                 pool.",
+                |peers| sum(peers, PeerStats::active_connections) as f64,
+            ),
+            (
+                "jalb_pool_errors_total",
+                "counter",
+                "Connections and requests to the peers of each pool that failed other than by \
+                 timing out.",
+                |peers| sum(peers, PeerStats::errors) as f64,
+            ),
+            (
+                "jalb_pool_error_ratio",
+                "gauge",
+                "Connections and requests to each pool since start that failed, timed out \
+                 included, over those it was picked for.",
+                |peers| {
+                    let failed =
+                        sum(peers, PeerStats::errors) + sum(peers, PeerStats::request_timeouts);
+                    let picked = sum(peers, PeerStats::connections);
+                    if picked == 0 {
+                        0.0
+                    } else {
+                        failed as f64 / picked as f64
+                    }
+                },
+            ),
+        ];
+        for (name, kind, help, rollup) in rollups {
+            family(&mut out, name, kind, help);
+            for (pool, peers) in &pools {
+                sample(&mut out, name, &[("pool", pool)], rollup(peers));
+            }
+        }
+
+        let names = dns::lookups().names();
+        family(
+            &mut out,
+            "jalb_dns_lookups_total",
+            "counter",
+            "Lookups of each URL and host:port peer's name, by result.",
+        );
+        for lookups in &names {
+            let ok = lookups.lookups - lookups.failures;
+            for (result, value) in [("ok", ok), ("failed", lookups.failures)] {
+                let labels = [("name", lookups.name.as_str()), ("result", result)];
+                sample(&mut out, "jalb_dns_lookups_total", &labels, value);
+            }
+        }
+        family(
+            &mut out,
+            "jalb_dns_records",
+            "gauge",
+            "Addresses the latest lookup of each name returned, 0 when it failed.",
+        );
+        for lookups in &names {
+            let labels = [("name", lookups.name.as_str())];
+            sample(&mut out, "jalb_dns_records", &labels, lookups.records);
+        }
+        family(
+            &mut out,
+            "jalb_dns_ttl_seconds",
+            "gauge",
+            "How long each name's records are used before it is looked up again, 0 for every \
+             connection. Left out for names kept until the next reload.",
+        );
+        for lookups in &names {
+            if let Some(ttl) = lookups.ttl_seconds {
+                let labels = [("name", lookups.name.as_str())];
+                sample(&mut out, "jalb_dns_ttl_seconds", &labels, ttl);
+            }
+        }
+        family(
+            &mut out,
+            "jalb_dns_lookup_seconds",
+            "summary",
+            "How long the lookups of each name took.",
+        );
+        for lookups in &names {
+            let label = ("name", lookups.name.as_str());
+            summary(
+                &mut out,
+                "jalb_dns_lookup_seconds",
+                label,
+                &lookups.duration,
+            );
+        }
+        out
+    }
+}
+
+/// A pool's peers summed up into one value.
+type PoolRollup = fn(&[Arc<Peer>]) -> f64;
+
+/// `counter` summed over `peers`.
+fn sum(peers: &[Arc<Peer>], counter: PeerCounter) -> u64 {
+    peers.iter().map(|peer| counter(peer.stats())).sum()
+}
+
+/// One of the counters on [`PeerStats`].
+type PeerCounter = fn(&PeerStats) -> u64;
+/// One of the histograms on [`PeerStats`].
+type PeerHistogram = fn(&PeerStats) -> &Histogram;
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// The [`QUANTILES`] of `snapshot` in seconds, with its sum and count.
+fn summary(out: &mut String, name: &str, label: (&str, &str), snapshot: &HistogramSnapshot) {
+    for quantile in QUANTILES {
+        let value = snapshot.quantile(quantile).as_secs_f64();
+        let quantile = quantile.to_string();
+        sample(out, name, &[label, ("quantile", &quantile)], value);
+    }
+    let sum = snapshot.sum().as_secs_f64();
+    sample(out, &format!("{}_sum", name), &[label], sum);
+    sample(out, &format!("{}_count", name), &[label], snapshot.count());
+}
+
+/// The [`QUANTILES`] of `snapshot`'s byte counts, with their sum and count.
+fn byte_summary(
+    out: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    snapshot: &HistogramSnapshot,
+) {
+    for quantile in QUANTILES {
+        let value = snapshot.value_quantile(quantile);
+        let quantile = quantile.to_string();
+        let mut labels = labels.to_vec();
+        labels.push(("quantile", &quantile));
+        sample(out, name, &labels, value);
+    }
+    sample(out, &format!("{}_sum", name), labels, snapshot.value_sum());
+    sample(out, &format!("{}_count", name), labels, snapshot.count());
+}
+
+/// `value` fit to go between the quotes of a label.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub async fn serve(listener: TcpListener, state: Arc<MetricsState>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, state.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::error!("metrics connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    state: Arc<MetricsState>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(state.render()))),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::new())),
+    };
+    Ok(response.unwrap())
+}
+
+/// A request or response body counted into [`Metrics`] as it is read.
+pub(crate) struct CountedBody {
+    inner: ProxyBody,
+    metrics: Arc<Metrics>,
+    direction: Direction,
+}
+
+impl CountedBody {
+    pub(crate) fn new(inner: ProxyBody, metrics: Arc<Metrics>, direction: Direction) -> Self {
+        Self {
+            inner,
+            metrics,
+            direction,
+        }
+    }
+}
+
+impl Body for CountedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.metrics.proxied(self.direction, data.len() as u64);
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::relay::Side;
+
+    #[tokio::test]
+    async fn test_metrics_exposition() {
+        let mut peer = Peer::new("10.0.0.1:80").unwrap();
+        peer.backend = "web".to_string();
+        let peer = Arc::new(peer);
+        peer.stats()
+            .connect_latency()
+            .record(std::time::Duration::from_millis(2));
+        let connections = Arc::new(ConnectionRegistry::new());
+        let _connection = connections.register("10.1.0.1:5000".parse().unwrap(), peer.clone());
+        let closed = connections.register("10.1.0.2:5000".parse().unwrap(), peer.clone());
+        closed.transfer().read(Side::B, 300);
+        drop(closed);
+        connections.sample_throughput();
+        connections.sample_throughput();
+        peer.stats().proxied(512, 4096);
+        peer.stats().failed();
+        let mut security = Security::new();
+        security.add_to_blacklist("10.9.9.9".parse().unwrap());
+        assert!(security.check(&"10.9.9.9".parse().unwrap()).is_err());
+        let refused = Err("connection refused".to_string());
+        dns::lookups().record("metrics.test:53", std::time::Duration::ZERO, refused);
+
+        let metrics = Arc::new(Metrics::new());
+        metrics.accepted();
+        metrics.accepted();
+        metrics.rejected("max_connections");
+        metrics.request_rejected("rate_limit");
+        metrics.proxied(Direction::FromClient, 100);
+        metrics.proxied(Direction::ToClient, 2048);
+        let state = Arc::new(MetricsState {
+            metrics,
+            peers: watch::channel(vec![peer]).1,
+            connections,
+            security,
+            strategy: LoadBalancerStrategy::RoundRobin,
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state));
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: jalb\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        for line in [
+            "# TYPE jalb_connections_accepted_total counter",
+            "jalb_connections_accepted_total 2",
+            "jalb_connections_rejected_total{reason=\"blacklisted\"} 1",
+            "jalb_connections_rejected_total{reason=\"max_connections\"} 1",
+            "jalb_requests_rejected_total{reason=\"rate_limit\"} 1",
+            "jalb_active_sessions 1",
+            "jalb_proxied_bytes_total{direction=\"to_client\"} 2048",
+            "jalb_peer_selections_total{peer=\"10.0.0.1:80\"} 2",
+            "jalb_peer_errors_total{peer=\"10.0.0.1:80\"} 1",
+            "jalb_peer_bytes_total{peer=\"10.0.0.1:80\",direction=\"received\"} 4096",
+            "jalb_peer_up{peer=\"10.0.0.1:80\"} 1",
+            "jalb_pool_healthy_peers{pool=\"web\"} 1",
+            "jalb_pool_active_connections{pool=\"web\"} 1",
+            "jalb_pool_error_ratio{pool=\"web\"} 0.5",
+            "jalb_pool_selection_skew{pool=\"web\"} 1",
+            "jalb_pool_session_bytes_sum{pool=\"web\",direction=\"to_client\"} 300",
+            "jalb_pool_session_bytes_count{pool=\"web\",direction=\"from_client\"} 1",
+            "jalb_pool_throughput_bytes_per_second{pool=\"web\",direction=\"to_client\"} 0",
+            "# TYPE jalb_pool_connect_seconds summary",
+            "jalb_pool_connect_seconds{pool=\"web\",quantile=\"0.99\"} 0.002015",
+            "jalb_peer_connect_seconds_count{peer=\"10.0.0.1:80\"} 1",
+            "jalb_peer_session_seconds{peer=\"10.0.0.1:80\",quantile=\"0.5\"} 0",
+            "jalb_dns_lookups_total{name=\"metrics.test:53\",result=\"failed\"} 1",
+            "jalb_dns_records{name=\"metrics.test:53\"} 0",
+            "jalb_dns_ttl_seconds{name=\"metrics.test:53\"} 0",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                response
+            );
+        }
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
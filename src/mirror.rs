@@ -0,0 +1,250 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use http::HeaderMap;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::errors::MirrorError;
+
+/// Frames of a request body a mirror may be behind the peer by before its copy is given up on.
+const MIRROR_BUFFER_FRAMES: usize = 16;
+
+/// `mirror` of a `[[route]]`: a copy of `percent` of its requests is also sent to `backend`,
+/// whose responses are thrown away.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub backend: String,
+    #[serde(default = "default_percent")]
+    pub percent: u32,
+}
+
+fn default_percent() -> u32 {
+    100
+}
+
+impl MirrorConfig {
+    pub fn validate(&self, backends: &[&str]) -> Result<(), String> {
+        if !(1..=100).contains(&self.percent) {
+            return Err(format!("percent {} is not between 1 and 100", self.percent));
+        }
+        if !backends.contains(&self.backend.as_str()) {
+            return Err(format!("{} is not a configured backend", self.backend));
+        }
+        Ok(())
+    }
+}
+
+/// Picks the requests of a route that are mirrored, evenly spread: with `percent = 25`, every
+/// fourth one.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    backend: String,
+    percent: u64,
+    requests: Arc<AtomicU64>,
+}
+
+impl Mirror {
+    pub fn from_config(config: &MirrorConfig) -> Self {
+        Self {
+            backend: config.backend.clone(),
+            percent: u64::from(config.percent),
+            requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The `[[backend]]` copies go to.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Whether the next request is mirrored.
+    pub fn sample(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        (n + 1) * self.percent / 100 > n * self.percent / 100
+    }
+}
+
+/// Splits a request body in two: the body passed on to the peer as it is read, and a copy of it
+/// for the mirror. A mirror that falls behind, or a body that fails, ends the copy with an
+/// error rather than slowing the request down or passing on half a body as a whole one.
+pub fn tee<B: Body>(body: B) -> (Tee<B>, Mirrored) {
+    let (frames, receiver) = mpsc::channel(MIRROR_BUFFER_FRAMES);
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let copy = Mirrored {
+        frames: receiver,
+        abandoned: abandoned.clone(),
+        size_hint: body.size_hint(),
+    };
+    // an empty body may never be read, its copy is done already
+    let frames = (!body.is_end_stream()).then_some(frames);
+    let tee = Tee {
+        inner: body,
+        frames,
+        abandoned,
+    };
+    (tee, copy)
+}
+
+/// The side of a [`tee`] sent to the peer.
+pub struct Tee<B> {
+    inner: B,
+    frames: Option<mpsc::Sender<Frame<Bytes>>>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl<B> Tee<B> {
+    fn abandon(&mut self) {
+        self.abandoned.store(true, Ordering::Relaxed);
+        self.frames = None;
+    }
+}
+
+impl<B> Drop for Tee<B> {
+    /// A body dropped before its end, e.g. when the peer couldn't be reached, is only part of
+    /// one.
+    fn drop(&mut self) {
+        if self.frames.is_some() {
+            self.abandon();
+        }
+    }
+}
+
+impl<B> Body for Tee<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                let copied = copy_frame(frame);
+                if let Some(frames) = &self.frames
+                    && let Some(copied) = copied
+                    && frames.try_send(copied).is_err()
+                {
+                    self.abandon();
+                }
+            }
+            Some(Err(_)) => self.abandon(),
+            // dropping the sender ends the copy
+            None => self.frames = None,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn copy_frame(frame: &Frame<Bytes>) -> Option<Frame<Bytes>> {
+    if let Some(data) = frame.data_ref() {
+        return Some(Frame::data(data.clone()));
+    }
+    frame
+        .trailers_ref()
+        .map(|trailers: &HeaderMap| Frame::trailers(trailers.clone()))
+}
+
+/// The side of a [`tee`] sent to the mirror.
+pub struct Mirrored {
+    frames: mpsc::Receiver<Frame<Bytes>>,
+    abandoned: Arc<AtomicBool>,
+    /// the original body's, so the copy is sent with the same framing
+    size_hint: SizeHint,
+}
+
+impl Body for Mirrored {
+    type Data = Bytes;
+    type Error = MirrorError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, MirrorError>>> {
+        match ready!(self.frames.poll_recv(cx)) {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None if self.abandoned.load(Ordering::Relaxed) => {
+                Poll::Ready(Some(Err(MirrorError::Abandoned)))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_closed() && self.frames.is_empty() && !self.abandoned.load(Ordering::Relaxed)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.size_hint.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    /// A body of `n` one-byte frames, all there at once.
+    struct Frames(usize);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            if self.0 == 0 {
+                return Poll::Ready(None);
+            }
+            self.0 -= 1;
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from("x")))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror_copies() {
+        let mirror = Mirror::from_config(&MirrorConfig {
+            backend: "shadow".to_string(),
+            percent: 25,
+        });
+        let sampled = (0..8).filter(|_| mirror.sample()).count();
+        assert_eq!(sampled, 2);
+
+        let (body, copy) = tee(Full::new(Bytes::from("hello")));
+        let copying = tokio::spawn(copy.collect());
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        let copied = copying.await.unwrap().unwrap();
+        assert_eq!(copied.to_bytes(), "hello");
+
+        // a mirror that doesn't keep up loses its copy, the peer's is untouched
+        let (body, copy) = tee(Frames(MIRROR_BUFFER_FRAMES + 1));
+        let sent = body.collect().await.unwrap().to_bytes();
+        assert_eq!(sent.len(), MIRROR_BUFFER_FRAMES + 1);
+        assert!(copy.collect().await.is_err());
+    }
+}
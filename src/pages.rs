@@ -0,0 +1,336 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use http::{
+    HeaderValue, Response, StatusCode,
+    header::{CONTENT_TYPE, RETRY_AFTER},
+};
+use hyper::body::Bytes;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// One entry of a `[[route]]`'s `error_pages`: when jalb answers a request itself with one of
+/// the `on` statuses, e.g. 503 without a peer to pick or 502 when the peer failed, the client
+/// gets `body`, or the contents of `file`, instead, with `status` if set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    pub on: Vec<u16>,
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub file: Option<PathBuf>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+/// `maintenance` of a `[[route]]`: every request is answered by jalb with `status`, `body` or
+/// the contents of `file`, none reaching a peer but those from `allow`, until it is taken out
+/// again. The admin api turns it on and off until the next reload.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// off keeps the page ready for the admin api to turn on
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_maintenance_status")]
+    pub status: u16,
+    pub body: Option<String>,
+    pub file: Option<PathBuf>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// sent as `Retry-After`, how long clients should wait before trying again
+    pub retry_after_seconds: Option<u64>,
+    /// addresses and networks whose requests are still forwarded, e.g. to check the backend
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+fn default_maintenance_status() -> u16 {
+    503
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ErrorPageConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.on.is_empty() {
+            return Err("on lists no statuses".to_string());
+        }
+        if let Some(status) = self.on.iter().find(|s| !(400..=599).contains(*s)) {
+            return Err(format!("{} is not an error status", status));
+        }
+        if let Some(status) = self.status {
+            valid_status(status)?;
+        }
+        validate_content(
+            self.body.as_deref(),
+            self.file.as_deref(),
+            &self.content_type,
+        )
+    }
+}
+
+impl MaintenanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        valid_status(self.status)?;
+        self.allowed()?;
+        validate_content(
+            self.body.as_deref(),
+            self.file.as_deref(),
+            &self.content_type,
+        )
+    }
+
+    /// Whether some requests still go to the route's backend, which it then needs.
+    pub fn forwards(&self) -> bool {
+        !self.enabled || !self.allow.is_empty()
+    }
+
+    /// `allow` as networks, a single address as a network of one.
+    fn allowed(&self) -> Result<Vec<IpNet>, String> {
+        self.allow
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map(|net| net.trunc())
+                    .map_err(|_| format!("{} in allow is not an address or network", entry))
+            })
+            .collect()
+    }
+}
+
+fn valid_status(status: u16) -> Result<(), String> {
+    match StatusCode::from_u16(status) {
+        Ok(status) if status.as_u16() >= 200 => Ok(()),
+        _ => Err(format!(
+            "{} is not a status a page can be sent with",
+            status
+        )),
+    }
+}
+
+/// Either `body` or a readable `file`, and a `content_type` a header can carry.
+fn validate_content(
+    body: Option<&str>,
+    file: Option<&Path>,
+    content_type: &str,
+) -> Result<(), String> {
+    match (body, file) {
+        (Some(_), Some(_)) => return Err("set body or file, not both".to_string()),
+        (None, None) => return Err("set body or file".to_string()),
+        (None, Some(file)) => {
+            std::fs::metadata(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        }
+        (Some(_), None) => {}
+    }
+    HeaderValue::from_str(content_type)
+        .map_err(|_| format!("{:?} is not a content type", content_type))?;
+    Ok(())
+}
+
+/// A response jalb sends itself, its body read when the config was loaded.
+#[derive(Debug, Clone)]
+pub struct StaticPage {
+    /// the status of the response it replaces when unset
+    status: Option<StatusCode>,
+    body: Bytes,
+    content_type: HeaderValue,
+    retry_after: Option<HeaderValue>,
+}
+
+impl StaticPage {
+    fn new(
+        status: Option<u16>,
+        body: Option<&str>,
+        file: Option<&Path>,
+        content_type: &str,
+    ) -> Self {
+        let body = match (body, file) {
+            (Some(body), _) => Bytes::from(body.to_string()),
+            // checked when the config was loaded, but it may have gone since
+            (None, Some(file)) => std::fs::read(file).map(Bytes::from).unwrap_or_else(|e| {
+                tracing::error!("reading page {}: {}", file.display(), e);
+                Bytes::new()
+            }),
+            (None, None) => Bytes::new(),
+        };
+        Self {
+            status: status.and_then(|status| StatusCode::from_u16(status).ok()),
+            body,
+            content_type: HeaderValue::from_str(content_type).expect("validated content type"),
+            retry_after: None,
+        }
+    }
+
+    /// The page as the response to a request jalb would have answered with `status`.
+    pub fn response(&self, status: StatusCode) -> Response<Bytes> {
+        let mut response = Response::new(self.body.clone());
+        *response.status_mut() = self.status.unwrap_or(status);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, self.content_type.clone());
+        if let Some(retry_after) = &self.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.clone());
+        }
+        response
+    }
+}
+
+/// A route's error pages by the statuses they replace.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: Vec<(Vec<u16>, StaticPage)>,
+}
+
+impl ErrorPages {
+    /// `pages` were checked by [`ErrorPageConfig::validate`] when the config was loaded.
+    pub fn from_config(pages: &[ErrorPageConfig]) -> Self {
+        let pages = pages
+            .iter()
+            .map(|page| {
+                let content = StaticPage::new(
+                    page.status,
+                    page.body.as_deref(),
+                    page.file.as_deref(),
+                    &page.content_type,
+                );
+                (page.on.clone(), content)
+            })
+            .collect();
+        Self { pages }
+    }
+
+    /// The page replacing jalb's own response with `status`, the first listing it.
+    pub fn page(&self, status: StatusCode) -> Option<&StaticPage> {
+        self.pages
+            .iter()
+            .find(|(on, _)| on.contains(&status.as_u16()))
+            .map(|(_, page)| page)
+    }
+}
+
+/// A route's [`MaintenanceConfig`], turned on and off through the admin api.
+#[derive(Debug)]
+pub struct Maintenance {
+    /// the route's position in the config, counting from 1
+    route: usize,
+    page: StaticPage,
+    allow: Vec<IpNet>,
+    enabled: AtomicBool,
+    /// whether the route has a backend to send requests to while maintenance is off
+    has_backend: bool,
+}
+
+impl Maintenance {
+    /// `config` was checked by [`MaintenanceConfig::validate`] when the config was loaded.
+    pub fn from_config(route: usize, config: &MaintenanceConfig, has_backend: bool) -> Self {
+        let mut page = StaticPage::new(
+            Some(config.status),
+            config.body.as_deref(),
+            config.file.as_deref(),
+            &config.content_type,
+        );
+        page.retry_after = config.retry_after_seconds.map(HeaderValue::from);
+        Self {
+            route,
+            page,
+            allow: config.allowed().unwrap_or_default(),
+            enabled: AtomicBool::new(config.enabled),
+            has_backend,
+        }
+    }
+
+    pub fn route(&self) -> usize {
+        self.route
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn has_backend(&self) -> bool {
+        self.has_backend
+    }
+
+    /// Applied from the next request on, returning whether it was on before. A route without
+    /// a backend stays in maintenance.
+    pub fn set_enabled(&self, enabled: bool) -> bool {
+        self.enabled
+            .swap(enabled || !self.has_backend, Ordering::Relaxed)
+    }
+
+    /// The page answering a request from `client`, unless maintenance is off or the client is
+    /// let through.
+    pub fn page_for(&self, client: IpAddr) -> Option<&StaticPage> {
+        let allowed = self.allow.iter().any(|net| net.contains(&client));
+        (self.enabled() && !allowed).then_some(&self.page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_pages() {
+        let file = std::env::temp_dir().join(format!("jalb-page-{}.html", std::process::id()));
+        std::fs::write(&file, "<h1>back soon</h1>").unwrap();
+
+        let pages: Vec<ErrorPageConfig> = vec![
+            toml::from_str("on = [502, 504]\nstatus = 503\nbody = \"try again\"").unwrap(),
+            toml::from_str(&format!("on = [503]\nfile = {:?}", file)).unwrap(),
+        ];
+        assert!(pages.iter().all(|page| page.validate().is_ok()));
+        let pages = ErrorPages::from_config(&pages);
+        let response = pages
+            .page(StatusCode::BAD_GATEWAY)
+            .unwrap()
+            .response(StatusCode::BAD_GATEWAY);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.body(), "try again");
+        let response = pages
+            .page(StatusCode::SERVICE_UNAVAILABLE)
+            .unwrap()
+            .response(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.body(), "<h1>back soon</h1>");
+        assert!(pages.page(StatusCode::PAYLOAD_TOO_LARGE).is_none());
+
+        let maintenance: MaintenanceConfig = toml::from_str(&format!(
+            "file = {:?}\nretry_after_seconds = 600\nallow = [\"10.0.0.0/8\"]",
+            file
+        ))
+        .unwrap();
+        assert!(maintenance.validate().is_ok());
+        let maintenance = Maintenance::from_config(1, &maintenance, true);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let response = maintenance
+            .page_for(client)
+            .unwrap()
+            .response(StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "600");
+        assert!(maintenance.page_for("10.1.2.3".parse().unwrap()).is_none());
+        assert!(maintenance.set_enabled(false));
+        assert!(maintenance.page_for(client).is_none());
+        std::fs::remove_file(&file).unwrap();
+
+        let invalid = |toml: &str| toml::from_str::<ErrorPageConfig>(toml).unwrap().validate();
+        assert!(invalid("on = [502]").is_err());
+        assert!(invalid("on = [200]\nbody = \"\"").is_err());
+        assert!(invalid("on = [502]\nfile = \"/no/such/page.html\"").is_err());
+    }
+}
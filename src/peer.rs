@@ -1,14 +1,20 @@
 use geo;
-use log::error;
-use std::{io, net::SocketAddr, str::FromStr, time::Duration};
-use tokio::{
-    net::{TcpSocket, TcpStream, ToSocketAddrs},
-    time::timeout,
+use tracing::error;
+use std::{
+    io,
+    str::FromStr,
+    net::SocketAddr,
+    sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}},
+    time::Duration,
 };
+use tokio::{net::TcpSocket, sync::watch, time::{Instant, timeout}};
+use url::Url;
 
 use crate::{
-    config::{BackendOptions, NetworkTarget, PeerConfig},
+    config::{AddressFamily, BackendOptions, DnsStrategy, NetworkTarget, PeerConfig},
     errors::NetworkTargetError,
+    health::{HealthCheck, HealthCheckKind},
+    histogram::Histogram,
 };
 
 pub(crate) fn tcpsocket_from_address(addr: &std::net::SocketAddr) -> Result<TcpSocket, io::Error> {
@@ -19,13 +25,125 @@ pub(crate) fn tcpsocket_from_address(addr: &std::net::SocketAddr) -> Result<TcpS
     TcpSocket::new_v6()
 }
 
+/// What went through a peer while it was in the pool.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    active_connections: AtomicU64,
+    /// Connections and requests the peer was picked for
+    connections: AtomicU64,
+    /// Sent to the peer and received from it, counted as requests stream through but only
+    /// once a tcp session or WebSocket ends without an error
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Connections and requests that failed other than by timing out
+    errors: AtomicU64,
+    /// Connections cut off by `request_timeout`
+    request_timeouts: AtomicU64,
+    /// How long opening a connection to the peer took
+    connect_latency: Histogram,
+    /// How long tcp sessions, or requests until their response was passed on, took
+    session_duration: Histogram,
+    /// Bytes each session, or request with its response, sent to the peer and got back
+    session_sent: Histogram,
+    session_received: Histogram,
+}
+
+impl PeerStats {
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn request_timeouts(&self) -> u64 {
+        self.request_timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn connect_latency(&self) -> &Histogram {
+        &self.connect_latency
+    }
+
+    pub fn session_duration(&self) -> &Histogram {
+        &self.session_duration
+    }
+
+    pub fn session_sent(&self) -> &Histogram {
+        &self.session_sent
+    }
+
+    pub fn session_received(&self) -> &Histogram {
+        &self.session_received
+    }
+
+    /// A session with the peer ended after `sent` bytes went to it and `received` came back.
+    pub(crate) fn proxied(&self, sent: u64, received: u64) {
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records into the peer's `session_duration` how long since `started` it was dropped.
+pub(crate) struct SessionTimer {
+    peer: Arc<Peer>,
+    started: Instant,
+}
+
+impl SessionTimer {
+    pub(crate) fn new(peer: Arc<Peer>, started: Instant) -> Self {
+        Self { peer, started }
+    }
+
+    pub(crate) fn peer(&self) -> &Arc<Peer> {
+        &self.peer
+    }
+}
+
+impl Drop for SessionTimer {
+    fn drop(&mut self) {
+        self.peer.stats.session_duration.record(self.started.elapsed());
+    }
+}
+
 #[derive(Debug)]
 pub struct Peer {
-    pub healthy: bool,
+    live: AtomicBool,
+    ready: AtomicBool,
+    /// Set once the peer is dropped from the pool by a reload, stops its health checks
+    retired: AtomicBool,
+    stats: PeerStats,
+    dns_strategy: DnsStrategy,
+    dns_prefer: AddressFamily,
+    next_record: AtomicUsize,
     pub health_endpoint: Option<NetworkTarget>,
     pub address: NetworkTarget,
-    pub weight: u32,
+    /// The `[[backend]]` the peer is in, empty for peers made with [`Peer::new`]
+    pub backend: String,
+    /// Read by the selectors on every pick, so changes apply from the next selection on
+    weight: watch::Sender<u32>,
     pub coordinates: Option<geo::Coord>,
+    /// The backend's request settings, or the peer's own where its `[[backend.peers]]` entry
+    /// overrides them
+    pub request_timeout: Option<Duration>,
+    pub failed_request_threshold: Option<u32>,
+    pub rate_limit: Option<u64>,
 }
 
 impl Peer {
@@ -33,11 +151,21 @@ impl Peer {
         let target = NetworkTarget::from_str(addr)?;
 
         Ok(Self {
-            healthy: false,
+            live: AtomicBool::new(true),
+            ready: AtomicBool::new(true),
+            retired: AtomicBool::new(false),
+            stats: PeerStats::default(),
+            dns_strategy: DnsStrategy::default(),
+            dns_prefer: AddressFamily::default(),
+            next_record: AtomicUsize::new(0),
             address: target,
-            weight: 1,
+            backend: String::new(),
+            weight: watch::Sender::new(1),
             coordinates: None,
             health_endpoint: None,
+            request_timeout: None,
+            failed_request_threshold: None,
+            rate_limit: None,
         })
     }
 
@@ -48,82 +176,184 @@ impl Peer {
         let addr = options.get_addr();
         let mut health_addr: Option<NetworkTarget> = None;
 
-        if let Some(path) = backend_config.health_endpoint.clone() {
-            let mut base_health_addr = addr.clone();
-            health_addr = Some(base_health_addr)
+        if backend_config.health_endpoint.is_some() {
+            health_addr = Some(addr.clone())
         }
 
+        // peers with a readiness check start out of rotation until the first check passes
+        let ready = backend_config.readiness_check().is_none();
+
         Ok(Self {
-            healthy: false,
+            live: AtomicBool::new(true),
+            ready: AtomicBool::new(ready),
+            retired: AtomicBool::new(false),
+            stats: PeerStats::default(),
+            dns_strategy: backend_config.dns_strategy,
+            dns_prefer: backend_config.dns_prefer,
+            next_record: AtomicUsize::new(0),
             address: addr,
-            weight: options.get_weight().unwrap_or(1),
+            backend: backend_config.name.clone(),
+            weight: watch::Sender::new(options.get_weight().unwrap_or(1)),
             coordinates: options.get_coordinates(),
             health_endpoint: health_addr,
+            request_timeout: options.get_request_timeout(backend_config),
+            failed_request_threshold: options.get_failed_request_threshold(backend_config),
+            rate_limit: options.get_rate_limit(backend_config),
         })
     }
 
-    pub async fn health_check(&mut self, connect_timeout: Duration) -> Result<bool, io::Error> {
-        if self.health_endpoint.is_none() {
+    pub fn weight(&self) -> u32 {
+        *self.weight.borrow()
+    }
+
+    /// Changes the weight without interrupting traffic, returning the previous weight. A weight
+    /// of 0 drains the peer from weighted selection.
+    pub fn set_weight(&self, weight: u32) -> u32 {
+        self.weight.send_replace(weight)
+    }
+
+    /// Notified whenever the weight changes.
+    pub fn watch_weight(&self) -> watch::Receiver<u32> {
+        self.weight.subscribe()
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_live(&self, live: bool) {
+        self.live.store(live, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Marks the peer as removed from the pool. Connections already open to it run to
+    /// completion.
+    pub(crate) fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+
+    /// The address to open the next upstream connection to, following the backend's
+    /// `dns_strategy` and `dns_prefer` settings.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let addrs = self.address.resolve(self.dns_prefer);
+        if addrs.is_empty() {
+            return None;
+        }
+
+        match self.dns_strategy {
+            DnsStrategy::RoundRobinAcrossRecords => {
+                let idx = self.next_record.fetch_add(1, Ordering::Relaxed) % addrs.len();
+                Some(addrs[idx])
+            }
+            DnsStrategy::First | DnsStrategy::AllAsPeers => Some(addrs[0]),
+        }
+    }
+
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.stats.active_connections()
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.stats.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        let _ = self.stats.active_connections.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |n| n.checked_sub(1),
+        );
+    }
+
+    pub fn request_timeouts(&self) -> u64 {
+        self.stats.request_timeouts()
+    }
+
+    pub(crate) fn request_timed_out(&self) {
+        self.stats.request_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_active_connections(&self, count: u64) {
+        self.stats.active_connections.store(count, Ordering::Relaxed);
+    }
+
+    /// Runs a single health check against the peer. `Ok(false)` means the peer answered but did
+    /// not pass, e.g. a non 2xx status or a connect timeout.
+    pub async fn health_check(
+        &self,
+        check: &HealthCheck,
+        client: &reqwest::Client,
+    ) -> Result<bool, io::Error> {
+        let Some(socket_addr) = self.socket_addr() else {
             let error = io::Error::new(
                 io::ErrorKind::AddrNotAvailable,
-                "no health endpoint was configured",
+                format!("{} has no socket address", self.address.as_string()),
             );
             return Err(error);
-        }
+        };
 
-        match self.address {
-            NetworkTarget::SocketAddr(socket_addr) => {
+        match &check.kind {
+            HealthCheckKind::Tcp => {
                 let socket = tcpsocket_from_address(&socket_addr)?;
                 let future = socket.connect(socket_addr);
-                match timeout(connect_timeout, future).await {
-                    Ok(Ok(_stream)) => {
-                        self.healthy = true;
-                        Ok(true)
-                    }
+                match timeout(check.timeout, future).await {
+                    Ok(Ok(_stream)) => Ok(true),
                     Ok(Err(e)) => {
                         error!("health check for {} failed: {}", socket_addr, e);
-                        self.healthy = false;
                         Err(e)
                     }
                     Err(_) => {
                         error!(
                             "tcp health check for {} timed out after {:?}",
-                            socket_addr, connect_timeout
+                            socket_addr, check.timeout
                         );
-                        self.healthy = false;
                         Ok(false)
                     }
                 }
             }
-            NetworkTarget::Url(ref url) => {
-                if url.port_or_known_default().is_none() {
-                    let error = io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "url has no associated port. Network balancers require all urls to have a port",
-                    );
-                    return Err(error);
-                }
-
-                let future = TcpStream::connect(url.as_str());
-                match timeout(connect_timeout, future).await {
-                    Ok(Ok(_stream)) => {
-                        self.healthy = true;
-                        Ok(true)
-                    }
-                    Ok(Err(e)) => {
-                        error!("health check for {} failed: {}", url.as_str(), e);
-                        self.healthy = false;
-                        Err(e)
+            HealthCheckKind::Http { path } => {
+                let url = match &self.address {
+                    NetworkTarget::Url(url) => url.join(path).map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+                    })?,
+                    NetworkTarget::SocketAddr(_) | NetworkTarget::Host(..) => {
+                        Url::parse(&format!("http://{}", self.address.as_string()))
+                            .and_then(|base| base.join(path))
+                            .map_err(|e| {
+                                io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+                            })?
                     }
-                    Err(_) => {
+                };
+
+                match client.get(url.as_str()).timeout(check.timeout).send().await {
+                    Ok(response) => Ok(response.status().is_success()),
+                    Err(e) if e.is_timeout() => {
                         error!(
-                            "tcp health check for {} timed out after {:?}",
-                            url.as_str(),
-                            connect_timeout
+                            "http health check for {} timed out after {:?}",
+                            url, check.timeout
                         );
-                        self.healthy = false;
                         Ok(false)
                     }
+                    Err(e) => {
+                        error!("health check for {} failed: {}", url, e);
+                        Err(io::Error::other(e))
+                    }
                 }
             }
         }
@@ -0,0 +1,334 @@
+use std::{ops::DerefMut, sync::Arc};
+
+use http::{HeaderMap, HeaderValue};
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+
+use crate::{
+    affinity::CookieAffinity,
+    backend::Backend,
+    config::{BackendOptions, Config, HttpVersion, RequestTimeoutScope},
+    events::{EventKind, EventLog},
+    health::{self, HealthGuard},
+    load_balancer::{ProxyOptions, selector_from_config},
+    peer::Peer,
+    selector::Selector,
+};
+
+/// The peers of one `[[backend]]`, selected between on their own and health checked against
+/// that backend's settings.
+pub struct Pool {
+    pub name: String,
+    backend: Backend,
+    selector: Box<dyn Selector>,
+    /// the selector's peers, republished whenever a reload changes them
+    peer_list: watch::Sender<Vec<Arc<Peer>>>,
+    health: Option<Arc<HealthGuard>>,
+    /// the `[[backend]]` as of the latest reload, resolved again on DNS refreshes
+    options: BackendOptions,
+    /// when `host:port` peers are next resolved again, `None` when they aren't
+    next_dns_refresh: Option<Instant>,
+    affinity: Option<CookieAffinity>,
+}
+
+impl Pool {
+    pub(crate) fn from_config(cfg: &Config, options: &BackendOptions) -> Self {
+        Self::with_peers(cfg, options, options.peers())
+    }
+
+    /// Builds the pool for `options` but with `peers` instead of the configured ones.
+    pub(crate) fn with_peers(cfg: &Config, options: &BackendOptions, peers: Vec<Peer>) -> Self {
+        let mut selector = selector_from_config(cfg);
+        for peer in peers {
+            selector.add_peer(peer);
+        }
+
+        Self {
+            name: options.name.clone(),
+            backend: Backend::from_config(options),
+            peer_list: watch::Sender::new(selector.peers()),
+            selector,
+            health: None,
+            options: options.clone(),
+            next_dns_refresh: options
+                .dns_refresh_interval()
+                .map(|every| Instant::now() + every),
+            affinity: options.affinity().map(CookieAffinity::from_config),
+        }
+    }
+
+    /// The peer the pool's strategy picks for the next connection.
+    pub fn pick(&mut self) -> Option<Arc<Peer>> {
+        self.selector.next()
+    }
+
+    /// The picked peer that isn't one of `tried`, as long as one comes up within as many picks
+    /// as the pool has peers; the next one picked regardless otherwise.
+    pub fn pick_untried(&mut self, tried: &[Arc<Peer>]) -> Option<Arc<Peer>> {
+        let mut peer = self.pick();
+        if tried.is_empty() {
+            return peer;
+        }
+
+        let was_tried = |peer: &Arc<Peer>| tried.iter().any(|t| Arc::ptr_eq(t, peer));
+        for _ in 1..self.selector.peers().len() {
+            match &peer {
+                Some(p) if was_tried(p) => peer = self.pick(),
+                _ => break,
+            }
+        }
+        peer
+    }
+
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.selector.peers()
+    }
+
+    /// `base` with `peer`'s request timeout applied to connecting to it, and to the whole
+    /// session when the backend's `request_timeout_scope` is `session`.
+    pub fn proxy_options(&self, peer: &Peer, base: ProxyOptions) -> ProxyOptions {
+        let session = self.backend.request_timeout_scope == RequestTimeoutScope::Session;
+        ProxyOptions {
+            connect_timeout: peer.request_timeout,
+            session_timeout: peer.request_timeout.filter(|_| session),
+            ..base
+        }
+    }
+
+    /// The peer a request's affinity cookie pins it to, while that peer is ready.
+    pub fn pinned_peer(&self, headers: &HeaderMap) -> Option<Arc<Peer>> {
+        let affinity = self.affinity.as_ref()?;
+        affinity
+            .peer(headers, &self.selector.peers())
+            .filter(|peer| peer.is_ready())
+    }
+
+    /// The `Set-Cookie` value pinning clients to `peer`, when the pool has affinity.
+    pub fn affinity_cookie(&self, peer: &Peer) -> Option<HeaderValue> {
+        self.affinity
+            .as_ref()
+            .map(|affinity| affinity.set_cookie(peer))
+    }
+
+    /// What the application balancer speaks to the pool's peers.
+    pub fn http_version(&self) -> HttpVersion {
+        self.options.http_version
+    }
+
+    /// The pool's peers, following reloads.
+    pub fn watch_peers(&self) -> watch::Receiver<Vec<Arc<Peer>>> {
+        self.peer_list.subscribe()
+    }
+
+    /// Starts health checking the pool's peers, including those added by later reloads.
+    pub fn spawn_health_checks(&mut self, events: &Arc<EventLog>) -> Arc<HealthGuard> {
+        let guard = health::spawn_health_checks(&self.backend, self.watch_peers(), events);
+        self.health = Some(guard.clone());
+        guard
+    }
+
+    /// Brings the pool in line with a reloaded `options`: peers are added, removed or
+    /// reweighted. Returns what changed.
+    pub fn apply_config(
+        &mut self,
+        options: &BackendOptions,
+        events: &Arc<EventLog>,
+    ) -> Vec<String> {
+        self.backend = Backend::from_config(options);
+        self.options = options.clone();
+        self.affinity = match (self.affinity.take(), options.affinity()) {
+            (Some(mut affinity), Some(config)) => {
+                affinity.reconfigure(config);
+                Some(affinity)
+            }
+            (_, config) => config.map(CookieAffinity::from_config),
+        };
+        self.next_dns_refresh = options
+            .dns_refresh_interval()
+            .map(|every| Instant::now() + every);
+        self.sync_peers(options.peers(), true, events)
+    }
+
+    /// When the pool's `host:port` peers are next due to be resolved again.
+    pub fn next_dns_refresh(&self) -> Option<Instant> {
+        self.next_dns_refresh
+    }
+
+    /// The pool's settings to resolve peers from once the refresh is due, scheduling the next.
+    /// Resolving blocks, so it is left to the caller.
+    pub fn take_dns_refresh(&mut self, now: Instant) -> Option<BackendOptions> {
+        if self.next_dns_refresh? > now {
+            return None;
+        }
+
+        self.next_dns_refresh = self.options.dns_refresh_interval().map(|every| now + every);
+        Some(self.options.clone())
+    }
+
+    /// Peers from a DNS refresh: addresses that appeared are added and those gone removed.
+    /// Weights are left alone, they may have been changed through the admin api since.
+    pub fn apply_resolved(&mut self, resolved: Vec<Peer>, events: &Arc<EventLog>) -> Vec<String> {
+        self.sync_peers(resolved, false, events)
+    }
+
+    /// Adds and removes peers so the pool serves `desired`, and with `reweight` sets the weights
+    /// of those it already has. Returns what changed.
+    fn sync_peers(
+        &mut self,
+        desired: Vec<Peer>,
+        reweight: bool,
+        events: &Arc<EventLog>,
+    ) -> Vec<String> {
+        let current = self.selector.peers();
+        let mut changes = Vec::new();
+
+        for peer in &current {
+            let address = peer.address.as_string();
+            if !desired.iter().any(|p| p.address.as_string() == address) {
+                self.selector.remove_peer(&address);
+                peer.retire();
+                changes.push(format!("removed {}", address));
+            }
+        }
+
+        for peer in desired {
+            let address = peer.address.as_string();
+            if let Some(existing) = current.iter().find(|p| p.address.as_string() == address) {
+                if !reweight {
+                    continue;
+                }
+                let previous = existing.set_weight(peer.weight());
+                if previous != peer.weight() {
+                    changes.push(format!(
+                        "weight of {} {} -> {}",
+                        address,
+                        previous,
+                        peer.weight()
+                    ));
+                }
+                continue;
+            }
+
+            self.selector.add_peer(peer);
+            if let Some(guard) = &self.health
+                && let Some(added) = self
+                    .selector
+                    .peers()
+                    .into_iter()
+                    .find(|p| p.address.as_string() == address)
+            {
+                health::spawn_peer_checks(&self.backend, &added, events, guard);
+            }
+            changes.push(format!("added {}", address));
+        }
+        self.peer_list.send_replace(self.selector.peers());
+
+        changes
+    }
+}
+
+/// The index of the pool named `backend` among pools named `names`, `default_pool` when unset
+/// or unknown.
+pub(crate) fn pool_for<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    backend: Option<&str>,
+    default_pool: usize,
+) -> usize {
+    backend
+        .and_then(|backend| names.into_iter().position(|name| name == backend))
+        .unwrap_or(default_pool)
+}
+
+/// Applies a reloaded `cfg` to each of `pools`, returning what changed per pool. Backends
+/// added or removed are only logged, the set of pools changes on restart. Like the other
+/// functions here, `pools` are borrowed or locked one at a time.
+pub(crate) fn apply_config(
+    pools: impl IntoIterator<Item = impl DerefMut<Target = Pool>>,
+    cfg: &Config,
+    events: &Arc<EventLog>,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut served = Vec::new();
+    for mut pool in pools {
+        let Some(options) = cfg.backends().iter().find(|o| o.name == pool.name) else {
+            tracing::warn!(
+                "backend {} was removed, it is served until a restart",
+                pool.name
+            );
+            continue;
+        };
+
+        let pool_changes = pool.apply_config(options, events);
+        if !pool_changes.is_empty() {
+            changes.push(format!("{}: {}", pool.name, pool_changes.join(", ")));
+        }
+        served.push(pool.name.clone());
+    }
+    for options in cfg.backends() {
+        if !served.contains(&options.name) {
+            tracing::warn!(
+                "backend {} was added, it is only served after a restart",
+                options.name
+            );
+        }
+    }
+
+    changes
+}
+
+/// Records a reload in `events`, with the peer `changes` from [`apply_config`].
+pub(crate) fn record_reload(events: &EventLog, changes: &[String]) {
+    let peers = if changes.is_empty() {
+        "peers unchanged".to_string()
+    } else {
+        changes.join("; ")
+    };
+    events.record(EventKind::Reload, format!("config reloaded: {}", peers));
+}
+
+/// Resolves the peers of every pool due for a DNS refresh. Lookups block, so they run off the
+/// caller's task and the results come back through `resolved`.
+pub(crate) fn refresh_dns(
+    pools: impl IntoIterator<Item = impl DerefMut<Target = Pool>>,
+    resolved: &mpsc::Sender<(String, Vec<Peer>)>,
+) {
+    let now = Instant::now();
+    for mut pool in pools {
+        let Some(options) = pool.take_dns_refresh(now) else {
+            continue;
+        };
+
+        let resolved = resolved.clone();
+        tokio::spawn(async move {
+            let name = options.name.clone();
+            let peers = tokio::task::spawn_blocking(move || options.resolved_peers()).await;
+            // a failed lookup keeps the pool as it is until the next refresh
+            if let Ok(Some(peers)) = peers {
+                let _ = resolved.send((name, peers)).await;
+            }
+        });
+    }
+}
+
+/// Applies the peers `name` resolved to on a DNS refresh, returning whether any changed.
+pub(crate) fn apply_resolved(
+    pools: impl IntoIterator<Item = impl DerefMut<Target = Pool>>,
+    name: &str,
+    peers: Vec<Peer>,
+    events: &Arc<EventLog>,
+) -> bool {
+    let Some(mut pool) = pools.into_iter().find(|p| p.name == name) else {
+        return false;
+    };
+
+    let changes = pool.apply_resolved(peers, events);
+    if changes.is_empty() {
+        return false;
+    }
+
+    let message = format!("dns refresh of {}: {}", pool.name, changes.join(", "));
+    events.record(EventKind::Reload, message);
+    true
+}
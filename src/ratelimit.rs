@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::store::StateStore;
+
+/// Past this many tracked clients, buckets idle for longer than `CLIENT_IDLE` are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+const CLIENT_IDLE: Duration = Duration::from_secs(60);
+
+/// Classic token bucket. Holds up to `capacity` tokens and refills at `rate` tokens per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// A bucket allowing `rate` operations per second with a burst of one second's worth.
+    pub fn per_second(rate: u64) -> Self {
+        Self::new(rate, rate)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        self.acquire_at(now).is_ok()
+    }
+
+    /// Takes a token, or tells how long until the next one is there.
+    pub fn acquire_at(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.rate <= 0.0 {
+            return Err(Duration::MAX);
+        }
+
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+/// A [`TokenBucket`] shared by every task without a lock around it. Rather than counting
+/// tokens it keeps when the bucket will be full again, which each token taken moves later by
+/// the time the token takes to refill.
+#[derive(Debug)]
+pub struct AtomicTokenBucket {
+    /// nanoseconds a token takes to refill
+    interval: u64,
+    /// nanoseconds a full bucket takes to refill
+    refill: u64,
+    started: Instant,
+    /// when the bucket is full again, in nanoseconds since `started`
+    full_at: AtomicU64,
+}
+
+impl AtomicTokenBucket {
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        let interval = 1_000_000_000 / rate.max(1);
+        Self {
+            interval,
+            refill: interval.saturating_mul(capacity),
+            started: Instant::now(),
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    /// A bucket allowing `rate` operations per second with a burst of one second's worth.
+    pub fn per_second(rate: u64) -> Self {
+        Self::new(rate, rate)
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&self, now: Instant) -> bool {
+        let now = now.saturating_duration_since(self.started).as_nanos() as u64;
+        self.full_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |full_at| {
+                let full_at = full_at.max(now) + self.interval;
+                (full_at - now <= self.refill).then_some(full_at)
+            })
+            .is_ok()
+    }
+}
+
+/// `rate_limit` of a `[[route]]`: each client gets `requests_per_second` of the route's
+/// requests, with bursts of up to `burst`, and is answered with a 429 past that. Clients are
+/// told apart by address, or by the value of `key_header`, e.g. an API key, for requests
+/// carrying it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: u64,
+    /// one second's worth when unset
+    pub burst: Option<u64>,
+    pub key_header: Option<String>,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.requests_per_second == 0 {
+            return Err("requests_per_second must be at least 1".to_string());
+        }
+        if self.burst == Some(0) {
+            return Err("burst must be at least 1".to_string());
+        }
+        if let Some(header) = &self.key_header
+            && HeaderName::try_from(header.as_str()).is_err()
+        {
+            return Err(format!("{} is not a header name", header));
+        }
+        Ok(())
+    }
+}
+
+/// Who a route's request counts against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RequestKey {
+    Client(IpAddr),
+    Header(HeaderValue),
+}
+
+/// A route's [`RateLimitConfig`], with a token bucket per client in this process.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    rate: u64,
+    burst: u64,
+    key_header: Option<HeaderName>,
+    buckets: Mutex<HashMap<RequestKey, TokenBucket>>,
+}
+
+impl RequestLimiter {
+    /// `config` was checked by [`RateLimitConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        Self {
+            rate: config.requests_per_second,
+            burst: config.burst.unwrap_or(config.requests_per_second),
+            key_header: config
+                .key_header
+                .as_deref()
+                .map(|header| HeaderName::try_from(header).expect("validated header")),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request from `client` with `headers` may go on, or else how many seconds the
+    /// client should wait before its next one.
+    pub fn check(&self, client: IpAddr, headers: &HeaderMap) -> Result<(), u64> {
+        self.check_at(client, headers, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, headers: &HeaderMap, now: Instant) -> Result<(), u64> {
+        let key = match self.key_header.as_ref().and_then(|name| headers.get(name)) {
+            Some(value) => RequestKey::Header(value.clone()),
+            None => RequestKey::Client(client),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                now.saturating_duration_since(bucket.last_refill) < CLIENT_IDLE
+            });
+        }
+
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst))
+            .acquire_at(now)
+            .map_err(|wait| wait.as_secs_f64().ceil().max(1.0) as u64)
+    }
+}
+
+/// Caps new connections per client address per second.
+///
+/// `Local` keeps a token bucket per client in this process. `Shared` counts in one-second
+/// windows in a state store, so instances sharing a store (e.g. Redis) enforce the limit across
+/// the fleet.
+#[derive(Debug)]
+pub enum ClientRateLimiter {
+    Local {
+        rate: u64,
+        buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    },
+    Shared {
+        rate: u64,
+        store: Arc<dyn StateStore>,
+    },
+}
+
+impl ClientRateLimiter {
+    pub fn local(rate: u64) -> Self {
+        ClientRateLimiter::Local {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn shared(rate: u64, store: Arc<dyn StateStore>) -> Self {
+        ClientRateLimiter::Shared { rate, store }
+    }
+
+    /// Whether `client` may open another connection now. A failing shared store lets clients
+    /// through rather than refusing everyone.
+    pub async fn try_acquire(&self, client: IpAddr) -> bool {
+        let window = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.try_acquire_in(client, Instant::now(), window).await
+    }
+
+    async fn try_acquire_in(&self, client: IpAddr, now: Instant, window: u64) -> bool {
+        match self {
+            ClientRateLimiter::Local { rate, buckets } => {
+                let mut buckets = buckets.lock().unwrap();
+                if buckets.len() >= MAX_TRACKED_CLIENTS {
+                    buckets.retain(|_, bucket| {
+                        now.saturating_duration_since(bucket.last_refill) < CLIENT_IDLE
+                    });
+                }
+
+                buckets
+                    .entry(client)
+                    .or_insert_with(|| TokenBucket::per_second(*rate))
+                    .try_acquire_at(now)
+            }
+            ClientRateLimiter::Shared { rate, store } => {
+                let key = format!("ratelimit/{}/{}", client, window);
+                let store = store.clone();
+
+                // the store may block on the network, keep that off the runtime's threads
+                let counted = tokio::task::spawn_blocking(move || {
+                    store.increment(&key, Duration::from_secs(2))
+                })
+                .await;
+
+                match counted {
+                    Ok(Ok(count)) => count <= *rate,
+                    Ok(Err(e)) => {
+                        tracing::error!(
+                            "shared rate limit unavailable, allowing {}: {}",
+                            client,
+                            e
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "shared rate limit check failed, allowing {}: {}",
+                            client,
+                            e
+                        );
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2);
+
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+
+        // 10 tokens/s refills one token every 100ms
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(100)));
+
+        // never exceeds capacity
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_acquire_at(later));
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+
+        let shared = AtomicTokenBucket::new(10, 2);
+        let start = Instant::now();
+        assert!(shared.try_acquire_at(start));
+        assert!(shared.try_acquire_at(start));
+        assert!(!shared.try_acquire_at(start));
+        assert!(shared.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!shared.try_acquire_at(start + Duration::from_millis(100)));
+        let later = start + Duration::from_secs(10);
+        assert!(shared.try_acquire_at(later));
+        assert!(shared.try_acquire_at(later));
+        assert!(!shared.try_acquire_at(later));
+    }
+
+    #[tokio::test]
+    async fn test_client_limits() {
+        let now = Instant::now();
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let local = ClientRateLimiter::local(2);
+        assert!(local.try_acquire_in(client, now, 0).await);
+        assert!(local.try_acquire_in(client, now, 0).await);
+        assert!(!local.try_acquire_in(client, now, 0).await);
+        assert!(local.try_acquire_in(other, now, 0).await);
+
+        // two instances sharing a store split one budget per window
+        let store: Arc<dyn StateStore> = Arc::new(crate::store::MemoryStore::new());
+        let first = ClientRateLimiter::shared(2, store.clone());
+        let second = ClientRateLimiter::shared(2, store);
+        assert!(first.try_acquire_in(client, now, 7).await);
+        assert!(second.try_acquire_in(client, now, 7).await);
+        assert!(!first.try_acquire_in(client, now, 7).await);
+        assert!(second.try_acquire_in(other, now, 7).await);
+        assert!(first.try_acquire_in(client, now, 8).await);
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let now = Instant::now();
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let config: RateLimitConfig =
+            toml::from_str("requests_per_second = 1\nburst = 2\nkey_header = \"X-Api-Key\"")
+                .unwrap();
+        assert!(config.validate().is_ok());
+        let limiter = RequestLimiter::from_config(&config);
+
+        let anonymous = HeaderMap::new();
+        let mut keyed = HeaderMap::new();
+        keyed.insert("x-api-key", HeaderValue::from_static("k1"));
+        assert_eq!(limiter.check_at(client, &anonymous, now), Ok(()));
+        assert_eq!(limiter.check_at(client, &anonymous, now), Ok(()));
+        assert_eq!(limiter.check_at(client, &anonymous, now), Err(1));
+        // the same address with a key has a budget of its own
+        assert_eq!(limiter.check_at(client, &keyed, now), Ok(()));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(client, &anonymous, later), Ok(()));
+
+        let invalid = |toml: &str| toml::from_str::<RateLimitConfig>(toml).unwrap().validate();
+        assert!(invalid("requests_per_second = 0").is_err());
+        assert!(invalid("requests_per_second = 5\nkey_header = \"not a header\"").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+
+use http::{HeaderValue, Method, Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::headers::{RequestContext, Template};
+
+/// `redirect` of a `[[route]]`: matching requests are answered with a redirect to `to` rather
+/// than forwarded. `to` is a template like header values, e.g. `https://new.example.com{path}`
+/// with `{query}` to keep the query string.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub to: String,
+    /// 301, 302, 303, 307 or 308
+    #[serde(default = "default_status")]
+    pub status: u16,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+impl RedirectConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if ![301, 302, 303, 307, 308].contains(&self.status) {
+            return Err(format!("{} is not a redirect status", self.status));
+        }
+        let template = Template::parse(&self.to)?;
+        if HeaderValue::from_str(&template.literal()).is_err() {
+            return Err(format!("{:?} is not a url", self.to));
+        }
+        Ok(())
+    }
+}
+
+/// A route's [`RedirectConfig`], its target ready to fill in.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    to: Template,
+    status: StatusCode,
+}
+
+impl Redirect {
+    /// `config` was checked by [`RedirectConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &RedirectConfig) -> Self {
+        Self {
+            to: Template::parse(&config.to).expect("validated template"),
+            status: StatusCode::from_u16(config.status).expect("validated status"),
+        }
+    }
+
+    /// The status and `Location` of the redirect for the request `context` describes, `None`
+    /// when the target it renders to can't be sent in a header.
+    pub fn target(&self, context: &RequestContext) -> Option<(StatusCode, HeaderValue)> {
+        let location = HeaderValue::from_str(&self.to.render(context)).ok()?;
+        Some((self.status, location))
+    }
+}
+
+/// `redirect_http_to_https`: requests reaching a listener that doesn't speak TLS are sent to
+/// the same host and path over https instead.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    port: u16,
+    /// `[[tls.listener]]` addresses, whose requests are https already
+    tls_listeners: Vec<SocketAddr>,
+}
+
+impl HttpsRedirect {
+    pub fn new(port: u16, tls_listeners: Vec<SocketAddr>) -> Self {
+        Self {
+            port,
+            tls_listeners,
+        }
+    }
+
+    /// Whether requests reaching `listener` are redirected.
+    pub fn applies(&self, listener: SocketAddr) -> bool {
+        !self.tls_listeners.contains(&listener)
+    }
+
+    /// Where `req` for `host` goes over https. Requests that may carry a body keep their
+    /// method by getting a 308 rather than a 301.
+    pub fn target<B>(&self, req: &Request<B>, host: &str) -> Option<(StatusCode, HeaderValue)> {
+        let authority: http::uri::Authority = host.parse().ok()?;
+        let mut location = format!("https://{}", authority.host());
+        if self.port != 443 {
+            location.push_str(&format!(":{}", self.port));
+        }
+        location.push_str(req.uri().path_and_query().map_or("/", |path| path.as_str()));
+        location.parse::<Uri>().ok()?;
+
+        let status = match *req.method() {
+            Method::GET | Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+            _ => StatusCode::PERMANENT_REDIRECT,
+        };
+        Some((status, HeaderValue::from_str(&location).ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirects() {
+        let config: RedirectConfig =
+            toml::from_str("to = \"https://new.example.com/v2{path}{query}\"\nstatus = 308")
+                .unwrap();
+        assert!(config.validate().is_ok());
+        let req = Request::get("/users?page=2")
+            .header("host", "old.example.com")
+            .body(())
+            .unwrap();
+        let context = RequestContext::new("10.0.0.9:5000".parse().unwrap(), &req);
+        let (status, location) = Redirect::from_config(&config).target(&context).unwrap();
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(location, "https://new.example.com/v2/users?page=2");
+
+        let invalid = |toml: &str| toml::from_str::<RedirectConfig>(toml).unwrap().validate();
+        assert!(invalid("to = \"/\"\nstatus = 200").is_err());
+        assert!(invalid("to = \"/{nope}\"").is_err());
+
+        let https = HttpsRedirect::new(8443, vec!["0.0.0.0:443".parse().unwrap()]);
+        assert!(!https.applies("0.0.0.0:443".parse().unwrap()));
+        assert!(https.applies("0.0.0.0:80".parse().unwrap()));
+        let (status, location) = https.target(&req, "old.example.com:80").unwrap();
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(location, "https://old.example.com:8443/users?page=2");
+        let post = Request::post("/form").body(()).unwrap();
+        let (status, _) = https.target(&post, "old.example.com").unwrap();
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    }
+}
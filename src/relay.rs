@@ -0,0 +1,435 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU8, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional_with_sizes},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+/// Bytes copied each way at a time unless a relay is given another size, tokio's own default.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Last time either side of a relay produced data, in milliseconds since the relay started.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    started: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_ms.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+/// One of the two streams given to [`relay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+/// What a relay copied and which side closed first, still there when the relay failed.
+#[derive(Debug, Default)]
+pub struct Transfer {
+    from_a: AtomicU64,
+    from_b: AtomicU64,
+    /// 0 while both sides are open, then 1 for `a` or 2 for `b`
+    closed_first: AtomicU8,
+    started: OnceLock<Instant>,
+}
+
+impl Transfer {
+    /// The bytes read from `a` and from `b`.
+    pub fn copied(&self) -> (u64, u64) {
+        (
+            self.from_a.load(Ordering::Relaxed),
+            self.from_b.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The side that sent end of file first, `None` while neither has.
+    pub fn closed_first(&self) -> Option<Side> {
+        match self.closed_first.load(Ordering::Relaxed) {
+            1 => Some(Side::A),
+            2 => Some(Side::B),
+            _ => None,
+        }
+    }
+
+    /// When the relay began copying, `None` until it has.
+    pub fn started(&self) -> Option<Instant> {
+        self.started.get().copied()
+    }
+
+    pub(crate) fn start(&self) {
+        let _ = self.started.set(Instant::now());
+    }
+
+    /// Counts `len` bytes read from `side`, for sessions copying without [`relay_tracked`].
+    pub(crate) fn read(&self, side: Side, len: usize) {
+        let counter = match side {
+            Side::A => &self.from_a,
+            Side::B => &self.from_b,
+        };
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn closed(&self, side: Side) {
+        let side = match side {
+            Side::A => 1,
+            Side::B => 2,
+        };
+        let _ = self
+            .closed_first
+            .compare_exchange(0, side, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+/// Records reads on the wrapped stream into `transfer`, and into `activity` when idle sessions
+/// are timed out.
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    side: Side,
+    transfer: &'a Transfer,
+    activity: Option<Arc<Activity>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let wanted = buf.remaining() > 0;
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - before;
+            if read > 0 {
+                self.transfer.read(self.side, read);
+                if let Some(activity) = &self.activity {
+                    activity.touch();
+                }
+            } else if wanted {
+                self.transfer.closed(self.side);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies data both ways until either side closes. With `idle_timeout`, the relay is torn down
+/// with `ErrorKind::TimedOut` once neither side has sent anything for that long.
+///
+/// Returns the bytes copied from `a` to `b` and from `b` to `a`.
+pub async fn relay<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let transfer = Transfer::default();
+    relay_tracked(a, b, idle_timeout, &transfer, DEFAULT_BUFFER_SIZE).await
+}
+
+/// [`relay`], counting into `transfer` as it goes so what was copied is known even when the
+/// relay fails or is cancelled. Each direction is copied through a buffer of `buffer_size`.
+pub async fn relay_tracked<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_timeout: Option<Duration>,
+    transfer: &Transfer,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    transfer.start();
+    let activity = idle_timeout.map(|_| Arc::new(Activity::new()));
+    let mut a = Tracked {
+        inner: a,
+        side: Side::A,
+        transfer,
+        activity: activity.clone(),
+    };
+    let mut b = Tracked {
+        inner: b,
+        side: Side::B,
+        transfer,
+        activity: activity.clone(),
+    };
+
+    let copy = copy_bidirectional_with_sizes(&mut a, &mut b, buffer_size, buffer_size);
+    let (Some(idle_timeout), Some(activity)) = (idle_timeout, activity) else {
+        return copy.await;
+    };
+
+    tokio::select! {
+        copied = copy => copied,
+        _ = idle_watchdog(&activity, idle_timeout) => Err(idle_error(idle_timeout)),
+    }
+}
+
+/// How [`relay_tcp`] moves a session's bytes between its two sockets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RelayPath {
+    /// Through a buffer on the runtime, like [`relay_tracked`]
+    #[default]
+    Copy,
+    /// Through a pipe with splice(2), never leaving the kernel
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    Splice,
+    /// On the io_uring threads, which must have been started
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    Uring,
+}
+
+impl RelayPath {
+    /// The fastest path this build and kernel offer: the io_uring threads when `io_uring` asks
+    /// for them and they started, splice(2) when the kernel supports it, copying otherwise.
+    pub fn fastest(io_uring: bool) -> Self {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        if io_uring && crate::uring::started() {
+            return Self::Uring;
+        }
+        #[cfg(all(feature = "splice", target_os = "linux"))]
+        if crate::splice::supported() {
+            return Self::Splice;
+        }
+        let _ = io_uring;
+        Self::Copy
+    }
+}
+
+/// [`relay_tracked`] between two sockets, moving the bytes along `path`, `buffer_size` at a
+/// time.
+pub async fn relay_tcp(
+    mut a: TcpStream,
+    mut b: TcpStream,
+    idle_timeout: Option<Duration>,
+    transfer: &Arc<Transfer>,
+    buffer_size: usize,
+    path: RelayPath,
+) -> io::Result<(u64, u64)> {
+    match path {
+        RelayPath::Copy => relay_tracked(&mut a, &mut b, idle_timeout, transfer, buffer_size).await,
+        #[cfg(all(feature = "splice", target_os = "linux"))]
+        RelayPath::Splice => relay_spliced(&a, &b, idle_timeout, transfer, buffer_size).await,
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        RelayPath::Uring => {
+            crate::uring::relay(a, b, idle_timeout, transfer.clone(), buffer_size).await
+        }
+    }
+}
+
+#[cfg(all(feature = "splice", target_os = "linux"))]
+async fn relay_spliced(
+    a: &TcpStream,
+    b: &TcpStream,
+    idle_timeout: Option<Duration>,
+    transfer: &Transfer,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)> {
+    use crate::splice::copy;
+
+    transfer.start();
+    let activity = &Activity::new();
+    let one_way = move |from, to, side| {
+        copy(from, to, buffer_size, move |len| match len {
+            0 => transfer.closed(side),
+            len => {
+                transfer.read(side, len);
+                activity.touch();
+            }
+        })
+    };
+    let session = async { tokio::try_join!(one_way(a, b, Side::A), one_way(b, a, Side::B)) };
+
+    let Some(idle_timeout) = idle_timeout else {
+        return session.await;
+    };
+    tokio::select! {
+        copied = session => copied,
+        _ = idle_watchdog(activity, idle_timeout) => Err(idle_error(idle_timeout)),
+    }
+}
+
+pub(crate) fn idle_error(idle_timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("session idle for longer than {:?}", idle_timeout),
+    )
+}
+
+pub(crate) async fn idle_watchdog(activity: &Activity, idle_timeout: Duration) {
+    loop {
+        let idle = activity.idle_for();
+        if idle >= idle_timeout {
+            return;
+        }
+        sleep(idle_timeout - idle).await;
+    }
+}
+
+/// Waits for the client to send its first byte, without consuming it.
+pub async fn await_first_byte(stream: &TcpStream, deadline: Duration) -> io::Result<()> {
+    let mut buf = [0u8; 1];
+    match timeout(deadline, stream.peek(&mut buf)).await {
+        Ok(Ok(0)) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "client closed before sending any data",
+        )),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("client sent no data within {:?}", deadline),
+        )),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, duplex},
+        net::TcpListener,
+    };
+
+    /// Both ends of a loopback tcp connection, the connecting one first.
+    pub(crate) async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Relays a session along `path` several buffers long one way and short the other, with
+    /// the upstream side closing first.
+    pub(crate) async fn check_relay_tcp(path: RelayPath) {
+        let (mut client, client_side) = connected().await;
+        let (upstream_side, mut upstream) = connected().await;
+        let transfer = Arc::new(Transfer::default());
+
+        let tracked = transfer.clone();
+        let relay = tokio::spawn(async move {
+            relay_tcp(client_side, upstream_side, None, &tracked, 4096, path).await
+        });
+
+        let payload = vec![7u8; 200_005];
+        client.write_all(&payload).await.unwrap();
+        let mut received = vec![0u8; payload.len()];
+        upstream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+        upstream.write_all(b"pong!").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"pong!");
+        assert_eq!(transfer.closed_first(), Some(Side::B));
+        drop(client);
+
+        let copied = (payload.len() as u64, 5);
+        assert_eq!(relay.await.unwrap().unwrap(), copied);
+        assert_eq!(transfer.copied(), copied);
+    }
+
+    #[tokio::test]
+    async fn test_relay_tcp_copying() {
+        check_relay_tcp(RelayPath::Copy).await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_closes_idle_session() {
+        let (mut client, mut client_side) = duplex(64);
+        let (mut upstream_side, mut upstream) = duplex(64);
+
+        let relay = tokio::spawn(async move {
+            relay(
+                &mut client_side,
+                &mut upstream_side,
+                Some(Duration::from_millis(100)),
+            )
+            .await
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let err = relay.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_relay_reports_closing_side() {
+        let (mut client, mut client_side) = duplex(64);
+        let (mut upstream_side, mut upstream) = duplex(64);
+        let transfer = Arc::new(Transfer::default());
+
+        let tracked = transfer.clone();
+        let relay = tokio::spawn(async move {
+            relay_tracked(&mut client_side, &mut upstream_side, None, &tracked, 16).await
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        upstream.write_all(b"pong!").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(transfer.closed_first(), Some(Side::B));
+        drop(client);
+
+        assert_eq!(relay.await.unwrap().unwrap(), (4, 5));
+        assert_eq!(transfer.copied(), (4, 5));
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    error::Error,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::mpsc,
+};
+use toml::Value;
+
+use crate::{
+    config::{Config, ConfigFormat, ConfigOverrides},
+    events::{EventKind, EventLog},
+    logger,
+};
+
+/// How long the config file has to stay unchanged before a change is picked up, editors often
+/// save in more than one write.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Settings whose values are kept out of the logged diff.
+const SECRET_KEYS: [&str; 2] = [".token", "redis_url"];
+
+/// Re-reads the config on every SIGHUP, and whenever its file changes if `watch_config` is set,
+/// and passes it on once it has loaded and validated. A config failing either is logged and
+/// recorded as an event, and the running config is kept as a whole. The log level is applied
+/// here, everything else by the receiver.
+pub fn spawn_config_reload(
+    overrides: ConfigOverrides,
+    running: &Config,
+    events: Arc<EventLog>,
+) -> mpsc::Receiver<Config> {
+    let (sender, receiver) = mpsc::channel(1);
+    let path = running.source().to_path_buf();
+    let format = running.format();
+    let profile = running.profile().map(str::to_string);
+    let watch = running.watch_config();
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                tracing::error!(
+                    "failed to listen for SIGHUP, config reload on SIGHUP disabled: {}",
+                    e
+                );
+                None
+            }
+        };
+
+        let mut changes = match watch.then(|| ConfigWatcher::new(&path)) {
+            Some(Ok(watcher)) => Some(watcher),
+            Some(Err(e)) => {
+                tracing::error!(
+                    "failed to watch {}, config reload on change disabled: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+            None => None,
+        };
+
+        let mut applied = read_settings(&path, format, profile.as_deref())
+            .unwrap_or_else(|_| Value::Table(Default::default()));
+
+        loop {
+            let on_change = tokio::select! {
+                Some(_) = async { hangup.as_mut()?.recv().await } => false,
+                Some(_) = async { changes.as_mut()?.changed().await } => true,
+                else => return,
+            };
+
+            let (cfg, settings) = match load(&overrides) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    let message =
+                        format!("config reload rejected, keeping the running config: {}", e);
+                    tracing::error!("{}", message);
+                    events.record(EventKind::Reload, message);
+                    continue;
+                }
+            };
+
+            let changed = diff(&applied, &settings);
+            if changed.is_empty() && on_change {
+                tracing::debug!(
+                    "{} was written without changing any setting",
+                    path.display()
+                );
+                continue;
+            }
+
+            tracing::info!(
+                "reloading {}, {} settings changed",
+                path.display(),
+                changed.len()
+            );
+            for change in &changed {
+                tracing::info!("  {}", change);
+            }
+
+            applied = settings;
+            logger::reload_levels(&cfg);
+            if sender.send(cfg).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Loads the config and checks up front what applying it could trip over, so a reload is
+/// applied fully or not at all. Also returns the file's raw settings to diff against.
+fn load(overrides: &ConfigOverrides) -> Result<(Config, Value), String> {
+    let cfg = Config::load(overrides.clone()).map_err(|e| describe(&e))?;
+
+    if let Some(backend) = cfg.backends().iter().find(|b| b.peers.is_empty()) {
+        return Err(format!("backend {} has no peers", backend.name));
+    }
+
+    // a fresh copy, so the running balancer's lists are untouched
+    cfg.pool_security()
+        .load_list_files()
+        .map_err(|e| describe(&e))?;
+
+    let settings = read_settings(cfg.source(), cfg.format(), cfg.profile())?;
+    Ok((cfg, settings))
+}
+
+fn read_settings(
+    path: &Path,
+    format: ConfigFormat,
+    profile: Option<&str>,
+) -> Result<Value, String> {
+    Config::settings(path, format, profile).map_err(|e| describe(&e))
+}
+
+/// `e` followed by its sources, the top level config errors alone don't say what is wrong.
+fn describe(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+/// Filesystem events for the config file.
+struct ConfigWatcher {
+    /// events stop once this is dropped
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl ConfigWatcher {
+    fn new(path: &Path) -> notify::Result<Self> {
+        // editors often save by renaming a new file over the old one, which a watch on the file
+        // itself loses track of, so the directory is watched instead
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let name: Option<OsString> = path.file_name().map(Into::into);
+
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event)
+                    if !event.kind.is_access()
+                        && event.paths.iter().any(|p| p.file_name() == name.as_deref()) =>
+                {
+                    let _ = sender.send(());
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("error watching the config file: {}", e),
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Waits for the file to change and then to stay unchanged for `WATCH_DEBOUNCE`.
+    async fn changed(&mut self) -> Option<()> {
+        self.events.recv().await?;
+        loop {
+            match tokio::time::timeout(WATCH_DEBOUNCE, self.events.recv()).await {
+                Ok(Some(())) => continue,
+                Ok(None) => return None,
+                Err(_) => return Some(()),
+            }
+        }
+    }
+}
+
+/// One line per setting that differs between `old` and `new`, e.g.
+/// `loadbalancer.max_connections: 1000 -> 2000`. Tables and arrays are compared entry by entry.
+fn diff(old: &Value, new: &Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff_values("", Some(old), Some(new), &mut changes);
+    changes
+}
+
+fn diff_values(key: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<String>) {
+    if old == new {
+        return;
+    }
+
+    let (Some(old_entries), Some(new_entries)) = (entries(key, old), entries(key, new)) else {
+        changes.push(format!("{}: {} -> {}", key, show(key, old), show(key, new)));
+        return;
+    };
+
+    for (entry, old_value) in &old_entries {
+        let new_value = new_entries
+            .iter()
+            .find(|(k, _)| k == entry)
+            .map(|(_, v)| *v);
+        diff_values(entry, Some(old_value), new_value, changes);
+    }
+    for (entry, new_value) in &new_entries {
+        if !old_entries.iter().any(|(k, _)| k == entry) {
+            diff_values(entry, None, Some(new_value), changes);
+        }
+    }
+}
+
+/// The keyed entries of a table or array, none for an unset value and `None` for a scalar.
+fn entries<'a>(key: &str, value: Option<&'a Value>) -> Option<Vec<(String, &'a Value)>> {
+    match value {
+        None => Some(Vec::new()),
+        Some(Value::Table(table)) => Some(
+            table
+                .iter()
+                .map(|(k, v)| match key {
+                    "" => (k.clone(), v),
+                    _ => (format!("{}.{}", key, k), v),
+                })
+                .collect(),
+        ),
+        Some(Value::Array(items)) => Some(
+            items
+                .iter()
+                .enumerate()
+                .map(|(idx, v)| (format!("{}[{}]", key, idx), v))
+                .collect(),
+        ),
+        Some(_) => None,
+    }
+}
+
+fn show(key: &str, value: Option<&Value>) -> String {
+    match value {
+        None => "unset".to_string(),
+        Some(_) if SECRET_KEYS.iter().any(|secret| key.ends_with(secret)) => "***".to_string(),
+        Some(value) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_settings() {
+        let old: Value = toml::from_str(
+            r#"
+            [loadbalancer]
+            max_connections = 1000
+            [[admin.tokens]]
+            token = "old-secret"
+            [backend]
+            peers = ["127.0.0.1:8080"]
+            "#,
+        )
+        .unwrap();
+        let new: Value = toml::from_str(
+            r#"
+            [loadbalancer]
+            max_connections = 2000
+            watch_config = true
+            [[admin.tokens]]
+            token = "new-secret"
+            [backend]
+            peers = ["127.0.0.1:8080", "127.0.0.1:8081"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            diff(&old, &new),
+            [
+                "admin.tokens[0].token: *** -> ***",
+                "backend.peers[1]: unset -> \"127.0.0.1:8081\"",
+                "loadbalancer.max_connections: 1000 -> 2000",
+                "loadbalancer.watch_config: unset -> true",
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// Retries the budget can save up, so a quiet route still has a few to spend on its next
+/// failures.
+const MAX_SAVED_RETRIES: u64 = 10;
+
+/// `retry` of a `[[route]]`: requests whose peer couldn't be connected to or answered with one
+/// of `statuses` are sent again, to another peer where there is one, up to `max_attempts`
+/// tries in all. Only requests made with a method that is safe to repeat are retried, and only
+/// those without a body unless `buffer_body_kb` lets it be kept to send again.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// the first try included
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_statuses")]
+    pub statuses: Vec<u16>,
+    #[serde(default = "default_connect_errors")]
+    pub connect_errors: bool,
+    /// wait before the first retry, doubled before each one after it
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// retries allowed as a percentage of requests, so a failing backend isn't sent several
+    /// times its usual traffic
+    #[serde(default = "default_budget_percent")]
+    pub budget_percent: u32,
+    /// bodies with a `Content-Length` up to this are read into memory before the request is
+    /// sent, rather than streamed, so it can be sent again; 0 streams every body
+    #[serde(default)]
+    pub buffer_body_kb: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    2
+}
+
+fn default_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_connect_errors() -> bool {
+    true
+}
+
+fn default_backoff_ms() -> u64 {
+    25
+}
+
+fn default_budget_percent() -> u32 {
+    20
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        if let Some(status) = self.statuses.iter().find(|s| !(100..=599).contains(*s)) {
+            return Err(format!("{} is not an http status", status));
+        }
+        if self.budget_percent == 0 {
+            return Err("budget_percent must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A route's [`RetryConfig`] with the budget its requests share.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    config: RetryConfig,
+    budget: Arc<RetryBudget>,
+}
+
+impl RetryPolicy {
+    /// `config` was checked by [`RetryConfig::validate`] when the config was loaded.
+    pub fn from_config(config: &RetryConfig) -> Self {
+        Self {
+            config: config.clone(),
+            budget: Arc::new(RetryBudget::new(config.budget_percent)),
+        }
+    }
+
+    /// Tries a request made with `method` gets, counting it towards the budget. Methods that
+    /// may change something when repeated get one.
+    pub fn attempts(&self, method: &Method) -> u32 {
+        self.budget.deposit();
+        if self.retries_method(method) {
+            self.config.max_attempts
+        } else {
+            1
+        }
+    }
+
+    /// Whether requests made with `method` can be repeated without changing anything more.
+    pub fn retries_method(&self, method: &Method) -> bool {
+        matches!(
+            *method,
+            Method::GET
+                | Method::HEAD
+                | Method::OPTIONS
+                | Method::TRACE
+                | Method::PUT
+                | Method::DELETE
+        )
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.config.statuses.contains(&status.as_u16())
+    }
+
+    pub fn retries_connect_errors(&self) -> bool {
+        self.config.connect_errors
+    }
+
+    /// The largest body in bytes held back to retry the request with, 0 for none.
+    pub fn buffer_body(&self) -> u64 {
+        self.config.buffer_body_kb.saturating_mul(1024)
+    }
+
+    /// Whether the budget allows another retry, spending it if so.
+    pub fn try_retry(&self) -> bool {
+        self.budget.withdraw()
+    }
+
+    /// How long to wait before retry number `retry`, counting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u64 << retry.saturating_sub(1).min(16);
+        Duration::from_millis(self.config.backoff_ms.saturating_mul(factor))
+    }
+}
+
+/// Retries saved up by the requests of a route, in hundredths of a retry: each request adds
+/// `percent` and each retry takes a whole one.
+#[derive(Debug)]
+struct RetryBudget {
+    percent: u64,
+    balance: AtomicU64,
+}
+
+impl RetryBudget {
+    fn new(percent: u32) -> Self {
+        Self {
+            percent: u64::from(percent),
+            balance: AtomicU64::new(MAX_SAVED_RETRIES * 100),
+        }
+    }
+
+    fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                Some((balance + self.percent).min(MAX_SAVED_RETRIES * 100))
+            });
+    }
+
+    fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                balance.checked_sub(100)
+            })
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let config: RetryConfig = toml::from_str("max_attempts = 3\nbudget_percent = 50").unwrap();
+        assert!(config.validate().is_ok());
+        let policy = RetryPolicy::from_config(&config);
+
+        assert_eq!(policy.attempts(&Method::GET), 3);
+        assert_eq!(policy.attempts(&Method::POST), 1);
+        assert!(policy.retries_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!policy.retries_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!(policy.backoff(1), Duration::from_millis(25));
+        assert_eq!(policy.backoff(3), Duration::from_millis(100));
+
+        // the saved up retries run out, then every other request earns one back
+        while policy.try_retry() {}
+        policy.attempts(&Method::GET);
+        assert!(!policy.try_retry());
+        policy.attempts(&Method::GET);
+        assert!(policy.try_retry());
+
+        let invalid = |toml: &str| toml::from_str::<RetryConfig>(toml).unwrap().validate();
+        assert!(invalid("max_attempts = 0").is_err());
+        assert!(invalid("statuses = [700]").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use http::uri::PathAndQuery;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// One entry of a `[[route]]`'s `rewrite`: a request whose path and query `regex` finds a match
+/// in is forwarded with the match replaced by `replacement`, where `$1` or `${name}` stand for
+/// what a group captured and `$$` for a dollar sign. E.g. `^/v1/(.*)$` and `/api/$1`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub regex: String,
+    pub replacement: String,
+}
+
+impl RewriteConfig {
+    /// `regex` compiles, and `replacement` is a path that only names groups `regex` has.
+    pub fn validate(&self) -> Result<(), String> {
+        let regex = Regex::new(&self.regex).map_err(|e| e.to_string())?;
+        if !self.replacement.starts_with(['/', '$']) {
+            return Err(format!("{} doesn't start with /", self.replacement));
+        }
+        for group in group_refs(&self.replacement)? {
+            let known = match group.parse::<usize>() {
+                Ok(idx) => idx < regex.captures_len(),
+                Err(_) => regex.capture_names().any(|name| name == Some(group)),
+            };
+            if !known {
+                return Err(format!("{} has no group {}", self.regex, group));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The groups `replacement` refers to, by number or name.
+fn group_refs(replacement: &str) -> Result<Vec<&str>, String> {
+    let mut groups = Vec::new();
+    let mut rest = replacement;
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        let (group, after) = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}').ok_or("unterminated ${")?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            // like the regex crate, the longest run of name characters after the $
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        if group.is_empty() {
+            return Err("$ without a group, write $$ for a dollar sign".to_string());
+        }
+        groups.push(group);
+        rest = after;
+    }
+    Ok(groups)
+}
+
+/// A route's rewrite rules ready to apply, the first one matching a request's target wins.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Rewrite {
+    /// `rules` were checked by [`RewriteConfig::validate`] when the config was loaded.
+    pub fn from_config(rules: &[RewriteConfig]) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.regex).expect("validated regex");
+                (regex, rule.replacement.clone())
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// `target` rewritten by the first rule matching it, as it is when none does or the
+    /// rewritten one isn't a valid path.
+    pub fn apply(&self, target: &PathAndQuery) -> PathAndQuery {
+        let Some((regex, replacement)) = self
+            .rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(target.as_str()))
+        else {
+            return target.clone();
+        };
+
+        let rewritten = regex.replace(target.as_str(), replacement.as_str());
+        match rewritten.parse::<PathAndQuery>() {
+            Ok(rewritten) if rewritten.as_str().starts_with('/') => rewritten,
+            _ => {
+                tracing::debug!("rewriting {} gave {}, not a path", target, rewritten);
+                target.clone()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_rules() {
+        let rule = |regex: &str, replacement: &str| RewriteConfig {
+            regex: regex.to_string(),
+            replacement: replacement.to_string(),
+        };
+        let rules = [
+            rule(r"^/v1/(.*)$", "/api/$1"),
+            rule(r"^/users/(?P<id>\d+)$", "/profile?id=${id}"),
+            rule(r"^/v1/", "/never"),
+        ];
+        assert!(rules.iter().all(|rule| rule.validate().is_ok()));
+        assert!(rule(r"^/(a", "/$1").validate().is_err());
+        assert!(rule(r"^/(a)", "/$2").validate().is_err());
+        assert!(rule(r"^/(a)", "/${name}").validate().is_err());
+        assert!(rule(r"^/a", "b").validate().is_err());
+
+        let rewrite = Rewrite::from_config(&rules);
+        let target = |target: &'static str| PathAndQuery::from_static(target);
+        assert_eq!(
+            rewrite.apply(&target("/v1/users?page=2")),
+            "/api/users?page=2"
+        );
+        assert_eq!(rewrite.apply(&target("/users/42")), "/profile?id=42");
+        assert_eq!(rewrite.apply(&target("/other")), "/other");
+    }
+}
@@ -0,0 +1,880 @@
+use std::sync::Arc;
+
+use http::{HeaderName, Method, Request, header::HOST, uri::PathAndQuery};
+use regex::Regex;
+use rustls::pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bluegreen::{BlueGreen, BlueGreenConfig},
+    compression::{Compression, CompressionConfig},
+    errors::ConfigError,
+    experiment::{Experiment, ExperimentConfig},
+    headers::{HeaderRewrite, HeaderRules},
+    hostname::{HostPattern, normalize_host},
+    jwt::{JwtAuth, JwtConfig},
+    mirror::{Mirror, MirrorConfig},
+    pages::{ErrorPageConfig, ErrorPages, Maintenance, MaintenanceConfig},
+    ratelimit::{RateLimitConfig, RequestLimiter},
+    redirect::{Redirect, RedirectConfig},
+    retry::{RetryConfig, RetryPolicy},
+    rewrite::{Rewrite, RewriteConfig},
+    split::{SplitConfig, TrafficSplit},
+    timeouts::{RouteTimeouts, TimeoutConfig},
+};
+
+/// One `[[route]]`: requests for any of `hosts` whose path is under `path_prefix`, made with
+/// one of `methods` and carrying every one of `headers`, go to `backend` instead of the
+/// listener's pool, are spread over the backends of `split` or the variants of an `experiment`,
+/// or are answered with a `redirect`. Any of the conditions may be left out, not all. Their
+/// headers can be rewritten on the way to the peer and back, failed requests retried, a share of
+/// requests mirrored and responses compressed. A route with `jwt` only forwards requests with a
+/// valid bearer token, keeping the token from the peer with `auth = "terminate"`, one with
+/// `rate_limit` only so many per client, and one in `maintenance` is answered by jalb alone.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// `api.example.com`, or `*.example.com` for any single label below it; any host when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// `/api` matches `/api` and everything below it, but not `/apis`
+    pub path_prefix: Option<String>,
+    /// forward `/api/users` as `/users`
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// regex replacements of the path and query forwarded, after `strip_prefix`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrite: Vec<RewriteConfig>,
+    /// `GET`, `POST`... in any case, any method when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderMatch>,
+    /// the `[[backend]]` matching requests go to, unless they are split
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub backend: String,
+    /// backends sharing the route's requests by weight, e.g. a stable pool and a canary
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub split: Vec<SplitConfig>,
+    /// clients put in variants, each sent to a backend of its own
+    pub experiment: Option<ExperimentConfig>,
+    /// a second pool to switch `backend` for through the admin api
+    pub blue_green: Option<BlueGreenConfig>,
+    /// answer with a redirect instead of forwarding
+    pub redirect: Option<RedirectConfig>,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+    /// `max_request_body_kb` of `[loadbalancer]` for the route's requests
+    pub max_body_kb: Option<u64>,
+    pub retry: Option<RetryConfig>,
+    /// the route's own connect, first byte and total timeouts, over its backend's
+    pub timeouts: Option<TimeoutConfig>,
+    pub mirror: Option<MirrorConfig>,
+    pub compression: Option<CompressionConfig>,
+    /// what clients get instead of jalb's own error responses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub error_pages: Vec<ErrorPageConfig>,
+    /// answer requests with a page instead of forwarding, `backend` may be left out when none
+    /// are let through
+    pub maintenance: Option<MaintenanceConfig>,
+    pub jwt: Option<JwtConfig>,
+    /// who checks the credentials of the route's requests, jalb with `jwt` when it is set and
+    /// the peer otherwise
+    pub auth: Option<AuthMode>,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// `auth` of a `[[route]]`: whether jalb or the peer checks the credentials of its requests.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Forward `Authorization` and every other header untouched, `jwt` can't be set
+    Passthrough,
+    /// Check the bearer token with `jwt` and forward only the identity `claims_to_headers`
+    /// takes from it, not the token
+    Terminate,
+}
+
+/// A header a routed request must carry: with exactly `value`, with a value `regex` finds a
+/// match in, or with any value when neither is set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderMatch {
+    pub name: String,
+    pub value: Option<String>,
+    /// unanchored, `^...$` to match the whole value
+    pub regex: Option<String>,
+}
+
+impl RouteConfig {
+    /// Every route names valid hosts and prefixes, no two routes claim the same host and prefix,
+    /// and each sends to a configured backend.
+    pub fn validate(routes: &[RouteConfig], backends: &[&str]) -> Result<(), ConfigError> {
+        let mut claimed = Vec::new();
+
+        for (idx, route) in routes.iter().enumerate() {
+            let invalid = |reason: String| {
+                Err(ConfigError::InvalidRoutes(format!(
+                    "route {} to {}: {}",
+                    idx + 1,
+                    route.destination(),
+                    reason
+                )))
+            };
+
+            let prefix = route.path_prefix.as_deref();
+            if route.hosts.is_empty()
+                && prefix.is_none()
+                && route.methods.is_empty()
+                && route.headers.is_empty()
+            {
+                return invalid("set hosts, path_prefix, methods or headers".to_string());
+            }
+            if let Some(prefix) = prefix
+                && (!prefix.starts_with('/') || prefix.contains(['?', '#']))
+            {
+                return invalid(format!("path_prefix {} is not a path like /api", prefix));
+            }
+            if route.strip_prefix && prefix.is_none() {
+                return invalid("strip_prefix needs a path_prefix".to_string());
+            }
+            for rule in &route.rewrite {
+                if let Err(e) = rule.validate() {
+                    return invalid(format!("rewrite {}: {}", rule.regex, e));
+                }
+            }
+            if let Some(method) = route.methods.iter().find(|m| m.parse::<Method>().is_err()) {
+                return invalid(format!("{} is not an http method", method));
+            }
+            for header in &route.headers {
+                if HeaderName::try_from(header.name.as_str()).is_err() {
+                    return invalid(format!("{} is not a header name", header.name));
+                }
+                if header.value.is_some() && header.regex.is_some() {
+                    return invalid(format!(
+                        "header {}: set value or regex, not both",
+                        header.name
+                    ));
+                }
+                if let Some(Err(e)) = header.regex.as_deref().map(Regex::new) {
+                    return invalid(format!("header {}: {}", header.name, e));
+                }
+            }
+            if let Err(e) = route.request_headers.validate() {
+                return invalid(format!("request_headers: {}", e));
+            }
+            if let Err(e) = route.response_headers.validate() {
+                return invalid(format!("response_headers: {}", e));
+            }
+            if let Some(Err(e)) = route.retry.as_ref().map(RetryConfig::validate) {
+                return invalid(format!("retry: {}", e));
+            }
+            if let Some(Err(e)) = route.timeouts.as_ref().map(TimeoutConfig::validate) {
+                return invalid(format!("timeouts: {}", e));
+            }
+            if let Some(Err(e)) = route.mirror.as_ref().map(|m| m.validate(backends)) {
+                return invalid(format!("mirror: {}", e));
+            }
+            if let Some(Err(e)) = route.compression.as_ref().map(CompressionConfig::validate) {
+                return invalid(format!("compression: {}", e));
+            }
+            for (idx, page) in route.error_pages.iter().enumerate() {
+                if let Err(e) = page.validate() {
+                    return invalid(format!("error_pages {}: {}", idx + 1, e));
+                }
+            }
+            if let Some(Err(e)) = route.maintenance.as_ref().map(MaintenanceConfig::validate) {
+                return invalid(format!("maintenance: {}", e));
+            }
+            if let Some(Err(e)) = route.jwt.as_ref().map(JwtConfig::validate) {
+                return invalid(format!("jwt: {}", e));
+            }
+            match (route.auth, &route.jwt) {
+                (Some(AuthMode::Passthrough), Some(_)) => {
+                    return invalid("jwt doesn't apply to auth = \"passthrough\"".to_string());
+                }
+                (Some(AuthMode::Terminate), None) => {
+                    return invalid("auth = \"terminate\" needs a jwt".to_string());
+                }
+                _ => {}
+            }
+            if let Some(Err(e)) = route.rate_limit.as_ref().map(RateLimitConfig::validate) {
+                return invalid(format!("rate_limit: {}", e));
+            }
+
+            let hosts: Vec<Option<&String>> = match route.hosts.is_empty() {
+                true => vec![None],
+                false => route.hosts.iter().map(Some).collect(),
+            };
+            for host in hosts {
+                if let Some(host) = host {
+                    let name = host.strip_prefix("*.").unwrap_or(host);
+                    if name.is_empty() || ServerName::try_from(name).is_err() {
+                        return invalid(format!("{} is not a hostname or *.example.com", host));
+                    }
+                }
+
+                let claim = (
+                    host.map(|h| HostPattern::parse(h)),
+                    prefix.map(normalize_prefix),
+                    &route.methods,
+                    &route.headers,
+                );
+                if claimed.contains(&claim) {
+                    return invalid(format!(
+                        "{}{} is routed more than once",
+                        host.map_or("", String::as_str),
+                        prefix.unwrap_or("")
+                    ));
+                }
+                claimed.push(claim);
+            }
+
+            if let Some(redirect) = &route.redirect {
+                if !route.backend.is_empty()
+                    || !route.split.is_empty()
+                    || route.experiment.is_some()
+                {
+                    return invalid(
+                        "set backend, split, experiment or redirect, only one".to_string(),
+                    );
+                }
+                if let Err(e) = redirect.validate() {
+                    return invalid(format!("redirect: {}", e));
+                }
+                continue;
+            }
+            if let Some(experiment) = &route.experiment {
+                if !route.backend.is_empty() || !route.split.is_empty() {
+                    return invalid("set backend, split or experiment, only one".to_string());
+                }
+                if route.blue_green.is_some() {
+                    return invalid(
+                        "blue_green needs a backend rather than an experiment".to_string(),
+                    );
+                }
+                if let Err(e) = experiment.validate(backends) {
+                    return invalid(format!("experiment: {}", e));
+                }
+                continue;
+            }
+            match (route.backend.is_empty(), route.split.is_empty()) {
+                (true, true) if route.maintenance.as_ref().is_some_and(|m| !m.forwards()) => {}
+                (true, true) => {
+                    return invalid("set backend, split, experiment or redirect".to_string());
+                }
+                (false, false) => return invalid("set backend or split, not both".to_string()),
+                (false, true) if !backends.contains(&route.backend.as_str()) => {
+                    return invalid(format!("{} is not a configured backend", route.backend));
+                }
+                (false, true) => {
+                    if let Some(Err(e)) = route
+                        .blue_green
+                        .as_ref()
+                        .map(|blue_green| blue_green.validate(&route.backend, backends))
+                    {
+                        return invalid(format!("blue_green: {}", e));
+                    }
+                }
+                (true, false) => {
+                    if let Err(e) = SplitConfig::validate(&route.split, backends) {
+                        return invalid(format!("split: {}", e));
+                    }
+                    if route.blue_green.is_some() {
+                        return invalid(
+                            "blue_green needs a backend rather than a split".to_string(),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `backend`, the backends of `split` or `experiment`, or the target of a redirect.
+    fn destination(&self) -> String {
+        if let Some(redirect) = &self.redirect {
+            return redirect.to.clone();
+        }
+        if let Some(experiment) = &self.experiment {
+            let backends: Vec<&str> = experiment
+                .variants
+                .iter()
+                .map(|variant| variant.backend.as_str())
+                .collect();
+            return backends.join(" and ");
+        }
+        if self.backend.is_empty() && self.split.is_empty() && self.maintenance.is_some() {
+            return "maintenance".to_string();
+        }
+        if self.split.is_empty() {
+            return self.backend.clone();
+        }
+        let backends: Vec<&str> = self.split.iter().map(|arm| arm.backend.as_str()).collect();
+        backends.join(" and ")
+    }
+}
+
+/// `/api/` and `/api` cover the same paths.
+fn normalize_prefix(prefix: &str) -> &str {
+    match prefix.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    }
+}
+
+/// A route as requests are matched against it.
+#[derive(Debug, Clone)]
+pub struct Route {
+    hosts: Vec<HostPattern>,
+    /// without a trailing slash, `/` alone for every path
+    path_prefix: Option<String>,
+    strip_prefix: bool,
+    rewrite: Rewrite,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, ValueMatch)>,
+    /// the first of the split's or experiment's backends for a route with either
+    backend: String,
+    split: Option<Arc<TrafficSplit>>,
+    experiment: Option<Arc<Experiment>>,
+    blue_green: Option<Arc<BlueGreen>>,
+    redirect: Option<Redirect>,
+    request_headers: HeaderRewrite,
+    response_headers: HeaderRewrite,
+    /// in bytes
+    max_body: Option<u64>,
+    retry: Option<RetryPolicy>,
+    timeouts: RouteTimeouts,
+    mirror: Option<Mirror>,
+    compression: Option<Compression>,
+    error_pages: ErrorPages,
+    maintenance: Option<Arc<Maintenance>>,
+    jwt: Option<Arc<JwtAuth>>,
+    /// `Authorization` is removed once `jwt` accepted it
+    terminates_auth: bool,
+    rate_limit: Option<Arc<RequestLimiter>>,
+}
+
+#[derive(Debug, Clone)]
+enum ValueMatch {
+    Any,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl ValueMatch {
+    fn matches(&self, value: &[u8]) -> bool {
+        match self {
+            ValueMatch::Any => true,
+            ValueMatch::Exact(expected) => value == expected.as_bytes(),
+            ValueMatch::Regex(regex) => std::str::from_utf8(value).is_ok_and(|v| regex.is_match(v)),
+        }
+    }
+}
+
+impl Route {
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// How the route's requests are spread over several backends, when they are.
+    pub fn split(&self) -> Option<&Arc<TrafficSplit>> {
+        self.split.as_ref()
+    }
+
+    /// Which backend each client's requests go to, when the route runs an experiment.
+    pub fn experiment(&self) -> Option<&Experiment> {
+        self.experiment.as_deref()
+    }
+
+    /// Which of two pools takes the route's requests, when it has a standby one.
+    pub fn blue_green(&self) -> Option<&Arc<BlueGreen>> {
+        self.blue_green.as_ref()
+    }
+
+    /// Where the route's requests are redirected to, when they aren't forwarded.
+    pub fn redirect(&self) -> Option<&Redirect> {
+        self.redirect.as_ref()
+    }
+
+    /// Rewrites the headers of a request sent on to a peer.
+    pub fn request_headers(&self) -> &HeaderRewrite {
+        &self.request_headers
+    }
+
+    /// Rewrites the headers of the response to a routed request, jalb's own included.
+    pub fn response_headers(&self) -> &HeaderRewrite {
+        &self.response_headers
+    }
+
+    /// The largest request body the route passes on in bytes, when it sets its own.
+    pub fn max_body(&self) -> Option<u64> {
+        self.max_body
+    }
+
+    /// How requests that failed are retried, when they are.
+    pub fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// The route's own timeouts, each unset one left to the backend.
+    pub fn timeouts(&self) -> RouteTimeouts {
+        self.timeouts
+    }
+
+    /// Where copies of the route's requests go, when they are mirrored.
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    /// Which of the route's responses are compressed, when they are.
+    pub fn compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    /// The pages replacing jalb's own error responses to the route's requests.
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    /// The page requests are answered with while the route is in maintenance.
+    pub fn maintenance(&self) -> Option<&Maintenance> {
+        self.maintenance.as_deref()
+    }
+
+    /// Checks the bearer tokens of the route's requests, when it asks for one.
+    pub fn jwt(&self) -> Option<&JwtAuth> {
+        self.jwt.as_deref()
+    }
+
+    /// Whether the credentials `jwt` checked are kept from the peer.
+    pub fn terminates_auth(&self) -> bool {
+        self.terminates_auth
+    }
+
+    /// Caps the requests each client sends the route, when it is limited.
+    pub fn rate_limit(&self) -> Option<&RequestLimiter> {
+        self.rate_limit.as_deref()
+    }
+
+    /// How closely the route matches `req` for `host`, `None` when it doesn't. The host counts
+    /// first, an exact one over a wildcard over any host, then the longer prefix, then the
+    /// number of method and header conditions.
+    fn specificity<B>(&self, req: &Request<B>, host: Option<&str>) -> Option<(u8, usize, usize)> {
+        let host_rank = if self.hosts.is_empty() {
+            0
+        } else {
+            let host = host?;
+            self.hosts
+                .iter()
+                .filter(|pattern| pattern.matches(host))
+                .map(|pattern| match pattern {
+                    HostPattern::Exact(_) => 2,
+                    HostPattern::Wildcard(_) => 1,
+                })
+                .max()?
+        };
+
+        let prefix_len = match &self.path_prefix {
+            Some(prefix) if under_prefix(req.uri().path(), prefix) => prefix.len(),
+            Some(_) => return None,
+            None => 0,
+        };
+
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return None;
+        }
+        let headers_match = self.headers.iter().all(|(name, expected)| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .any(|value| expected.matches(value.as_bytes()))
+        });
+        if !headers_match {
+            return None;
+        }
+        let conditions = usize::from(!self.methods.is_empty()) + self.headers.len();
+
+        Some((host_rank, prefix_len, conditions))
+    }
+
+    /// The path and query to forward a request for `target` with: the prefix taken off with
+    /// `strip_prefix` and then `rewrite` applied, as written otherwise.
+    pub fn forwarded(&self, target: &PathAndQuery) -> PathAndQuery {
+        self.rewrite.apply(&self.stripped(target))
+    }
+
+    fn stripped(&self, target: &PathAndQuery) -> PathAndQuery {
+        let Some(prefix) = self.path_prefix.as_deref().filter(|_| self.strip_prefix) else {
+            return target.clone();
+        };
+
+        let rest = target.path().strip_prefix(prefix).unwrap_or(target.path());
+        let path = match rest {
+            "" => "/".to_string(),
+            rest if rest.starts_with('/') => rest.to_string(),
+            rest => format!("/{}", rest),
+        };
+        let stripped = match target.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        stripped.parse().unwrap_or_else(|_| target.clone())
+    }
+}
+
+/// Whether `path` is `prefix` or below it. `prefix` has no trailing slash except for `/`.
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix == "/" || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Picks the route of each request to the application balancer, before a peer is picked from
+/// the route's pool.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn from_config(routes: &[RouteConfig]) -> Self {
+        let routes = routes
+            .iter()
+            .enumerate()
+            .map(|(idx, route)| Route {
+                hosts: route.hosts.iter().map(|h| HostPattern::parse(h)).collect(),
+                path_prefix: route
+                    .path_prefix
+                    .as_deref()
+                    .map(|p| normalize_prefix(p).to_string()),
+                strip_prefix: route.strip_prefix,
+                rewrite: Rewrite::from_config(&route.rewrite),
+                methods: route
+                    .methods
+                    .iter()
+                    .filter_map(|m| m.to_ascii_uppercase().parse().ok())
+                    .collect(),
+                headers: route.headers.iter().map(header_matcher).collect(),
+                backend: match (route.split.first(), &route.experiment) {
+                    (Some(arm), _) => arm.backend.clone(),
+                    (None, Some(experiment)) => experiment
+                        .variants
+                        .first()
+                        .map_or_else(String::new, |variant| variant.backend.clone()),
+                    (None, None) => route.backend.clone(),
+                },
+                split: (!route.split.is_empty())
+                    .then(|| Arc::new(TrafficSplit::from_config(idx + 1, &route.split))),
+                experiment: route
+                    .experiment
+                    .as_ref()
+                    .map(|experiment| Arc::new(Experiment::from_config(experiment))),
+                blue_green: route.blue_green.as_ref().map(|blue_green| {
+                    Arc::new(BlueGreen::from_config(idx + 1, &route.backend, blue_green))
+                }),
+                redirect: route.redirect.as_ref().map(Redirect::from_config),
+                request_headers: HeaderRewrite::from_rules(&route.request_headers),
+                response_headers: HeaderRewrite::from_rules(&route.response_headers),
+                max_body: route.max_body_kb.map(|kb| kb.saturating_mul(1024)),
+                retry: route.retry.as_ref().map(RetryPolicy::from_config),
+                timeouts: route
+                    .timeouts
+                    .as_ref()
+                    .map(RouteTimeouts::from_config)
+                    .unwrap_or_default(),
+                mirror: route.mirror.as_ref().map(Mirror::from_config),
+                compression: route.compression.as_ref().map(Compression::from_config),
+                error_pages: ErrorPages::from_config(&route.error_pages),
+                maintenance: route
+                    .maintenance
+                    .as_ref()
+                    .map(|maintenance| {
+                        let has_backend = !route.backend.is_empty()
+                            || !route.split.is_empty()
+                            || route.experiment.is_some();
+                        Arc::new(Maintenance::from_config(idx + 1, maintenance, has_backend))
+                    }),
+                jwt: route
+                    .jwt
+                    .as_ref()
+                    .map(|jwt| Arc::new(JwtAuth::from_config(jwt))),
+                terminates_auth: route.auth == Some(AuthMode::Terminate),
+                rate_limit: route
+                    .rate_limit
+                    .as_ref()
+                    .map(|limit| Arc::new(RequestLimiter::from_config(limit))),
+            })
+            .collect();
+
+        Self { routes }
+    }
+
+    /// Every split route's split, in config order.
+    pub fn splits(&self) -> Vec<Arc<TrafficSplit>> {
+        self.routes.iter().filter_map(|r| r.split.clone()).collect()
+    }
+
+    /// Every route's maintenance, in config order.
+    pub fn maintenances(&self) -> Vec<Arc<Maintenance>> {
+        self.routes
+            .iter()
+            .filter_map(|r| r.maintenance.clone())
+            .collect()
+    }
+
+    /// Every blue-green route's pools, in config order.
+    pub fn blue_greens(&self) -> Vec<Arc<BlueGreen>> {
+        self.routes
+            .iter()
+            .filter_map(|r| r.blue_green.clone())
+            .collect()
+    }
+
+    /// The route `req` matches most closely, the first of them on a tie. `None` sends the
+    /// request to its listener's pool.
+    pub fn route<B>(&self, req: &Request<B>) -> Option<&Route> {
+        let host = request_host(req);
+
+        let mut best: Option<((u8, usize, usize), &Route)> = None;
+        for route in &self.routes {
+            if let Some(specificity) = route.specificity(req, host.as_deref())
+                && best.is_none_or(|(closest, _)| specificity > closest)
+            {
+                best = Some((specificity, route));
+            }
+        }
+
+        best.map(|(_, route)| route)
+    }
+}
+
+/// Names and regexes were checked by [`RouteConfig::validate`] when the config was loaded.
+fn header_matcher(header: &HeaderMatch) -> (HeaderName, ValueMatch) {
+    let name = HeaderName::try_from(header.name.as_str()).expect("validated header name");
+    let expected = match (&header.value, &header.regex) {
+        (Some(value), _) => ValueMatch::Exact(value.clone()),
+        (None, Some(regex)) => ValueMatch::Regex(Regex::new(regex).expect("validated regex")),
+        (None, None) => ValueMatch::Any,
+    };
+    (name, expected)
+}
+
+/// The host `req` is for: the authority of an absolute-form target, or its `Host` header.
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(normalize_host(host));
+    }
+
+    let header = req.headers().get(HOST)?.to_str().ok()?;
+    Some(normalize_host(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_routes() {
+        let route = |hosts: &[&str], backend: &str| RouteConfig {
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            path_prefix: None,
+            strip_prefix: false,
+            rewrite: Vec::new(),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            backend: backend.to_string(),
+            split: Vec::new(),
+            experiment: None,
+            blue_green: None,
+            redirect: None,
+            request_headers: HeaderRules::default(),
+            response_headers: HeaderRules::default(),
+            max_body_kb: None,
+            retry: None,
+            timeouts: None,
+            mirror: None,
+            compression: None,
+            error_pages: Vec::new(),
+            maintenance: None,
+            jwt: None,
+            auth: None,
+            rate_limit: None,
+        };
+        let routes = [
+            route(&["*.example.com"], "wildcard"),
+            route(&["api.example.com", "api.example.org"], "api"),
+        ];
+        assert!(RouteConfig::validate(&routes, &["wildcard", "api"]).is_ok());
+        assert!(RouteConfig::validate(&routes, &["api"]).is_err());
+        let twice = [
+            route(&["a.example.com"], "api"),
+            route(&["A.example.com."], "api"),
+        ];
+        assert!(RouteConfig::validate(&twice, &["api"]).is_err());
+
+        let router = Router::from_config(&routes);
+        let backend = |host: &str| {
+            let req = Request::get("/").header(HOST, host).body(()).unwrap();
+            router.route(&req).map(|route| route.backend().to_string())
+        };
+        // the exact host wins over the wildcard listed before it
+        assert_eq!(backend("API.example.com:8080").as_deref(), Some("api"));
+        assert_eq!(backend("www.example.com").as_deref(), Some("wildcard"));
+        assert_eq!(backend("example.com"), None);
+
+        let absolute = Request::get("http://api.example.org/x").body(()).unwrap();
+        assert_eq!(router.route(&absolute).unwrap().backend(), "api");
+    }
+
+    #[test]
+    fn test_path_routes() {
+        let route = |prefix: &str, strip_prefix: bool, backend: &str| RouteConfig {
+            hosts: Vec::new(),
+            path_prefix: Some(prefix.to_string()),
+            strip_prefix,
+            rewrite: Vec::new(),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            backend: backend.to_string(),
+            split: Vec::new(),
+            experiment: None,
+            blue_green: None,
+            redirect: None,
+            request_headers: HeaderRules::default(),
+            response_headers: HeaderRules::default(),
+            max_body_kb: None,
+            retry: None,
+            timeouts: None,
+            mirror: None,
+            compression: None,
+            error_pages: Vec::new(),
+            maintenance: None,
+            jwt: None,
+            auth: None,
+            rate_limit: None,
+        };
+        let mut cdn = route("/static", false, "cdn");
+        cdn.rewrite = vec![RewriteConfig {
+            regex: "^/static/(.*)$".to_string(),
+            replacement: "/assets/$1".to_string(),
+        }];
+        let routes = [
+            route("/api", true, "api"),
+            route("/api/v2/", false, "v2"),
+            cdn,
+        ];
+        assert!(RouteConfig::validate(&routes, &["api", "v2", "cdn"]).is_ok());
+        let twice = [route("/api", false, "api"), route("/api/", false, "v2")];
+        assert!(RouteConfig::validate(&twice, &["api", "v2"]).is_err());
+        assert!(RouteConfig::validate(&[route("api", false, "api")], &["api"]).is_err());
+        let mut terminated = route("/api", false, "api");
+        terminated.auth = Some(AuthMode::Terminate);
+        assert!(RouteConfig::validate(&[terminated], &["api"]).is_err());
+
+        let router = Router::from_config(&routes);
+        let forwarded = |target: &str| {
+            let req = Request::get(target).body(()).unwrap();
+            router.route(&req).map(|route| {
+                let path = route.forwarded(req.uri().path_and_query().unwrap());
+                (route.backend().to_string(), path.to_string())
+            })
+        };
+        // the longest prefix wins, and only whole path segments match
+        assert_eq!(
+            forwarded("/api/v2/users"),
+            Some(("v2".to_string(), "/api/v2/users".to_string()))
+        );
+        assert_eq!(
+            forwarded("/api/users?page=2"),
+            Some(("api".to_string(), "/users?page=2".to_string()))
+        );
+        assert_eq!(
+            forwarded("/api"),
+            Some(("api".to_string(), "/".to_string()))
+        );
+        assert_eq!(
+            forwarded("/static/app.js"),
+            Some(("cdn".to_string(), "/assets/app.js".to_string()))
+        );
+        assert_eq!(forwarded("/apis"), None);
+        assert_eq!(forwarded("/"), None);
+    }
+
+    #[test]
+    fn test_method_and_header_routes() {
+        let header = |name: &str, value: Option<&str>, regex: Option<&str>| HeaderMatch {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            regex: regex.map(str::to_string),
+        };
+        let route = |methods: &[&str], headers: Vec<HeaderMatch>, backend: &str| RouteConfig {
+            hosts: Vec::new(),
+            path_prefix: Some("/upload".to_string()),
+            strip_prefix: false,
+            rewrite: Vec::new(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            headers,
+            backend: backend.to_string(),
+            split: Vec::new(),
+            experiment: None,
+            blue_green: None,
+            redirect: None,
+            request_headers: HeaderRules::default(),
+            response_headers: HeaderRules::default(),
+            max_body_kb: None,
+            retry: None,
+            timeouts: None,
+            mirror: None,
+            compression: None,
+            error_pages: Vec::new(),
+            maintenance: None,
+            jwt: None,
+            auth: None,
+            rate_limit: None,
+        };
+        let routes = [
+            route(&[], Vec::new(), "uploads"),
+            route(&["POST", "PUT"], Vec::new(), "writes"),
+            route(&[], vec![header("X-Canary", Some("true"), None)], "canary"),
+            route(
+                &["POST"],
+                vec![header("user-agent", None, Some("^curl/"))],
+                "curl",
+            ),
+        ];
+        let backends = ["uploads", "writes", "canary", "curl"];
+        assert!(RouteConfig::validate(&routes, &backends).is_ok());
+        let bad_regex = route(&[], vec![header("x-a", None, Some("("))], "uploads");
+        assert!(RouteConfig::validate(&[bad_regex], &backends).is_err());
+        let bad_method = route(&["GE T"], Vec::new(), "uploads");
+        assert!(RouteConfig::validate(&[bad_method], &backends).is_err());
+
+        let router = Router::from_config(&routes);
+        let backend = |method: &str, headers: &[(&str, &str)]| {
+            let mut req = Request::builder().method(method).uri("/upload/a");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            let req = req.body(()).unwrap();
+            router.route(&req).map(|route| route.backend().to_string())
+        };
+        assert_eq!(backend("GET", &[]).as_deref(), Some("uploads"));
+        assert_eq!(backend("PUT", &[]).as_deref(), Some("writes"));
+        assert_eq!(
+            backend("GET", &[("x-canary", "true")]).as_deref(),
+            Some("canary")
+        );
+        assert_eq!(
+            backend("GET", &[("x-canary", "yes")]).as_deref(),
+            Some("uploads")
+        );
+        // two conditions beat one
+        let curl = [("user-agent", "curl/8.0")];
+        assert_eq!(backend("POST", &curl).as_deref(), Some("curl"));
+    }
+}
@@ -0,0 +1,116 @@
+use std::{env, fmt, fs};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::errors::ConfigError;
+
+/// A config value holding secret material, so it can be kept out of jalb.toml.
+///
+/// Written as `file:/path/to/secret` the value is the file's contents without trailing newlines.
+/// Otherwise every `${ENV_VAR}` in it is replaced by that variable, e.g.
+/// `redis://:${REDIS_PASSWORD}@127.0.0.1/`, and anything else is taken as written. A config file
+/// has its `${ENV_VAR}`s expanded with the rest of its text, so only a config built in code has
+/// them replaced here. Resolved once when the config is loaded; neither `Debug` nor `Serialize`
+/// show the value.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Replaces a `file:` reference with the file's contents, leaving any other value as it is.
+    /// `field` names the setting in errors.
+    pub fn read_file(&mut self, field: &str) -> Result<(), ConfigError> {
+        if let Some(path) = self.0.strip_prefix("file:") {
+            let contents = fs::read_to_string(path).map_err(|e| {
+                ConfigError::UnresolvedSecret(
+                    field.to_string(),
+                    format!("cannot read {}: {}", path, e),
+                )
+            })?;
+            self.0 = contents.trim_end_matches(['\r', '\n']).to_string();
+        }
+        Ok(())
+    }
+
+    /// Replaces the reference with what it points at, a `file:` or every `${ENV_VAR}`. `field`
+    /// names the setting in errors.
+    pub fn resolve(&mut self, field: &str) -> Result<(), ConfigError> {
+        let unresolved = |reason: String| ConfigError::UnresolvedSecret(field.to_string(), reason);
+
+        if self.0.starts_with("file:") {
+            return self.read_file(field);
+        }
+
+        let mut resolved = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find("${") {
+            resolved.push_str(&rest[..start]);
+            let Some(len) = rest[start + 2..].find('}') else {
+                return Err(unresolved("unterminated ${".to_string()));
+            };
+
+            let name = &rest[start + 2..start + 2 + len];
+            let value = env::var(name)
+                .map_err(|_| unresolved(format!("environment variable {} is not set", name)))?;
+            resolved.push_str(&value);
+            rest = &rest[start + 2 + len + 1..];
+        }
+        resolved.push_str(rest);
+
+        self.0 = resolved;
+        Ok(())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+/// Serialized as `***`, a dumped config never shows the value either.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("***")
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_references() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { env::set_var("JALB_TEST_SECRET", "hunter2") };
+
+        let mut inline = Secret::from("redis://:${JALB_TEST_SECRET}@127.0.0.1/");
+        inline.resolve("state.redis_url").unwrap();
+        assert_eq!(inline.expose(), "redis://:hunter2@127.0.0.1/");
+        assert_eq!(format!("{:?}", inline), "Secret(..)");
+
+        let path = env::temp_dir().join(format!("jalb-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let mut from_file = Secret::from(format!("file:{}", path.display()).as_str());
+        from_file.resolve("admin.tokens[0].token").unwrap();
+        assert_eq!(from_file.expose(), "from-file");
+        fs::remove_file(&path).unwrap();
+
+        let mut missing = Secret::from("${JALB_TEST_SECRET_UNSET}");
+        let err = missing.resolve("admin.tokens[0].token").unwrap_err();
+        assert!(err.to_string().contains("JALB_TEST_SECRET_UNSET"));
+
+        let mut missing_file = Secret::from("file:/nonexistent/jalb-secret");
+        assert!(missing_file.resolve("state.redis_url").is_err());
+    }
+}
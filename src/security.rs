@@ -1,13 +1,270 @@
-use std::{collections::HashSet, net::IpAddr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    task::JoinHandle,
+};
 
-use crate::config::NetworkTarget;
+use crate::{
+    asn::AsnDatabase,
+    hostname::HostPattern,
+    tls::TlsConfig,
+    errors::{SecurityError, StoreError},
+    store::StateStore,
+};
 
-#[derive(Debug, Deserialize, Clone)]
+/// State store namespace for timed bans, valued with the expiry in unix milliseconds.
+const BANS_PREFIX: &str = "bans/";
+
+/// Addresses and networks read from a newline-delimited list file. Blank lines and `#` comments
+/// are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct IpList {
+    addrs: HashSet<IpAddr>,
+    nets: Vec<IpNet>,
+}
+
+impl IpList {
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, SecurityError> {
+        let mut list = IpList::default();
+
+        for (idx, line) in contents.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            if let Ok(ip) = entry.parse::<IpAddr>() {
+                list.addrs.insert(ip);
+            } else if let Ok(net) = entry.parse::<IpNet>() {
+                list.nets.push(net.trunc());
+            } else {
+                return Err(SecurityError::InvalidListEntry(
+                    path.display().to_string(),
+                    idx + 1,
+                    entry.to_string(),
+                ));
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SecurityError> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(path, &contents)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.addrs.contains(ip) || self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty() && self.nets.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len() + self.nets.len()
+    }
+
+    /// Every address and network on the list.
+    pub fn entries(&self) -> impl Iterator<Item = String> + '_ {
+        let addrs = self.addrs.iter().map(IpAddr::to_string);
+        addrs.chain(self.nets.iter().map(IpNet::to_string))
+    }
+}
+
+/// What happens to an address that appears in neither list.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// What to do with a connection refused for exceeding a rate limit or connection cap.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Reset the connection immediately
+    #[default]
+    Reset,
+    /// Hold the connection open without ever answering, then drop it
+    Tarpit,
+    /// Leave the connection waiting until the limit clears, resetting it if it doesn't
+    Delay,
+    /// Answer with `429 Too Many Requests`, with the application balancer only
+    #[serde(rename = "http_429")]
+    TooManyRequests,
+}
+
+impl LimitAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LimitAction::Reset => "reset",
+            LimitAction::Tarpit => "tarpit",
+            LimitAction::Delay => "delay",
+            LimitAction::TooManyRequests => "http_429",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Blacklisted,
+    NotWhitelisted,
+    AsnDenied,
+    DefaultDeny,
+    HostMismatch,
+}
+
+impl RejectReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectReason::Blacklisted => "blacklisted",
+            RejectReason::NotWhitelisted => "not_whitelisted",
+            RejectReason::AsnDenied => "asn_denied",
+            RejectReason::DefaultDeny => "default_deny",
+            RejectReason::HostMismatch => "host_mismatch",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RejectionCounts {
+    blacklisted: AtomicU64,
+    not_whitelisted: AtomicU64,
+    asn_denied: AtomicU64,
+    default_deny: AtomicU64,
+    host_mismatch: AtomicU64,
+}
+
+impl RejectionCounts {
+    fn counter(&self, reason: RejectReason) -> &AtomicU64 {
+        match reason {
+            RejectReason::Blacklisted => &self.blacklisted,
+            RejectReason::NotWhitelisted => &self.not_whitelisted,
+            RejectReason::AsnDenied => &self.asn_denied,
+            RejectReason::DefaultDeny => &self.default_deny,
+            RejectReason::HostMismatch => &self.host_mismatch,
+        }
+    }
+}
+
+/// The lists and policies [`Security`] decides by, as the admin api shows them. The whitelist
+/// and blacklist hold both the configured entries and those of the list files.
+#[derive(Debug, Serialize)]
+pub struct SecurityLists {
+    pub whitelist: Vec<String>,
+    pub blacklist: Vec<String>,
+    /// addresses banned for a while, with the seconds left on each ban
+    pub timed_bans: BTreeMap<IpAddr, u64>,
+    pub asn_allow: Vec<u32>,
+    pub asn_deny: Vec<u32>,
+    pub default_policy: DefaultPolicy,
+    pub hostnames: Vec<String>,
+}
+
+/// A backend pool's overrides of the global `[security]` block. Anything left unset falls back to
+/// the global setting.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolSecurity {
+    /// Replaces the global whitelist for this pool
+    pub ip_whitelist: Option<HashSet<IpAddr>>,
+    /// Banned from this pool on top of the global blacklist
+    #[serde(default)]
+    pub ip_blacklist: HashSet<IpAddr>,
+    pub default_policy: Option<DefaultPolicy>,
+    pub on_limit: Option<LimitAction>,
+    pub max_connections: Option<u32>,
+    pub max_accepts_per_second: Option<u64>,
+    pub max_client_accepts_per_second: Option<u64>,
+    /// Replaces the global hostnames for this pool
+    pub hostnames: Option<Vec<String>>,
+    pub tls: Option<TlsConfig>,
+}
+
+/// Matches the host a connection asks for against the configured hostnames, counting
+/// mismatches with the other rejections.
+#[derive(Debug)]
+pub struct HostFilter {
+    patterns: Vec<HostPattern>,
+    rejections: Arc<RejectionCounts>,
+}
+
+impl HostFilter {
+    pub fn check(&self, host: Option<&str>) -> Result<(), RejectReason> {
+        let matched =
+            host.is_some_and(|host| self.patterns.iter().any(|pattern| pattern.matches(host)));
+        if matched {
+            return Ok(());
+        }
+
+        self.rejections
+            .counter(RejectReason::HostMismatch)
+            .fetch_add(1, Ordering::Relaxed);
+        Err(RejectReason::HostMismatch)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ListFiles {
+    whitelist: IpList,
+    blacklist: IpList,
+    asn: Option<AsnDatabase>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Security {
     ip_whitelist: HashSet<IpAddr>,
     ip_blacklist: HashSet<IpAddr>,
+    whitelist_file: Option<PathBuf>,
+    blacklist_file: Option<PathBuf>,
+    /// MaxMind `.mmdb` or `<cidr> <asn>` text file, reloaded along with the list files
+    asn_database: Option<PathBuf>,
+    /// Autonomous systems admitted like whitelisted addresses
+    #[serde(default)]
+    asn_allow: HashSet<u32>,
+    /// Autonomous systems rejected unless the address itself is whitelisted
+    #[serde(default)]
+    asn_deny: HashSet<u32>,
+    /// Applied to addresses that are neither blacklisted nor, with an empty whitelist, whitelisted
+    #[serde(default)]
+    default_policy: DefaultPolicy,
+    #[serde(default)]
+    on_limit: LimitAction,
+    /// Hosts that may be asked for by SNI or `Host` header, exact or `*.example.com`. Connections
+    /// naming another host, or none, are rejected. Empty disables the check.
+    #[serde(default)]
+    hostnames: Vec<String>,
+    /// Bans that lift themselves at the stored deadline. Shared between clones so a ban issued
+    /// through one handle is enforced everywhere.
+    #[serde(skip)]
+    timed_blacklist: Arc<RwLock<HashMap<IpAddr, Instant>>>,
+    /// Contents of `whitelist_file`, `blacklist_file` and `asn_database`, shared between clones like the timed
+    /// bans so a reload reaches every balancer.
+    #[serde(skip)]
+    list_files: Arc<RwLock<ListFiles>>,
+    #[serde(skip)]
+    rejections: Arc<RejectionCounts>,
+    /// Timed bans are written through to this store so they survive a restart
+    #[serde(skip)]
+    state_store: Option<Arc<dyn StateStore>>,
 }
 
 impl Security {
@@ -15,27 +272,373 @@ impl Security {
         Security {
             ip_blacklist: HashSet::new(),
             ip_whitelist: HashSet::new(),
+            whitelist_file: None,
+            blacklist_file: None,
+            asn_database: None,
+            asn_allow: HashSet::new(),
+            asn_deny: HashSet::new(),
+            default_policy: DefaultPolicy::default(),
+            on_limit: LimitAction::default(),
+            hostnames: Vec::new(),
+            timed_blacklist: Arc::new(RwLock::new(HashMap::new())),
+            list_files: Arc::new(RwLock::new(ListFiles::default())),
+            rejections: Arc::new(RejectionCounts::default()),
+            state_store: None,
+        }
+    }
+
+    /// Persists timed bans to `store` from now on. Call before cloning so every clone shares it.
+    pub fn set_state_store(&mut self, store: Arc<dyn StateStore>) {
+        self.state_store = Some(store);
+    }
+
+    pub fn state_store(&self) -> Option<Arc<dyn StateStore>> {
+        self.state_store.clone()
+    }
+
+    /// Reinstates the timed bans persisted by a previous run, returning how many are still in
+    /// force. Bans that expired while the balancer was down are dropped from the store.
+    pub fn restore_bans(&self) -> Result<usize, StoreError> {
+        let Some(store) = &self.state_store else {
+            return Ok(0);
+        };
+
+        let now = SystemTime::now();
+        let mut restored = 0;
+        for key in store.keys(BANS_PREFIX)? {
+            let ip = key[BANS_PREFIX.len()..].parse::<IpAddr>();
+            let expires_at = store
+                .get(&key)?
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+
+            let remaining = expires_at.and_then(|at| at.duration_since(now).ok());
+            match (ip, remaining) {
+                (Ok(ip), Some(remaining)) => {
+                    self.timed_blacklist
+                        .write()
+                        .unwrap()
+                        .insert(ip, Instant::now() + remaining);
+                    restored += 1;
+                }
+                _ => store.delete(&key)?,
+            }
+        }
+
+        Ok(restored)
+    }
+
+    fn persist_ban(&self, ip: &IpAddr, expires_at: Option<Instant>) {
+        let Some(store) = &self.state_store else {
+            return;
+        };
+
+        let key = format!("{}{}", BANS_PREFIX, ip);
+        let result = match expires_at {
+            Some(expires_at) => {
+                let remaining = expires_at.saturating_duration_since(Instant::now());
+                let unix_ms = (SystemTime::now() + remaining)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                store.put(&key, &unix_ms.to_string())
+            }
+            None => store.delete(&key),
+        };
+
+        if let Err(e) = result {
+            tracing::error!("failed to persist ban for {}: {}", ip, e);
+        }
+    }
+
+    /// Takes the address lists and policies from a reloaded `new`, keeping the timed bans,
+    /// rejection counts and state store. List files are left to the list file watcher, which
+    /// keeps polling the paths it started with.
+    pub fn reload_rules(&mut self, new: &Security) {
+        if self.whitelist_file != new.whitelist_file
+            || self.blacklist_file != new.blacklist_file
+            || self.asn_database != new.asn_database
+        {
+            tracing::warn!("list file and asn database paths only change on restart");
+        }
+
+        self.ip_whitelist = new.ip_whitelist.clone();
+        self.ip_blacklist = new.ip_blacklist.clone();
+        self.asn_allow = new.asn_allow.clone();
+        self.asn_deny = new.asn_deny.clone();
+        self.default_policy = new.default_policy;
+        self.on_limit = new.on_limit;
+        self.hostnames = new.hostnames.clone();
+    }
+
+    /// Addresses on both the whitelist and the blacklist, sorted. The blacklist wins, so
+    /// whitelisting them has no effect.
+    pub fn whitelisted_and_blacklisted(&self) -> Vec<IpAddr> {
+        let mut both: Vec<IpAddr> = self
+            .ip_whitelist
+            .intersection(&self.ip_blacklist)
+            .copied()
+            .collect();
+        both.sort();
+        both
+    }
+
+    /// The effective policy for a pool. Timed bans, list files and rejection counts stay shared
+    /// with the global policy.
+    pub fn for_pool(&self, pool: &PoolSecurity) -> Security {
+        let mut security = self.clone();
+        if let Some(whitelist) = &pool.ip_whitelist {
+            security.ip_whitelist = whitelist.clone();
+        }
+        security.ip_blacklist.extend(pool.ip_blacklist.iter().copied());
+        if let Some(policy) = pool.default_policy {
+            security.default_policy = policy;
+        }
+        if let Some(action) = pool.on_limit {
+            security.on_limit = action;
+        }
+        if let Some(hostnames) = &pool.hostnames {
+            security.hostnames = hostnames.clone();
+        }
+
+        security
+    }
+
+    pub fn with_default_policy(mut self, policy: DefaultPolicy) -> Self {
+        self.default_policy = policy;
+        self
+    }
+
+    pub fn on_limit(&self) -> LimitAction {
+        self.on_limit
+    }
+
+    /// Checker for `hostnames`, or `None` when any host is accepted.
+    pub fn host_filter(&self) -> Option<HostFilter> {
+        if self.hostnames.is_empty() {
+            return None;
+        }
+
+        Some(HostFilter {
+            patterns: self.hostnames.iter().map(|h| HostPattern::parse(h)).collect(),
+            rejections: self.rejections.clone(),
+        })
+    }
+
+    pub fn lists(&self) -> SecurityLists {
+        let files = self.list_files.read().unwrap();
+        let sorted = |configured: &HashSet<IpAddr>, file: &IpList| {
+            let mut entries: Vec<String> = configured.iter().map(IpAddr::to_string).collect();
+            entries.extend(file.entries());
+            entries.sort();
+            entries.dedup();
+            entries
+        };
+        let now = Instant::now();
+        let timed_bans = self
+            .timed_blacklist
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, expires_at)| **expires_at > now)
+            .map(|(ip, expires_at)| (*ip, (*expires_at - now).as_secs()))
+            .collect();
+        let mut asn_allow: Vec<u32> = self.asn_allow.iter().copied().collect();
+        asn_allow.sort();
+        let mut asn_deny: Vec<u32> = self.asn_deny.iter().copied().collect();
+        asn_deny.sort();
+
+        SecurityLists {
+            whitelist: sorted(&self.ip_whitelist, &files.whitelist),
+            blacklist: sorted(&self.ip_blacklist, &files.blacklist),
+            timed_bans,
+            asn_allow,
+            asn_deny,
+            default_policy: self.default_policy,
+            hostnames: self.hostnames.clone(),
         }
     }
 
+    /// Decides whether `ip` may connect, without counting the outcome. The blacklist always
+    /// wins, then a whitelisted address is admitted even from a denied autonomous system. A
+    /// non-empty whitelist or `asn_allow` admits only its entries; anything else falls to the
+    /// default policy.
+    pub fn evaluate(&self, ip: &IpAddr) -> Result<(), RejectReason> {
+        if self.is_blacklisted(ip) {
+            return Err(RejectReason::Blacklisted);
+        }
+
+        if self.is_whitelisted(ip) {
+            return Ok(());
+        }
+
+        if let Some(asn) = self.asn_of(ip) {
+            if self.asn_deny.contains(&asn) {
+                return Err(RejectReason::AsnDenied);
+            }
+            if self.asn_allow.contains(&asn) {
+                return Ok(());
+            }
+        }
+
+        if self.has_whitelist() || !self.asn_allow.is_empty() {
+            return Err(RejectReason::NotWhitelisted);
+        }
+
+        match self.default_policy {
+            DefaultPolicy::Allow => Ok(()),
+            DefaultPolicy::Deny => Err(RejectReason::DefaultDeny),
+        }
+    }
+
+    /// Like `evaluate`, counting every rejection against its reason.
+    pub fn check(&self, ip: &IpAddr) -> Result<(), RejectReason> {
+        let decision = self.evaluate(ip);
+        if let Err(reason) = decision {
+            self.rejections
+                .counter(reason)
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        decision
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.check(ip).is_ok()
+    }
+
+    /// Rejections so far, keyed by reason name. Shared between clones.
+    pub fn rejections(&self) -> BTreeMap<&'static str, u64> {
+        [
+            RejectReason::Blacklisted,
+            RejectReason::NotWhitelisted,
+            RejectReason::AsnDenied,
+            RejectReason::DefaultDeny,
+            RejectReason::HostMismatch,
+        ]
+        .into_iter()
+        .map(|reason| {
+            let count = self.rejections.counter(reason).load(Ordering::Relaxed);
+            (reason.name(), count)
+        })
+        .collect()
+    }
+
     pub fn is_blacklisted(&self, ip: &IpAddr) -> bool {
         if self.ip_blacklist.contains(ip) {
             return true;
         }
 
+        if self.list_files.read().unwrap().blacklist.contains(ip) {
+            return true;
+        }
+
+        if let Some(expires_at) = self.timed_blacklist.read().unwrap().get(ip) {
+            return *expires_at > Instant::now();
+        }
+
         false
     }
 
     pub fn is_whitelisted(&self, ip: &IpAddr) -> bool {
-        if self.ip_whitelist.is_empty() {
-            return true;
-        }
+        self.ip_whitelist.contains(ip) || self.list_files.read().unwrap().whitelist.contains(ip)
+    }
 
-        if self.ip_whitelist.contains(ip) {
-            return true;
+    /// Autonomous system announcing `ip`, if an ASN database is loaded and knows it.
+    pub fn asn_of(&self, ip: &IpAddr) -> Option<u32> {
+        self.list_files.read().unwrap().asn.as_ref()?.lookup(ip)
+    }
+
+    fn has_whitelist(&self) -> bool {
+        !self.ip_whitelist.is_empty() || !self.list_files.read().unwrap().whitelist.is_empty()
+    }
+
+    /// (Re)reads `whitelist_file`, `blacklist_file` and `asn_database`. Every file is parsed
+    /// before any list is replaced, so a bad file leaves the previous lists in force.
+    pub fn load_list_files(&self) -> Result<(), SecurityError> {
+        let whitelist = match &self.whitelist_file {
+            Some(path) => IpList::load(path)?,
+            None => IpList::default(),
+        };
+        let blacklist = match &self.blacklist_file {
+            Some(path) => IpList::load(path)?,
+            None => IpList::default(),
+        };
+        let asn = match &self.asn_database {
+            Some(path) => Some(AsnDatabase::open(path)?),
+            None => None,
+        };
+
+        tracing::info!(
+            "loaded {} whitelist and {} blacklist entries from list files",
+            whitelist.len(),
+            blacklist.len()
+        );
+
+        *self.list_files.write().unwrap() = ListFiles {
+            whitelist,
+            blacklist,
+            asn,
+        };
+
+        Ok(())
+    }
+
+    fn list_file_mtimes(&self) -> Vec<Option<SystemTime>> {
+        [&self.whitelist_file, &self.blacklist_file, &self.asn_database]
+            .into_iter()
+            .map(|path| {
+                path.as_ref()
+                    .and_then(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            })
+            .collect()
+    }
+
+    /// Reloads the list files when any of them changes on disk, checked every `poll_interval`,
+    /// or when the process receives SIGHUP. Does nothing if no list file is configured.
+    pub fn spawn_list_file_watcher(&self, poll_interval: Duration) -> Option<JoinHandle<()>> {
+        if self.whitelist_file.is_none()
+            && self.blacklist_file.is_none()
+            && self.asn_database.is_none()
+        {
+            return None;
         }
 
-        false
+        let security = self.clone();
+        Some(tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    tracing::error!(
+                        "failed to listen for SIGHUP, list files reload on change only: {}",
+                        e
+                    );
+                    None
+                }
+            };
+
+            let mut interval = tokio::time::interval(poll_interval);
+            let mut last_seen = security.list_file_mtimes();
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let mtimes = security.list_file_mtimes();
+                        if mtimes == last_seen {
+                            continue;
+                        }
+                        last_seen = mtimes;
+                    }
+                    Some(_) = async { hangup.as_mut()?.recv().await } => {}
+                }
+
+                if let Err(e) = security.load_list_files() {
+                    tracing::error!(
+                        "failed to reload ip list files, keeping previous lists: {}",
+                        e
+                    );
+                }
+            }
+        }))
     }
 
     pub fn add_to_whitelist(&mut self, ip: IpAddr) {
@@ -50,8 +653,61 @@ impl Security {
         self.ip_whitelist.remove(ip);
     }
 
+    /// Blacklists `ip` until `ttl` has elapsed. Extends an existing timed ban but never shortens
+    /// one.
+    pub fn add_to_blacklist_for(&self, ip: IpAddr, ttl: Duration) {
+        let expires_at = Instant::now() + ttl;
+        let mut timed = self.timed_blacklist.write().unwrap();
+        let entry = timed.entry(ip).or_insert(expires_at);
+        if *entry < expires_at {
+            *entry = expires_at;
+        }
+        let expires_at = *entry;
+        drop(timed);
+
+        self.persist_ban(&ip, Some(expires_at));
+    }
+
     pub fn remove_from_blacklist(&mut self, ip: &IpAddr) {
         self.ip_blacklist.remove(ip);
+        if self.timed_blacklist.write().unwrap().remove(ip).is_some() {
+            self.persist_ban(ip, None);
+        }
+    }
+
+    /// Drops expired timed bans, returning how many were removed.
+    pub fn sweep_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.timed_blacklist
+            .write()
+            .unwrap()
+            .retain(|ip, expires_at| {
+                let keep = *expires_at > now;
+                if !keep {
+                    expired.push(*ip);
+                }
+                keep
+            });
+
+        for ip in &expired {
+            self.persist_ban(ip, None);
+        }
+
+        expired.len()
+    }
+
+    /// Periodically sweeps expired timed bans. Expired entries are already ignored by
+    /// `is_blacklisted`, the sweeper only keeps the table from growing.
+    pub fn spawn_blacklist_sweeper(&self, interval: Duration) -> JoinHandle<()> {
+        let security = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                security.sweep_expired();
+            }
+        })
     }
 }
 
@@ -64,9 +720,10 @@ impl Default for Security {
 #[cfg(test)]
 mod test {
 
-    use std::{net::Ipv4Addr, str::FromStr};
+    use std::net::Ipv4Addr;
 
     use super::*;
+    use crate::store::MemoryStore;
 
     #[test]
     fn test_ip_filter_basic() {
@@ -76,8 +733,12 @@ mod test {
         let allowed_ip: IpAddr = "127.0.0.1".parse().unwrap();
         let disallowed_ip: IpAddr = "192.168.2.11".parse().unwrap();
 
-        assert!(filter.is_blacklisted(&allowed_ip));
-        assert!(!filter.is_blacklisted(&disallowed_ip));
+        assert!(filter.is_allowed(&allowed_ip));
+        assert!(!filter.is_allowed(&disallowed_ip));
+        assert_eq!(
+            filter.evaluate(&disallowed_ip),
+            Err(RejectReason::NotWhitelisted)
+        );
     }
 
     #[test]
@@ -100,10 +761,128 @@ mod test {
         ];
 
         for ip in allowed_ips {
-            assert!(filter.is_blacklisted(&ip))
+            assert!(filter.is_allowed(&ip))
         }
 
         let disallowed_ip = IpAddr::V4(Ipv4Addr::new(168, 11, 12, 15));
-        assert!(!filter.is_blacklisted(&disallowed_ip));
+        assert!(!filter.is_allowed(&disallowed_ip));
+        assert_eq!(filter.rejections()["blacklisted"], 1);
+    }
+
+    #[test]
+    fn test_asn_rules() {
+        let table = "203.0.113.0/24 64500\n198.51.100.0/24 64501\n";
+        let mut filter = Security::new();
+        filter.list_files.write().unwrap().asn =
+            Some(AsnDatabase::parse_table(Path::new("asn.txt"), table).unwrap());
+        filter.asn_deny.insert(64500);
+
+        let hosting: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.7".parse().unwrap();
+        assert_eq!(filter.evaluate(&hosting), Err(RejectReason::AsnDenied));
+        assert!(filter.evaluate(&other).is_ok());
+
+        // a whitelisted address gets through even from a denied autonomous system, but once
+        // there is a whitelist everyone else has to be on it
+        filter.add_to_whitelist(hosting);
+        assert!(filter.evaluate(&hosting).is_ok());
+        assert_eq!(filter.evaluate(&other), Err(RejectReason::NotWhitelisted));
+
+        filter.asn_allow.insert(64501);
+        assert!(filter.evaluate(&other).is_ok());
+    }
+
+    #[test]
+    fn test_pool_overrides() {
+        let mut global = Security::new();
+        global.add_to_blacklist("10.0.0.1".parse().unwrap());
+        global.add_to_whitelist("10.0.0.2".parse().unwrap());
+
+        let pool = PoolSecurity {
+            ip_whitelist: Some(HashSet::new()),
+            ip_blacklist: HashSet::from(["10.0.0.3".parse().unwrap()]),
+            ..PoolSecurity::default()
+        };
+        let pool_security = global.for_pool(&pool);
+
+        assert!(!pool_security.is_allowed(&"10.0.0.1".parse().unwrap()));
+        assert!(!pool_security.is_allowed(&"10.0.0.3".parse().unwrap()));
+        // the empty pool whitelist lets everyone else in
+        assert!(pool_security.is_allowed(&"10.0.0.4".parse().unwrap()));
+        assert!(!global.is_allowed(&"10.0.0.4".parse().unwrap()));
+
+        // bans issued through the pool policy are enforced globally
+        pool_security.add_to_blacklist_for("10.0.0.2".parse().unwrap(), Duration::from_secs(60));
+        assert!(global.is_blacklisted(&"10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_default_deny_policy() {
+        let mut filter = Security::new().with_default_policy(DefaultPolicy::Deny);
+        let listed: IpAddr = "10.0.0.1".parse().unwrap();
+        let unlisted: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert_eq!(filter.check(&unlisted), Err(RejectReason::DefaultDeny));
+
+        filter.add_to_whitelist(listed);
+        assert!(filter.is_allowed(&listed));
+        assert_eq!(filter.check(&unlisted), Err(RejectReason::NotWhitelisted));
+
+        let rejections = filter.clone().rejections();
+        assert_eq!(rejections["default_deny"], 1);
+        assert_eq!(rejections["not_whitelisted"], 1);
+        assert_eq!(rejections["blacklisted"], 0);
+    }
+
+    #[test]
+    fn test_ip_list_parse() {
+        let contents = "
+            # office
+            203.0.113.7
+            10.0.0.0/8     # internal
+            2001:db8::/32
+        ";
+        let list = IpList::parse(Path::new("list.txt"), contents).unwrap();
+
+        assert_eq!(list.len(), 3);
+        assert!(list.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(list.contains(&"10.20.30.40".parse().unwrap()));
+        assert!(list.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!list.contains(&"192.168.0.1".parse().unwrap()));
+
+        let err = IpList::parse(Path::new("list.txt"), "10.0.0.1\nnot-an-ip\n").unwrap_err();
+        assert!(matches!(err, SecurityError::InvalidListEntry(_, 2, _)));
+    }
+
+    #[test]
+    fn test_timed_blacklist_expires() {
+        let filter = Security::new();
+        let banned: IpAddr = "10.1.1.1".parse().unwrap();
+        let expired: IpAddr = "10.1.1.2".parse().unwrap();
+
+        filter.add_to_blacklist_for(banned, Duration::from_secs(3600));
+        filter.add_to_blacklist_for(expired, Duration::ZERO);
+
+        assert!(filter.is_blacklisted(&banned));
+        assert!(!filter.is_blacklisted(&expired));
+        assert_eq!(filter.sweep_expired(), 1);
+        assert!(filter.clone().is_blacklisted(&banned));
+    }
+
+    #[test]
+    fn test_timed_bans_restored_from_store() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStore::new());
+        let banned: IpAddr = "10.1.1.1".parse().unwrap();
+
+        let mut filter = Security::new();
+        filter.set_state_store(store.clone());
+        filter.add_to_blacklist_for(banned, Duration::from_secs(3600));
+        store.put("bans/10.1.1.2", "0").unwrap();
+
+        let mut restarted = Security::new();
+        restarted.set_state_store(store.clone());
+        assert_eq!(restarted.restore_bans().unwrap(), 1);
+        assert!(restarted.is_blacklisted(&banned));
+        assert_eq!(store.keys("bans/").unwrap(), vec!["bans/10.1.1.1"]);
     }
 }
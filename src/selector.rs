@@ -1,13 +1,19 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::{atomic::{AtomicUsize, Ordering}, Arc, RwLock},
-};
+use std::sync::Arc;
 
-use crate::{config::LoadBalancerConfig, peer::Peer};
+use serde::Serialize;
+
+use crate::{config::LoadBalancerStrategy, peer::Peer};
 
 pub trait Selector: Send + Sync {
     fn next(&mut self) -> Option<Arc<Peer>>;
     fn add_peer(&mut self, peer: Peer);
+    /// Takes the peer at `address` out of selection, returning it.
+    fn remove_peer(&mut self, address: &str) -> Option<Arc<Peer>>;
+    fn peers(&self) -> Vec<Arc<Peer>>;
+}
+
+fn position(pool: &[Arc<Peer>], address: &str) -> Option<usize> {
+    pool.iter().position(|peer| peer.address.as_string() == address)
 }
 
 #[derive(Debug)]
@@ -39,18 +45,232 @@ impl Selector for RoundRobin {
             return None;
         }
 
-        self.last_idx = (self.last_idx + 1) % len;
-        self.pool.get(self.last_idx).cloned()
+        for _ in 0..len {
+            self.last_idx = (self.last_idx + 1) % len;
+            let peer = &self.pool[self.last_idx];
+            if peer.is_ready() {
+                return Some(peer.clone());
+            }
+        }
+
+        None
     }
 
     fn add_peer(&mut self, peer: Peer) {
         self.pool.push(Arc::new(peer))
     }
+
+    fn remove_peer(&mut self, address: &str) -> Option<Arc<Peer>> {
+        let idx = position(&self.pool, address)?;
+        Some(self.pool.remove(idx))
+    }
+
+    fn peers(&self) -> Vec<Arc<Peer>> {
+        self.pool.clone()
+    }
+}
+
+/// Picks the ready peer with the fewest active connections per unit of weight. Ties go to the
+/// peer after the last one picked, so an idle pool is still walked round robin. Peers weighted
+/// 0 are skipped.
+#[derive(Debug)]
+pub struct LeastUsed {
+    last_idx: usize,
+    pool: Vec<Arc<Peer>>,
+}
+
+impl LeastUsed {
+    pub fn new() -> Self {
+        Self {
+            last_idx: 0,
+            pool: Vec::new(),
+        }
+    }
+}
+
+impl Default for LeastUsed {
+    fn default() -> Self {
+        LeastUsed::new()
+    }
+}
+
+impl Selector for LeastUsed {
+    fn next(&mut self) -> Option<Arc<Peer>> {
+        let len = self.pool.len();
+        let mut best: Option<(usize, f64)> = None;
+
+        for offset in 1..=len {
+            let idx = (self.last_idx + offset) % len;
+            let peer = &self.pool[idx];
+            let weight = peer.weight();
+            if !peer.is_ready() || weight == 0 {
+                continue;
+            }
+
+            let load = peer.active_connections() as f64 / weight as f64;
+            if best.is_none_or(|(_, lowest)| load < lowest) {
+                best = Some((idx, load));
+            }
+        }
+
+        let (idx, _) = best?;
+        self.last_idx = idx;
+        self.pool.get(idx).cloned()
+    }
+
+    fn add_peer(&mut self, peer: Peer) {
+        self.pool.push(Arc::new(peer))
+    }
+
+    fn remove_peer(&mut self, address: &str) -> Option<Arc<Peer>> {
+        let idx = position(&self.pool, address)?;
+        Some(self.pool.remove(idx))
+    }
+
+    fn peers(&self) -> Vec<Arc<Peer>> {
+        self.pool.clone()
+    }
+}
+
+/// Smooth weighted round robin: over any window each ready peer is picked in proportion to its
+/// weight, with picks of the same peer spread out rather than bunched. Weights are read on every
+/// pick, so adjusting one takes effect immediately.
+#[derive(Debug)]
+pub struct Weighted {
+    /// running score per peer, parallel to `pool`
+    current: Vec<i64>,
+    pool: Vec<Arc<Peer>>,
+}
+
+impl Weighted {
+    pub fn new() -> Self {
+        Self {
+            current: Vec::new(),
+            pool: Vec::new(),
+        }
+    }
+}
+
+impl Default for Weighted {
+    fn default() -> Self {
+        Weighted::new()
+    }
+}
+
+impl Selector for Weighted {
+    fn next(&mut self) -> Option<Arc<Peer>> {
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+
+        for (idx, peer) in self.pool.iter().enumerate() {
+            let weight = peer.weight() as i64;
+            if !peer.is_ready() || weight == 0 {
+                self.current[idx] = 0;
+                continue;
+            }
+
+            self.current[idx] += weight;
+            total += weight;
+            if best.is_none_or(|b| self.current[idx] > self.current[b]) {
+                best = Some(idx);
+            }
+        }
+
+        let idx = best?;
+        self.current[idx] -= total;
+        self.pool.get(idx).cloned()
+    }
+
+    fn add_peer(&mut self, peer: Peer) {
+        self.pool.push(Arc::new(peer));
+        self.current.push(0);
+    }
+
+    fn remove_peer(&mut self, address: &str) -> Option<Arc<Peer>> {
+        let idx = position(&self.pool, address)?;
+        self.current.remove(idx);
+        Some(self.pool.remove(idx))
+    }
+
+    fn peers(&self) -> Vec<Arc<Peer>> {
+        self.pool.clone()
+    }
+}
+
+/// How often one peer of a pool was picked, next to how often the strategy should pick it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SelectionShare {
+    pub peer: String,
+    pub selections: u64,
+    /// of the pool's selections
+    pub share: f64,
+    /// the share the peer's weight asks for among the peers in rotation now, 0 for the others
+    pub expected_share: f64,
+}
+
+/// How a pool's selections since start spread over its peers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SelectionDistribution {
+    pub total: u64,
+    pub peers: Vec<SelectionShare>,
+    /// The most picked peer in rotation over what it should get, measured among the peers in
+    /// rotation: 1 is perfectly balanced, 2 is twice its due. `None` until one was picked.
+    pub skew: Option<f64>,
+}
+
+impl SelectionDistribution {
+    /// The distribution over `peers`, all of one pool, as `strategy` should spread them:
+    /// evenly for `round_robin`, by weight otherwise.
+    pub fn of(strategy: LoadBalancerStrategy, peers: &[Arc<Peer>]) -> Self {
+        let by_weight = !matches!(strategy, LoadBalancerStrategy::RoundRobin);
+        let due = |peer: &Peer| match (peer.is_ready(), by_weight) {
+            (false, _) => 0,
+            (true, true) => u64::from(peer.weight()),
+            (true, false) => 1,
+        };
+        let selections: u64 = peers.iter().map(|peer| peer.stats().connections()).sum();
+        let total_due: u64 = peers.iter().map(|peer| due(peer)).sum();
+        let in_rotation: u64 = peers
+            .iter()
+            .filter(|peer| due(peer) > 0)
+            .map(|peer| peer.stats().connections())
+            .sum();
+
+        let ratio = |part: u64, whole: u64| match whole {
+            0 => 0.0,
+            whole => part as f64 / whole as f64,
+        };
+        let skew = (in_rotation > 0).then(|| {
+            peers
+                .iter()
+                .filter(|peer| due(peer) > 0)
+                .map(|peer| {
+                    let share = ratio(peer.stats().connections(), in_rotation);
+                    share / ratio(due(peer), total_due)
+                })
+                .fold(0.0, f64::max)
+        });
+
+        Self {
+            total: selections,
+            peers: peers
+                .iter()
+                .map(|peer| SelectionShare {
+                    peer: peer.address.as_string(),
+                    selections: peer.stats().connections(),
+                    share: ratio(peer.stats().connections(), selections),
+                    expected_share: ratio(due(peer), total_due),
+                })
+                .collect(),
+            skew,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{RoundRobin, Selector};
+    use super::{LeastUsed, RoundRobin, SelectionDistribution, Selector, Weighted};
+    use crate::config::LoadBalancerStrategy;
     use crate::peer::Peer;
 
     #[test]
@@ -70,6 +290,96 @@ mod tests {
             selector.add_peer(peer);
         }
 
-        let peer1 = selector.next();
+        assert!(selector.next().is_some());
+    }
+
+    #[test]
+    fn test_least_used() {
+        let mut selector = LeastUsed::default();
+        selector.add_peer(Peer::new("127.0.0.1:8080").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8081").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8082").unwrap());
+
+        let peers = selector.peers();
+        peers[0].set_active_connections(3);
+        peers[1].set_active_connections(1);
+        peers[2].set_active_connections(2);
+
+        let chosen = selector.next().unwrap();
+        assert_eq!(chosen.address, peers[1].address);
+
+        peers[1].set_ready(false);
+        let chosen = selector.next().unwrap();
+        assert_eq!(chosen.address, peers[2].address);
+    }
+
+    #[test]
+    fn test_weighted_follows_weight_changes() {
+        let mut selector = Weighted::default();
+        selector.add_peer(Peer::new("127.0.0.1:8080").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8081").unwrap());
+
+        let peers = selector.peers();
+        peers[0].set_weight(3);
+
+        let picks = |selector: &mut Weighted| {
+            let mut counts = [0; 2];
+            for _ in 0..8 {
+                let chosen = selector.next().unwrap();
+                let idx = peers.iter().position(|p| p.address == chosen.address).unwrap();
+                counts[idx] += 1;
+            }
+            counts
+        };
+
+        assert_eq!(picks(&mut selector), [6, 2]);
+
+        // drain the first peer without rebuilding the selector
+        peers[0].set_weight(0);
+        assert_eq!(picks(&mut selector), [0, 8]);
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        let mut selector = Weighted::default();
+        selector.add_peer(Peer::new("127.0.0.1:8080").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8081").unwrap());
+
+        let removed = selector.remove_peer("127.0.0.1:8080").unwrap();
+        assert_eq!(removed.address.as_string(), "127.0.0.1:8080");
+        assert!(selector.remove_peer("127.0.0.1:8080").is_none());
+
+        for _ in 0..3 {
+            let chosen = selector.next().unwrap();
+            assert_eq!(chosen.address.as_string(), "127.0.0.1:8081");
+        }
+    }
+
+    #[test]
+    fn test_selection_distribution() {
+        let mut selector = Weighted::default();
+        selector.add_peer(Peer::new("127.0.0.1:8080").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8081").unwrap());
+        selector.add_peer(Peer::new("127.0.0.1:8082").unwrap());
+        let peers = selector.peers();
+        peers[0].set_weight(2);
+        peers[2].set_ready(false);
+
+        let strategy = LoadBalancerStrategy::WeightedAverage;
+        assert_eq!(SelectionDistribution::of(strategy, &peers).skew, None);
+
+        for _ in 0..30 {
+            selector.next().unwrap().connection_opened();
+        }
+        let distribution = SelectionDistribution::of(strategy, &peers);
+        assert_eq!(distribution.total, 30);
+        assert_eq!(distribution.peers[0].selections, 20);
+        assert_eq!(distribution.peers[2].expected_share, 0.0);
+        assert_eq!(distribution.skew, Some(1.0));
+
+        // round robin is due the same from every peer whatever their weight
+        let distribution = SelectionDistribution::of(LoadBalancerStrategy::RoundRobin, &peers);
+        assert_eq!(distribution.peers[1].expected_share, 0.5);
+        assert!((distribution.skew.unwrap() - 4.0 / 3.0).abs() < 1e-9);
     }
 }
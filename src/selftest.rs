@@ -0,0 +1,186 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    config::Config, load_balancer::NetworkLoadBalancer, peer::Peer, security::RejectReason,
+};
+
+const STUB_PEERS: usize = 3;
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Outcome of one subsystem check.
+struct Check {
+    subsystem: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(subsystem: &'static str, passed: bool, detail: impl Into<String>) -> Self {
+        Self {
+            subsystem,
+            passed,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Echo server standing in for a real peer, counting the connections it receives.
+struct StubPeer {
+    addr: SocketAddr,
+    accepted: Arc<AtomicUsize>,
+}
+
+impl StubPeer {
+    async fn spawn() -> Result<Self, io::Error> {
+        let listener = TcpListener::bind((LOOPBACK, 0)).await?;
+        let addr = listener.local_addr()?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        Ok(Self { addr, accepted })
+    }
+}
+
+async fn echo_through(addr: SocketAddr, payload: &[u8]) -> Result<bool, io::Error> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(payload).await?;
+
+    let mut echoed = vec![0u8; payload.len()];
+    stream.read_exact(&mut echoed).await?;
+
+    Ok(echoed == payload)
+}
+
+/// Boots the configured balancer on an ephemeral loopback port in front of stub peers, pushes
+/// `connections` synthetic connections through it and prints a pass/fail line per subsystem.
+///
+/// Returns whether every check passed.
+pub async fn run(cfg: &Config, connections: usize) -> bool {
+    let mut checks = vec![Check::new("config", true, "loaded and parsed")];
+
+    // acl: the configured policy must still enforce bans, and loopback has to get through for
+    // the rest of the test to mean anything
+    let mut security = cfg.pool_security();
+    let probe: IpAddr = "192.0.2.1".parse().unwrap();
+    security.add_to_blacklist_for(probe, Duration::from_secs(1));
+    let enforced = security.evaluate(&probe) == Err(RejectReason::Blacklisted);
+    security.remove_from_blacklist(&probe);
+    let loopback_allowed = security.evaluate(&LOOPBACK).is_ok();
+    checks.push(Check::new(
+        "acl",
+        enforced,
+        match (enforced, loopback_allowed) {
+            (false, _) => "blacklist entries are not enforced",
+            (true, true) => "bans enforced, loopback allowed",
+            (true, false) => "bans enforced, loopback allowed for the test only",
+        },
+    ));
+    if !loopback_allowed {
+        security.remove_from_blacklist(&LOOPBACK);
+        security.add_to_whitelist(LOOPBACK);
+    }
+
+    let mut stubs = Vec::with_capacity(STUB_PEERS);
+    for _ in 0..STUB_PEERS {
+        match StubPeer::spawn().await {
+            Ok(stub) => stubs.push(stub),
+            Err(e) => {
+                checks.push(Check::new("stub peers", false, e.to_string()));
+                return report(&checks);
+            }
+        }
+    }
+
+    let peers = stubs
+        .iter()
+        .map(|stub| Peer::new(&stub.addr.to_string()).unwrap())
+        .collect();
+    let load_balancer = NetworkLoadBalancer::with_peers(cfg, peers).with_security(security);
+
+    let listener = match TcpListener::bind((LOOPBACK, 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            checks.push(Check::new("listener", false, e.to_string()));
+            return report(&checks);
+        }
+    };
+    let addr = listener.local_addr().unwrap();
+    let balancer =
+        tokio::spawn(async move { load_balancer.run_forever(vec![listener.into()]).await });
+
+    // proxying: every byte sent must come back through the balancer unchanged
+    let mut tasks = Vec::with_capacity(connections);
+    for i in 0..connections {
+        let payload = format!("jalb self-test {}", i).into_bytes();
+        tasks.push(tokio::spawn(async move {
+            matches!(
+                timeout(CONNECTION_TIMEOUT, echo_through(addr, &payload)).await,
+                Ok(Ok(true))
+            )
+        }));
+    }
+
+    let mut echoed = 0;
+    for task in tasks {
+        if task.await.unwrap_or(false) {
+            echoed += 1;
+        }
+    }
+    checks.push(Check::new(
+        "proxy",
+        echoed == connections,
+        format!("{} of {} connections echoed", echoed, connections),
+    ));
+
+    // selection: with enough connections every stub should have been picked at least once
+    let distribution: Vec<usize> = stubs
+        .iter()
+        .map(|stub| stub.accepted.load(Ordering::Relaxed))
+        .collect();
+    let all_selected = connections < stubs.len() || distribution.iter().all(|n| *n > 0);
+    checks.push(Check::new(
+        "selection",
+        all_selected,
+        format!("connections per peer {:?}", distribution),
+    ));
+
+    balancer.abort();
+    report(&checks)
+}
+
+fn report(checks: &[Check]) -> bool {
+    for check in checks {
+        let status = if check.passed { "pass" } else { "FAIL" };
+        println!("{:<12} {}  {}", check.subsystem, status, check.detail);
+    }
+
+    let passed = checks.iter().all(|c| c.passed);
+    println!("self-test {}", if passed { "passed" } else { "failed" });
+
+    passed
+}
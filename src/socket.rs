@@ -0,0 +1,106 @@
+use std::{io, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+
+/// Keepalive probes sent on a connection that has gone quiet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the connection is idle before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes, the system's when unset
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped, the system's when unset
+    pub retries: Option<u32>,
+}
+
+/// Options set on the client and peer sockets of every session, from `[tcp]`. Those left unset
+/// keep the system defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// `TCP_NODELAY`, sending small writes at once instead of coalescing them
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Keepalive>,
+    /// `SO_RCVBUF` in bytes
+    pub recv_buffer: Option<usize>,
+    /// `SO_SNDBUF` in bytes
+    pub send_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options on `socket`. Buffer sizes only affect the TCP window when set before
+    /// connecting, so upstream sockets are given them before `connect`.
+    pub fn apply<'s>(&self, socket: impl Into<SockRef<'s>>) -> io::Result<()> {
+        let socket = socket.into();
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            #[allow(unused_mut)]
+            let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            {
+                if let Some(interval) = keepalive.interval {
+                    params = params.with_interval(interval);
+                }
+                if let Some(retries) = keepalive.retries {
+                    params = params.with_retries(retries);
+                }
+            }
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpSocket};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_options_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(60),
+                interval: Some(Duration::from_secs(10)),
+                retries: Some(3),
+            }),
+            recv_buffer: Some(256 * 1024),
+            send_buffer: Some(128 * 1024),
+        };
+
+        let socket = TcpSocket::new_v4().unwrap();
+        options.apply(&socket).unwrap();
+        let stream = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        options.apply(&accepted).unwrap();
+
+        for stream in [&stream, &accepted] {
+            let socket = SockRef::from(stream);
+            assert!(socket.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+            #[cfg(target_os = "linux")]
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+            // linux doubles what it is asked for, to leave room for its own bookkeeping
+            assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+            assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+        }
+
+        let untouched = TcpSocket::new_v4().unwrap();
+        SocketOptions::default().apply(&untouched).unwrap();
+        assert!(!SockRef::from(&untouched).keepalive().unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use hyper_util::rt::TokioIo;
+use rustls::ClientConfig;
+use tokio::{net::UnixStream, sync::watch};
+use tonic::transport::{Endpoint, Uri};
+
+use crate::{errors::SpiffeError, tls::TlsPolicy, upstream_tls::svid_client_config};
+
+pub mod proto {
+    tonic::include_proto!("_");
+}
+
+use proto::{X509svidRequest, spiffe_workload_api_client::SpiffeWorkloadApiClient};
+
+/// Backoff between attempts to reach the workload API, doubling up to the maximum.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Keeps an upstream TLS client config built from this workload's X.509 SVID, fetched from the
+/// SPIFFE workload API at `socket`. The agent pushes a new SVID before the current one expires and
+/// the config is rebuilt each time, so rotation needs nothing from the caller. Connections fail
+/// until the first SVID arrives.
+pub fn spawn_svid_watcher(
+    socket: PathBuf,
+    policy: TlsPolicy,
+) -> watch::Receiver<Option<Arc<ClientConfig>>> {
+    let (sender, receiver) = watch::channel(None);
+
+    tokio::spawn(async move {
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            match stream_svids(&socket, &policy, &sender).await {
+                Ok(()) => {
+                    tracing::warn!(
+                        "workload api at {} closed the svid stream",
+                        socket.display()
+                    );
+                    backoff = RECONNECT_BACKOFF;
+                }
+                Err(e) => tracing::error!("svid stream from {} failed: {}", socket.display(), e),
+            }
+
+            if sender.is_closed() {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    });
+
+    receiver
+}
+
+async fn stream_svids(
+    socket: &Path,
+    policy: &TlsPolicy,
+    sender: &watch::Sender<Option<Arc<ClientConfig>>>,
+) -> Result<(), SpiffeError> {
+    let workload_api = |e: &dyn std::fmt::Display| SpiffeError::WorkloadApi(e.to_string());
+
+    // the uri is required but unused, every connection goes to the socket
+    let path = socket.to_path_buf();
+    let channel = Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            async move { UnixStream::connect(path).await.map(TokioIo::new) }
+        }))
+        .await
+        .map_err(|e| workload_api(&e))?;
+
+    let mut request = tonic::Request::new(X509svidRequest {});
+    // the workload api refuses requests without this header
+    request
+        .metadata_mut()
+        .insert("workload.spiffe.io", "true".parse().unwrap());
+
+    let mut svids = SpiffeWorkloadApiClient::new(channel)
+        .fetch_x509svid(request)
+        .await
+        .map_err(|e| workload_api(&e))?
+        .into_inner();
+
+    while let Some(response) = svids.message().await.map_err(|e| workload_api(&e))? {
+        let svid = response.svids.first().ok_or(SpiffeError::NoSvid)?;
+        let config =
+            svid_client_config(&svid.x509_svid, &svid.x509_svid_key, &svid.bundle, policy)?;
+
+        tracing::info!("using svid {} for upstream tls", svid.spiffe_id);
+        if sender.send(Some(Arc::new(config))).is_err() {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
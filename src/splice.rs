@@ -0,0 +1,176 @@
+use std::{
+    io::{self, Write},
+    net::Shutdown,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::OnceLock,
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+/// What a pipe holds unless it is grown.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+static SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+/// Whether splice(2) works on this kernel, found out once by splicing a byte through a pipe.
+/// Sessions are copied through userspace as usual when it doesn't, e.g. under a sandbox
+/// filtering the syscall.
+pub fn supported() -> bool {
+    *SUPPORTED.get_or_init(|| match probe() {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(
+                "splice unavailable, copying sessions through userspace: {}",
+                e
+            );
+            false
+        }
+    })
+}
+
+fn probe() -> io::Result<()> {
+    let (mut tx, rx) = std::os::unix::net::UnixStream::pair()?;
+    tx.write_all(b"x")?;
+    let pipe = Pipe::new()?;
+    match splice(rx.as_raw_fd(), pipe.write.as_raw_fd(), 1)? {
+        1 => Ok(()),
+        _ => Err(io::Error::other("splice moved nothing")),
+    }
+}
+
+/// A pipe bytes pass through on their way from one socket to the other, never leaving the
+/// kernel.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: pipe2 writes two descriptors into an array of two
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both were just opened by pipe2 and nothing else owns them
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Self { read, write })
+    }
+
+    /// Grows the pipe to hold `capacity` bytes. It keeps its size when the kernel refuses, e.g.
+    /// past `/proc/sys/fs/pipe-max-size`, and takes less per splice instead.
+    fn grow(&self, capacity: usize) {
+        if capacity <= PIPE_CAPACITY {
+            return;
+        }
+        // SAFETY: F_SETPIPE_SZ takes an int and only resizes the pipe's buffer
+        let grown = unsafe {
+            libc::fcntl(
+                self.write.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                capacity as libc::c_int,
+            )
+        };
+        if grown < 0 {
+            let e = io::Error::last_os_error();
+            tracing::debug!("keeping splice pipe at {} bytes: {}", PIPE_CAPACITY, e);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: null offsets are what splice takes for sockets and pipes, the descriptors are
+    // borrowed from live owners for the length of the call
+    let moved = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if moved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
+
+/// Moves what `from` sends to `to`, up to `chunk` bytes at a time, until `from` closes, then
+/// shuts down writing on `to` like `copy_bidirectional` does. `read` is told the size of every
+/// chunk taken from `from`, and 0 at the end of file before the shutdown lets `to` see it.
+pub(crate) async fn copy(
+    from: &TcpStream,
+    to: &TcpStream,
+    chunk: usize,
+    mut read: impl FnMut(usize),
+) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    pipe.grow(chunk);
+    let mut copied = 0;
+    loop {
+        // the pipe is empty here, so it not taking anything means the socket had nothing
+        let moved = loop {
+            from.readable().await?;
+            let fill = || splice(from.as_raw_fd(), pipe.write.as_raw_fd(), chunk);
+            match from.try_io(Interest::READABLE, fill) {
+                Ok(moved) => break moved,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if moved == 0 {
+            read(0);
+            socket2::SockRef::from(to).shutdown(Shutdown::Write)?;
+            return Ok(copied);
+        }
+        read(moved);
+
+        let mut pending = moved;
+        while pending > 0 {
+            to.writable().await?;
+            let drain = || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending);
+            match to.try_io(Interest::WRITABLE, drain) {
+                Ok(drained) => pending -= drained,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        copied += moved as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::relay::{
+        RelayPath,
+        tests::{check_relay_tcp, connected},
+    };
+
+    #[tokio::test]
+    async fn test_spliced_relay() {
+        assert!(supported());
+        check_relay_tcp(RelayPath::Splice).await;
+    }
+
+    #[tokio::test]
+    async fn test_copy_through_grown_pipe() {
+        let (mut client, from) = connected().await;
+        let (to, mut upstream) = connected().await;
+        let payload = vec![7u8; 3 * PIPE_CAPACITY + 5];
+        let mut chunks = Vec::new();
+
+        let (copied, received) = tokio::join!(
+            copy(&from, &to, 2 * PIPE_CAPACITY, |len| chunks.push(len)),
+            async {
+                client.write_all(&payload).await.unwrap();
+                drop(client);
+                let mut received = Vec::new();
+                upstream.read_to_end(&mut received).await.unwrap();
+                received
+            }
+        );
+        assert_eq!(copied.unwrap(), payload.len() as u64);
+        assert_eq!(received, payload);
+        assert_eq!(chunks.last(), Some(&0));
+        assert!(chunks.iter().all(|&len| len <= 2 * PIPE_CAPACITY));
+        assert_eq!(chunks.iter().sum::<usize>(), payload.len());
+    }
+}
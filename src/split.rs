@@ -0,0 +1,195 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use http::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// One entry of a `[[route]]`'s `split`: the share of its requests `backend` gets, in
+/// proportion to the other entries' weights.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    pub backend: String,
+    pub weight: u32,
+}
+
+impl SplitConfig {
+    /// Every backend is configured and listed once, and at least one of them gets requests.
+    pub fn validate(split: &[SplitConfig], backends: &[&str]) -> Result<(), String> {
+        for (idx, arm) in split.iter().enumerate() {
+            if !backends.contains(&arm.backend.as_str()) {
+                return Err(format!("{} is not a configured backend", arm.backend));
+            }
+            if split[..idx]
+                .iter()
+                .any(|other| other.backend == arm.backend)
+            {
+                return Err(format!("{} is listed more than once", arm.backend));
+            }
+        }
+        if split.iter().all(|arm| arm.weight == 0) {
+            return Err("every weight is 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Spreads a route's requests over the backends of its `split` with smooth weighted round
+/// robin, counting what each got back so a canary can be compared with the stable pool.
+/// Weights can be changed while running, through the admin api, until the next reload.
+#[derive(Debug)]
+pub struct TrafficSplit {
+    /// the route's position in the config, counting from 1
+    route: usize,
+    arms: Vec<Arc<SplitArm>>,
+    /// running score per arm, parallel to `arms`
+    current: Mutex<Vec<i64>>,
+}
+
+/// A backend of a [`TrafficSplit`] with its share and what it answered.
+#[derive(Debug)]
+pub struct SplitArm {
+    backend: String,
+    weight: AtomicU32,
+    requests: AtomicU64,
+    /// 5xx responses and failed gRPC calls, jalb's own included
+    errors: AtomicU64,
+}
+
+impl TrafficSplit {
+    /// `split` was checked by [`SplitConfig::validate`] when the config was loaded.
+    pub fn from_config(route: usize, split: &[SplitConfig]) -> Self {
+        let arms = split
+            .iter()
+            .map(|arm| {
+                Arc::new(SplitArm {
+                    backend: arm.backend.clone(),
+                    weight: AtomicU32::new(arm.weight),
+                    requests: AtomicU64::new(0),
+                    errors: AtomicU64::new(0),
+                })
+            })
+            .collect();
+
+        Self {
+            route,
+            arms,
+            current: Mutex::new(vec![0; split.len()]),
+        }
+    }
+
+    pub fn route(&self) -> usize {
+        self.route
+    }
+
+    pub fn arms(&self) -> &[Arc<SplitArm>] {
+        &self.arms
+    }
+
+    /// The arm of `backend`, if the split has one.
+    pub fn arm(&self, backend: &str) -> Option<&Arc<SplitArm>> {
+        self.arms.iter().find(|arm| arm.backend == backend)
+    }
+
+    /// The arm the next request goes to. With every weight set to 0 it is the first one.
+    pub fn next(&self) -> Arc<SplitArm> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+
+        for (idx, arm) in self.arms.iter().enumerate() {
+            let weight = i64::from(arm.weight());
+            if weight == 0 {
+                current[idx] = 0;
+                continue;
+            }
+
+            current[idx] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[idx] > current[b]) {
+                best = Some(idx);
+            }
+        }
+
+        let idx = best.unwrap_or(0);
+        current[idx] -= total;
+        self.arms[idx].clone()
+    }
+}
+
+impl SplitArm {
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    /// Applied from the next request on, returning the weight it replaced.
+    pub fn set_weight(&self, weight: u32) -> u32 {
+        self.weight.swap(weight, Ordering::Relaxed)
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request the arm answered with `response`.
+    pub fn record<B>(&self, response: &Response<B>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if is_failure(response) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A 5xx response, or a gRPC call that failed without a body to carry its status in trailers.
+pub(crate) fn is_failure<B>(response: &Response<B>) -> bool {
+    let grpc_failed = response
+        .headers()
+        .get("grpc-status")
+        .is_some_and(|status| status != "0");
+    response.status() >= StatusCode::INTERNAL_SERVER_ERROR || grpc_failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_split() {
+        let arm = |backend: &str, weight| SplitConfig {
+            backend: backend.to_string(),
+            weight,
+        };
+        let split = [arm("stable", 3), arm("canary", 1)];
+        assert!(SplitConfig::validate(&split, &["stable", "canary"]).is_ok());
+        assert!(SplitConfig::validate(&split, &["stable"]).is_err());
+        let twice = [arm("stable", 1), arm("stable", 1)];
+        assert!(SplitConfig::validate(&twice, &["stable"]).is_err());
+
+        let traffic = TrafficSplit::from_config(1, &split);
+        let picked: Vec<_> = (0..8)
+            .map(|_| traffic.next().backend().to_string())
+            .collect();
+        assert_eq!(picked.iter().filter(|b| *b == "canary").count(), 2);
+        // spread out rather than bunched
+        assert!(!picked.windows(2).any(|pair| pair == ["canary", "canary"]));
+
+        let canary = traffic.arm("canary").unwrap();
+        canary.record(&Response::builder().status(503).body(()).unwrap());
+        canary.record(&Response::new(()));
+        assert_eq!((canary.requests(), canary.errors()), (2, 1));
+
+        // ramped down to nothing, the canary gets no more requests
+        canary.set_weight(0);
+        assert!((0..4).all(|_| traffic.next().backend() == "stable"));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Config, StateBackend},
+    errors::StoreError,
+};
+
+/// Key/value persistence for state that has to survive a restart. Keys are `/`-separated paths
+/// namespaced by feature, e.g. `bans/10.0.0.1`.
+pub trait StateStore: Debug + Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>, StoreError>;
+
+    fn put(&self, key: &str, value: &str) -> Result<(), StoreError>;
+
+    fn delete(&self, key: &str) -> Result<(), StoreError>;
+
+    /// Every key starting with `prefix`, in no particular order.
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StoreError>;
+
+    /// Atomically bumps the counter at `key`, returning the new count. A counter is created
+    /// with an expiry of `ttl` and not persisted; only a shared backend makes counters visible
+    /// across instances.
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, StoreError>;
+}
+
+/// Expiring in-process counters backing `increment` for the local stores.
+#[derive(Debug, Default)]
+struct Counters {
+    counts: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl Counters {
+    /// Past this many counters, expired ones are dropped before adding another.
+    const PURGE_THRESHOLD: usize = 4096;
+
+    fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        if counts.len() >= Self::PURGE_THRESHOLD {
+            counts.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        let entry = counts.entry(key.to_string()).or_insert((0, now + ttl));
+        if entry.1 <= now {
+            *entry = (0, now + ttl);
+        }
+        entry.0 += 1;
+        entry.0
+    }
+}
+
+/// Opens the store configured under `[state]`, or `None` if nothing should be persisted.
+pub fn from_config(cfg: &Config) -> Result<Option<Arc<dyn StateStore>>, StoreError> {
+    let store: Arc<dyn StateStore> = match cfg.state_backend() {
+        None => return Ok(None),
+        Some(StateBackend::File) => Arc::new(FileStore::open(cfg.state_path())?),
+        #[cfg(feature = "redis")]
+        Some(StateBackend::Redis) => {
+            let url = cfg.redis_url().ok_or(StoreError::MissingRedisUrl)?;
+            Arc::new(redis_store::RedisStore::open(url)?)
+        }
+        #[cfg(not(feature = "redis"))]
+        Some(StateBackend::Redis) => return Err(StoreError::RedisDisabled),
+    };
+
+    Ok(Some(store))
+}
+
+/// Keeps everything in memory, lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<BTreeMap<String, String>>,
+    counters: Counters,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &str) -> Result<(), StoreError> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        Ok(prefix_keys(&self.entries.lock().unwrap(), prefix))
+    }
+
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, StoreError> {
+        Ok(self.counters.increment(key, ttl))
+    }
+}
+
+/// Single JSON file holding every entry, rewritten on each change. The new contents are written
+/// next to the file and renamed over it, so a crash mid-write leaves the previous state intact.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, String>>,
+    counters: Counters,
+}
+
+impl FileStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, StoreError> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+            counters: Counters::default(),
+        })
+    }
+
+    fn flush(&self, entries: &BTreeMap<String, String>) -> Result<(), StoreError> {
+        let tmp = tmp_path(&self.path);
+        fs::write(&tmp, serde_json::to_vec_pretty(entries)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+impl StateStore for FileStore {
+    fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &str) -> Result<(), StoreError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(key).is_some_and(|v| v == value) {
+            return Ok(());
+        }
+        entries.insert(key.to_string(), value.to_string());
+        self.flush(&entries)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(key).is_none() {
+            return Ok(());
+        }
+        self.flush(&entries)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        Ok(prefix_keys(&self.entries.lock().unwrap(), prefix))
+    }
+
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, StoreError> {
+        Ok(self.counters.increment(key, ttl))
+    }
+}
+
+fn prefix_keys(entries: &BTreeMap<String, String>, prefix: &str) -> Vec<String> {
+    entries
+        .range(prefix.to_string()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::{sync::Mutex, time::Duration};
+
+    use redis::Commands;
+
+    use super::StateStore;
+    use crate::errors::StoreError;
+
+    /// Entries live under the `jalb:` namespace so the database can be shared.
+    const NAMESPACE: &str = "jalb:";
+
+    pub struct RedisStore {
+        connection: Mutex<redis::Connection>,
+    }
+
+    impl std::fmt::Debug for RedisStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisStore").finish_non_exhaustive()
+        }
+    }
+
+    impl RedisStore {
+        pub fn open(url: &str) -> Result<Self, StoreError> {
+            let connection = redis::Client::open(url)?.get_connection()?;
+            Ok(Self {
+                connection: Mutex::new(connection),
+            })
+        }
+    }
+
+    impl StateStore for RedisStore {
+        fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+            let mut connection = self.connection.lock().unwrap();
+            Ok(connection.get(format!("{}{}", NAMESPACE, key))?)
+        }
+
+        fn put(&self, key: &str, value: &str) -> Result<(), StoreError> {
+            let mut connection = self.connection.lock().unwrap();
+            let _: () = connection.set(format!("{}{}", NAMESPACE, key), value)?;
+            Ok(())
+        }
+
+        fn delete(&self, key: &str) -> Result<(), StoreError> {
+            let mut connection = self.connection.lock().unwrap();
+            let _: () = connection.del(format!("{}{}", NAMESPACE, key))?;
+            Ok(())
+        }
+
+        fn keys(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+            let mut connection = self.connection.lock().unwrap();
+            let pattern = format!("{}{}*", NAMESPACE, prefix);
+            let keys: Vec<String> = connection.scan_match(pattern)?.collect();
+            Ok(keys
+                .into_iter()
+                .filter_map(|key| key.strip_prefix(NAMESPACE).map(str::to_string))
+                .collect())
+        }
+
+        fn increment(&self, key: &str, ttl: Duration) -> Result<u64, StoreError> {
+            let mut connection = self.connection.lock().unwrap();
+            let key = format!("{}{}", NAMESPACE, key);
+            // NX only sets the expiry when the counter is created, so the window stays fixed
+            let (count,): (u64,) = redis::pipe()
+                .atomic()
+                .incr(&key, 1)
+                .cmd("PEXPIRE")
+                .arg(&key)
+                .arg(ttl.as_millis() as u64)
+                .arg("NX")
+                .ignore()
+                .query(&mut *connection)?;
+            Ok(count)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("jalb-store-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap();
+        store.put("bans/10.0.0.1", "100").unwrap();
+        store.put("bans/10.0.0.2", "200").unwrap();
+        store.put("sessions/abc", "10.0.0.9:80").unwrap();
+        store.delete("bans/10.0.0.2").unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("bans/10.0.0.1").unwrap().as_deref(), Some("100"));
+        assert_eq!(store.get("bans/10.0.0.2").unwrap(), None);
+        assert_eq!(store.keys("bans/").unwrap(), vec!["bans/10.0.0.1"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_counters_reset_after_ttl() {
+        let store = MemoryStore::new();
+        assert_eq!(store.increment("hits", Duration::from_secs(60)).unwrap(), 1);
+        assert_eq!(store.increment("hits", Duration::from_secs(60)).unwrap(), 2);
+
+        assert_eq!(store.increment("expired", Duration::ZERO).unwrap(), 1);
+        assert_eq!(store.increment("expired", Duration::ZERO).unwrap(), 1);
+    }
+}
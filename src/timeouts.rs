@@ -0,0 +1,165 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, Sleep};
+
+use crate::errors::ProxyError;
+
+/// `timeouts` of a `[[route]]`, in place of its backend's `request_timeout_seconds`: how long
+/// connecting to the peer may take, how long the peer may take to start answering once it has
+/// the request, and how long the whole request may take, retries and the response body
+/// included. Requests running out of any of them are answered with a 504 saying which.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect_ms: Option<u64>,
+    pub first_byte_ms: Option<u64>,
+    pub total_ms: Option<u64>,
+}
+
+impl TimeoutConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let timeouts = [
+            ("connect_ms", self.connect_ms),
+            ("first_byte_ms", self.first_byte_ms),
+            ("total_ms", self.total_ms),
+        ];
+        if timeouts.iter().all(|(_, ms)| ms.is_none()) {
+            return Err("set connect_ms, first_byte_ms or total_ms".to_string());
+        }
+        if let Some((name, _)) = timeouts.iter().find(|(_, ms)| *ms == Some(0)) {
+            return Err(format!("{} must be at least 1", name));
+        }
+        Ok(())
+    }
+}
+
+/// A route's [`TimeoutConfig`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RouteTimeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl RouteTimeouts {
+    pub fn from_config(config: &TimeoutConfig) -> Self {
+        Self {
+            connect: config.connect_ms.map(Duration::from_millis),
+            first_byte: config.first_byte_ms.map(Duration::from_millis),
+            total: config.total_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// A response body passed on as it is read that fails once `deadline` has passed, so a peer
+/// trickling out its answer can't hold a request past its route's `total_ms`.
+pub struct DeadlineBody<B> {
+    inner: B,
+    deadline: Pin<Box<Sleep>>,
+    total: Duration,
+}
+
+impl<B> DeadlineBody<B> {
+    /// `total` is what the deadline was set to, to report once it passed.
+    pub fn new(inner: B, deadline: Instant, total: Duration) -> Self {
+        Self {
+            inner,
+            deadline: Box::pin(tokio::time::sleep_until(deadline)),
+            total,
+        }
+    }
+}
+
+impl<B> Body for DeadlineBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: From<ProxyError>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                let total = self.total;
+                Poll::Ready(Some(Err(ProxyError::TotalTimeout(total).into())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Sends one chunk and then nothing more.
+    struct Stalled(bool);
+
+    impl Body for Stalled {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            if std::mem::replace(&mut self.0, true) {
+                return Poll::Pending;
+            }
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from("partial")))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deadline_body() {
+        let config: TimeoutConfig = toml::from_str("first_byte_ms = 500\ntotal_ms = 50").unwrap();
+        assert!(config.validate().is_ok());
+        assert!(
+            toml::from_str::<TimeoutConfig>("")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(
+            toml::from_str::<TimeoutConfig>("connect_ms = 0")
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        let timeouts = RouteTimeouts::from_config(&config);
+        assert_eq!(timeouts.first_byte, Some(Duration::from_millis(500)));
+        assert_eq!(timeouts.connect, None);
+
+        let total = timeouts.total.unwrap();
+        let mut body = DeadlineBody::new(Stalled(false), Instant::now() + total, total);
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "partial");
+        let e = body.frame().await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("50ms"));
+    }
+}
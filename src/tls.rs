@@ -0,0 +1,420 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use rustls::{
+    SupportedCipherSuite, SupportedProtocolVersion,
+    crypto::{CryptoProvider, ring},
+    pki_types::ServerName,
+    version::{TLS12, TLS13},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ConfigError, hostname::HostPattern};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &TLS12,
+            TlsVersion::Tls13 => &TLS13,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+}
+
+/// The `[tls]` section as written.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+    /// IANA names, e.g. `TLS13_AES_128_GCM_SHA256` or `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    /// Unset keeps the provider defaults.
+    cipher_suites: Option<Vec<String>>,
+    /// Listeners where client TLS is terminated or passed through. Global only.
+    #[serde(rename = "listener", default, skip_serializing_if = "Vec::is_empty")]
+    listeners: Vec<TlsListenerConfig>,
+    /// TLS opened to peers, overridable per backend
+    upstream: Option<UpstreamTlsConfig>,
+    /// Backends picked by the SNI a client asks for. Global only.
+    #[serde(rename = "route", default, skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<SniRoute>,
+}
+
+/// A `[[tls.listener]]`, one of the `[[listener]]` addresses speaking TLS to clients.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListenerConfig {
+    pub address: SocketAddr,
+    /// Forward the handshake untouched, routing on its SNI, instead of terminating it
+    #[serde(default)]
+    pub passthrough: bool,
+    /// PEM certificate chain and private key served to clients, required unless passthrough
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    /// PEM bundle client certificates must chain to. Unset doesn't ask for one.
+    pub client_ca: Option<PathBuf>,
+    /// Protocols offered over ALPN, most preferred first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<String>,
+}
+
+/// `[tls.upstream]`: re-encrypting what was received before sending it to a peer.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle peer certificates must chain to, required unless `verify = false`
+    pub ca_bundle: Option<PathBuf>,
+    /// SNI sent to peers instead of their address
+    pub server_name: Option<String>,
+    /// PEM client certificate chain and key for peers that ask for one
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    #[serde(default = "verify_default")]
+    pub verify: bool,
+}
+
+fn verify_default() -> bool {
+    true
+}
+
+/// A `[[tls.route]]`: connections asking for `server_name` go to `backend`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRoute {
+    /// exact or `*.example.com`
+    pub server_name: String,
+    pub backend: String,
+    /// Served for this name instead of the listener's certificate
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// `self` with any unset field taken from `base`.
+    pub fn or(&self, base: &TlsConfig) -> TlsConfig {
+        TlsConfig {
+            min_version: self.min_version.or(base.min_version),
+            max_version: self.max_version.or(base.max_version),
+            cipher_suites: self
+                .cipher_suites
+                .clone()
+                .or_else(|| base.cipher_suites.clone()),
+            listeners: base.listeners.clone(),
+            upstream: self.upstream.clone().or_else(|| base.upstream.clone()),
+            routes: base.routes.clone(),
+        }
+    }
+
+    pub fn listeners(&self) -> &[TlsListenerConfig] {
+        &self.listeners
+    }
+
+    /// The TLS settings of the listener on `address`, `None` for plain TCP.
+    pub fn listener(&self, address: SocketAddr) -> Option<&TlsListenerConfig> {
+        self.listeners.iter().find(|l| l.address == address)
+    }
+
+    pub fn upstream(&self) -> Option<&UpstreamTlsConfig> {
+        self.upstream.as_ref()
+    }
+
+    pub fn routes(&self) -> &[SniRoute] {
+        &self.routes
+    }
+
+    /// Checks the section against the `[[listener]]` addresses and backend names it refers to.
+    pub fn validate(&self, listeners: &[SocketAddr], backends: &[&str]) -> Result<(), ConfigError> {
+        for (idx, listener) in self.listeners.iter().enumerate() {
+            let invalid = |reason: &str| {
+                Err(ConfigError::InvalidTls(format!(
+                    "[[tls.listener]] {}: {}",
+                    listener.address, reason
+                )))
+            };
+
+            if !listeners.contains(&listener.address) {
+                return invalid("not one of the addresses jalb listens on");
+            }
+            if self.listeners[..idx]
+                .iter()
+                .any(|l| l.address == listener.address)
+            {
+                return invalid("listed more than once");
+            }
+
+            if listener.passthrough {
+                let terminating = listener.certificate.is_some()
+                    || listener.private_key.is_some()
+                    || listener.client_ca.is_some()
+                    || !listener.alpn.is_empty();
+                if terminating {
+                    return invalid(
+                        "passthrough forwards the handshake, \
+                         certificate, private_key, client_ca and alpn don't apply",
+                    );
+                }
+            } else if listener.certificate.is_none() || listener.private_key.is_none() {
+                return invalid("certificate and private_key are required to terminate tls");
+            }
+
+            if listener.alpn.iter().any(String::is_empty) {
+                return invalid("alpn protocols can't be empty");
+            }
+        }
+
+        let mut patterns = Vec::with_capacity(self.routes.len());
+        for route in &self.routes {
+            let invalid = |reason: &str| {
+                Err(ConfigError::InvalidTls(format!(
+                    "[[tls.route]] {}: {}",
+                    route.server_name, reason
+                )))
+            };
+
+            let wildcard = route.server_name.strip_prefix("*.");
+            let name = wildcard.unwrap_or(&route.server_name);
+            if name.is_empty() || ServerName::try_from(name).is_err() {
+                return invalid("not a hostname or *.example.com");
+            }
+
+            let pattern = HostPattern::parse(&route.server_name);
+            if patterns.contains(&pattern) {
+                return invalid("routed more than once");
+            }
+            patterns.push(pattern);
+
+            if !backends.contains(&route.backend.as_str()) {
+                return invalid(&format!("{} is not a configured backend", route.backend));
+            }
+            if route.certificate.is_some() != route.private_key.is_some() {
+                return invalid("certificate and private_key go together");
+            }
+        }
+
+        if !self.routes.is_empty() && self.listeners.is_empty() {
+            return Err(ConfigError::InvalidTls(
+                "[[tls.route]] needs a [[tls.listener]] to read the sni from".to_string(),
+            ));
+        }
+
+        match &self.upstream {
+            Some(upstream) => upstream.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// A backend's `[backend.security.tls]` may only set the policy and upstream settings.
+    pub fn validate_override(&self, backend: &str) -> Result<(), ConfigError> {
+        if !self.listeners.is_empty() || !self.routes.is_empty() {
+            return Err(ConfigError::InvalidTls(format!(
+                "backend {}: listeners and routes are only set in the global [tls] section",
+                backend
+            )));
+        }
+
+        match &self.upstream {
+            Some(upstream) => upstream.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl UpstreamTlsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid =
+            |reason: String| Err(ConfigError::InvalidTls(format!("upstream: {}", reason)));
+
+        if self.verify && self.ca_bundle.is_none() {
+            return invalid("ca_bundle is required unless verify = false".to_string());
+        }
+        if !self.verify && self.ca_bundle.is_some() {
+            return invalid("ca_bundle is unused with verify = false".to_string());
+        }
+        if self.certificate.is_some() != self.private_key.is_some() {
+            return invalid("certificate and private_key go together".to_string());
+        }
+        if let Some(name) = &self.server_name
+            && ServerName::try_from(name.as_str()).is_err()
+        {
+            return invalid(format!("{} is not a valid server_name", name));
+        }
+
+        Ok(())
+    }
+}
+
+/// Validated protocol versions and cipher suites to build TLS configs from.
+#[derive(Debug, Clone)]
+pub struct TlsPolicy {
+    versions: Vec<&'static SupportedProtocolVersion>,
+    cipher_suites: Vec<SupportedCipherSuite>,
+}
+
+impl TlsPolicy {
+    pub fn from_config(cfg: &TlsConfig) -> Result<Self, ConfigError> {
+        let min = cfg.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = cfg.max_version.unwrap_or(TlsVersion::Tls13);
+        if min > max {
+            return Err(ConfigError::InvalidTls(format!(
+                "min_version {} is above max_version {}",
+                min.name(),
+                max.name()
+            )));
+        }
+
+        let enabled: Vec<TlsVersion> = [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|v| (min..=max).contains(v))
+            .collect();
+
+        let cipher_suites = match &cfg.cipher_suites {
+            Some(names) => names
+                .iter()
+                .map(|name| cipher_suite(name))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => ring::DEFAULT_CIPHER_SUITES.to_vec(),
+        };
+
+        // a version without a usable suite would fail every handshake negotiating it
+        for version in &enabled {
+            let usable = cipher_suites
+                .iter()
+                .any(|suite| suite.version() == version.protocol());
+            if !usable {
+                return Err(ConfigError::InvalidTls(format!(
+                    "no cipher suite for TLS {} in cipher_suites",
+                    version.name()
+                )));
+            }
+        }
+
+        Ok(Self {
+            versions: enabled.iter().map(|v| v.protocol()).collect(),
+            cipher_suites: cipher_suites
+                .into_iter()
+                .filter(|suite| enabled.iter().any(|v| suite.version() == v.protocol()))
+                .collect(),
+        })
+    }
+
+    pub fn protocol_versions(&self) -> &[&'static SupportedProtocolVersion] {
+        &self.versions
+    }
+
+    /// The ring provider restricted to the configured cipher suites.
+    pub fn crypto_provider(&self) -> CryptoProvider {
+        CryptoProvider {
+            cipher_suites: self.cipher_suites.clone(),
+            ..ring::default_provider()
+        }
+    }
+}
+
+fn cipher_suite(name: &str) -> Result<SupportedCipherSuite, ConfigError> {
+    ring::ALL_CIPHER_SUITES
+        .iter()
+        .find(|suite| suite.suite().as_str() == Some(name))
+        .copied()
+        .ok_or_else(|| {
+            let known: Vec<&str> = ring::ALL_CIPHER_SUITES
+                .iter()
+                .filter_map(|suite| suite.suite().as_str())
+                .collect();
+            ConfigError::InvalidTls(format!(
+                "unknown cipher suite {}, expected one of {}",
+                name,
+                known.join(", ")
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_config(toml_str: &str) -> TlsConfig {
+        toml::from_str(toml_str).unwrap()
+    }
+
+    #[test]
+    fn test_tls_policy_validation() {
+        let policy = TlsPolicy::from_config(&TlsConfig::default()).unwrap();
+        assert_eq!(policy.protocol_versions().len(), 2);
+
+        let policy = TlsPolicy::from_config(&tls_config(
+            r#"
+            min_version = "1.3"
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"]
+            "#,
+        ))
+        .unwrap();
+        assert_eq!(policy.protocol_versions(), &[&TLS13]);
+        // the 1.2 suite is dropped rather than offered
+        assert_eq!(policy.crypto_provider().cipher_suites.len(), 1);
+
+        let inverted = tls_config(
+            r#"
+            min_version = "1.3"
+            max_version = "1.2"
+            "#,
+        );
+        assert!(TlsPolicy::from_config(&inverted).is_err());
+
+        let unknown = tls_config(r#"cipher_suites = ["TLS_RSA_WITH_RC4_128_MD5"]"#);
+        assert!(TlsPolicy::from_config(&unknown).is_err());
+
+        let no_tls12_suite = tls_config(r#"cipher_suites = ["TLS13_AES_128_GCM_SHA256"]"#);
+        assert!(TlsPolicy::from_config(&no_tls12_suite).is_err());
+    }
+
+    #[test]
+    fn test_tls_schema_validation() {
+        let listeners: Vec<SocketAddr> = vec!["0.0.0.0:443".parse().unwrap()];
+        let backends = ["web", "api"];
+        let valid = tls_config(
+            r#"
+            [[listener]]
+            address = "0.0.0.0:443"
+            certificate = "/etc/jalb/cert.pem"
+            private_key = "/etc/jalb/key.pem"
+            alpn = ["h2", "http/1.1"]
+
+            [[route]]
+            server_name = "*.api.example.com"
+            backend = "api"
+
+            [upstream]
+            verify = false
+            "#,
+        );
+        valid.validate(&listeners, &backends).unwrap();
+        assert!(valid.listener(listeners[0]).is_some());
+        assert!(!valid.upstream().unwrap().verify);
+
+        let invalid = |toml_str: &str| tls_config(toml_str).validate(&listeners, &backends);
+        let no_certificate = "[[listener]]\naddress = \"0.0.0.0:443\"";
+        assert!(invalid(no_certificate).is_err());
+        assert!(invalid(&format!("{}\npassthrough = true", no_certificate)).is_ok());
+        assert!(invalid("[[listener]]\naddress = \"0.0.0.0:8443\"\npassthrough = true").is_err());
+        assert!(
+            invalid("[[route]]\nserver_name = \"web.example.com\"\nbackend = \"web\"").is_err()
+        );
+        assert!(invalid("[upstream]\nserver_name = \"api.internal\"").is_err());
+
+        let override_listeners = tls_config(no_certificate);
+        assert!(override_listeners.validate_override("api").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::Instrument;
+
+use crate::{
+    audit::AuditLog,
+    config::Config,
+    connections::{ConnectionHandle, ConnectionRegistry},
+    events::{EventKind, EventLog},
+    load_balancer::selector_from_config,
+    metrics::{Direction, Metrics},
+    peer::Peer,
+    relay::Side,
+    security::Security,
+    selector::Selector,
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const QUIC_LONG_HEADER: u8 = 0x80;
+const QUIC_FIXED_BIT: u8 = 0x40;
+const QUIC_MAX_CID_LENGTH: usize = 20;
+
+/// Returns the destination connection ID of a QUIC packet sent by a client.
+///
+/// Long header packets carry the length of the ID inline. Short header packets do not, so
+/// `short_cid_len` must match the length of the IDs issued by the upstream servers.
+pub fn quic_destination_cid(packet: &[u8], short_cid_len: usize) -> Option<&[u8]> {
+    let first = *packet.first()?;
+    if first & QUIC_FIXED_BIT == 0 {
+        return None;
+    }
+
+    if first & QUIC_LONG_HEADER != 0 {
+        let (dcid, _) = quic_long_header_cids(packet)?;
+        return Some(dcid);
+    }
+
+    if short_cid_len == 0 {
+        return None;
+    }
+
+    packet.get(1..1 + short_cid_len)
+}
+
+/// Returns the source connection ID of a long header QUIC packet. This is how an upstream server
+/// announces the ID clients will address it by for the rest of the connection.
+pub fn quic_source_cid(packet: &[u8]) -> Option<&[u8]> {
+    let first = *packet.first()?;
+    if first & QUIC_LONG_HEADER == 0 || first & QUIC_FIXED_BIT == 0 {
+        return None;
+    }
+
+    let (_, scid) = quic_long_header_cids(packet)?;
+    Some(scid)
+}
+
+/// Long header layout: flags (1) | version (4) | dcid len (1) | dcid | scid len (1) | scid
+fn quic_long_header_cids(packet: &[u8]) -> Option<(&[u8], &[u8])> {
+    let dcid_len = *packet.get(5)? as usize;
+    if dcid_len > QUIC_MAX_CID_LENGTH {
+        return None;
+    }
+    let dcid = packet.get(6..6 + dcid_len)?;
+
+    let scid_len_idx = 6 + dcid_len;
+    let scid_len = *packet.get(scid_len_idx)? as usize;
+    if scid_len > QUIC_MAX_CID_LENGTH {
+        return None;
+    }
+    let scid = packet.get(scid_len_idx + 1..scid_len_idx + 1 + scid_len)?;
+
+    Some((dcid, scid))
+}
+
+#[derive(Debug)]
+struct UdpSession {
+    client: Mutex<SocketAddr>,
+    upstream: UdpSocket,
+    connection: ConnectionHandle,
+}
+
+#[derive(Debug, Default)]
+struct SessionTable {
+    by_addr: HashMap<SocketAddr, Arc<UdpSession>>,
+    by_cid: HashMap<Vec<u8>, Arc<UdpSession>>,
+}
+
+impl SessionTable {
+    fn remove(&mut self, session: &Arc<UdpSession>) {
+        self.by_addr.retain(|_, s| !Arc::ptr_eq(s, session));
+        self.by_cid.retain(|_, s| !Arc::ptr_eq(s, session));
+    }
+}
+
+/// Balances UDP datagrams, pinning each client flow to a single peer.
+///
+/// Flows are keyed on the client's address. With `quic` enabled, QUIC connection IDs are tracked
+/// as well so a connection keeps its peer when NAT rebinding changes the client's source port.
+pub struct UdpLoadBalancer {
+    socket: Arc<UdpSocket>,
+    security: Security,
+    selector: Box<dyn Selector>,
+    sessions: Arc<Mutex<SessionTable>>,
+    session_timeout: Duration,
+    quic_cid_length: Option<usize>,
+    events: Arc<EventLog>,
+    connections: Arc<ConnectionRegistry>,
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl UdpLoadBalancer {
+    pub fn new_from_config(cfg: &Config, socket: UdpSocket) -> Self {
+        let mut selector = selector_from_config(cfg);
+
+        cfg.backend().peers().drain(0..).for_each(|p| {
+            selector.add_peer(p);
+        });
+
+        Self {
+            socket: Arc::new(socket),
+            security: cfg.pool_security(),
+            selector,
+            sessions: Arc::new(Mutex::new(SessionTable::default())),
+            session_timeout: cfg.udp_session_timeout(),
+            quic_cid_length: cfg.quic_connection_id_length(),
+            events: Arc::new(EventLog::new(cfg.event_buffer_size())),
+            connections: Arc::new(ConnectionRegistry::new()),
+            metrics: Arc::new(Metrics::new()),
+            audit: None,
+        }
+    }
+
+    /// Writes every rejected datagram to `audit`.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn events(&self) -> Arc<EventLog> {
+        self.events.clone()
+    }
+
+    pub fn connections(&self) -> Arc<ConnectionRegistry> {
+        self.connections.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.selector.peers()
+    }
+
+    pub async fn run_forever(&mut self) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::error!("udp receive failed: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.forward(&buf[..len], client).await {
+                self.events.record(
+                    EventKind::Error,
+                    format!("error forwarding datagram from {}: {}", client, e),
+                );
+            }
+        }
+    }
+
+    async fn forward(&mut self, packet: &[u8], client: SocketAddr) -> Result<(), io::Error> {
+        let ip = client.ip();
+        if let Err(reason) = self.security.check(&ip) {
+            if let Some(audit) = &self.audit {
+                audit.reject(ip, reason.name(), Some("udp".to_string()));
+            }
+            self.connections.clients().rejected(ip);
+            return Ok(());
+        }
+
+        let cid = self
+            .quic_cid_length
+            .and_then(|len| quic_destination_cid(packet, len));
+
+        let session = match self.find_session(client, cid) {
+            Some(session) => session,
+            None => match self.open_session(client, cid).await? {
+                Some(session) => session,
+                None => return Ok(()),
+            },
+        };
+
+        session.upstream.send(packet).await?;
+        session.connection.transfer().read(Side::A, packet.len());
+        self.metrics
+            .proxied(Direction::FromClient, packet.len() as u64);
+        self.connections
+            .clients()
+            .transferred(ip, (packet.len() as u64, 0));
+        Ok(())
+    }
+
+    fn find_session(&self, client: SocketAddr, cid: Option<&[u8]>) -> Option<Arc<UdpSession>> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        if let Some(session) = cid.and_then(|cid| sessions.by_cid.get(cid)).cloned() {
+            let mut current = session.client.lock().unwrap();
+            if *current != client {
+                // the client migrated or was rebound by a NAT, follow it
+                sessions.by_addr.remove(&current);
+                sessions.by_addr.insert(client, session.clone());
+                *current = client;
+            }
+            drop(current);
+            return Some(session);
+        }
+
+        sessions.by_addr.get(&client).cloned()
+    }
+
+    async fn open_session(
+        &mut self,
+        client: SocketAddr,
+        cid: Option<&[u8]>,
+    ) -> Result<Option<Arc<UdpSession>>, io::Error> {
+        let Some(peer) = self.selector.next() else {
+            return Ok(None);
+        };
+
+        let Some(peer_addr) = peer.socket_addr() else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("peer {} has no socket address", peer.address.as_string()),
+            ));
+        };
+
+        let local: IpAddr = if peer_addr.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let upstream = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        upstream.connect(peer_addr).await?;
+
+        let connection = self.connections.register(client, peer);
+        self.connections.clients().connected(client.ip());
+        let span = tracing::info_span!(
+            "session",
+            id = connection.id(),
+            client = %client,
+            peer = %peer_addr,
+        );
+        let session = Arc::new(UdpSession {
+            client: Mutex::new(client),
+            upstream,
+            connection,
+        });
+        self.metrics.accepted();
+
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.by_addr.insert(client, session.clone());
+            if let Some(cid) = cid {
+                sessions.by_cid.insert(cid.to_vec(), session.clone());
+            }
+        }
+
+        let relay = relay_replies(
+            session.clone(),
+            self.socket.clone(),
+            self.sessions.clone(),
+            self.metrics.clone(),
+            self.connections.clone(),
+            self.session_timeout,
+            self.quic_cid_length.is_some(),
+        );
+        tokio::spawn(relay.instrument(span));
+
+        Ok(Some(session))
+    }
+}
+
+/// Sends upstream replies back to the client until the session has been idle for `idle_timeout`
+/// or is terminated.
+async fn relay_replies(
+    session: Arc<UdpSession>,
+    downstream: Arc<UdpSocket>,
+    sessions: Arc<Mutex<SessionTable>>,
+    metrics: Arc<Metrics>,
+    connections: Arc<ConnectionRegistry>,
+    idle_timeout: Duration,
+    quic: bool,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let received = tokio::select! {
+            received = timeout(idle_timeout, session.upstream.recv(&mut buf)) => received,
+            _ = session.connection.terminated() => {
+                tracing::info!("udp session terminated");
+                break;
+            }
+        };
+        let len = match received {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) => {
+                tracing::error!("udp upstream receive failed: {}", e);
+                break;
+            }
+            Err(_) => break,
+        };
+
+        let packet = &buf[..len];
+        if quic && let Some(scid) = quic_source_cid(packet) {
+            let mut table = sessions.lock().unwrap();
+            if !table.by_cid.contains_key(scid) {
+                table.by_cid.insert(scid.to_vec(), session.clone());
+            }
+        }
+
+        let client = *session.client.lock().unwrap();
+        match downstream.send_to(packet, client).await {
+            Ok(sent) => {
+                session.connection.transfer().read(Side::B, sent);
+                metrics.proxied(Direction::ToClient, sent as u64);
+                connections
+                    .clients()
+                    .transferred(client.ip(), (0, sent as u64));
+            }
+            Err(e) => tracing::error!("udp send to {} failed: {}", client, e),
+        }
+    }
+
+    sessions.lock().unwrap().remove(&session);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_header(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01];
+        packet.push(dcid.len() as u8);
+        packet.extend_from_slice(dcid);
+        packet.push(scid.len() as u8);
+        packet.extend_from_slice(scid);
+        packet.extend_from_slice(&[0u8; 16]);
+        packet
+    }
+
+    #[test]
+    fn test_quic_long_header_cids() {
+        let packet = long_header(&[1, 2, 3, 4, 5, 6, 7, 8], &[9, 9, 9, 9]);
+
+        assert_eq!(
+            quic_destination_cid(&packet, 8),
+            Some(&[1, 2, 3, 4, 5, 6, 7, 8][..])
+        );
+        assert_eq!(quic_source_cid(&packet), Some(&[9, 9, 9, 9][..]));
+    }
+
+    #[test]
+    fn test_quic_short_header_uses_configured_length() {
+        let mut packet = vec![0x41];
+        packet.extend_from_slice(&[7, 7, 7, 7, 7, 7, 7, 7]);
+        packet.extend_from_slice(&[0u8; 20]);
+
+        assert_eq!(quic_destination_cid(&packet, 8), Some(&[7u8; 8][..]));
+        assert_eq!(quic_source_cid(&packet), None);
+    }
+
+    #[test]
+    fn test_non_quic_datagram() {
+        assert_eq!(quic_destination_cid(&[0x00, 0x01, 0x02], 8), None);
+        assert_eq!(quic_destination_cid(&[], 8), None);
+
+        let mut truncated = long_header(&[1, 2, 3, 4], &[5, 6]);
+        truncated.truncate(8);
+        assert_eq!(quic_destination_cid(&truncated, 8), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+use crate::{errors::SpiffeError, tls::TlsPolicy};
+
+/// Client side of TLS to upstream peers. The identity presented can be swapped while running,
+/// connections opened afterwards use the new one and established ones are left alone.
+#[derive(Debug, Clone)]
+pub struct UpstreamTls {
+    config: watch::Receiver<Option<Arc<ClientConfig>>>,
+    /// SNI sent to every peer, their address when unset
+    server_name: Option<ServerName<'static>>,
+    /// protocols offered through ALPN, none when empty
+    alpn: Vec<Vec<u8>>,
+}
+
+impl UpstreamTls {
+    pub fn new(
+        config: watch::Receiver<Option<Arc<ClientConfig>>>,
+        server_name: Option<&str>,
+    ) -> Result<Self, SpiffeError> {
+        let server_name = server_name
+            .map(|name| ServerName::try_from(name.to_string()))
+            .transpose()
+            .map_err(|_| {
+                SpiffeError::InvalidServerName(server_name.unwrap_or_default().to_string())
+            })?;
+
+        Ok(Self {
+            config,
+            server_name,
+            alpn: Vec::new(),
+        })
+    }
+
+    /// Offers `protocols` to peers through ALPN, e.g. `h2` for peers spoken to in HTTP/2.
+    pub fn with_alpn(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        self
+    }
+
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+        upstream: SocketAddr,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let Some(mut config) = self.config.borrow().clone() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no client certificate for upstream tls yet",
+            ));
+        };
+
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| ServerName::IpAddress(upstream.ip().into()));
+        if !self.alpn.is_empty() {
+            Arc::make_mut(&mut config).alpn_protocols = self.alpn.clone();
+        }
+        TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await
+    }
+}
+
+/// Builds a client config presenting `chain` and trusting peers whose certificates chain to
+/// `bundle`, all ASN.1 DER as handed out by the SPIFFE workload API.
+pub fn svid_client_config(
+    chain: &[u8],
+    key: &[u8],
+    bundle: &[u8],
+    policy: &TlsPolicy,
+) -> Result<ClientConfig, SpiffeError> {
+    let chain = split_certificates(chain)?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.to_vec()));
+
+    let mut roots = RootCertStore::empty();
+    for cert in split_certificates(bundle)? {
+        roots.add(cert)?;
+    }
+
+    let provider = Arc::new(policy.crypto_provider());
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| SpiffeError::MalformedSvid(e.to_string()))?;
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(policy.protocol_versions())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(TrustDomainVerifier(verifier)))
+        .with_client_auth_cert(chain, key)?;
+
+    Ok(config)
+}
+
+/// Accepts any peer certificate chaining to the trust domain's bundle. SPIFFE identities live
+/// in a URI SAN rather than a DNS name, so the name the peer was dialed by isn't checked.
+#[derive(Debug)]
+struct TrustDomainVerifier(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for TrustDomainVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // the chain is verified before the name, so a name error means the chain was fine
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::NotValidForName
+                | rustls::CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Splits back-to-back DER certificates by reading each outer SEQUENCE's length.
+fn split_certificates(mut der: &[u8]) -> Result<Vec<CertificateDer<'static>>, SpiffeError> {
+    let malformed = || SpiffeError::MalformedSvid("truncated certificate".to_string());
+    let mut certs = Vec::new();
+
+    while !der.is_empty() {
+        let (&tag, rest) = der.split_first().ok_or_else(malformed)?;
+        let (&first, rest) = rest.split_first().ok_or_else(malformed)?;
+        if tag != 0x30 {
+            return Err(SpiffeError::MalformedSvid(
+                "certificate is not a DER sequence".to_string(),
+            ));
+        }
+
+        // short form holds the length itself, long form the number of length bytes that follow
+        let (len, header) = if first & 0x80 == 0 {
+            (first as usize, 2)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err(malformed());
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, &b| (len << 8) | b as usize);
+            (len, 2 + count)
+        };
+
+        let end = header.checked_add(len).filter(|&end| end <= der.len());
+        let end = end.ok_or_else(malformed)?;
+        certs.push(CertificateDer::from(der[..end].to_vec()));
+        der = &der[end..];
+    }
+
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_certificates() {
+        let short = [0x30, 0x02, 0xaa, 0xbb];
+        let mut long = vec![0x30, 0x81, 0x80];
+        long.extend_from_slice(&[0u8; 0x80]);
+
+        let mut der = short.to_vec();
+        der.extend_from_slice(&long);
+        let certs = split_certificates(&der).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].as_ref(), &short);
+        assert_eq!(certs[1].as_ref(), long.as_slice());
+
+        assert!(split_certificates(&der[..der.len() - 1]).is_err());
+        assert!(split_certificates(&[0x02, 0x01, 0x00]).is_err());
+    }
+}
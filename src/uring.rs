@@ -0,0 +1,198 @@
+use std::{
+    io,
+    net::Shutdown,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_uring::buf::IoBuf;
+
+use crate::relay::{Activity, Side, Transfer, idle_error, idle_watchdog};
+
+static RELAYS: OnceLock<Relays> = OnceLock::new();
+
+/// Threads running a runtime of their own driven by io_uring, each relaying the sessions sent
+/// to it.
+struct Relays {
+    threads: Vec<mpsc::UnboundedSender<Session>>,
+    next: AtomicUsize,
+}
+
+/// A session handed over to an io_uring thread, with where to send how it ended.
+struct Session {
+    a: std::net::TcpStream,
+    b: std::net::TcpStream,
+    idle_timeout: Option<Duration>,
+    transfer: Arc<Transfer>,
+    buffer_size: usize,
+    done: oneshot::Sender<io::Result<(u64, u64)>>,
+}
+
+/// Starts `threads` threads that plain tcp sessions along
+/// [`RelayPath::Uring`](crate::relay::RelayPath::Uring) are relayed on, unless the kernel
+/// doesn't offer io_uring, e.g. when a seccomp profile filters it. Returns whether they were
+/// started.
+pub fn start(threads: usize) -> bool {
+    if let Err(e) = io_uring::IoUring::new(8) {
+        tracing::warn!(
+            "io_uring unavailable, relaying sessions on the main runtime: {}",
+            e
+        );
+        return false;
+    }
+    RELAYS.get_or_init(|| {
+        let threads = (0..threads)
+            .map(|idx| {
+                let (tx, mut sessions) = mpsc::unbounded_channel::<Session>();
+                let spawned = thread::Builder::new()
+                    .name(format!("jalb-uring-{}", idx))
+                    .spawn(move || {
+                        tokio_uring::start(async move {
+                            while let Some(session) = sessions.recv().await {
+                                tokio_uring::spawn(serve(session));
+                            }
+                        })
+                    });
+                if let Err(e) = spawned {
+                    tracing::error!("cannot start io_uring thread {}: {}", idx, e);
+                }
+                tx
+            })
+            .collect();
+        tracing::info!("relaying tcp sessions on io_uring");
+        Relays {
+            threads,
+            next: AtomicUsize::new(0),
+        }
+    });
+    true
+}
+
+/// Whether sessions are relayed on io_uring threads.
+pub fn started() -> bool {
+    RELAYS.get().is_some()
+}
+
+/// Relays between `a` and `b` on the next io_uring thread, like `relay_tracked`. Dropping the
+/// returned future, e.g. when the session times out, closes both sockets.
+pub(crate) async fn relay(
+    a: TcpStream,
+    b: TcpStream,
+    idle_timeout: Option<Duration>,
+    transfer: Arc<Transfer>,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)> {
+    let stopped = || io::Error::other("io_uring relay thread stopped");
+    let relays = RELAYS.get().ok_or_else(stopped)?;
+    let (done, relayed) = oneshot::channel();
+    let session = Session {
+        a: blocking(a)?,
+        b: blocking(b)?,
+        idle_timeout,
+        transfer,
+        buffer_size,
+        done,
+    };
+    let idx = relays.next.fetch_add(1, Ordering::Relaxed) % relays.threads.len();
+    relays.threads[idx].send(session).map_err(|_| stopped())?;
+    relayed.await.unwrap_or_else(|_| Err(stopped()))
+}
+
+/// io_uring waits for blocking sockets itself, non-blocking ones fail with `WouldBlock`.
+fn blocking(stream: TcpStream) -> io::Result<std::net::TcpStream> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+async fn serve(session: Session) {
+    let Session {
+        a,
+        b,
+        idle_timeout,
+        transfer,
+        buffer_size,
+        mut done,
+    } = session;
+    let a = tokio_uring::net::TcpStream::from_std(a);
+    let b = tokio_uring::net::TcpStream::from_std(b);
+
+    transfer.start();
+    let activity = &Activity::new();
+    let transfer = &transfer;
+    let one_way = move |from, to, side| {
+        copy(from, to, buffer_size, move |len| match len {
+            0 => transfer.closed(side),
+            len => {
+                transfer.read(side, len);
+                activity.touch();
+            }
+        })
+    };
+    let session = async { tokio::try_join!(one_way(&a, &b, Side::A), one_way(&b, &a, Side::B)) };
+    let idle = async {
+        match idle_timeout {
+            Some(idle_timeout) => idle_watchdog(activity, idle_timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let relayed = tokio::select! {
+        relayed = session => relayed,
+        _ = idle => Err(idle_error(idle_timeout.unwrap_or_default())),
+        // the session was given up on, dropping the sockets closes them
+        _ = done.closed() => return,
+    };
+    let _ = done.send(relayed);
+}
+
+/// Copies what `from` sends to `to` through a buffer of `buffer_size` until `from` closes,
+/// then shuts down writing on `to`. `read` is told the size of every chunk, and 0 at the end
+/// of file before the shutdown lets `to` see it.
+async fn copy(
+    from: &tokio_uring::net::TcpStream,
+    to: &tokio_uring::net::TcpStream,
+    buffer_size: usize,
+    mut read: impl FnMut(usize),
+) -> io::Result<u64> {
+    let mut buf = Vec::with_capacity(buffer_size);
+    let mut copied = 0;
+    loop {
+        buf.clear();
+        let (received, filled) = from.read(buf).await;
+        let received = received?;
+        if received == 0 {
+            read(0);
+            to.shutdown(Shutdown::Write)?;
+            return Ok(copied);
+        }
+        read(received);
+        let (written, slice) = to.write_all(filled.slice(..received)).await;
+        written?;
+        buf = slice.into_inner();
+        copied += received as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{RelayPath, tests::check_relay_tcp};
+
+    #[tokio::test]
+    async fn test_uring_relay() {
+        // sessions only go to the threads when asked to, whichever test started them
+        if !start(2) {
+            return;
+        }
+        check_relay_tcp(RelayPath::Uring).await;
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, watch};
+use url::Url;
+
+use crate::{
+    events::{EventKind, EventLog, PeerState, TransitionRecord},
+    peer::Peer,
+};
+
+/// What payloads can have filled in, see [`Notification`].
+const VARS: [&str; 9] = [
+    "event",
+    "peer",
+    "backend",
+    "probe",
+    "check",
+    "detail",
+    "healthy",
+    "total",
+    "timestamp",
+];
+
+/// What a webhook can be called for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// one of a peer's checks started failing
+    PeerDown,
+    /// one of a peer's checks passes again
+    PeerUp,
+    /// the last peer of a backend in rotation went down
+    PoolDown,
+    /// a backend without any peer in rotation has one again
+    PoolUp,
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PeerDown => "peer_down",
+            Self::PeerUp => "peer_up",
+            Self::PoolDown => "pool_down",
+            Self::PoolUp => "pool_up",
+        }
+    }
+}
+
+/// `[[webhook]]`: a URL jalb POSTs JSON to when peers or whole backends go down or come back,
+/// so alerts can fire without a metrics pipeline.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: Url,
+    /// all four when left out
+    #[serde(default = "all_events")]
+    pub events: BTreeSet<WebhookEvent>,
+    /// The body, with `{var}`s in its strings filled in from the [`Notification`] and `{{` and
+    /// `}}` for literal braces. The notification itself is sent when left out.
+    pub payload: Option<serde_json::Value>,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn all_events() -> BTreeSet<WebhookEvent> {
+    BTreeSet::from([
+        WebhookEvent::PeerDown,
+        WebhookEvent::PeerUp,
+        WebhookEvent::PoolDown,
+        WebhookEvent::PoolUp,
+    ])
+}
+
+fn default_timeout_seconds() -> u64 {
+    5
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !matches!(self.url.scheme(), "http" | "https") {
+            return Err(format!("{}: url must be http or https", self.url));
+        }
+        if self.events.is_empty() {
+            return Err(format!("{}: events must name at least one event", self.url));
+        }
+        if self.timeout_seconds == 0 {
+            return Err(format!("{}: timeout_seconds must be at least 1", self.url));
+        }
+        if let Some(payload) = &self.payload {
+            let known = |name: &str| VARS.contains(&name).then(String::new);
+            fill(payload, &known).map_err(|e| format!("{}: payload {}", self.url, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// What a webhook is called with, and what its payload's `{var}`s are filled in from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub event: WebhookEvent,
+    /// the peer whose transition it is, for pool events the one that tipped the pool over
+    pub peer: String,
+    pub backend: String,
+    /// `liveness` or `readiness`
+    pub probe: &'static str,
+    pub check: String,
+    /// why the check failed, empty when it passes
+    pub detail: String,
+    /// peers of the backend in rotation, and all of them
+    pub healthy: usize,
+    pub total: usize,
+    /// milliseconds since the unix epoch
+    pub timestamp: u128,
+}
+
+impl Notification {
+    fn var(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "event" => self.event.name().to_string(),
+            "peer" => self.peer.clone(),
+            "backend" => self.backend.clone(),
+            "probe" => self.probe.to_string(),
+            "check" => self.check.clone(),
+            "detail" => self.detail.clone(),
+            "healthy" => self.healthy.to_string(),
+            "total" => self.total.to_string(),
+            "timestamp" => self.timestamp.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// The body to send to `hook`.
+    pub fn payload(&self, hook: &WebhookConfig) -> serde_json::Value {
+        match &hook.payload {
+            Some(payload) => fill(payload, &|name| self.var(name)).expect("validated payload"),
+            None => serde_json::to_value(self).unwrap_or_default(),
+        }
+    }
+}
+
+/// `value` with the `{var}`s in every string in it replaced by `lookup`.
+fn fill(
+    value: &serde_json::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<serde_json::Value, String> {
+    use serde_json::Value;
+
+    Ok(match value {
+        Value::String(template) => Value::String(render(template, lookup)?),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| fill(item, lookup))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, item)| Ok((key.clone(), fill(item, lookup)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+fn render(template: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(idx) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            rendered.push_str(&rest[..1]);
+            rest = after;
+            continue;
+        }
+        if rest.starts_with('}') {
+            return Err("has an unmatched }, write }} for a literal one".to_string());
+        }
+
+        let Some(end) = rest.find('}') else {
+            return Err("has an unterminated {".to_string());
+        };
+        match lookup(&rest[1..end]) {
+            Some(value) => rendered.push_str(&value),
+            None => return Err(format!("{} is not a webhook variable", &rest[..=end])),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Calls `webhooks` on the peer transitions recorded into `events`, for as long as the process
+/// runs. `peers` tells which backends are left without a peer in rotation.
+pub fn spawn_notifier(
+    webhooks: Vec<WebhookConfig>,
+    events: Arc<EventLog>,
+    peers: watch::Receiver<Vec<Arc<Peer>>>,
+) {
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let mut transitions = events.subscribe_transitions();
+    let client = reqwest::Client::new();
+    tokio::spawn(async move {
+        let mut pools_down: HashSet<String> = HashSet::new();
+        loop {
+            let record = match transitions.recv().await {
+                Ok(record) => record,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("webhooks missed {} peer transitions", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for notification in notifications(&record, &peers.borrow(), &mut pools_down) {
+                for hook in webhooks
+                    .iter()
+                    .filter(|hook| hook.events.contains(&notification.event))
+                {
+                    tokio::spawn(deliver(
+                        client.clone(),
+                        hook.clone(),
+                        notification.clone(),
+                        events.clone(),
+                    ));
+                }
+            }
+        }
+    });
+}
+
+/// What `record` calls for: the peer's own transition, and its backend's when that left the
+/// backend without a peer in rotation or gave it one back.
+fn notifications(
+    record: &TransitionRecord,
+    peers: &[Arc<Peer>],
+    pools_down: &mut HashSet<String>,
+) -> Vec<Notification> {
+    let transition = &record.transition;
+    let pool: Vec<&Arc<Peer>> = peers
+        .iter()
+        .filter(|peer| peer.backend == transition.backend)
+        .collect();
+    let healthy = pool
+        .iter()
+        .filter(|peer| peer.is_live() && peer.is_ready())
+        .count();
+
+    let notification = |event| Notification {
+        event,
+        peer: transition.peer.clone(),
+        backend: transition.backend.clone(),
+        probe: transition.probe,
+        check: transition.check.clone(),
+        detail: transition.detail.clone().unwrap_or_default(),
+        healthy,
+        total: pool.len(),
+        timestamp: record.timestamp,
+    };
+
+    let mut notifications = vec![notification(match transition.to {
+        PeerState::Up => WebhookEvent::PeerUp,
+        PeerState::Down => WebhookEvent::PeerDown,
+    })];
+    if healthy == 0 && pools_down.insert(transition.backend.clone()) {
+        notifications.push(notification(WebhookEvent::PoolDown));
+    } else if healthy > 0 && pools_down.remove(&transition.backend) {
+        notifications.push(notification(WebhookEvent::PoolUp));
+    }
+    notifications
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    hook: WebhookConfig,
+    notification: Notification,
+    events: Arc<EventLog>,
+) {
+    let body = notification.payload(&hook).to_string();
+    let sent = client
+        .post(hook.url.clone())
+        .timeout(Duration::from_secs(hook.timeout_seconds))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+
+    if let Err(e) = sent {
+        let message = format!(
+            "webhook {} for {} of {} failed: {}",
+            hook.url,
+            notification.event.name(),
+            notification.peer,
+            e
+        );
+        tracing::warn!("{}", message);
+        events.record(EventKind::Error, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::events::Transition;
+
+    #[tokio::test]
+    async fn test_webhook_on_pool_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let hook: WebhookConfig = toml::from_str(&format!(
+            r#"
+            url = "{}"
+            events = ["pool_down"]
+            payload = {{ text = "{{backend}} lost {{peer}}: {{detail}}", braces = "{{{{}}}}" }}
+            "#,
+            url
+        ))
+        .unwrap();
+        assert!(hook.validate().is_ok());
+        let mut unknown = hook.clone();
+        unknown.payload = Some(serde_json::json!({ "text": "{nope}" }));
+        assert!(unknown.validate().unwrap_err().contains("{nope}"));
+
+        let mut peer = Peer::new("10.0.0.1:80").unwrap();
+        peer.backend = "web".to_string();
+        let peer = Arc::new(peer);
+        let events = Arc::new(EventLog::new(16));
+        spawn_notifier(
+            vec![hook],
+            events.clone(),
+            watch::channel(vec![peer.clone()]).1,
+        );
+
+        peer.set_live(false);
+        events.record_transition(
+            Transition {
+                peer: "10.0.0.1:80".to_string(),
+                backend: "web".to_string(),
+                probe: "liveness",
+                check: "tcp".to_string(),
+                from: PeerState::Up,
+                to: PeerState::Down,
+                detail: Some("connection \"refused\"".to_string()),
+            },
+            "peer 10.0.0.1:80 liveness check failing",
+        );
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"}") {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"), "{}", request);
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "text": "web lost 10.0.0.1:80: connection \"refused\"",
+                "braces": "{}",
+            })
+        );
+    }
+}